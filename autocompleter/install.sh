@@ -22,14 +22,6 @@ if [[ -f "$URL_FILE" ]]; then
     EXISTING_URL=$(cat "$URL_FILE")
 fi
 
-# Ask for API Token
-if [[ -n "$EXISTING_TOKEN" ]]; then
-    read -p "Enter your CMVault API Token or 6-Digit Device Code (Press Enter to keep existing): " INPUT_TOKEN
-    INPUT_TOKEN=${INPUT_TOKEN:-$EXISTING_TOKEN}
-else
-    read -p "Enter your CMVault API Token or 6-Digit Device Code: " INPUT_TOKEN
-fi
-
 # Ask for API URL
 read -p "Enter your CMVault API URL (default: $EXISTING_URL): " API_URL
 API_URL=${API_URL:-$EXISTING_URL}
@@ -37,15 +29,72 @@ API_URL=${API_URL:-$EXISTING_URL}
 echo "$API_URL" > "$URL_FILE"
 chmod 600 "$URL_FILE"
 
-# Check if input is a 6-digit code (simple regex check)
-if [[ "$INPUT_TOKEN" =~ ^[0-9]{6}$ ]]; then
+# Ask for API Token; an empty answer signs in through the browser instead
+if [[ -n "$EXISTING_TOKEN" ]]; then
+    read -p "Enter your CMVault API Token, 'login' to sign in through the browser, or press Enter to keep the existing one: " INPUT_TOKEN
+    INPUT_TOKEN=${INPUT_TOKEN:-$EXISTING_TOKEN}
+else
+    read -p "Enter your CMVault API Token, or press Enter to sign in through the browser: " INPUT_TOKEN
+    INPUT_TOKEN=${INPUT_TOKEN:-login}
+fi
+
+# Extract JSON fields using grep/sed to avoid a jq dependency
+json_string() { echo "$1" | grep -o "\"$2\":\"[^\"]*\"" | sed "s/\"$2\":\"//;s/\"$//"; }
+json_number() { echo "$1" | grep -o "\"$2\":[0-9]*" | sed "s/\"$2\"://"; }
+
+if [[ "$INPUT_TOKEN" == "login" ]]; then
+    # Device authorization flow: approve this terminal from a signed-in browser
+    RESPONSE=$(curl -s -X POST "$API_URL/api/device/authorize" \
+        --data-urlencode "device_name=$(whoami)@$(hostname)" \
+        --data-urlencode "scope=read write")
+    DEVICE_CODE=$(json_string "$RESPONSE" device_code)
+    USER_CODE=$(json_string "$RESPONSE" user_code)
+    VERIFY_URL=$(json_string "$RESPONSE" verification_uri_complete)
+    INTERVAL=$(json_number "$RESPONSE" interval)
+    EXPIRES_IN=$(json_number "$RESPONSE" expires_in)
+
+    if [[ -z "$DEVICE_CODE" ]]; then
+        echo "Error: Failed to start sign-in. Server response: $RESPONSE"
+        exit 1
+    fi
+
+    echo ""
+    echo "Open $VERIFY_URL"
+    echo "and check that it shows the code: $USER_CODE"
+    echo ""
+    echo "Waiting for approval..."
+
+    DEADLINE=$(( $(date +%s) + ${EXPIRES_IN:-600} ))
+    TOKEN=""
+    while [[ -z "$TOKEN" ]]; do
+        sleep "${INTERVAL:-5}"
+        RESPONSE=$(curl -s -X POST "$API_URL/api/device/token" \
+            --data-urlencode "grant_type=urn:ietf:params:oauth:grant-type:device_code" \
+            --data-urlencode "device_code=$DEVICE_CODE")
+        TOKEN=$(json_string "$RESPONSE" access_token)
+        ERROR=$(json_string "$RESPONSE" error)
+
+        case "$ERROR" in
+            "" | authorization_pending) ;;
+            slow_down) INTERVAL=$(( ${INTERVAL:-5} + 5 )) ;;
+            access_denied) echo "Sign-in was denied."; exit 1 ;;
+            *) echo "Error: Sign-in failed. Server response: $RESPONSE"; exit 1 ;;
+        esac
+
+        if [[ -z "$TOKEN" && $(date +%s) -ge $DEADLINE ]]; then
+            echo "Sign-in request expired. Run the installer again."
+            exit 1
+        fi
+    done
+    echo "Successfully authenticated!"
+# Codes created with the web UI's "Device code" button
+elif [[ "$INPUT_TOKEN" =~ ^[A-Za-z]{6}-?[A-Za-z]{6}$ ]]; then
     echo "Exchanging device code for API token..."
     RESPONSE=$(curl -s -X POST "$API_URL/api/exchange-token" \
         -H "Content-Type: application/json" \
         -d "{\"code\": \"$INPUT_TOKEN\"}")
 
-    # Extract token using grep/sed to avoid jq dependency
-    TOKEN=$(echo "$RESPONSE" | grep -o '"token":"[^"]*"' | sed 's/"token":"//;s/"//')
+    TOKEN=$(json_string "$RESPONSE" token)
 
     if [[ -z "$TOKEN" ]]; then
         echo "Error: Failed to exchange code. Server response: $RESPONSE"
@@ -85,6 +134,7 @@ cat << 'EOF' > ~/.config/cmvault/cmvault-plugin.zsh
 # CMVault Zsh Autocomplete (clean version, no logs)
 
 zmodload zsh/system
+zmodload zsh/datetime
 
 CMVAULT_URL_FILE="$HOME/.config/cmvault/url"
 CMVAULT_TOKEN_FILE="$HOME/.config/cmvault/token"
@@ -108,6 +158,11 @@ typeset -g _cm_ipc="${TMPDIR:-/tmp}/cmvault-ipc-$$.txt"
 typeset -g _cm_raw="${TMPDIR:-/tmp}/cmvault-raw-$$.bin"
 typeset -g _cm_rid="${TMPDIR:-/tmp}/cmvault-rid-$$.txt"
 typeset -gi _cm_last_seen=0
+
+typeset -g _cm_learn_cmd=""
+typeset -g _cm_learn_pwd=""
+typeset -g _cm_learn_ls=""
+typeset -gF _cm_learn_start=0
 typeset -g _cm_prev_tmout="${TMOUT-}"
 
 print -r -- 0 >| "$_cm_rid"
@@ -334,20 +389,32 @@ _cmvault_preexec() {
         fi
     fi
 
-    local cmd="$1"
-    local os=$(uname -s)
-    local pwd="$PWD"
+    # Sent from precmd once the exit status and duration are known
+    _cm_learn_cmd="$1"
+    _cm_learn_pwd="$PWD"
+    _cm_learn_start=$EPOCHREALTIME
     # Capture ls output (limit to first 20 lines)
-    local ls_output=$(ls -1 2>/dev/null | head -n 20)
-    ls_output="${ls_output//$'\n'/\\n}"
+    _cm_learn_ls=$(ls -1 2>/dev/null | head -n 20)
+    _cm_learn_ls="${_cm_learn_ls//$'\n'/\\n}"
+}
+
+_cmvault_precmd() {
+    local exit_code=$?
+    [[ -n "$_cm_learn_cmd" ]] || return
+
+    local cmd="$_cm_learn_cmd"
+    _cm_learn_cmd=""
+    local os=$(uname -s)
+    local -i duration_ms=$(( (EPOCHREALTIME - _cm_learn_start) * 1000 ))
     (curl -s -X POST "${CMVAULT_API_URL}/api/learn" \
         -H "Authorization: Bearer ${CMVAULT_TOKEN}" \
         -H "Content-Type: application/json" \
-        -d "{\"executed_command\": \"$cmd\", \"os\": \"$os\", \"pwd\": \"$pwd\", \"ls_output\": \"$ls_output\"}" &) >/dev/null 2>&1
+        -d "{\"executed_command\": \"$cmd\", \"os\": \"$os\", \"pwd\": \"$_cm_learn_pwd\", \"ls_output\": \"$_cm_learn_ls\", \"exit_code\": $exit_code, \"duration_ms\": $duration_ms, \"shell\": \"zsh\", \"hostname\": \"$HOST\"}" &) >/dev/null 2>&1
 }
 
 autoload -U add-zsh-hook
 add-zsh-hook preexec _cmvault_preexec
+add-zsh-hook precmd _cmvault_precmd
 
 EOF
 
@@ -0,0 +1,10 @@
+-- Soft delete: trashed rows keep their data until purged
+ALTER TABLE commands
+    ADD COLUMN IF NOT EXISTS deleted_at TIMESTAMPTZ;
+
+ALTER TABLE learned_commands
+    ADD COLUMN IF NOT EXISTS deleted_at TIMESTAMPTZ;
+
+-- Partial indexes so the background purge and trash listing stay cheap
+CREATE INDEX IF NOT EXISTS idx_commands_deleted_at ON commands (deleted_at) WHERE deleted_at IS NOT NULL;
+CREATE INDEX IF NOT EXISTS idx_learned_commands_deleted_at ON learned_commands (deleted_at) WHERE deleted_at IS NOT NULL;
@@ -0,0 +1,5 @@
+-- Track which command a copy was forked from
+ALTER TABLE commands
+    ADD COLUMN IF NOT EXISTS forked_from UUID REFERENCES commands(id) ON DELETE SET NULL;
+
+CREATE INDEX IF NOT EXISTS idx_commands_forked_from ON commands (forked_from) WHERE forked_from IS NOT NULL;
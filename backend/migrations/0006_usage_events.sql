@@ -0,0 +1,19 @@
+-- Per-command usage aggregates, split by where the use came from
+ALTER TABLE commands
+    ADD COLUMN IF NOT EXISTS copy_count INTEGER NOT NULL DEFAULT 0,
+    ADD COLUMN IF NOT EXISTS run_count INTEGER NOT NULL DEFAULT 0,
+    ADD COLUMN IF NOT EXISTS accept_count INTEGER NOT NULL DEFAULT 0;
+
+-- Raw usage events (copy from the web UI, execution in a shell, accepted suggestion)
+CREATE TABLE IF NOT EXISTS usage_events (
+    id UUID PRIMARY KEY,
+    command_id UUID NOT NULL REFERENCES commands(id) ON DELETE CASCADE,
+    token_id UUID REFERENCES api_tokens(id) ON DELETE SET NULL,
+    source TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS idx_usage_events_command_id ON usage_events (command_id, created_at DESC);
+
+CREATE INDEX IF NOT EXISTS idx_commands_usage_count ON commands (usage_count DESC);
+CREATE INDEX IF NOT EXISTS idx_commands_copy_count ON commands (copy_count DESC);
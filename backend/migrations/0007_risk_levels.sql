@@ -0,0 +1,14 @@
+-- Risk classification of command text ('safe', 'caution', 'dangerous', 'critical').
+-- NULL means not classified yet; the server backfills those rows on startup.
+ALTER TABLE commands
+    ADD COLUMN IF NOT EXISTS risk_level TEXT;
+
+ALTER TABLE learned_commands
+    ADD COLUMN IF NOT EXISTS risk_level TEXT;
+
+-- Per-user preferences
+CREATE TABLE IF NOT EXISTS user_settings (
+    user_id UUID PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+    hide_dangerous_suggestions BOOLEAN NOT NULL DEFAULT FALSE,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
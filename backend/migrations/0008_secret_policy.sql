@@ -0,0 +1,3 @@
+-- How learned commands containing secrets are handled: 'reject', 'redact' or 'mask'
+ALTER TABLE user_settings
+    ADD COLUMN IF NOT EXISTS secret_policy TEXT NOT NULL DEFAULT 'redact';
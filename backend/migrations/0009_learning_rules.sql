@@ -0,0 +1,10 @@
+-- Per-token rules that keep commands out of learned_commands
+CREATE TABLE IF NOT EXISTS learning_rules (
+    id UUID PRIMARY KEY,
+    owner_token UUID NOT NULL REFERENCES api_tokens(id) ON DELETE CASCADE,
+    kind TEXT NOT NULL,
+    pattern TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS learning_rules_owner_idx ON learning_rules(owner_token);
@@ -0,0 +1,17 @@
+-- When a learned entry was promoted to a saved command; promoted entries are never pruned
+ALTER TABLE learned_commands
+    ADD COLUMN IF NOT EXISTS promoted_at TIMESTAMPTZ;
+
+-- Entries promoted before the column existed: the owner has a command with the same text
+UPDATE learned_commands l
+SET promoted_at = c.created_at
+FROM commands c
+WHERE l.promoted_at IS NULL
+  AND c.owner_token = l.owner_token
+  AND c.text = l.content;
+
+-- Per-user retention of learned history; NULL means the limit is not enforced
+ALTER TABLE user_settings
+    ADD COLUMN IF NOT EXISTS learned_max_age_days INTEGER,
+    ADD COLUMN IF NOT EXISTS learned_max_rows INTEGER,
+    ADD COLUMN IF NOT EXISTS learned_min_usage INTEGER;
@@ -0,0 +1,9 @@
+-- Idempotency keys of learn requests already processed, so clients can safely retry
+CREATE TABLE IF NOT EXISTS learn_receipts (
+    owner_token UUID NOT NULL REFERENCES api_tokens(id) ON DELETE CASCADE,
+    idempotency_key TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (owner_token, idempotency_key)
+);
+
+CREATE INDEX IF NOT EXISTS learn_receipts_created_idx ON learn_receipts(created_at);
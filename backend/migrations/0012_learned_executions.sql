@@ -0,0 +1,18 @@
+-- One row per learned execution that reported how it went
+CREATE TABLE IF NOT EXISTS learned_executions (
+    id UUID PRIMARY KEY,
+    learned_id UUID NOT NULL REFERENCES learned_commands(id) ON DELETE CASCADE,
+    exit_code INTEGER,
+    duration_ms BIGINT,
+    shell TEXT,
+    hostname TEXT,
+    pwd TEXT,
+    executed_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS learned_executions_learned_idx ON learned_executions(learned_id);
+
+-- Running totals of successful / failed runs, so suggestions can demote failing commands cheaply
+ALTER TABLE learned_commands
+    ADD COLUMN IF NOT EXISTS success_count INTEGER NOT NULL DEFAULT 0,
+    ADD COLUMN IF NOT EXISTS failure_count INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,4 @@
+-- Indexes backing the per-token aggregates of GET /api/stats
+CREATE INDEX IF NOT EXISTS idx_usage_events_token_created ON usage_events (token_id, created_at);
+CREATE INDEX IF NOT EXISTS idx_learned_commands_owner_last_used ON learned_commands (owner_token, last_used_at);
+CREATE INDEX IF NOT EXISTS idx_learned_executions_executed_at ON learned_executions (executed_at);
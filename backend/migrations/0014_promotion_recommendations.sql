@@ -0,0 +1,3 @@
+-- Learned entries the user does not want recommended for promotion again
+ALTER TABLE learned_commands
+    ADD COLUMN IF NOT EXISTS recommendation_dismissed_at TIMESTAMPTZ;
@@ -0,0 +1,26 @@
+CREATE TABLE IF NOT EXISTS user_passkeys (
+    id UUID PRIMARY KEY,
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    -- base64url credential id, unique across all users
+    credential_id TEXT NOT NULL UNIQUE,
+    name TEXT NOT NULL,
+    -- serialized webauthn-rs Passkey (public key, counter, backup flags)
+    passkey JSONB NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    last_used_at TIMESTAMPTZ
+);
+
+CREATE INDEX IF NOT EXISTS user_passkeys_user_idx ON user_passkeys(user_id);
+
+-- Pending registration and login challenges; rows are consumed when answered
+CREATE TABLE IF NOT EXISTS passkey_ceremonies (
+    id UUID PRIMARY KEY,
+    kind TEXT NOT NULL,
+    user_id UUID NOT NULL,
+    email TEXT,
+    state JSONB NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    expires_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS passkey_ceremonies_expires_idx ON passkey_ceremonies(expires_at);
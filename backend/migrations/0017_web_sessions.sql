@@ -0,0 +1,33 @@
+-- Bearer tokens can be revoked by "log out everywhere" without deleting the data they own
+ALTER TABLE api_tokens ADD COLUMN IF NOT EXISTS revoked_at TIMESTAMPTZ;
+
+-- Cookie sessions of the web UI; each is backed by an API token that owns its data
+CREATE TABLE IF NOT EXISTS web_sessions (
+    id UUID PRIMARY KEY,
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    token_id UUID NOT NULL REFERENCES api_tokens(id) ON DELETE CASCADE,
+    access_token_hash TEXT NOT NULL UNIQUE,
+    access_expires_at TIMESTAMPTZ NOT NULL,
+    csrf_token TEXT NOT NULL,
+    user_agent TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    last_refreshed_at TIMESTAMPTZ,
+    expires_at TIMESTAMPTZ NOT NULL,
+    revoked_at TIMESTAMPTZ,
+    revoked_reason TEXT
+);
+
+CREATE INDEX IF NOT EXISTS web_sessions_user_idx ON web_sessions(user_id);
+
+-- Every refresh token ever issued for a session; used ones are kept to detect reuse
+CREATE TABLE IF NOT EXISTS session_refresh_tokens (
+    token_hash TEXT PRIMARY KEY,
+    session_id UUID NOT NULL REFERENCES web_sessions(id) ON DELETE CASCADE,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    expires_at TIMESTAMPTZ NOT NULL,
+    used_at TIMESTAMPTZ
+);
+
+CREATE INDEX IF NOT EXISTS session_refresh_tokens_session_idx ON session_refresh_tokens(session_id);
+
+ALTER TABLE oidc_login_states ADD COLUMN IF NOT EXISTS session BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,12 @@
+-- Failed login and device-code attempts per client IP and per account
+CREATE TABLE IF NOT EXISTS auth_throttles (
+    scope TEXT NOT NULL,
+    subject TEXT NOT NULL,
+    failures INTEGER NOT NULL DEFAULT 0,
+    last_failure_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    locked_until TIMESTAMPTZ,
+    PRIMARY KEY (scope, subject)
+);
+
+-- Wrong guesses made while a device code was live; it stops working after a few
+ALTER TABLE device_codes ADD COLUMN IF NOT EXISTS failed_attempts INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,22 @@
+-- Terminal-initiated logins (RFC 8628): the terminal polls with device_code while a
+-- signed-in web user approves the request by its user_code
+CREATE TABLE IF NOT EXISTS device_authorizations (
+    id UUID PRIMARY KEY,
+    device_code_hash TEXT NOT NULL UNIQUE,
+    user_code TEXT NOT NULL UNIQUE,
+    device_name TEXT NOT NULL,
+    scopes TEXT[] NOT NULL,
+    requester_ip TEXT,
+    user_agent TEXT,
+    -- 'pending', 'approved', 'denied' or 'redeemed'
+    status TEXT NOT NULL DEFAULT 'pending',
+    user_id UUID REFERENCES users(id) ON DELETE CASCADE,
+    token_id UUID REFERENCES api_tokens(id) ON DELETE CASCADE,
+    poll_interval INTEGER NOT NULL,
+    last_polled_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    expires_at TIMESTAMPTZ NOT NULL,
+    decided_at TIMESTAMPTZ
+);
+
+CREATE INDEX IF NOT EXISTS device_authorizations_expires_idx ON device_authorizations(expires_at);
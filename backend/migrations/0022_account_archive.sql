@@ -0,0 +1,5 @@
+-- Owns public commands that stay published after their author deleted the account.
+-- It is revoked from the start, so nobody can authenticate with it.
+INSERT INTO api_tokens (id, label, token, user_id, created_at, revoked_at)
+VALUES ('00000000-0000-0000-0000-00000000a4c1', 'Archived commands', 'archive-' || md5(random()::text), NULL, now(), now())
+ON CONFLICT (id) DO NOTHING;
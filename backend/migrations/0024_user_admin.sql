@@ -0,0 +1,36 @@
+-- 'user' or 'admin'; admins may use the /api/admin endpoints like the ADMIN_API_TOKEN token
+ALTER TABLE users ADD COLUMN IF NOT EXISTS role TEXT NOT NULL DEFAULT 'user';
+
+-- Blocked accounts cannot log in, and their tokens and sessions stop working until unblocked
+ALTER TABLE users ADD COLUMN IF NOT EXISTS blocked_at TIMESTAMPTZ;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS blocked_reason TEXT;
+
+-- 'password', 'oidc' or 'passkey'; existing accounts are classified by the credentials they have
+ALTER TABLE users ADD COLUMN IF NOT EXISTS registration_method TEXT;
+UPDATE users u
+SET registration_method = CASE
+    WHEN u.password_hash IS NOT NULL THEN 'password'
+    WHEN EXISTS (SELECT 1 FROM user_identities i WHERE i.user_id = u.id) THEN 'oidc'
+    ELSE 'passkey'
+END
+WHERE registration_method IS NULL;
+ALTER TABLE users ALTER COLUMN registration_method SET DEFAULT 'password';
+ALTER TABLE users ALTER COLUMN registration_method SET NOT NULL;
+
+-- Read-only access for support: the bearer value resolves to one of the user's own
+-- tokens, so the admin sees exactly the data that token owns
+CREATE TABLE IF NOT EXISTS impersonation_grants (
+    id UUID PRIMARY KEY,
+    token_hash TEXT NOT NULL UNIQUE,
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    token_id UUID NOT NULL REFERENCES api_tokens(id) ON DELETE CASCADE,
+    -- who asked for it; the admin token has no user
+    granted_by_user_id UUID,
+    granted_by_token_id UUID NOT NULL,
+    reason TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    expires_at TIMESTAMPTZ NOT NULL,
+    revoked_at TIMESTAMPTZ
+);
+
+CREATE INDEX IF NOT EXISTS impersonation_grants_user_idx ON impersonation_grants(user_id);
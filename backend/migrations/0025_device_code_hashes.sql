@@ -0,0 +1,12 @@
+-- Legacy pairing codes become 12 consonants and are stored hashed. Wrong guesses are only
+-- throttled per IP now: counting them against every live code let one client disable
+-- everyone's codes. Live codes last ten minutes, so existing ones are simply dropped.
+DROP TABLE IF EXISTS device_codes;
+
+CREATE TABLE device_codes (
+    code_hash TEXT PRIMARY KEY,
+    token_id UUID NOT NULL REFERENCES api_tokens(id) ON DELETE CASCADE,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    expires_at TIMESTAMPTZ NOT NULL,
+    consumed BOOLEAN NOT NULL DEFAULT FALSE
+);
@@ -0,0 +1,3 @@
+-- Scopes were shown on the approval page but never enforced on the issued token, so
+-- they are gone until tokens can actually be limited
+ALTER TABLE device_authorizations DROP COLUMN IF EXISTS scopes;
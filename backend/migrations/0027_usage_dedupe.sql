@@ -0,0 +1,11 @@
+-- When a caller last had a use of someone else's command counted, so repeated copies of a
+-- public command within a short window count once. `actor` is `token:<id>` or a hashed IP.
+CREATE TABLE IF NOT EXISTS usage_dedupe (
+    actor TEXT NOT NULL,
+    command_id UUID NOT NULL REFERENCES commands(id) ON DELETE CASCADE,
+    source TEXT NOT NULL,
+    counted_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (actor, command_id, source)
+);
+
+CREATE INDEX IF NOT EXISTS usage_dedupe_counted_at_idx ON usage_dedupe(counted_at);
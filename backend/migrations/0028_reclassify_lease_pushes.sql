@@ -0,0 +1,4 @@
+-- `git push --force-with-lease` used to be flagged as a force push; clearing the level
+-- lets the startup backfill classify these rows again
+UPDATE commands SET risk_level = NULL WHERE text LIKE '%--force-with-lease%';
+UPDATE learned_commands SET risk_level = NULL WHERE content LIKE '%--force-with-lease%';
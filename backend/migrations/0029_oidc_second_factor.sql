@@ -0,0 +1,2 @@
+-- OIDC logins are challenged too; the finished login is recorded with the method it started with
+ALTER TABLE login_challenges ADD COLUMN IF NOT EXISTS method TEXT NOT NULL DEFAULT 'password';
@@ -0,0 +1,23 @@
+-- Deleting an account strips the personal data from its audit entries. That is the one
+-- change the log accepts: it must be switched on for the transaction, and may only clear
+-- the request metadata, the details and the target id of an existing entry.
+CREATE OR REPLACE FUNCTION audit_log_append_only() RETURNS trigger AS $$
+BEGIN
+    IF TG_OP = 'UPDATE'
+       AND current_setting('cmvault.audit_anonymize', true) = 'on'
+       AND NEW.id = OLD.id
+       AND NEW.actor_user_id IS NOT DISTINCT FROM OLD.actor_user_id
+       AND NEW.token_id IS NOT DISTINCT FROM OLD.token_id
+       AND NEW.action = OLD.action
+       AND NEW.target_type IS NOT DISTINCT FROM OLD.target_type
+       AND NEW.created_at = OLD.created_at
+       AND (NEW.ip IS NULL OR NEW.ip IS NOT DISTINCT FROM OLD.ip)
+       AND (NEW.user_agent IS NULL OR NEW.user_agent IS NOT DISTINCT FROM OLD.user_agent)
+       AND (NEW.details IS NULL OR NEW.details IS NOT DISTINCT FROM OLD.details)
+       AND (NEW.target_id IS NULL OR NEW.target_id IS NOT DISTINCT FROM OLD.target_id)
+    THEN
+        RETURN NEW;
+    END IF;
+    RAISE EXCEPTION 'audit_log is append-only';
+END;
+$$ LANGUAGE plpgsql;
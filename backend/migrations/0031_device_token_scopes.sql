@@ -0,0 +1,5 @@
+-- Devices ask for 'read' and/or 'write'; the issued token is read-only without 'write'
+ALTER TABLE device_authorizations ADD COLUMN IF NOT EXISTS scopes TEXT[] NOT NULL DEFAULT '{read,write}';
+
+-- Read-only tokens may only make GET, HEAD and OPTIONS requests
+ALTER TABLE api_tokens ADD COLUMN IF NOT EXISTS read_only BOOLEAN NOT NULL DEFAULT FALSE;
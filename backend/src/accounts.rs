@@ -0,0 +1,224 @@
+use serde_json::{Map, Value};
+use sqlx::{PgConnection, PgPool};
+use uuid::Uuid;
+
+use crate::audit;
+use crate::errors::ApiError;
+
+/// Token seeded by migration 0022 that takes over public commands of deleted accounts.
+pub const ARCHIVE_TOKEN_ID: Uuid = Uuid::from_u128(0xa4c1);
+
+/// Sections of the personal-data export; each query takes the user id as `$1`.
+/// Credentials (password hash, token values, TOTP secret, passkey keys) are left out.
+const EXPORT_SECTIONS: &[(&str, &str)] = &[
+    (
+        "settings",
+        "SELECT hide_dangerous_suggestions, secret_policy, learned_max_age_days, learned_max_rows, learned_min_usage, updated_at
+         FROM user_settings WHERE user_id = $1",
+    ),
+    (
+        "api_tokens",
+        "SELECT id, label, created_at, revoked_at FROM api_tokens WHERE user_id = $1 ORDER BY created_at",
+    ),
+    (
+        "commands",
+        "SELECT c.id, c.title, c.text, c.description, c.platform, c.visibility, c.favorite, c.usage_count,
+                c.copy_count, c.run_count, c.accept_count, c.owner_token, c.created_at, c.updated_at,
+                c.last_used_at, c.deleted_at, c.forked_from, c.risk_level,
+                ARRAY(SELECT t.name FROM command_tags ct JOIN tags t ON t.id = ct.tag_id
+                      WHERE ct.command_id = c.id ORDER BY t.name) AS tags
+         FROM commands c JOIN api_tokens o ON o.id = c.owner_token
+         WHERE o.user_id = $1 ORDER BY c.created_at",
+    ),
+    (
+        "tags",
+        "SELECT t.name, t.owner_token FROM tags t JOIN api_tokens o ON o.id = t.owner_token
+         WHERE o.user_id = $1 ORDER BY t.name",
+    ),
+    (
+        "learned_commands",
+        "SELECT l.id, l.content, l.os, l.pwd, l.ls_output, l.owner_token, l.usage_count, l.success_count,
+                l.failure_count, l.risk_level, l.created_at, l.last_used_at, l.promoted_at,
+                l.recommendation_dismissed_at, l.deleted_at,
+                (SELECT coalesce(json_agg(e ORDER BY e.executed_at), '[]') FROM (
+                    SELECT exit_code, duration_ms, shell, hostname, pwd, executed_at
+                    FROM learned_executions WHERE learned_id = l.id) e) AS executions
+         FROM learned_commands l JOIN api_tokens o ON o.id = l.owner_token
+         WHERE o.user_id = $1 ORDER BY l.created_at",
+    ),
+    (
+        "learning_rules",
+        "SELECT r.id, r.kind, r.pattern, r.owner_token, r.created_at
+         FROM learning_rules r JOIN api_tokens o ON o.id = r.owner_token
+         WHERE o.user_id = $1 ORDER BY r.created_at",
+    ),
+    (
+        "usage_events",
+        "SELECT e.command_id, e.source, e.token_id, e.created_at
+         FROM usage_events e JOIN api_tokens o ON o.id = e.token_id
+         WHERE o.user_id = $1 ORDER BY e.created_at",
+    ),
+    (
+        "identities",
+        "SELECT issuer, subject, email, created_at, last_login_at FROM user_identities
+         WHERE user_id = $1 ORDER BY created_at",
+    ),
+    (
+        "passkeys",
+        "SELECT id, name, created_at, last_used_at FROM user_passkeys WHERE user_id = $1 ORDER BY created_at",
+    ),
+    (
+        "web_sessions",
+        "SELECT id, user_agent, created_at, last_refreshed_at, expires_at, revoked_at, revoked_reason
+         FROM web_sessions WHERE user_id = $1 ORDER BY created_at",
+    ),
+    (
+        "device_authorizations",
+        "SELECT device_name, scopes, requester_ip, user_agent, status, created_at, decided_at
+         FROM device_authorizations WHERE user_id = $1 ORDER BY created_at",
+    ),
+    (
+        "audit_events",
+        "SELECT action, token_id, target_type, target_id, ip, user_agent, details, created_at FROM audit_log
+         WHERE actor_user_id = $1 OR (target_type = 'user' AND target_id = $1::text) ORDER BY created_at",
+    ),
+];
+
+/// Everything stored about the account, as one JSON document.
+pub async fn export(pool: &PgPool, user_id: Uuid) -> Result<Value, ApiError> {
+    let account: Option<Value> = sqlx::query_scalar(
+        r#"
+        SELECT row_to_json(a) FROM (
+            SELECT u.id, u.email, u.email_verified_at, u.created_at, u.password_hash IS NOT NULL AS has_password,
+                   u.role, u.registration_method,
+                   t.enabled_at AS two_factor_enabled_at,
+                   (SELECT count(*) FROM user_recovery_codes r WHERE r.user_id = u.id AND r.used_at IS NULL) AS recovery_codes_remaining
+            FROM users u LEFT JOIN user_totp t ON t.user_id = u.id AND t.enabled_at IS NOT NULL
+            WHERE u.id = $1
+        ) a
+        "#,
+    )
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await?;
+    let account = account.ok_or(ApiError::NotFound)?;
+
+    let mut document = Map::new();
+    document.insert(
+        "exported_at".into(),
+        Value::from(chrono::Utc::now().to_rfc3339()),
+    );
+    document.insert("account".into(), account);
+
+    for (name, query) in EXPORT_SECTIONS {
+        let rows: Value = sqlx::query_scalar(&format!(
+            "SELECT coalesce(json_agg(s), '[]') FROM ({query}) s"
+        ))
+        .bind(user_id)
+        .fetch_one(pool)
+        .await?;
+        // Settings are a single row, or absent when the user never changed them
+        let value = if *name == "settings" {
+            rows.get(0).cloned().unwrap_or(Value::Null)
+        } else {
+            rows
+        };
+        document.insert((*name).into(), value);
+    }
+
+    Ok(Value::Object(document))
+}
+
+/// Moves the user's public, non-trashed commands (with their tags) to the archive token,
+/// so they survive the account's deletion. Returns how many were moved.
+pub async fn archive_public_commands(
+    conn: &mut PgConnection,
+    user_id: Uuid,
+) -> Result<u64, ApiError> {
+    let moved: Vec<Uuid> = sqlx::query_scalar(
+        r#"
+        SELECT c.id
+        FROM commands c JOIN api_tokens o ON o.id = c.owner_token
+        WHERE o.user_id = $1 AND c.visibility = 'PUBLIC' AND c.deleted_at IS NULL
+        FOR UPDATE OF c
+        "#,
+    )
+    .bind(user_id)
+    .fetch_all(&mut *conn)
+    .await?;
+    if moved.is_empty() {
+        return Ok(0);
+    }
+
+    // Tags belong to tokens, so recreate the names under the archive token and relink
+    let names: Vec<String> = sqlx::query_scalar(
+        r#"
+        SELECT DISTINCT t.name
+        FROM command_tags ct JOIN tags t ON t.id = ct.tag_id
+        WHERE ct.command_id = ANY($1)
+        "#,
+    )
+    .bind(&moved)
+    .fetch_all(&mut *conn)
+    .await?;
+    for name in &names {
+        sqlx::query(
+            "INSERT INTO tags (id, name, owner_token) VALUES ($1, $2, $3) ON CONFLICT (owner_token, name) DO NOTHING",
+        )
+        .bind(Uuid::new_v4())
+        .bind(name)
+        .bind(ARCHIVE_TOKEN_ID)
+        .execute(&mut *conn)
+        .await?;
+    }
+    sqlx::query(
+        r#"
+        UPDATE command_tags ct
+        SET tag_id = a.id
+        FROM tags t, tags a
+        WHERE ct.tag_id = t.id
+          AND ct.command_id = ANY($1)
+          AND a.owner_token = $2
+          AND a.name = t.name
+        "#,
+    )
+    .bind(&moved)
+    .bind(ARCHIVE_TOKEN_ID)
+    .execute(&mut *conn)
+    .await?;
+
+    let result = sqlx::query("UPDATE commands SET owner_token = $1 WHERE id = ANY($2)")
+        .bind(ARCHIVE_TOKEN_ID)
+        .bind(&moved)
+        .execute(&mut *conn)
+        .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// Deletes the account. Its tokens go first, which cascades to the commands, tags,
+/// learned history, rules and device codes they own; usage events the user recorded on
+/// other people's commands keep their counts but lose the link to the token. Audit
+/// entries stay, stripped of the user's IPs, user agents and email.
+pub async fn delete_user(conn: &mut PgConnection, user_id: Uuid) -> Result<(), ApiError> {
+    let email: String = sqlx::query_scalar("SELECT email FROM users WHERE id = $1")
+        .bind(user_id)
+        .fetch_one(&mut *conn)
+        .await?;
+    audit::anonymize_user(conn, user_id, &email).await?;
+    sqlx::query("DELETE FROM api_tokens WHERE user_id = $1")
+        .bind(user_id)
+        .execute(&mut *conn)
+        .await?;
+    // Ceremonies reference the user without a foreign key (signups create it later)
+    sqlx::query("DELETE FROM passkey_ceremonies WHERE user_id = $1")
+        .bind(user_id)
+        .execute(&mut *conn)
+        .await?;
+    sqlx::query("DELETE FROM users WHERE id = $1")
+        .bind(user_id)
+        .execute(&mut *conn)
+        .await?;
+
+    Ok(())
+}
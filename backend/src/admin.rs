@@ -0,0 +1,192 @@
+use chrono::{DateTime, Duration, Utc};
+use sqlx::{PgConnection, PgPool};
+use uuid::Uuid;
+
+use crate::auth::{hash_token, random_token};
+use crate::errors::ApiError;
+use crate::models::{AdminTokenInfo, AdminUser, AdminUserQuery, ApiToken, Command};
+
+pub const ROLE_USER: &str = "user";
+pub const ROLE_ADMIN: &str = "admin";
+pub const ROLES: &[&str] = &[ROLE_USER, ROLE_ADMIN];
+
+/// How long a read-only impersonation token works.
+pub const IMPERSONATION_MINUTES: i64 = 30;
+
+const USER_COLUMNS: &str = r#"
+    SELECT u.id, u.email, u.role, u.registration_method, u.email_verified_at,
+           EXISTS (SELECT 1 FROM user_totp f WHERE f.user_id = u.id AND f.enabled_at IS NOT NULL) AS two_factor_enabled,
+           u.blocked_at, u.blocked_reason, u.created_at,
+           (SELECT count(*) FROM commands c JOIN api_tokens o ON o.id = c.owner_token
+            WHERE o.user_id = u.id AND c.deleted_at IS NULL) AS command_count,
+           (SELECT count(*) FROM api_tokens o WHERE o.user_id = u.id AND o.revoked_at IS NULL) AS active_token_count
+    FROM users u
+"#;
+
+/// Users matching the filters, oldest first.
+pub async fn list_users(
+    pool: &PgPool,
+    query: &AdminUserQuery,
+    limit: i64,
+    offset: i64,
+) -> Result<(Vec<AdminUser>, i64), ApiError> {
+    let filter = r#"
+        WHERE ($1::text IS NULL OR u.email ILIKE '%' || $1 || '%')
+          AND ($2::text IS NULL OR u.role = $2)
+          AND ($3::text IS NULL OR u.registration_method = $3)
+          AND ($4::boolean IS NULL OR (u.blocked_at IS NOT NULL) = $4)
+    "#;
+
+    let items = sqlx::query_as::<_, AdminUser>(&format!(
+        "{USER_COLUMNS} {filter} ORDER BY u.created_at, u.id LIMIT $5 OFFSET $6"
+    ))
+    .bind(query.q.as_deref())
+    .bind(query.role.as_deref())
+    .bind(query.registration_method.as_deref())
+    .bind(query.blocked)
+    .bind(limit)
+    .bind(offset)
+    .fetch_all(pool)
+    .await?;
+    let total: i64 = sqlx::query_scalar(&format!("SELECT count(*) FROM users u {filter}"))
+        .bind(query.q.as_deref())
+        .bind(query.role.as_deref())
+        .bind(query.registration_method.as_deref())
+        .bind(query.blocked)
+        .fetch_one(pool)
+        .await?;
+
+    Ok((items, total))
+}
+
+pub async fn get_user(pool: &PgPool, user_id: Uuid) -> Result<AdminUser, ApiError> {
+    sqlx::query_as::<_, AdminUser>(&format!("{USER_COLUMNS} WHERE u.id = $1"))
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await?
+        .ok_or(ApiError::NotFound)
+}
+
+/// Every token of the user, revoked ones included, without their secret values.
+pub async fn user_tokens(pool: &PgPool, user_id: Uuid) -> Result<Vec<AdminTokenInfo>, ApiError> {
+    let tokens = sqlx::query_as::<_, AdminTokenInfo>(
+        r#"
+        SELECT t.id, t.label, t.created_at, t.revoked_at,
+               (SELECT count(*) FROM commands c WHERE c.owner_token = t.id AND c.deleted_at IS NULL) AS command_count,
+               (SELECT count(*) FROM learned_commands l WHERE l.owner_token = t.id AND l.deleted_at IS NULL) AS learned_count,
+               (SELECT count(*) FROM web_sessions s
+                WHERE s.token_id = t.id AND s.revoked_at IS NULL AND s.expires_at > now()) AS active_sessions
+        FROM api_tokens t
+        WHERE t.user_id = $1
+        ORDER BY t.created_at DESC
+        "#,
+    )
+    .bind(user_id)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(tokens)
+}
+
+/// Commands owned by any of the user's tokens, newest first.
+pub async fn user_commands(
+    pool: &PgPool,
+    user_id: Uuid,
+    include_deleted: bool,
+    limit: i64,
+    offset: i64,
+) -> Result<(Vec<Command>, i64), ApiError> {
+    let filter = r#"
+        FROM commands c JOIN api_tokens o ON o.id = c.owner_token
+        WHERE o.user_id = $1 AND ($2 OR c.deleted_at IS NULL)
+    "#;
+
+    let items = sqlx::query_as::<_, Command>(&format!(
+        r#"
+        SELECT c.id, c.title, c.text, c.description, c.platform, c.visibility, c.favorite, c.usage_count, c.copy_count,
+               c.run_count, c.accept_count, c.owner_token, c.created_at, c.updated_at, c.last_used_at, c.deleted_at,
+               c.forked_from, c.risk_level
+        {filter}
+        ORDER BY c.created_at DESC
+        LIMIT $3 OFFSET $4
+        "#
+    ))
+    .bind(user_id)
+    .bind(include_deleted)
+    .bind(limit)
+    .bind(offset)
+    .fetch_all(pool)
+    .await?;
+    let total: i64 = sqlx::query_scalar(&format!("SELECT count(*) {filter}"))
+        .bind(user_id)
+        .bind(include_deleted)
+        .fetch_one(pool)
+        .await?;
+
+    Ok((items, total))
+}
+
+/// Issues a read-only bearer value that acts as `token_id`; only its hash is stored.
+pub async fn grant_impersonation(
+    conn: &mut PgConnection,
+    user_id: Uuid,
+    token_id: Uuid,
+    granted_by: &ApiToken,
+    reason: &str,
+) -> Result<(String, DateTime<Utc>), ApiError> {
+    let value = random_token();
+    let expires_at = Utc::now() + Duration::minutes(IMPERSONATION_MINUTES);
+
+    sqlx::query(
+        r#"
+        INSERT INTO impersonation_grants
+            (id, token_hash, user_id, token_id, granted_by_user_id, granted_by_token_id, reason, created_at, expires_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, now(), $8)
+        "#,
+    )
+    .bind(Uuid::new_v4())
+    .bind(hash_token(&value))
+    .bind(user_id)
+    .bind(token_id)
+    .bind(granted_by.user_id)
+    .bind(granted_by.id)
+    .bind(reason)
+    .bind(expires_at)
+    .execute(conn)
+    .await?;
+
+    Ok((value, expires_at))
+}
+
+/// The user token an unexpired impersonation value stands for. Revoked tokens still
+/// resolve, so support can look at data left behind by old logins; the token's own
+/// value is left empty so no handler can ever hand it out.
+pub async fn impersonated_token(pool: &PgPool, value: &str) -> Result<Option<ApiToken>, ApiError> {
+    let token = sqlx::query_as::<_, ApiToken>(
+        r#"
+        SELECT t.id, t.label, '' AS token, t.user_id, t.created_at
+        FROM impersonation_grants g
+        JOIN api_tokens t ON t.id = g.token_id
+        WHERE g.token_hash = $1 AND g.revoked_at IS NULL AND g.expires_at > now()
+        "#,
+    )
+    .bind(hash_token(value))
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(token)
+}
+
+pub async fn revoke_impersonations(pool: &PgPool, user_id: Uuid) -> Result<u64, ApiError> {
+    let result = sqlx::query(
+        r#"
+        UPDATE impersonation_grants SET revoked_at = now()
+        WHERE user_id = $1 AND revoked_at IS NULL AND expires_at > now()
+        "#,
+    )
+    .bind(user_id)
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected())
+}
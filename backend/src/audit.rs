@@ -0,0 +1,368 @@
+use actix_web::{http::header::USER_AGENT, HttpRequest};
+use serde_json::Value;
+use sqlx::{PgConnection, PgExecutor, PgPool};
+use uuid::Uuid;
+
+use crate::errors::ApiError;
+use crate::models::{ApiToken, AuditEntry, AuditQuery};
+use crate::state::AppState;
+use crate::throttle;
+
+/// Everything written to the audit log. The string forms are stored, so never rename them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    AccountRegistered,
+    AccountDeleted,
+    AccountExported,
+    LoginSucceeded,
+    LoginFailed,
+    SecondFactorFailed,
+    LoggedOut,
+    LoggedOutEverywhere,
+    RefreshTokenReused,
+    EmailVerified,
+    PasswordReset,
+    TwoFactorEnabled,
+    TwoFactorDisabled,
+    RecoveryCodesRegenerated,
+    PasskeyAdded,
+    PasskeyRemoved,
+    DeviceCodeCreated,
+    DeviceCodeExchanged,
+    DeviceCodeRejected,
+    DeviceRequestApproved,
+    DeviceRequestDenied,
+    DeviceTokenIssued,
+    CommandCreated,
+    CommandForked,
+    CommandDeleted,
+    CommandRestored,
+    CommandPurged,
+    CommandsBatchUpdated,
+    CommandsImported,
+    LearnedPromoted,
+    LearnedDeleted,
+    LearnedRestored,
+    LearnedPurged,
+    LearnedBatchUpdated,
+    HistoryImported,
+    TrashEmptied,
+    SettingsUpdated,
+    LearningRuleCreated,
+    LearningRuleUpdated,
+    LearningRuleDeleted,
+    LearningRuleMatchesPurged,
+    AdminUserViewed,
+    AdminRoleChanged,
+    AdminUserBlocked,
+    AdminUserUnblocked,
+    AdminTokensRevoked,
+    AdminImpersonationStarted,
+}
+
+/// Actions shown to users as their security history; the rest are data changes.
+const SECURITY_ACTIONS: &[Action] = &[
+    Action::AccountRegistered,
+    Action::AccountDeleted,
+    Action::AccountExported,
+    Action::LoginSucceeded,
+    Action::LoginFailed,
+    Action::SecondFactorFailed,
+    Action::LoggedOut,
+    Action::LoggedOutEverywhere,
+    Action::RefreshTokenReused,
+    Action::EmailVerified,
+    Action::PasswordReset,
+    Action::TwoFactorEnabled,
+    Action::TwoFactorDisabled,
+    Action::RecoveryCodesRegenerated,
+    Action::PasskeyAdded,
+    Action::PasskeyRemoved,
+    Action::DeviceCodeCreated,
+    Action::DeviceCodeExchanged,
+    Action::DeviceRequestApproved,
+    Action::DeviceRequestDenied,
+    Action::DeviceTokenIssued,
+    Action::AdminRoleChanged,
+    Action::AdminUserBlocked,
+    Action::AdminUserUnblocked,
+    Action::AdminTokensRevoked,
+    Action::AdminImpersonationStarted,
+];
+
+impl Action {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Action::AccountRegistered => "account.registered",
+            Action::AccountDeleted => "account.deleted",
+            Action::AccountExported => "account.exported",
+            Action::LoginSucceeded => "login.succeeded",
+            Action::LoginFailed => "login.failed",
+            Action::SecondFactorFailed => "login.second_factor_failed",
+            Action::LoggedOut => "session.logged_out",
+            Action::LoggedOutEverywhere => "session.logged_out_everywhere",
+            Action::RefreshTokenReused => "session.refresh_token_reused",
+            Action::EmailVerified => "email.verified",
+            Action::PasswordReset => "password.reset",
+            Action::TwoFactorEnabled => "two_factor.enabled",
+            Action::TwoFactorDisabled => "two_factor.disabled",
+            Action::RecoveryCodesRegenerated => "two_factor.recovery_codes_regenerated",
+            Action::PasskeyAdded => "passkey.added",
+            Action::PasskeyRemoved => "passkey.removed",
+            Action::DeviceCodeCreated => "device_code.created",
+            Action::DeviceCodeExchanged => "device_code.exchanged",
+            Action::DeviceCodeRejected => "device_code.rejected",
+            Action::DeviceRequestApproved => "device.approved",
+            Action::DeviceRequestDenied => "device.denied",
+            Action::DeviceTokenIssued => "device.token_issued",
+            Action::CommandCreated => "command.created",
+            Action::CommandForked => "command.forked",
+            Action::CommandDeleted => "command.deleted",
+            Action::CommandRestored => "command.restored",
+            Action::CommandPurged => "command.purged",
+            Action::CommandsBatchUpdated => "command.batch_updated",
+            Action::CommandsImported => "command.imported",
+            Action::LearnedPromoted => "learned.promoted",
+            Action::LearnedDeleted => "learned.deleted",
+            Action::LearnedRestored => "learned.restored",
+            Action::LearnedPurged => "learned.purged",
+            Action::LearnedBatchUpdated => "learned.batch_updated",
+            Action::HistoryImported => "learned.history_imported",
+            Action::TrashEmptied => "trash.emptied",
+            Action::SettingsUpdated => "settings.updated",
+            Action::LearningRuleCreated => "learning_rule.created",
+            Action::LearningRuleUpdated => "learning_rule.updated",
+            Action::LearningRuleDeleted => "learning_rule.deleted",
+            Action::LearningRuleMatchesPurged => "learning_rule.matches_purged",
+            Action::AdminUserViewed => "admin.user_viewed",
+            Action::AdminRoleChanged => "admin.role_changed",
+            Action::AdminUserBlocked => "admin.user_blocked",
+            Action::AdminUserUnblocked => "admin.user_unblocked",
+            Action::AdminTokensRevoked => "admin.tokens_revoked",
+            Action::AdminImpersonationStarted => "admin.impersonation_started",
+        }
+    }
+}
+
+/// Who made a request and from where.
+#[derive(Debug, Clone)]
+pub struct Actor {
+    pub user_id: Option<Uuid>,
+    pub token_id: Option<Uuid>,
+    pub ip: String,
+    pub user_agent: Option<String>,
+}
+
+impl Actor {
+    /// An anonymous actor; attach the caller with `token` or `user` once known.
+    pub fn new(req: &HttpRequest, state: &AppState) -> Self {
+        Actor {
+            user_id: None,
+            token_id: None,
+            ip: throttle::client_ip(req, state.trust_proxy_headers),
+            user_agent: req
+                .headers()
+                .get(USER_AGENT)
+                .and_then(|h| h.to_str().ok())
+                .map(str::to_string),
+        }
+    }
+
+    pub fn token(mut self, token: &ApiToken) -> Self {
+        self.user_id = token.user_id;
+        self.token_id = Some(token.id);
+        self
+    }
+
+    pub fn user(mut self, user_id: Uuid, token_id: Option<Uuid>) -> Self {
+        self.user_id = Some(user_id);
+        self.token_id = token_id;
+        self
+    }
+}
+
+/// One audit log entry, recorded with `record`.
+#[derive(Debug)]
+pub struct Event {
+    action: Action,
+    target: Option<(&'static str, String)>,
+    details: Option<Value>,
+}
+
+impl Event {
+    pub fn new(action: Action) -> Self {
+        Event {
+            action,
+            target: None,
+            details: None,
+        }
+    }
+
+    pub fn target(mut self, kind: &'static str, id: impl ToString) -> Self {
+        self.target = Some((kind, id.to_string()));
+        self
+    }
+
+    pub fn details(mut self, details: Value) -> Self {
+        self.details = Some(details);
+        self
+    }
+
+    pub async fn record<'e>(
+        self,
+        executor: impl PgExecutor<'e>,
+        actor: &Actor,
+    ) -> Result<(), ApiError> {
+        let (target_type, target_id) = self.target.unzip();
+        sqlx::query(
+            r#"
+            INSERT INTO audit_log (id, actor_user_id, token_id, action, target_type, target_id, ip, user_agent, details, created_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, now())
+            "#,
+        )
+        .bind(Uuid::new_v4())
+        .bind(actor.user_id)
+        .bind(actor.token_id)
+        .bind(self.action.as_str())
+        .bind(target_type)
+        .bind(target_id)
+        .bind(&actor.ip)
+        .bind(actor.user_agent.as_deref())
+        .bind(self.details)
+        .execute(executor)
+        .await?;
+
+        Ok(())
+    }
+}
+
+/// Strips what identifies a deleted user from the log, keeping the entries themselves:
+/// the IP and user agent of requests they made or that were made anonymously against
+/// their account, the email as a failed-login target, and details that mention the email.
+/// Run it in the deletion's transaction; the log refuses any other kind of update.
+pub async fn anonymize_user(
+    conn: &mut PgConnection,
+    user_id: Uuid,
+    email: &str,
+) -> Result<(), ApiError> {
+    sqlx::query("SELECT set_config('cmvault.audit_anonymize', 'on', true)")
+        .execute(&mut *conn)
+        .await?;
+    sqlx::query(
+        r#"
+        WITH concerned AS (
+            SELECT id,
+                   actor_user_id = $1
+                       OR (actor_user_id IS NULL AND target_type = 'user' AND target_id = $1::text)
+                       OR (target_type = 'email' AND target_id = $2) AS own_request
+            FROM audit_log
+            WHERE actor_user_id = $1
+               OR (target_type = 'user' AND target_id = $1::text)
+               OR (target_type = 'email' AND target_id = $2)
+               OR position($2 IN lower(details::text)) > 0
+        )
+        UPDATE audit_log a
+        SET ip = CASE WHEN c.own_request THEN NULL ELSE a.ip END,
+            user_agent = CASE WHEN c.own_request THEN NULL ELSE a.user_agent END,
+            target_id = CASE WHEN a.target_type = 'email' AND a.target_id = $2 THEN NULL ELSE a.target_id END,
+            details = CASE WHEN position($2 IN lower(a.details::text)) > 0 THEN NULL ELSE a.details END
+        FROM concerned c
+        WHERE a.id = c.id
+        "#,
+    )
+    .bind(user_id)
+    .bind(email.to_lowercase())
+    .execute(&mut *conn)
+    .await?;
+    sqlx::query("SELECT set_config('cmvault.audit_anonymize', 'off', true)")
+        .execute(&mut *conn)
+        .await?;
+
+    Ok(())
+}
+
+/// Security events that concern `user_id`: ones the user caused, and ones aimed at the
+/// account by someone else, like failed logins.
+pub async fn security_events(
+    pool: &PgPool,
+    user_id: Uuid,
+    limit: i64,
+    offset: i64,
+) -> Result<(Vec<AuditEntry>, i64), ApiError> {
+    let actions: Vec<&str> = SECURITY_ACTIONS.iter().map(|a| a.as_str()).collect();
+    let filter = r#"
+        FROM audit_log
+        WHERE (actor_user_id = $1 OR (target_type = 'user' AND target_id = $1::text))
+          AND action = ANY($2)
+    "#;
+
+    let items = sqlx::query_as::<_, AuditEntry>(&format!(
+        "SELECT {COLUMNS} {filter} ORDER BY created_at DESC LIMIT $3 OFFSET $4"
+    ))
+    .bind(user_id)
+    .bind(&actions)
+    .bind(limit)
+    .bind(offset)
+    .fetch_all(pool)
+    .await?;
+    let total: i64 = sqlx::query_scalar(&format!("SELECT count(*) {filter}"))
+        .bind(user_id)
+        .bind(&actions)
+        .fetch_one(pool)
+        .await?;
+
+    Ok((items, total))
+}
+
+/// The whole log, newest first, narrowed by whichever filters are set.
+pub async fn search(
+    pool: &PgPool,
+    query: &AuditQuery,
+    limit: i64,
+    offset: i64,
+) -> Result<(Vec<AuditEntry>, i64), ApiError> {
+    // `action` also matches a prefix ending in a dot, e.g. `login.` or `command.`
+    let filter = r#"
+        FROM audit_log
+        WHERE ($1::uuid IS NULL OR actor_user_id = $1 OR (target_type = 'user' AND target_id = $1::text))
+          AND ($2::uuid IS NULL OR token_id = $2)
+          AND ($3::text IS NULL OR action = $3 OR (right($3, 1) = '.' AND starts_with(action, $3)))
+          AND ($4::text IS NULL OR target_type = $4)
+          AND ($5::text IS NULL OR target_id = $5)
+          AND ($6::text IS NULL OR ip = $6)
+          AND ($7::timestamptz IS NULL OR created_at >= $7)
+          AND ($8::timestamptz IS NULL OR created_at < $8)
+    "#;
+
+    let items = sqlx::query_as::<_, AuditEntry>(&format!(
+        "SELECT {COLUMNS} {filter} ORDER BY created_at DESC LIMIT $9 OFFSET $10"
+    ))
+    .bind(query.user_id)
+    .bind(query.token_id)
+    .bind(query.action.as_deref())
+    .bind(query.target_type.as_deref())
+    .bind(query.target_id.as_deref())
+    .bind(query.ip.as_deref())
+    .bind(query.from)
+    .bind(query.to)
+    .bind(limit)
+    .bind(offset)
+    .fetch_all(pool)
+    .await?;
+    let total: i64 = sqlx::query_scalar(&format!("SELECT count(*) {filter}"))
+        .bind(query.user_id)
+        .bind(query.token_id)
+        .bind(query.action.as_deref())
+        .bind(query.target_type.as_deref())
+        .bind(query.target_id.as_deref())
+        .bind(query.ip.as_deref())
+        .bind(query.from)
+        .bind(query.to)
+        .fetch_one(pool)
+        .await?;
+
+    Ok((items, total))
+}
+
+const COLUMNS: &str =
+    "id, actor_user_id, token_id, action, target_type, target_id, ip, user_agent, details, created_at";
@@ -1,17 +1,26 @@
 use actix_web::http::header::AUTHORIZATION;
-use actix_web::HttpRequest;
-use sqlx::PgPool;
+use actix_web::http::Method;
+use actix_web::{HttpMessage, HttpRequest};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use rand_core::{OsRng, RngCore};
+use sha2::{Digest, Sha256};
+use sqlx::{FromRow, PgPool, Row};
 
+use crate::admin;
 use crate::errors::ApiError;
 use crate::models::ApiToken;
+use crate::sessions;
+use crate::state::AppState;
 
+/// Bearer tokens from the `Authorization` header take precedence; otherwise the web UI's
+/// session cookie is used, which needs the CSRF header on anything but safe methods.
 pub async fn optional_token(
     req: &HttpRequest,
     pool: &PgPool,
 ) -> Result<Option<ApiToken>, ApiError> {
     let header = match req.headers().get(AUTHORIZATION) {
         Some(h) => h.to_str().map_err(|_| ApiError::Unauthorized)?,
-        None => return Ok(None),
+        None => return session_token(req, pool).await,
     };
 
     let token_value = header
@@ -19,14 +28,49 @@ pub async fn optional_token(
         .ok_or(ApiError::Unauthorized)?
         .trim();
 
-    let token = sqlx::query_as::<_, ApiToken>(
-        r#"SELECT id, label, token, user_id, created_at FROM api_tokens WHERE token = $1"#,
+    let row = sqlx::query(
+        r#"
+        SELECT t.id, t.label, t.token, t.user_id, t.created_at, t.read_only
+        FROM api_tokens t
+        LEFT JOIN users u ON u.id = t.user_id
+        WHERE t.token = $1 AND t.revoked_at IS NULL AND u.blocked_at IS NULL
+        "#,
     )
     .bind(token_value)
     .fetch_optional(pool)
     .await?;
+    if let Some(row) = row {
+        // Devices approved without the `write` scope
+        if row.get::<bool, _>("read_only") && !is_safe(req.method()) {
+            return Err(ApiError::Forbidden("this token is read-only".into()));
+        }
+        return Ok(Some(ApiToken::from_row(&row)?));
+    }
 
-    Ok(token)
+    // Support staff acting as a user may look but not touch
+    let impersonated = admin::impersonated_token(pool, token_value).await?;
+    if impersonated.is_some() {
+        if !is_safe(req.method()) {
+            return Err(ApiError::Forbidden("impersonation is read-only".into()));
+        }
+        req.extensions_mut().insert(Impersonated);
+    }
+    Ok(impersonated)
+}
+
+/// Marks requests authenticated through an impersonation grant.
+#[derive(Debug, Clone, Copy)]
+pub struct Impersonated;
+
+/// For exports and security history, which support has no business reading. Call it
+/// after the token was resolved.
+pub fn deny_impersonation(req: &HttpRequest) -> Result<(), ApiError> {
+    if req.extensions().contains::<Impersonated>() {
+        return Err(ApiError::Forbidden(
+            "not available while impersonating".into(),
+        ));
+    }
+    Ok(())
 }
 
 pub async fn require_token(req: &HttpRequest, pool: &PgPool) -> Result<ApiToken, ApiError> {
@@ -35,3 +79,60 @@ pub async fn require_token(req: &HttpRequest, pool: &PgPool) -> Result<ApiToken,
         None => Err(ApiError::Unauthorized),
     }
 }
+
+/// Admin endpoints accept the token seeded from `ADMIN_API_TOKEN` and tokens of users
+/// with the `admin` role.
+pub async fn require_admin(req: &HttpRequest, state: &AppState) -> Result<ApiToken, ApiError> {
+    let token = require_token(req, &state.pool).await?;
+    if token.id == state.admin_token_id {
+        return Ok(token);
+    }
+
+    let role: Option<String> = match token.user_id {
+        Some(user_id) => {
+            sqlx::query_scalar("SELECT role FROM users WHERE id = $1")
+                .bind(user_id)
+                .fetch_optional(&state.pool)
+                .await?
+        }
+        None => None,
+    };
+    if role.as_deref() != Some(admin::ROLE_ADMIN) {
+        return Err(ApiError::Forbidden("admin access required".into()));
+    }
+    Ok(token)
+}
+
+async fn session_token(req: &HttpRequest, pool: &PgPool) -> Result<Option<ApiToken>, ApiError> {
+    let Some(session) = sessions::from_cookie(req, pool).await? else {
+        return Ok(None);
+    };
+
+    if !is_safe(req.method()) {
+        let header = req
+            .headers()
+            .get(sessions::CSRF_HEADER)
+            .and_then(|h| h.to_str().ok());
+        if header != Some(session.csrf_token.as_str()) {
+            return Err(ApiError::Forbidden("missing or invalid CSRF token".into()));
+        }
+    }
+
+    Ok(Some(session.token))
+}
+
+fn is_safe(method: &Method) -> bool {
+    matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS)
+}
+
+/// 32 random bytes, base64url encoded (43 characters, also a valid PKCE verifier).
+pub fn random_token() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+/// How single-use secrets (session and email tokens) are stored, so a database leak does not expose them.
+pub fn hash_token(token: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(token.as_bytes()))
+}
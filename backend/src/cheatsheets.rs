@@ -0,0 +1,322 @@
+use std::fs;
+use std::io::{Cursor, Read};
+use std::path::{Component, Path};
+
+use flate2::read::GzDecoder;
+use serde::{Deserialize, Serialize};
+
+/// Upper bounds that keep a hostile archive from exhausting memory.
+const MAX_FILE_BYTES: u64 = 1024 * 1024;
+const MAX_TOTAL_BYTES: u64 = 64 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CheatsheetSource {
+    Tldr,
+    Navi,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CheatsheetCommand {
+    pub title: Option<String>,
+    pub text: String,
+    pub description: Option<String>,
+    pub platform: String,
+    pub tags: Vec<String>,
+    pub file: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CheatsheetError {
+    pub file: String,
+    pub message: String,
+}
+
+#[derive(Debug, Default)]
+pub struct ParsedCheatsheets {
+    pub commands: Vec<CheatsheetCommand>,
+    pub errors: Vec<CheatsheetError>,
+}
+
+impl ParsedCheatsheets {
+    fn add_file(&mut self, source: CheatsheetSource, path: &str, text: &str, platform: &str) {
+        match source {
+            CheatsheetSource::Tldr => {
+                let platform = tldr_platform(path).unwrap_or(platform);
+                self.commands.extend(parse_tldr(path, text, platform));
+            }
+            CheatsheetSource::Navi => self.commands.extend(parse_navi(path, text, platform)),
+        }
+    }
+
+    fn error(&mut self, file: &str, message: impl Into<String>) {
+        self.errors.push(CheatsheetError {
+            file: file.to_string(),
+            message: message.into(),
+        });
+    }
+}
+
+/// Reads an uploaded `.zip`, `.tar.gz` or a single page/cheat file.
+pub fn from_upload(
+    source: CheatsheetSource,
+    raw: &[u8],
+    default_platform: &str,
+) -> Result<ParsedCheatsheets, String> {
+    let mut parsed = ParsedCheatsheets::default();
+
+    if raw.starts_with(b"PK\x03\x04") {
+        let mut archive = zip::ZipArchive::new(Cursor::new(raw)).map_err(|e| e.to_string())?;
+        let mut total = 0;
+        for i in 0..archive.len() {
+            let mut file = archive.by_index(i).map_err(|e| e.to_string())?;
+            if !file.is_file() || !wanted(source, file.name()) {
+                continue;
+            }
+            let name = file.name().to_string();
+            if let Some(text) = read_limited(&mut file, &name, &mut total, &mut parsed)? {
+                parsed.add_file(source, &name, &text, default_platform);
+            }
+        }
+    } else if raw.starts_with(&[0x1f, 0x8b]) {
+        let mut archive = tar::Archive::new(GzDecoder::new(raw));
+        let mut total = 0;
+        for entry in archive.entries().map_err(|e| e.to_string())? {
+            let mut entry = entry.map_err(|e| e.to_string())?;
+            if !entry.header().entry_type().is_file() {
+                continue;
+            }
+            let name = entry
+                .path()
+                .map_err(|e| e.to_string())?
+                .to_string_lossy()
+                .into_owned();
+            if !wanted(source, &name) {
+                continue;
+            }
+            if let Some(text) = read_limited(&mut entry, &name, &mut total, &mut parsed)? {
+                parsed.add_file(source, &name, &text, default_platform);
+            }
+        }
+    } else {
+        let text = std::str::from_utf8(raw)
+            .map_err(|_| "upload is not a zip, tar.gz or UTF-8 text file")?;
+        parsed.add_file(source, "upload", text, default_platform);
+    }
+
+    Ok(parsed)
+}
+
+/// Reads every matching file below `root` on the server.
+pub fn from_directory(
+    source: CheatsheetSource,
+    root: &Path,
+    default_platform: &str,
+) -> Result<ParsedCheatsheets, String> {
+    let mut parsed = ParsedCheatsheets::default();
+    let mut total = 0;
+    let mut pending = vec![root.to_path_buf()];
+
+    while let Some(dir) = pending.pop() {
+        let entries = fs::read_dir(&dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let Ok(file_type) = entry.file_type() else {
+                continue;
+            };
+            if file_type.is_dir() {
+                pending.push(path);
+                continue;
+            }
+            let relative = path.strip_prefix(root).unwrap_or(&path);
+            let name = relative.to_string_lossy().into_owned();
+            if !file_type.is_file() || !wanted(source, &name) {
+                continue;
+            }
+            let mut file = fs::File::open(&path).map_err(|e| format!("{}: {}", name, e))?;
+            if let Some(text) = read_limited(&mut file, &name, &mut total, &mut parsed)? {
+                parsed.add_file(source, &name, &text, default_platform);
+            }
+        }
+    }
+
+    Ok(parsed)
+}
+
+/// Rejects absolute paths and `..` so a request cannot escape the configured import root.
+pub fn is_safe_relative(path: &Path) -> bool {
+    path.components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+}
+
+fn wanted(source: CheatsheetSource, name: &str) -> bool {
+    match source {
+        // Only the English pages: `pages/<platform>/<command>.md`
+        CheatsheetSource::Tldr => name.ends_with(".md") && tldr_platform(name).is_some(),
+        CheatsheetSource::Navi => name.ends_with(".cheat"),
+    }
+}
+
+fn read_limited(
+    reader: &mut impl Read,
+    name: &str,
+    total: &mut u64,
+    parsed: &mut ParsedCheatsheets,
+) -> Result<Option<String>, String> {
+    let mut buf = Vec::new();
+    reader
+        .take(MAX_FILE_BYTES + 1)
+        .read_to_end(&mut buf)
+        .map_err(|e| format!("{}: {}", name, e))?;
+
+    if buf.len() as u64 > MAX_FILE_BYTES {
+        parsed.error(name, "file too large");
+        return Ok(None);
+    }
+
+    *total += buf.len() as u64;
+    if *total > MAX_TOTAL_BYTES {
+        return Err("archive too large".into());
+    }
+
+    match String::from_utf8(buf) {
+        Ok(text) => Ok(Some(text)),
+        Err(_) => {
+            parsed.error(name, "file is not valid UTF-8");
+            Ok(None)
+        }
+    }
+}
+
+/// Maps `.../pages/<dir>/<page>.md` to the platform names used by `commands.platform`.
+fn tldr_platform(path: &str) -> Option<&'static str> {
+    let mut parts = path.rsplit('/');
+    let _page = parts.next()?;
+    let dir = parts.next()?;
+    if parts.next()? != "pages" {
+        return None;
+    }
+    Some(match dir {
+        "linux" => "Linux",
+        "osx" => "macOS",
+        "windows" => "Windows",
+        "android" => "Android",
+        "common" | "sunos" | "freebsd" | "openbsd" | "netbsd" | "cisco-ios" => "Others",
+        _ => return None,
+    })
+}
+
+/// Parses a tldr page: `# name`, `> summary` lines, then `- description:` / `` `command` `` pairs.
+fn parse_tldr(file: &str, text: &str, platform: &str) -> Vec<CheatsheetCommand> {
+    let mut commands = Vec::new();
+    let mut page: Option<String> = None;
+    let mut summary: Vec<&str> = Vec::new();
+    let mut pending: Option<String> = None;
+
+    for line in text.lines().map(str::trim) {
+        if let Some(name) = line.strip_prefix("# ") {
+            page = Some(name.trim().to_string());
+        } else if let Some(quote) = line.strip_prefix('>') {
+            let quote = quote.trim();
+            if !quote.starts_with("More information") && !quote.starts_with("See also") {
+                summary.push(quote);
+            }
+        } else if let Some(description) = line.strip_prefix("- ") {
+            pending = Some(description.trim_end_matches(':').trim().to_string());
+        } else if line.len() > 1 && line.starts_with('`') && line.ends_with('`') {
+            let command = &line[1..line.len() - 1];
+            if command.trim().is_empty() {
+                continue;
+            }
+            commands.push(CheatsheetCommand {
+                title: pending.take(),
+                text: command.to_string(),
+                description: (!summary.is_empty()).then(|| summary.join(" ")),
+                platform: platform.to_string(),
+                tags: page.iter().cloned().collect(),
+                file: file.to_string(),
+            });
+        }
+    }
+
+    commands
+}
+
+/// Parses a navi cheat: `% tags`, `# description`, command lines, `$ var: source` and `;` comments.
+fn parse_navi(file: &str, text: &str, platform: &str) -> Vec<CheatsheetCommand> {
+    let mut commands = Vec::new();
+    let mut tags: Vec<String> = Vec::new();
+    let mut title: Option<String> = None;
+    let mut body: Vec<&str> = Vec::new();
+
+    let mut flush = |title: &mut Option<String>, body: &mut Vec<&str>, tags: &[String]| {
+        if !body.is_empty() {
+            commands.push(CheatsheetCommand {
+                title: title.take(),
+                text: navi_to_template(&body.join("\n")),
+                description: None,
+                platform: platform.to_string(),
+                tags: tags.to_vec(),
+                file: file.to_string(),
+            });
+        }
+        body.clear();
+    };
+
+    for line in text.lines() {
+        let trimmed = line.trim();
+        if let Some(header) = trimmed.strip_prefix('%') {
+            flush(&mut title, &mut body, &tags);
+            tags = header
+                .split(',')
+                .map(|t| t.trim().to_string())
+                .filter(|t| !t.is_empty())
+                .collect();
+        } else if let Some(description) = trimmed.strip_prefix('#') {
+            flush(&mut title, &mut body, &tags);
+            title = Some(description.trim().to_string());
+        } else if trimmed.is_empty()
+            || trimmed.starts_with('$')
+            || trimmed.starts_with(';')
+            || trimmed.starts_with('@')
+        {
+            // Variable sources, comments and blank lines end the current command
+            flush(&mut title, &mut body, &tags);
+        } else {
+            body.push(line.trim_end());
+        }
+    }
+    flush(&mut title, &mut body, &tags);
+
+    commands
+}
+
+/// Rewrites navi `<variable>` placeholders to the `{{variable}}` form tldr uses.
+fn navi_to_template(command: &str) -> String {
+    let mut out = String::with_capacity(command.len());
+    let mut rest = command;
+
+    while let Some(start) = rest.find('<') {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let name_len = after
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '-'))
+            .unwrap_or(after.len());
+        let is_var = name_len > 0
+            && after[name_len..].starts_with('>')
+            && after.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_');
+
+        if is_var {
+            out.push_str("{{");
+            out.push_str(&after[..name_len]);
+            out.push_str("}}");
+            rest = &after[name_len + 1..];
+        } else {
+            out.push('<');
+            rest = after;
+        }
+    }
+    out.push_str(rest);
+
+    out
+}
@@ -0,0 +1,141 @@
+use std::sync::LazyLock;
+
+use regex::Regex;
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum RiskLevel {
+    Safe,
+    Caution,
+    Dangerous,
+    Critical,
+}
+
+impl RiskLevel {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RiskLevel::Safe => "safe",
+            RiskLevel::Caution => "caution",
+            RiskLevel::Dangerous => "dangerous",
+            RiskLevel::Critical => "critical",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RiskAssessment {
+    pub level: RiskLevel,
+    pub reasons: Vec<&'static str>,
+}
+
+struct Rule {
+    level: RiskLevel,
+    reason: &'static str,
+    pattern: Regex,
+}
+
+fn rule(level: RiskLevel, reason: &'static str, pattern: &str) -> Rule {
+    Rule {
+        level,
+        reason,
+        pattern: Regex::new(pattern).expect("invalid danger rule"),
+    }
+}
+
+static RULES: LazyLock<Vec<Rule>> = LazyLock::new(|| {
+    use RiskLevel::*;
+    vec![
+        rule(
+            Critical,
+            "recursive delete of the root, home or a wildcard",
+            r#"(?i)\brm\s+(-[a-z]*\s+)*-[a-z]*(r[a-z]*f|f[a-z]*r|r)[a-z]*\s+(-[a-z-]+\s+)*(--no-preserve-root\s+)?("?/"?(\s|$|\*)|~/?(\s|$)|\$HOME/?(\s|$)|\*(\s|$))"#,
+        ),
+        rule(
+            Dangerous,
+            "recursive forced delete",
+            r"(?i)\brm\s+(-[a-z]*\s+)*-[a-z]*(r[a-z]*f|f[a-z]*r)\b|\brm\s+.*--recursive\b.*--force\b",
+        ),
+        rule(
+            Critical,
+            "writes directly to a block device",
+            r"\bdd\b.*\bof=/dev/(sd|hd|vd|xvd|nvme|mmcblk|disk)|>\s*/dev/(sd|hd|vd|xvd|nvme|mmcblk|disk)",
+        ),
+        rule(
+            Critical,
+            "formats a filesystem",
+            r"\bmkfs(\.\w+)?\s|\bwipefs\b",
+        ),
+        rule(
+            Critical,
+            "fork bomb",
+            r":\(\)\s*\{\s*:\s*\|\s*:\s*&\s*\}\s*;\s*:",
+        ),
+        rule(
+            Dangerous,
+            "recursive world-writable permissions",
+            r"(?i)\bchmod\s+(-[a-z]*\s+)*-[a-z]*R[a-z]*\s+(0?777|a\+rwx)\b|\bchmod\s+(0?777|a\+rwx)\s+(-[a-z]*\s+)*-[a-z]*R",
+        ),
+        rule(
+            Dangerous,
+            "recursive ownership change from the root",
+            r"(?i)\bchown\s+(-[a-z]*\s+)*-[a-z]*R[a-z]*\s+\S+\s+/(\s|$)",
+        ),
+        rule(
+            Dangerous,
+            "force push rewrites remote history",
+            // `--force-with-lease` refuses to overwrite unseen work, so it does not count
+            r"\bgit\s+push\b.*(\s--force(\s|$)|\s-f(\s|$)|\s\+\S+)",
+        ),
+        rule(
+            Caution,
+            "discards local changes",
+            r"\bgit\s+(reset\s+--hard|clean\s+-[a-z]*f)",
+        ),
+        rule(
+            Critical,
+            "drops a database or schema",
+            r"(?i)\bdrop\s+(database|schema)\b",
+        ),
+        rule(
+            Dangerous,
+            "drops or truncates a table",
+            r"(?i)\b(drop\s+table|truncate(\s+table)?\s+\w)",
+        ),
+        rule(
+            Dangerous,
+            "deletes every row without a WHERE clause",
+            r"(?i)\bdelete\s+from\s+\w+\s*(;|$)",
+        ),
+        rule(
+            Dangerous,
+            "pipes a download straight into a shell",
+            r"\b(curl|wget)\b[^|]*\|\s*(sudo\s+)?(ba|z|da|k)?sh\b",
+        ),
+        rule(
+            Caution,
+            "powers off or reboots the machine",
+            r"\b(shutdown|reboot|halt|poweroff)\b",
+        ),
+        rule(
+            Caution,
+            "kills every process it can",
+            r"\bkill\s+-9\s+-1\b|\bkillall\s+-9\b",
+        ),
+    ]
+});
+
+/// Classifies command text against the built-in rules; the highest matching level wins.
+pub fn classify(text: &str) -> RiskAssessment {
+    let mut level = RiskLevel::Safe;
+    let mut reasons = Vec::new();
+
+    for rule in RULES.iter() {
+        if rule.pattern.is_match(text) {
+            level = level.max(rule.level);
+            reasons.push(rule.reason);
+        }
+    }
+
+    RiskAssessment { level, reasons }
+}
@@ -0,0 +1,157 @@
+use actix_web::{http::StatusCode, HttpResponse, ResponseError};
+use rand::Rng;
+use thiserror::Error;
+
+use crate::errors::ApiError;
+
+/// The `grant_type` terminals poll the token endpoint with.
+pub const GRANT_TYPE: &str = "urn:ietf:params:oauth:grant-type:device_code";
+/// How long the user has to approve a request.
+pub const EXPIRES_IN_SECONDS: i64 = 10 * 60;
+/// Minimum seconds between polls; each `slow_down` adds another 5.
+pub const POLL_INTERVAL_SECONDS: i32 = 5;
+
+/// Scopes a device may ask for, with the description shown when approving. A token
+/// issued without `write` is read-only.
+pub const SCOPES: [(&str, &str); 2] = [
+    ("read", "Search and read your commands and learned history"),
+    (
+        "write",
+        "Add, change and delete commands, and record learned history",
+    ),
+];
+
+/// Consonants only, so codes cannot spell words and are easy to read out (RFC 8628 §6.1).
+const USER_CODE_ALPHABET: &[u8] = b"BCDFGHJKLMNPQRSTVWXZ";
+const USER_CODE_LENGTH: usize = 8;
+/// Legacy pairing codes are guessed without naming an account, so they get more
+/// characters: 20^12 is about 2^52.
+const PAIRING_CODE_LENGTH: usize = 12;
+
+/// Token endpoint errors, answered in the OAuth format terminals expect
+/// (`{"error": "authorization_pending"}`) rather than the usual API error body.
+#[derive(Debug, Error)]
+pub enum DeviceFlowError {
+    #[error("authorization_pending")]
+    AuthorizationPending,
+    #[error("slow_down")]
+    SlowDown,
+    #[error("access_denied")]
+    AccessDenied,
+    #[error("expired_token")]
+    ExpiredToken,
+    #[error("invalid_grant")]
+    InvalidGrant,
+    #[error("invalid_scope")]
+    InvalidScope,
+    #[error("unsupported_grant_type")]
+    UnsupportedGrantType,
+    #[error(transparent)]
+    Api(#[from] ApiError),
+}
+
+impl From<sqlx::Error> for DeviceFlowError {
+    fn from(err: sqlx::Error) -> Self {
+        DeviceFlowError::Api(err.into())
+    }
+}
+
+impl DeviceFlowError {
+    fn description(&self) -> &'static str {
+        match self {
+            DeviceFlowError::AuthorizationPending => "the request has not been approved yet",
+            DeviceFlowError::SlowDown => "polling too fast; wait longer between requests",
+            DeviceFlowError::AccessDenied => "the request was denied",
+            DeviceFlowError::ExpiredToken => "the request expired; start again",
+            DeviceFlowError::InvalidGrant => "unknown or already used device code",
+            DeviceFlowError::InvalidScope => "unknown scope requested",
+            DeviceFlowError::UnsupportedGrantType => "grant_type must be the device code grant",
+            DeviceFlowError::Api(_) => "",
+        }
+    }
+}
+
+impl ResponseError for DeviceFlowError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            DeviceFlowError::Api(err) => err.status_code(),
+            _ => StatusCode::BAD_REQUEST,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            DeviceFlowError::Api(err) => err.error_response(),
+            _ => HttpResponse::build(self.status_code()).json(serde_json::json!({
+                "error": self.to_string(),
+                "error_description": self.description(),
+            })),
+        }
+    }
+}
+
+/// Parses a space-separated `scope` parameter, defaulting to every scope.
+pub fn parse_scopes(scope: Option<&str>) -> Result<Vec<String>, DeviceFlowError> {
+    let requested: Vec<&str> = scope
+        .map(|s| s.split_whitespace().collect())
+        .unwrap_or_default();
+    if requested.is_empty() {
+        return Ok(SCOPES.iter().map(|(name, _)| name.to_string()).collect());
+    }
+
+    let mut scopes = Vec::new();
+    for name in requested {
+        if !SCOPES.iter().any(|(known, _)| *known == name) {
+            return Err(DeviceFlowError::InvalidScope);
+        }
+        if !scopes.iter().any(|s| s == name) {
+            scopes.push(name.to_string());
+        }
+    }
+    Ok(scopes)
+}
+
+pub fn scope_description(name: &str) -> &'static str {
+    SCOPES
+        .iter()
+        .find(|(known, _)| *known == name)
+        .map(|(_, description)| *description)
+        .unwrap_or("")
+}
+
+/// Whether a token issued for these scopes must refuse changes.
+pub fn is_read_only(scopes: &[String]) -> bool {
+    !scopes.iter().any(|s| s == "write")
+}
+
+/// Eight consonants, stored without the separator (e.g. `WDJBMJHT`).
+pub fn generate_user_code() -> String {
+    random_code(USER_CODE_LENGTH)
+}
+
+/// Twelve consonants for `POST /api/device-codes`, shown as `WDJBMJ-HTKSXQ`.
+pub fn generate_pairing_code() -> String {
+    random_code(PAIRING_CODE_LENGTH)
+}
+
+fn random_code(length: usize) -> String {
+    let mut rng = rand::thread_rng();
+    (0..length)
+        .map(|_| USER_CODE_ALPHABET[rng.gen_range(0..USER_CODE_ALPHABET.len())] as char)
+        .collect()
+}
+
+/// Accepts what users type: any case, with or without dashes and spaces.
+pub fn normalize_user_code(input: &str) -> String {
+    input
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_uppercase())
+        .collect()
+}
+
+/// `WDJB-MJHT`, the form shown to users.
+pub fn display_user_code(code: &str) -> String {
+    let (head, tail) = code.split_at(code.len() / 2);
+    format!("{head}-{tail}")
+}
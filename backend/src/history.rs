@@ -0,0 +1,297 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Longest command we are willing to store from an imported history file.
+const MAX_COMMAND_LEN: usize = 10_000;
+
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum HistoryFormat {
+    Zsh,
+    Bash,
+    Fish,
+}
+
+#[derive(Debug, Clone)]
+pub struct HistoryEntry {
+    pub command: String,
+    pub timestamp: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RejectedLine {
+    pub line: usize,
+    pub reason: String,
+}
+
+#[derive(Debug, Default)]
+pub struct ParsedHistory {
+    pub entries: Vec<HistoryEntry>,
+    pub rejected: Vec<RejectedLine>,
+}
+
+impl ParsedHistory {
+    fn push(&mut self, line: usize, command: String, timestamp: Option<DateTime<Utc>>) {
+        let command = command.trim().to_string();
+        if command.is_empty() {
+            return;
+        }
+        if command.len() > MAX_COMMAND_LEN {
+            self.reject(line, "command too long");
+            return;
+        }
+        self.entries.push(HistoryEntry { command, timestamp });
+    }
+
+    fn reject(&mut self, line: usize, reason: &str) {
+        self.rejected.push(RejectedLine {
+            line,
+            reason: reason.to_string(),
+        });
+    }
+}
+
+/// A distinct command from a history file with how often and when it ran.
+#[derive(Debug, Clone)]
+pub struct GroupedEntry {
+    pub command: String,
+    pub count: i32,
+    pub first_seen: Option<DateTime<Utc>>,
+    pub last_seen: Option<DateTime<Utc>>,
+}
+
+/// Collapses repeated commands, keeping first-occurrence order.
+pub fn group(entries: &[HistoryEntry]) -> Vec<GroupedEntry> {
+    let mut index: HashMap<&str, usize> = HashMap::new();
+    let mut grouped: Vec<GroupedEntry> = Vec::new();
+
+    for entry in entries {
+        let slot = *index.entry(entry.command.as_str()).or_insert_with(|| {
+            grouped.push(GroupedEntry {
+                command: entry.command.clone(),
+                count: 0,
+                first_seen: None,
+                last_seen: None,
+            });
+            grouped.len() - 1
+        });
+
+        let group = &mut grouped[slot];
+        group.count += 1;
+        if let Some(ts) = entry.timestamp {
+            group.first_seen = Some(group.first_seen.map_or(ts, |first| first.min(ts)));
+            group.last_seen = Some(group.last_seen.map_or(ts, |last| last.max(ts)));
+        }
+    }
+
+    grouped
+}
+
+pub fn parse(format: HistoryFormat, raw: &[u8]) -> ParsedHistory {
+    match format {
+        HistoryFormat::Zsh => parse_zsh(&String::from_utf8_lossy(&unmetafy(raw))),
+        HistoryFormat::Bash => parse_bash(&String::from_utf8_lossy(raw)),
+        HistoryFormat::Fish => parse_fish(&String::from_utf8_lossy(raw)),
+    }
+}
+
+/// zsh writes non-ASCII bytes "metafied": 0x83 followed by the byte XOR 32.
+fn unmetafy(raw: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(raw.len());
+    let mut bytes = raw.iter();
+    while let Some(&b) = bytes.next() {
+        if b == 0x83 {
+            if let Some(&next) = bytes.next() {
+                out.push(next ^ 32);
+            }
+        } else {
+            out.push(b);
+        }
+    }
+    out
+}
+
+/// Parses both plain and `EXTENDED_HISTORY` (`: <start>:<elapsed>;<command>`) files.
+/// Multi-line commands are stored with a trailing backslash before each embedded newline.
+/// A line starting with `: ` but without a `;` is a plain `:` command, not metadata.
+fn parse_zsh(input: &str) -> ParsedHistory {
+    let mut parsed = ParsedHistory::default();
+    let mut lines = input.lines().enumerate();
+
+    while let Some((idx, line)) = lines.next() {
+        let line_no = idx + 1;
+        let extended = line
+            .strip_prefix(": ")
+            .and_then(|rest| rest.split_once(';'));
+        let (timestamp, first) = match extended {
+            Some((meta, command)) => {
+                let start = meta.split(':').next().unwrap_or_default().trim();
+                match start.parse::<i64>().ok().and_then(from_unix) {
+                    Some(ts) => (Some(ts), command),
+                    None => {
+                        parsed.reject(line_no, "invalid extended history timestamp");
+                        skip_continuations(line, &mut lines);
+                        continue;
+                    }
+                }
+            }
+            None => (None, line),
+        };
+
+        let mut command = String::from(first);
+        let mut current = line;
+        while current.ends_with('\\') {
+            match lines.next() {
+                Some((_, next)) => {
+                    command.pop();
+                    command.push('\n');
+                    command.push_str(next);
+                    current = next;
+                }
+                None => break,
+            }
+        }
+
+        parsed.push(line_no, command, timestamp);
+    }
+
+    parsed
+}
+
+fn skip_continuations<'a>(
+    mut current: &'a str,
+    lines: &mut impl Iterator<Item = (usize, &'a str)>,
+) {
+    while current.ends_with('\\') {
+        match lines.next() {
+            Some((_, next)) => current = next,
+            None => break,
+        }
+    }
+}
+
+/// Parses bash history, optionally written with `HISTTIMEFORMAT` (`#<unix>` comment lines).
+/// When timestamps are present every line up to the next marker belongs to the same
+/// command, which is how bash stores multi-line entries with `lithist`.
+fn parse_bash(input: &str) -> ParsedHistory {
+    let mut parsed = ParsedHistory::default();
+    let has_timestamps = input.lines().any(|l| bash_timestamp(l).is_some());
+
+    if !has_timestamps {
+        for (idx, line) in input.lines().enumerate() {
+            parsed.push(idx + 1, line.to_string(), None);
+        }
+        return parsed;
+    }
+
+    struct Pending<'a> {
+        start: usize,
+        timestamp: Option<DateTime<Utc>>,
+        body: Vec<&'a str>,
+    }
+
+    let mut current: Option<Pending> = None;
+    for (idx, line) in input.lines().enumerate() {
+        let line_no = idx + 1;
+        if let Some(ts) = bash_timestamp(line) {
+            if let Some(done) = current.take() {
+                parsed.push(done.start, done.body.join("\n"), done.timestamp);
+            }
+            if ts.is_none() {
+                parsed.reject(line_no, "invalid timestamp");
+            }
+            current = Some(Pending {
+                start: line_no + 1,
+                timestamp: ts,
+                body: Vec::new(),
+            });
+            continue;
+        }
+
+        match current.as_mut() {
+            Some(pending) => pending.body.push(line),
+            // Lines before the first timestamp marker were written without HISTTIMEFORMAT
+            None => parsed.push(line_no, line.to_string(), None),
+        }
+    }
+
+    if let Some(done) = current {
+        parsed.push(done.start, done.body.join("\n"), done.timestamp);
+    }
+
+    parsed
+}
+
+/// Returns `Some(_)` for a `#<digits>` marker line, with `None` inside when it is out of range.
+fn bash_timestamp(line: &str) -> Option<Option<DateTime<Utc>>> {
+    let digits = line.strip_prefix('#')?;
+    if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    Some(digits.parse::<i64>().ok().and_then(from_unix))
+}
+
+/// Parses fish's YAML-like `fish_history` file.
+fn parse_fish(input: &str) -> ParsedHistory {
+    let mut parsed = ParsedHistory::default();
+    let mut current: Option<(usize, String, Option<DateTime<Utc>>)> = None;
+
+    for (idx, line) in input.lines().enumerate() {
+        let line_no = idx + 1;
+        if let Some(cmd) = line.strip_prefix("- cmd: ") {
+            if let Some((start, command, timestamp)) = current.take() {
+                parsed.push(start, command, timestamp);
+            }
+            current = Some((line_no, fish_unescape(cmd), None));
+        } else if let Some(when) = line.trim_start().strip_prefix("when: ") {
+            match current.as_mut() {
+                Some((_, _, timestamp)) => {
+                    match when.trim().parse::<i64>().ok().and_then(from_unix) {
+                        Some(ts) => *timestamp = Some(ts),
+                        None => parsed.reject(line_no, "invalid timestamp"),
+                    }
+                }
+                None => parsed.reject(line_no, "timestamp without command"),
+            }
+        } else if line.starts_with(' ') || line.trim().is_empty() {
+            // `paths:` lists and other per-entry metadata
+        } else {
+            parsed.reject(line_no, "unrecognised fish history line");
+        }
+    }
+
+    if let Some((start, command, timestamp)) = current {
+        parsed.push(start, command, timestamp);
+    }
+
+    parsed
+}
+
+fn fish_unescape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next() {
+                Some('n') => out.push('\n'),
+                Some('\\') => out.push('\\'),
+                Some(other) => {
+                    out.push('\\');
+                    out.push(other);
+                }
+                None => out.push('\\'),
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+/// Timestamps from the future (a wrong clock on the machine that wrote the file) count as now.
+fn from_unix(secs: i64) -> Option<DateTime<Utc>> {
+    let ts = Utc.timestamp_opt(secs, 0).single()?;
+    Some(ts.min(Utc::now()))
+}
@@ -1,13 +1,38 @@
+mod accounts;
+mod admin;
+mod audit;
 mod auth;
+mod cheatsheets;
+mod danger;
+mod device_flow;
 mod errors;
+mod history;
+mod mailer;
 mod models;
+mod oidc;
+mod passkeys;
+mod privacy;
+mod recommend;
+mod retention;
 mod routes;
+mod secrets;
+mod sessions;
 mod state;
+mod stats;
+mod tasks;
+mod throttle;
+mod two_factor;
+mod vault;
+mod verification;
 
-use std::env;
+use std::{env, path::PathBuf};
 
 use actix_cors::Cors;
-use actix_web::{middleware::Logger, web::Data, App, HttpServer};
+use actix_web::{
+    middleware::Logger,
+    web::{self, Data},
+    App, HttpServer,
+};
 use dotenvy::dotenv;
 use log::info;
 use sqlx::{postgres::PgPoolOptions, PgPool};
@@ -24,6 +49,16 @@ async fn main() -> std::io::Result<()> {
         env::var("DATABASE_URL").expect("DATABASE_URL must be set (postgres connection string)");
     let bind_address = env::var("BIND_ADDRESS").unwrap_or_else(|_| "0.0.0.0:8080".to_string());
     let admin_token = env::var("ADMIN_API_TOKEN").unwrap_or_else(|_| Uuid::new_v4().to_string());
+    let trash_retention_days = env::var("TRASH_RETENTION_DAYS")
+        .ok()
+        .and_then(|v| v.parse::<i64>().ok())
+        .unwrap_or(30);
+    let require_verified_email = env::var("REQUIRE_VERIFIED_EMAIL")
+        .map(|v| matches!(v.trim(), "true" | "1" | "yes"))
+        .unwrap_or(false);
+    let trust_proxy_headers = env::var("TRUST_PROXY_HEADERS")
+        .map(|v| matches!(v.trim(), "true" | "1" | "yes"))
+        .unwrap_or(false);
 
     let pool = PgPoolOptions::new()
         .max_connections(5)
@@ -36,64 +71,175 @@ async fn main() -> std::io::Result<()> {
         .await
         .expect("failed to run migrations");
 
-    ensure_admin_token(&pool, &admin_token)
+    let admin_token_id = ensure_admin_token(&pool, &admin_token)
         .await
         .expect("failed to ensure admin token");
 
-    let state = Data::new(AppState { pool });
+    tasks::spawn_risk_backfill(pool.clone());
+    tasks::spawn_retention_prune(pool.clone());
+    tasks::spawn_cleanup(pool.clone(), chrono::Duration::days(trash_retention_days));
+
+    let cheatsheet_dir = env::var("CHEATSHEET_IMPORT_DIR").ok().map(PathBuf::from);
+    let oidc = oidc::OidcConfig::from_env().map(|config| {
+        info!("OIDC login enabled for issuer {}", config.issuer);
+        oidc::OidcClient::new(config)
+    });
+    let passkeys = passkeys::from_env();
+    if passkeys.is_some() {
+        info!("Passkey login enabled");
+    }
+    // Browsers only send session cookies cross-origin when CORS allows credentials,
+    // which must never be combined with "any origin"
+    let web_origin = env::var("WEB_ORIGIN").ok().filter(|v| !v.trim().is_empty());
+
+    let state = Data::new(AppState {
+        pool,
+        cheatsheet_dir,
+        oidc,
+        passkeys,
+        sessions: sessions::SessionConfig::from_env(),
+        mailer: mailer::Mailer::from_env(),
+        web_url: web_origin
+            .as_deref()
+            .unwrap_or("http://localhost:5173")
+            .trim_end_matches('/')
+            .to_string(),
+        require_verified_email,
+        trust_proxy_headers,
+        admin_token_id,
+    });
 
     info!("Starting server on {}", bind_address);
 
     HttpServer::new(move || {
-        let cors = Cors::default()
-            .allow_any_origin()
-            .allow_any_method()
-            .allow_any_header();
+        let cors = match &web_origin {
+            Some(origin) => Cors::default()
+                .allowed_origin(origin.trim_end_matches('/'))
+                .supports_credentials(),
+            None => Cors::default().allow_any_origin(),
+        }
+        .allow_any_method()
+        .allow_any_header();
 
         App::new()
             .wrap(Logger::default())
             .wrap(cors)
             .app_data(state.clone())
+            // History imports upload whole shell history files
+            .app_data(web::PayloadConfig::new(16 * 1024 * 1024))
             .service(routes::health)
             .service(routes::list_commands)
             .service(routes::create_command)
             .service(routes::delete_command)
+            .service(routes::fork_command)
+            .service(routes::record_usage_event)
             .service(routes::suggest_commands)
+            .service(routes::get_stats)
+            .service(routes::get_settings)
+            .service(routes::update_settings)
+            .service(routes::list_learning_rules)
+            .service(routes::create_learning_rule)
+            .service(routes::purge_learning_rule_matches)
+            .service(routes::update_learning_rule)
+            .service(routes::delete_learning_rule)
             .service(routes::learn_command)
+            .service(routes::learn_batch)
+            .service(routes::import_history)
             .service(routes::list_learned)
             .service(routes::delete_learned)
+            .service(routes::preview_retention)
+            .service(routes::list_recommendations)
+            .service(routes::accept_recommendation)
+            .service(routes::dismiss_recommendation)
             .service(routes::promote_learned)
+            .service(routes::export_vault)
+            .service(routes::import_vault)
+            .service(routes::import_cheatsheets)
+            .service(routes::batch_commands)
+            .service(routes::batch_learned)
+            .service(routes::list_trashed_commands)
+            .service(routes::restore_command)
+            .service(routes::purge_command)
+            .service(routes::list_trashed_learned)
+            .service(routes::restore_learned)
+            .service(routes::purge_learned)
+            .service(routes::empty_trash)
             .service(routes::create_device_code)
             .service(routes::exchange_token)
+            .service(routes::start_device_authorization)
+            .service(routes::poll_device_token)
+            .service(routes::get_device_request)
+            .service(routes::approve_device_request)
+            .service(routes::deny_device_request)
             .service(routes::register_user)
             .service(routes::login_user)
+            .service(routes::login_second_factor)
+            .service(routes::oidc_authorize)
+            .service(routes::oidc_callback)
+            .service(routes::start_passkey_registration)
+            .service(routes::finish_passkey_registration)
+            .service(routes::start_passkey_login)
+            .service(routes::finish_passkey_login)
+            .service(routes::list_passkeys)
+            .service(routes::get_session)
+            .service(routes::refresh_session)
+            .service(routes::logout_session)
+            .service(routes::logout_everywhere)
+            .service(routes::delete_passkey)
+            .service(routes::get_account)
+            .service(routes::request_email_verification)
+            .service(routes::verify_email)
+            .service(routes::forgot_password)
+            .service(routes::reset_password)
+            .service(routes::get_two_factor)
+            .service(routes::start_totp_enrollment)
+            .service(routes::confirm_totp_enrollment)
+            .service(routes::regenerate_recovery_codes)
+            .service(routes::disable_two_factor)
+            .service(routes::delete_account)
+            .service(routes::export_account)
+            .service(routes::list_security_events)
+            .service(routes::search_audit_log)
+            .service(routes::admin_list_users)
+            .service(routes::admin_get_user)
+            .service(routes::admin_user_commands)
+            .service(routes::admin_user_tokens)
+            .service(routes::admin_set_role)
+            .service(routes::admin_block_user)
+            .service(routes::admin_unblock_user)
+            .service(routes::admin_revoke_user_tokens)
+            .service(routes::admin_impersonate_user)
     })
     .bind(bind_address)?
     .run()
     .await
 }
 
-async fn ensure_admin_token(pool: &PgPool, token: &str) -> Result<(), sqlx::Error> {
-    let existing = sqlx::query(r#"SELECT id FROM api_tokens WHERE token = $1 LIMIT 1"#)
-        .bind(token)
-        .fetch_optional(pool)
-        .await?;
-
-    if existing.is_none() {
-        sqlx::query(
-            r#"
-            INSERT INTO api_tokens (id, label, token, user_id, created_at)
-            VALUES ($1, $2, $3, NULL, now())
-            "#,
-        )
-        .bind(Uuid::new_v4())
-        .bind("Default Admin")
-        .bind(token)
-        .execute(pool)
-        .await?;
-
-        info!("Seeded default API token");
+async fn ensure_admin_token(pool: &PgPool, token: &str) -> Result<Uuid, sqlx::Error> {
+    let existing: Option<Uuid> =
+        sqlx::query_scalar(r#"SELECT id FROM api_tokens WHERE token = $1 LIMIT 1"#)
+            .bind(token)
+            .fetch_optional(pool)
+            .await?;
+
+    if let Some(id) = existing {
+        return Ok(id);
     }
 
-    Ok(())
+    let id = Uuid::new_v4();
+    sqlx::query(
+        r#"
+        INSERT INTO api_tokens (id, label, token, user_id, created_at)
+        VALUES ($1, $2, $3, NULL, now())
+        "#,
+    )
+    .bind(id)
+    .bind("Default Admin")
+    .bind(token)
+    .execute(pool)
+    .await?;
+
+    info!("Seeded default API token");
+
+    Ok(id)
 }
@@ -1,6 +1,7 @@
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
+use webauthn_rs::prelude::{PublicKeyCredential, RegisterPublicKeyCredential};
 
 #[derive(Debug, Clone, Serialize, sqlx::FromRow)]
 pub struct ApiToken {
@@ -21,10 +22,16 @@ pub struct Command {
     pub visibility: String,
     pub favorite: bool,
     pub usage_count: i32,
+    pub copy_count: i32,
+    pub run_count: i32,
+    pub accept_count: i32,
     pub owner_token: Uuid,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
     pub last_used_at: Option<DateTime<Utc>>,
+    pub deleted_at: Option<DateTime<Utc>>,
+    pub forked_from: Option<Uuid>,
+    pub risk_level: Option<String>,
 }
 
 #[derive(Debug, Serialize)]
@@ -37,9 +44,16 @@ pub struct CommandWithTags {
     pub visibility: String,
     pub favorite: bool,
     pub usage_count: i32,
+    pub copy_count: i32,
+    pub run_count: i32,
+    pub accept_count: i32,
     pub tags: Vec<String>,
     pub created_at: DateTime<Utc>,
     pub last_used_at: Option<DateTime<Utc>>,
+    pub deleted_at: Option<DateTime<Utc>>,
+    pub forked_from: Option<Uuid>,
+    pub fork_count: i64,
+    pub risk_level: Option<String>,
 }
 
 #[derive(Debug, Serialize)]
@@ -74,7 +88,18 @@ pub struct PromotePayload {
 pub struct SuggestRequest {
     pub query: String,
     pub os: Option<String>,
+    // Sent by the shell plugin but not used for ranking yet
+    #[allow(dead_code)]
     pub pwd: Option<String>,
+    /// Return objects with risk metadata instead of plain strings.
+    pub include_metadata: Option<bool>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Suggestion {
+    pub text: String,
+    pub risk_level: crate::danger::RiskLevel,
+    pub risk_reasons: Vec<&'static str>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -83,6 +108,42 @@ pub struct LearnRequest {
     pub os: Option<String>,
     pub pwd: Option<String>,
     pub ls_output: Option<String>,
+    pub exit_code: Option<i32>,
+    pub duration_ms: Option<i64>,
+    /// Shell name, e.g. `zsh`.
+    pub shell: Option<String>,
+    pub hostname: Option<String>,
+    /// When the command ran on the client; defaults to the time the server receives it.
+    pub executed_at: Option<DateTime<Utc>>,
+    /// Client-chosen key; a request carrying a key that was already processed is skipped.
+    pub idempotency_key: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LearnBatchRequest {
+    pub items: Vec<LearnRequest>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LearnItemResult {
+    pub index: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub idempotency_key: Option<String>,
+    pub status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rule_id: Option<Uuid>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub redactions: Vec<crate::secrets::SecretFinding>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct LearnBatchResponse {
+    pub results: Vec<LearnItemResult>,
+    pub stored: usize,
+    pub skipped: usize,
+    pub failed: usize,
 }
 
 #[derive(Debug, Serialize, sqlx::FromRow)]
@@ -96,6 +157,17 @@ pub struct LearnedCommand {
     pub usage_count: i32,
     pub created_at: DateTime<Utc>,
     pub last_used_at: Option<DateTime<Utc>>,
+    pub deleted_at: Option<DateTime<Utc>>,
+    pub risk_level: Option<String>,
+    pub promoted_at: Option<DateTime<Utc>>,
+    pub success_count: i32,
+    pub failure_count: i32,
+    /// Share of reported runs that exited 0; only filled in by `list_learned`.
+    #[sqlx(default)]
+    pub success_rate: Option<f64>,
+    /// Only filled in by `list_learned`.
+    #[sqlx(default)]
+    pub median_duration_ms: Option<f64>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -114,11 +186,70 @@ pub struct DeviceCodeResponse {
     pub expires_at: DateTime<Utc>,
 }
 
+/// Form body of `POST /api/device/authorize`, sent by the terminal.
+#[derive(Debug, Default, Deserialize)]
+pub struct DeviceAuthorizationRequest {
+    /// Shown to the user when approving, and used as the token label.
+    pub device_name: Option<String>,
+    /// Space-separated `read` and/or `write`; defaults to both.
+    pub scope: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeviceAuthorizationResponse {
+    pub device_code: String,
+    pub user_code: String,
+    pub verification_uri: String,
+    pub verification_uri_complete: String,
+    pub expires_in: i64,
+    pub interval: i32,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeviceTokenRequest {
+    pub grant_type: String,
+    pub device_code: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeviceTokenResponse {
+    pub access_token: String,
+    pub token_type: String,
+    pub scope: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeviceScope {
+    pub name: String,
+    pub description: String,
+}
+
+/// A pending request as shown on the approval page.
+#[derive(Debug, Serialize)]
+pub struct DeviceRequestInfo {
+    pub user_code: String,
+    pub device_name: String,
+    pub scopes: Vec<DeviceScope>,
+    pub requester_ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct DeviceApproval {
+    /// Label of the issued token; defaults to the device name.
+    pub label: Option<String>,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct RegisterRequest {
     pub email: String,
     pub password: String,
     pub label: Option<String>,
+    /// Start a cookie session for the web UI instead of returning a bearer token.
+    #[serde(default)]
+    pub session: bool,
 }
 
 #[derive(Debug, Deserialize)]
@@ -126,6 +257,26 @@ pub struct LoginRequest {
     pub email: String,
     pub password: String,
     pub label: Option<String>,
+    /// Start a cookie session for the web UI instead of returning a bearer token.
+    #[serde(default)]
+    pub session: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct OidcAuthorizeQuery {
+    pub label: Option<String>,
+    /// Start a cookie session for the web UI instead of returning a bearer token.
+    #[serde(default)]
+    pub session: bool,
+}
+
+/// Query string of the provider's redirect back to `OIDC_REDIRECT_URL`.
+#[derive(Debug, Deserialize)]
+pub struct OidcCallbackQuery {
+    pub code: Option<String>,
+    pub state: Option<String>,
+    pub error: Option<String>,
+    pub error_description: Option<String>,
 }
 
 #[derive(Debug, Serialize)]
@@ -134,6 +285,170 @@ pub struct AuthResponse {
     pub user_id: Uuid,
 }
 
+/// Returned instead of `AuthResponse` in cookie session mode; the cookies carry the
+/// credentials and `csrf_token` must be echoed in `X-CSRF-Token` on state-changing requests.
+#[derive(Debug, Serialize)]
+pub struct SessionResponse {
+    pub user_id: Uuid,
+    pub csrf_token: String,
+    pub access_expires_at: DateTime<Utc>,
+    /// The session cannot be refreshed past this point; log in again after it.
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct LogoutAllRequest {
+    /// Also revoke the account's bearer tokens, signing out the shell plugin too.
+    #[serde(default)]
+    pub include_tokens: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LogoutAllSummary {
+    pub sessions_revoked: u64,
+    pub tokens_revoked: u64,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct AccountInfo {
+    pub user_id: Uuid,
+    pub email: String,
+    pub email_verified_at: Option<DateTime<Utc>>,
+    /// False for accounts created through OIDC or a passkey signup.
+    pub has_password: bool,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct DeleteAccountRequest {
+    /// Required when the account has a password.
+    pub password: Option<String>,
+    /// Authenticator or recovery code, required when 2FA is enabled.
+    pub code: Option<String>,
+    /// Keep the user's public commands published, owned by the archive instead.
+    #[serde(default)]
+    pub transfer_public_commands: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AccountDeletionSummary {
+    pub archived_commands: u64,
+}
+
+/// Returned by `POST /api/login` instead of a token when the account has 2FA enabled;
+/// send `challenge` with a code to `POST /api/login/2fa` to finish logging in.
+#[derive(Debug, Serialize)]
+pub struct TwoFactorChallenge {
+    pub two_factor_required: bool,
+    pub challenge: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LoginSecondFactor {
+    pub challenge: String,
+    /// A current authenticator code or an unused recovery code.
+    pub code: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TwoFactorStatus {
+    pub enabled: bool,
+    pub enabled_at: Option<DateTime<Utc>>,
+    pub recovery_codes_remaining: i64,
+    /// Login methods that ask for a code once enabled.
+    pub challenged_logins: &'static [&'static str],
+}
+
+#[derive(Debug, Serialize)]
+pub struct TotpEnrollment {
+    /// Base32, for authenticator apps that cannot scan `otpauth_uri`.
+    pub secret: String,
+    pub otpauth_uri: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TwoFactorCode {
+    pub code: String,
+}
+
+/// Shown once; only hashes are stored.
+#[derive(Debug, Serialize)]
+pub struct RecoveryCodes {
+    pub recovery_codes: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TwoFactorEnabled {
+    pub recovery_codes: Vec<String>,
+    /// Login methods that now ask for a code.
+    pub challenged_logins: &'static [&'static str],
+}
+
+#[derive(Debug, Deserialize)]
+pub struct VerifyEmailRequest {
+    pub token: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ForgotPasswordRequest {
+    pub email: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ResetPasswordRequest {
+    pub token: String,
+    pub password: String,
+}
+
+/// Signed-in users add a passkey to their account; anyone else signs up with `email`.
+#[derive(Debug, Default, Deserialize)]
+pub struct PasskeyRegistrationStart {
+    pub email: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PasskeyRegistrationFinish {
+    pub ceremony_id: Uuid,
+    pub credential: RegisterPublicKeyCredential,
+    /// Shown in the passkey list, e.g. "YubiKey" or "MacBook".
+    pub name: Option<String>,
+    /// Label of the API token issued on signup.
+    pub label: Option<String>,
+    /// Start a cookie session for the web UI instead of returning a bearer token.
+    #[serde(default)]
+    pub session: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PasskeyLoginStart {
+    pub email: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PasskeyLoginFinish {
+    pub ceremony_id: Uuid,
+    pub credential: PublicKeyCredential,
+    pub label: Option<String>,
+    /// Start a cookie session for the web UI instead of returning a bearer token.
+    #[serde(default)]
+    pub session: bool,
+}
+
+/// Options to pass to `navigator.credentials.create()` / `.get()`, and the id to finish with.
+#[derive(Debug, Serialize)]
+pub struct PasskeyChallenge<T> {
+    pub ceremony_id: Uuid,
+    pub options: T,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct PasskeyInfo {
+    pub id: Uuid,
+    pub name: String,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
 #[derive(Debug, Serialize)]
 pub struct HealthResponse {
     pub status: String,
@@ -146,3 +461,377 @@ pub struct SuggestionRow {
     // We don't return these to the client, but we use them for debugging/sorting if needed
     pub score: Option<f64>,
 }
+
+#[derive(Debug, Deserialize)]
+pub struct BatchFilter {
+    pub q: Option<String>,
+    pub tag: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum CommandBatchAction {
+    Delete,
+    SetVisibility { visibility: String },
+    SetFavorite { favorite: bool },
+    AddTags { tags: Vec<String> },
+    RemoveTags { tags: Vec<String> },
+}
+
+impl CommandBatchAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CommandBatchAction::Delete => "delete",
+            CommandBatchAction::SetVisibility { .. } => "set_visibility",
+            CommandBatchAction::SetFavorite { .. } => "set_favorite",
+            CommandBatchAction::AddTags { .. } => "add_tags",
+            CommandBatchAction::RemoveTags { .. } => "remove_tags",
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CommandBatchRequest {
+    pub ids: Option<Vec<Uuid>>,
+    pub filter: Option<BatchFilter>,
+    pub action: CommandBatchAction,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum LearnedBatchAction {
+    Delete,
+    Promote(PromotePayload),
+}
+
+impl LearnedBatchAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LearnedBatchAction::Delete => "delete",
+            LearnedBatchAction::Promote(_) => "promote",
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LearnedBatchRequest {
+    pub ids: Option<Vec<Uuid>>,
+    pub filter: Option<BatchFilter>,
+    pub action: LearnedBatchAction,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BatchItemResult {
+    pub id: Uuid,
+    pub status: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub command_id: Option<Uuid>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BatchResponse {
+    pub results: Vec<BatchItemResult>,
+    pub succeeded: usize,
+    pub failed: usize,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum UsageSource {
+    Copy,
+    Run,
+    SuggestAccept,
+}
+
+impl UsageSource {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            UsageSource::Copy => "copy",
+            UsageSource::Run => "run",
+            UsageSource::SuggestAccept => "suggest_accept",
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UsageEventRequest {
+    pub command_id: Option<Uuid>,
+    pub text: Option<String>,
+    pub source: UsageSource,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct HistoryImportQuery {
+    pub format: crate::history::HistoryFormat,
+    pub os: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HistoryImportSummary {
+    pub parsed: usize,
+    /// Distinct commands stored as new learned entries.
+    pub imported: usize,
+    /// Distinct commands that were already learned; only their first and last use widen.
+    pub skipped: usize,
+    /// Repeats of a command earlier in the same file.
+    pub duplicates: usize,
+    /// Commands excluded by the owner's learning rules.
+    pub ignored: usize,
+    /// Commands stored with secrets redacted or masked.
+    pub redacted: usize,
+    /// Commands dropped because they contained secrets under the `reject` policy.
+    pub secrets_rejected: usize,
+    pub rejected: usize,
+    pub rejected_lines: Vec<crate::history::RejectedLine>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct VaultExportQuery {
+    pub format: Option<crate::vault::VaultFormat>,
+    pub include_learned: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct VaultImportQuery {
+    pub format: Option<crate::vault::VaultFormat>,
+    pub strategy: Option<crate::vault::ImportStrategy>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct VaultImportError {
+    pub index: usize,
+    pub text: String,
+    pub error: String,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct VaultImportSummary {
+    pub created: usize,
+    pub updated: usize,
+    pub skipped: usize,
+    pub learned_imported: usize,
+    /// Learned entries that updated a live entry with the same content.
+    pub learned_merged: usize,
+    /// Learned entries left alone because the `skip` strategy found a live one.
+    pub learned_skipped: usize,
+    /// Learned entries excluded by the owner's learning rules.
+    pub learned_ignored: usize,
+    /// Commands and learned entries stored with secrets redacted or masked.
+    pub redacted: usize,
+    /// Learned entries dropped because they contained secrets under the `reject` policy;
+    /// such commands are listed in `errors`.
+    pub secrets_rejected: usize,
+    pub errors: Vec<VaultImportError>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CheatsheetImportQuery {
+    pub source: crate::cheatsheets::CheatsheetSource,
+    pub path: Option<String>,
+    pub platform: Option<String>,
+    pub visibility: Option<String>,
+    pub dry_run: Option<bool>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CheatsheetImportSummary {
+    pub dry_run: bool,
+    pub parsed: usize,
+    pub new_commands: usize,
+    pub skipped: usize,
+    pub errors: Vec<crate::cheatsheets::CheatsheetError>,
+    pub preview: Vec<crate::cheatsheets::CheatsheetCommand>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct UserSettings {
+    pub hide_dangerous_suggestions: bool,
+    pub secret_policy: crate::secrets::SecretPolicy,
+    pub retention: crate::retention::RetentionPolicy,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SettingsPayload {
+    pub hide_dangerous_suggestions: Option<bool>,
+    pub secret_policy: Option<crate::secrets::SecretPolicy>,
+    /// Replaces the whole retention policy; omitted or zero limits are disabled.
+    pub retention: Option<crate::retention::RetentionPolicy>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RetentionPreviewQuery {
+    pub max_age_days: Option<i32>,
+    pub max_rows: Option<i32>,
+    pub min_usage: Option<i32>,
+    pub limit: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RetentionPreview {
+    pub policy: crate::retention::RetentionPolicy,
+    pub total: usize,
+    pub items: Vec<LearnedCommand>,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct LearningRule {
+    pub id: Uuid,
+    pub kind: String,
+    pub pattern: String,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LearningRulePayload {
+    pub kind: crate::privacy::RuleKind,
+    pub pattern: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RulePurgeQuery {
+    pub dry_run: Option<bool>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RulePurgeSummary {
+    pub dry_run: bool,
+    pub matched: usize,
+    pub purged: u64,
+    /// Whitespace-only prefix rules, which cannot match stored entries.
+    pub skipped_rules: Vec<Uuid>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct StatsQuery {
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub bucket: Option<crate::stats::Bucket>,
+    pub limit: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RecommendationQuery {
+    pub limit: Option<i64>,
+}
+
+/// Overrides for the suggested values when accepting a recommendation.
+#[derive(Debug, Default, Deserialize)]
+pub struct RecommendationAccept {
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub platform: Option<String>,
+    pub visibility: Option<String>,
+    pub tags: Option<Vec<String>>,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct AuditEntry {
+    pub id: Uuid,
+    pub actor_user_id: Option<Uuid>,
+    pub token_id: Option<Uuid>,
+    pub action: String,
+    pub target_type: Option<String>,
+    pub target_id: Option<String>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub details: Option<serde_json::Value>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SecurityEventsQuery {
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+/// Filters of `GET /api/admin/audit`; all optional and combined with AND.
+#[derive(Debug, Deserialize)]
+pub struct AuditQuery {
+    /// Events by this user, or aimed at their account.
+    pub user_id: Option<Uuid>,
+    pub token_id: Option<Uuid>,
+    /// An exact action, or a prefix ending in a dot such as `login.`.
+    pub action: Option<String>,
+    pub target_type: Option<String>,
+    pub target_id: Option<String>,
+    pub ip: Option<String>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+/// Filters of `GET /api/admin/users`; all optional and combined with AND.
+#[derive(Debug, Deserialize)]
+pub struct AdminUserQuery {
+    /// Part of the email address, case-insensitive.
+    pub q: Option<String>,
+    pub role: Option<String>,
+    /// `password`, `oidc` or `passkey`.
+    pub registration_method: Option<String>,
+    pub blocked: Option<bool>,
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct AdminUser {
+    pub id: Uuid,
+    pub email: String,
+    pub role: String,
+    pub registration_method: String,
+    pub email_verified_at: Option<DateTime<Utc>>,
+    pub two_factor_enabled: bool,
+    pub blocked_at: Option<DateTime<Utc>>,
+    pub blocked_reason: Option<String>,
+    pub created_at: DateTime<Utc>,
+    /// Commands outside the trash, across all of the user's tokens.
+    pub command_count: i64,
+    pub active_token_count: i64,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct AdminTokenInfo {
+    pub id: Uuid,
+    pub label: String,
+    pub created_at: DateTime<Utc>,
+    pub revoked_at: Option<DateTime<Utc>>,
+    pub command_count: i64,
+    pub learned_count: i64,
+    pub active_sessions: i64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AdminCommandsQuery {
+    /// Also list commands in the trash.
+    pub include_deleted: Option<bool>,
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RoleUpdate {
+    pub role: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct BlockUserRequest {
+    /// Shown to other admins; never to the blocked user.
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ImpersonationRequest {
+    /// Why support needs access; recorded in the audit log.
+    pub reason: String,
+    /// The user token to act as; defaults to their newest active one.
+    pub token_id: Option<Uuid>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ImpersonationResponse {
+    /// Bearer token for read-only requests as the user.
+    pub token: String,
+    pub token_id: Uuid,
+    pub expires_at: DateTime<Utc>,
+}
@@ -0,0 +1,403 @@
+use std::{env, time::Duration};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use jsonwebtoken::{jwk::JwkSet, Algorithm, DecodingKey, Validation};
+use log::warn;
+use serde::{Deserialize, Deserializer};
+use sha2::{Digest, Sha256};
+use sqlx::{PgConnection, PgPool, Row};
+use thiserror::Error;
+use uuid::Uuid;
+
+use crate::auth::random_token;
+use crate::errors::ApiError;
+
+/// How long a started login may take before its state is rejected.
+pub const STATE_TTL_MINUTES: i64 = 10;
+
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Asymmetric algorithms accepted for ID tokens; `HS*` would need the client secret as key.
+const ALLOWED_ALGORITHMS: [Algorithm; 7] = [
+    Algorithm::RS256,
+    Algorithm::RS384,
+    Algorithm::RS512,
+    Algorithm::PS256,
+    Algorithm::ES256,
+    Algorithm::ES384,
+    Algorithm::EdDSA,
+];
+
+#[derive(Debug, Error)]
+pub enum OidcError {
+    #[error("provider request failed: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("provider returned an error: {0}")]
+    Provider(String),
+    #[error("invalid ID token: {0}")]
+    InvalidToken(String),
+    #[error("the provider did not return a verified email address")]
+    UnverifiedEmail,
+}
+
+impl From<OidcError> for ApiError {
+    fn from(err: OidcError) -> Self {
+        match err {
+            OidcError::Http(err) => {
+                warn!("OIDC provider request failed: {}", err);
+                ApiError::Internal
+            }
+            OidcError::Provider(message) => ApiError::BadRequest(message),
+            OidcError::InvalidToken(reason) => {
+                warn!("Rejected OIDC ID token: {}", reason);
+                ApiError::Unauthorized
+            }
+            OidcError::UnverifiedEmail => ApiError::BadRequest(err.to_string()),
+        }
+    }
+}
+
+/// Read from `OIDC_*` variables; login is disabled unless issuer, client id and redirect URL are set.
+#[derive(Debug, Clone)]
+pub struct OidcConfig {
+    pub issuer: String,
+    pub client_id: String,
+    /// Unset for public clients, which rely on PKCE alone.
+    pub client_secret: Option<String>,
+    pub redirect_url: String,
+    pub scopes: String,
+}
+
+impl OidcConfig {
+    pub fn from_env() -> Option<Self> {
+        let var = |name: &str| env::var(name).ok().filter(|v| !v.trim().is_empty());
+
+        Some(OidcConfig {
+            issuer: var("OIDC_ISSUER")?,
+            client_id: var("OIDC_CLIENT_ID")?,
+            client_secret: var("OIDC_CLIENT_SECRET"),
+            redirect_url: var("OIDC_REDIRECT_URL")?,
+            scopes: var("OIDC_SCOPES").unwrap_or_else(|| "openid email profile".to_string()),
+        })
+    }
+}
+
+/// The parts of the provider's discovery document the login flow uses.
+#[derive(Debug, Deserialize)]
+struct Discovery {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    id_token: Option<String>,
+    error: Option<String>,
+    error_description: Option<String>,
+}
+
+/// Verified ID token claims.
+#[derive(Debug, Deserialize)]
+pub struct IdentityClaims {
+    pub iss: String,
+    pub sub: String,
+    pub email: Option<String>,
+    #[serde(default, deserialize_with = "bool_or_string")]
+    pub email_verified: bool,
+    pub nonce: Option<String>,
+}
+
+/// Secrets generated when a login starts and checked when the provider redirects back.
+pub struct LoginChallenge {
+    pub state: String,
+    pub nonce: String,
+    pub code_verifier: String,
+}
+
+impl LoginChallenge {
+    pub fn generate() -> Self {
+        LoginChallenge {
+            state: random_token(),
+            nonce: random_token(),
+            code_verifier: random_token(),
+        }
+    }
+
+    /// The S256 PKCE challenge for `code_verifier`.
+    fn code_challenge(&self) -> String {
+        URL_SAFE_NO_PAD.encode(Sha256::digest(self.code_verifier.as_bytes()))
+    }
+}
+
+#[derive(Clone)]
+pub struct OidcClient {
+    config: OidcConfig,
+    http: reqwest::Client,
+}
+
+impl OidcClient {
+    pub fn new(config: OidcConfig) -> Self {
+        let http = reqwest::Client::builder()
+            .timeout(HTTP_TIMEOUT)
+            .build()
+            .expect("failed to build OIDC HTTP client");
+
+        OidcClient { config, http }
+    }
+
+    /// Where to send the browser to start the authorization-code flow.
+    pub async fn authorization_url(&self, challenge: &LoginChallenge) -> Result<String, OidcError> {
+        let discovery = self.discover().await?;
+        let mut url = url::Url::parse(&discovery.authorization_endpoint)
+            .map_err(|e| OidcError::Provider(format!("invalid authorization endpoint: {e}")))?;
+
+        url.query_pairs_mut()
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &self.config.client_id)
+            .append_pair("redirect_uri", &self.config.redirect_url)
+            .append_pair("scope", &self.config.scopes)
+            .append_pair("state", &challenge.state)
+            .append_pair("nonce", &challenge.nonce)
+            .append_pair("code_challenge", &challenge.code_challenge())
+            .append_pair("code_challenge_method", "S256");
+
+        Ok(url.into())
+    }
+
+    /// Redeems an authorization code and returns the claims of the verified ID token.
+    pub async fn exchange(
+        &self,
+        code: &str,
+        code_verifier: &str,
+        nonce: &str,
+    ) -> Result<IdentityClaims, OidcError> {
+        let discovery = self.discover().await?;
+
+        let mut form = vec![
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", self.config.redirect_url.as_str()),
+            ("code_verifier", code_verifier),
+        ];
+        let mut request = self.http.post(&discovery.token_endpoint);
+        match &self.config.client_secret {
+            Some(secret) => request = request.basic_auth(&self.config.client_id, Some(secret)),
+            None => form.push(("client_id", self.config.client_id.as_str())),
+        }
+
+        let response: TokenResponse = request.form(&form).send().await?.json().await?;
+        if let Some(error) = response.error {
+            return Err(OidcError::Provider(match response.error_description {
+                Some(description) => format!("{error}: {description}"),
+                None => error,
+            }));
+        }
+        let id_token = response
+            .id_token
+            .ok_or_else(|| OidcError::Provider("token response has no id_token".into()))?;
+
+        let claims = self.verify(&discovery, &id_token).await?;
+        if claims.nonce.as_deref() != Some(nonce) {
+            return Err(OidcError::InvalidToken("nonce mismatch".into()));
+        }
+
+        Ok(claims)
+    }
+
+    async fn discover(&self) -> Result<Discovery, OidcError> {
+        let issuer = self.config.issuer.trim_end_matches('/');
+        let discovery: Discovery = self
+            .http
+            .get(format!("{issuer}/.well-known/openid-configuration"))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        if discovery.issuer.trim_end_matches('/') != issuer {
+            return Err(OidcError::Provider(format!(
+                "discovery document is for issuer {}",
+                discovery.issuer
+            )));
+        }
+
+        Ok(discovery)
+    }
+
+    /// Checks the signature against the provider's JWKS, then issuer, audience and expiry.
+    async fn verify(
+        &self,
+        discovery: &Discovery,
+        id_token: &str,
+    ) -> Result<IdentityClaims, OidcError> {
+        let invalid = |e: jsonwebtoken::errors::Error| OidcError::InvalidToken(e.to_string());
+
+        let header = jsonwebtoken::decode_header(id_token).map_err(invalid)?;
+        if !ALLOWED_ALGORITHMS.contains(&header.alg) {
+            return Err(OidcError::InvalidToken(format!(
+                "unsupported algorithm {:?}",
+                header.alg
+            )));
+        }
+
+        let jwks: JwkSet = self
+            .http
+            .get(&discovery.jwks_uri)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let jwk = match &header.kid {
+            Some(kid) => jwks.find(kid),
+            None if jwks.keys.len() == 1 => jwks.keys.first(),
+            None => None,
+        }
+        .ok_or_else(|| OidcError::InvalidToken("no matching signing key".into()))?;
+        let key = DecodingKey::from_jwk(jwk).map_err(invalid)?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_audience(&[&self.config.client_id]);
+        validation.set_issuer(&[&discovery.issuer]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+
+        let data =
+            jsonwebtoken::decode::<IdentityClaims>(id_token, &key, &validation).map_err(invalid)?;
+        Ok(data.claims)
+    }
+}
+
+/// Finds the user behind an identity, linking it to an existing account with the same
+/// email or creating a password-less account on first login. An existing account whose
+/// email was never verified loses its other credentials first.
+pub async fn link_user(pool: &PgPool, claims: &IdentityClaims) -> Result<Uuid, ApiError> {
+    let linked = sqlx::query(
+        r#"
+        UPDATE user_identities
+        SET last_login_at = now(), email = COALESCE($3, email)
+        WHERE issuer = $1 AND subject = $2
+        RETURNING user_id
+        "#,
+    )
+    .bind(&claims.iss)
+    .bind(&claims.sub)
+    .bind(claims.email.as_deref().map(str::to_lowercase))
+    .fetch_optional(pool)
+    .await?;
+    if let Some(row) = linked {
+        return Ok(row.get("user_id"));
+    }
+
+    let email = match &claims.email {
+        Some(email) if claims.email_verified && !email.trim().is_empty() => {
+            email.trim().to_lowercase()
+        }
+        _ => return Err(OidcError::UnverifiedEmail.into()),
+    };
+
+    // The provider vouches for the address, so it counts as verified either way
+    let mut tx = pool.begin().await?;
+    claim_unverified_account(&mut tx, &email).await?;
+    let user_id: Uuid = sqlx::query_scalar(
+        r#"
+        INSERT INTO users (id, email, password_hash, email_verified_at, registration_method, created_at)
+        VALUES ($1, $2, NULL, now(), 'oidc', now())
+        ON CONFLICT (email) DO UPDATE
+        SET email_verified_at = COALESCE(users.email_verified_at, now())
+        RETURNING id
+        "#,
+    )
+    .bind(Uuid::new_v4())
+    .bind(&email)
+    .fetch_one(&mut *tx)
+    .await?;
+
+    // A concurrent first login for the same identity may have linked it already
+    let user_id: Uuid = sqlx::query_scalar(
+        r#"
+        INSERT INTO user_identities (id, user_id, issuer, subject, email, created_at, last_login_at)
+        VALUES ($1, $2, $3, $4, $5, now(), now())
+        ON CONFLICT (issuer, subject) DO UPDATE SET last_login_at = now()
+        RETURNING user_id
+        "#,
+    )
+    .bind(Uuid::new_v4())
+    .bind(user_id)
+    .bind(&claims.iss)
+    .bind(&claims.sub)
+    .bind(&email)
+    .fetch_one(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+    Ok(user_id)
+}
+
+/// An account registered with an address nobody proved control of may have been set up
+/// by someone else in advance. Before the identity is linked to it, every credential and
+/// login it already has is removed, so only the provider's user of the address gets in.
+async fn claim_unverified_account(conn: &mut PgConnection, email: &str) -> Result<(), ApiError> {
+    let Some(user_id): Option<Uuid> = sqlx::query_scalar(
+        "SELECT id FROM users WHERE email = $1 AND email_verified_at IS NULL FOR UPDATE",
+    )
+    .bind(email)
+    .fetch_optional(&mut *conn)
+    .await?
+    else {
+        return Ok(());
+    };
+
+    sqlx::query("UPDATE users SET password_hash = NULL WHERE id = $1")
+        .bind(user_id)
+        .execute(&mut *conn)
+        .await?;
+    sqlx::query(
+        "UPDATE api_tokens SET revoked_at = now() WHERE user_id = $1 AND revoked_at IS NULL",
+    )
+    .bind(user_id)
+    .execute(&mut *conn)
+    .await?;
+    sqlx::query(
+        r#"
+        UPDATE web_sessions SET revoked_at = now(), revoked_reason = 'account_claimed'
+        WHERE user_id = $1 AND revoked_at IS NULL
+        "#,
+    )
+    .bind(user_id)
+    .execute(&mut *conn)
+    .await?;
+    for table in [
+        "user_passkeys",
+        "user_totp",
+        "user_recovery_codes",
+        "login_challenges",
+        "user_identities",
+        "email_tokens",
+    ] {
+        sqlx::query(&format!("DELETE FROM {table} WHERE user_id = $1"))
+            .bind(user_id)
+            .execute(&mut *conn)
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Some providers send `email_verified` as the string `"true"`.
+fn bool_or_string<'de, D: Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Flag {
+        Bool(bool),
+        Text(String),
+    }
+
+    Ok(match Option::<Flag>::deserialize(deserializer)? {
+        Some(Flag::Bool(value)) => value,
+        Some(Flag::Text(value)) => value.eq_ignore_ascii_case("true"),
+        None => false,
+    })
+}
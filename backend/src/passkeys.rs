@@ -0,0 +1,169 @@
+use std::env;
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{Duration, Utc};
+use log::warn;
+use serde::{de::DeserializeOwned, Serialize};
+use sqlx::{types::Json, PgPool, Row};
+use url::Url;
+use uuid::Uuid;
+use webauthn_rs::prelude::{Passkey, RequestChallengeResponse, WebauthnError};
+use webauthn_rs::{Webauthn, WebauthnBuilder};
+
+use crate::errors::ApiError;
+
+/// How long the browser has to answer a registration or login challenge.
+pub const CEREMONY_TTL_MINUTES: i64 = 5;
+
+/// Builds the relying party from `WEBAUTHN_RP_ORIGIN` (the frontend's origin), with
+/// `WEBAUTHN_RP_ID` defaulting to its host. Passkeys are disabled when no origin is set.
+pub fn from_env() -> Option<Webauthn> {
+    let origin = env::var("WEBAUTHN_RP_ORIGIN")
+        .ok()
+        .filter(|v| !v.trim().is_empty())?;
+    let origin = Url::parse(origin.trim()).expect("WEBAUTHN_RP_ORIGIN must be a URL");
+    let rp_id = env::var("WEBAUTHN_RP_ID")
+        .ok()
+        .filter(|v| !v.trim().is_empty())
+        .or_else(|| origin.host_str().map(str::to_string))
+        .expect("WEBAUTHN_RP_ID must be set when WEBAUTHN_RP_ORIGIN has no host");
+    let rp_name = env::var("WEBAUTHN_RP_NAME").unwrap_or_else(|_| "CMVault".to_string());
+
+    let webauthn = WebauthnBuilder::new(&rp_id, &origin)
+        .and_then(|builder| builder.rp_name(&rp_name).build())
+        .expect("invalid WebAuthn relying party configuration");
+
+    Some(webauthn)
+}
+
+/// Failed ceremonies are the client's fault (bad signature, wrong origin, replayed challenge).
+pub fn rejected(err: WebauthnError) -> ApiError {
+    warn!("Rejected passkey ceremony: {}", err);
+    ApiError::Unauthorized
+}
+
+/// Login options for an email with no passkeys, shaped like real ones but allowing no
+/// credentials, so the start of a login does not tell which accounts exist.
+pub fn decoy_login_options(webauthn: &Webauthn) -> Result<RequestChallengeResponse, ApiError> {
+    let (mut options, _) = webauthn
+        .start_discoverable_authentication()
+        .map_err(rejected)?;
+    options.mediation = None;
+    options.public_key.extensions = None;
+    Ok(options)
+}
+
+/// What a pending registration or login is for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CeremonyKind {
+    /// Adding a passkey to a signed-in account.
+    Register,
+    /// Creating a new account whose only credential is the passkey.
+    Signup,
+    Login,
+}
+
+impl CeremonyKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            CeremonyKind::Register => "register",
+            CeremonyKind::Signup => "signup",
+            CeremonyKind::Login => "login",
+        }
+    }
+}
+
+/// A challenge handed to the browser, with the server-side state needed to check its answer.
+pub struct Ceremony<T> {
+    pub user_id: Uuid,
+    /// Only set for signups, whose account does not exist yet.
+    pub email: Option<String>,
+    pub state: T,
+}
+
+pub async fn save_ceremony<T: Serialize>(
+    pool: &PgPool,
+    kind: CeremonyKind,
+    ceremony: &Ceremony<T>,
+) -> Result<Uuid, ApiError> {
+    let id = Uuid::new_v4();
+
+    sqlx::query(
+        r#"
+        INSERT INTO passkey_ceremonies (id, kind, user_id, email, state, created_at, expires_at)
+        VALUES ($1, $2, $3, $4, $5, now(), $6)
+        "#,
+    )
+    .bind(id)
+    .bind(kind.as_str())
+    .bind(ceremony.user_id)
+    .bind(&ceremony.email)
+    .bind(Json(&ceremony.state))
+    .bind(Utc::now() + Duration::minutes(CEREMONY_TTL_MINUTES))
+    .execute(pool)
+    .await?;
+
+    Ok(id)
+}
+
+/// Removes and returns a pending ceremony; each challenge can be answered once.
+pub async fn take_ceremony<T: DeserializeOwned>(
+    pool: &PgPool,
+    id: Uuid,
+    kinds: &[CeremonyKind],
+) -> Result<(CeremonyKind, Ceremony<T>), ApiError> {
+    let kinds: Vec<&str> = kinds.iter().map(|kind| kind.as_str()).collect();
+
+    let row = sqlx::query(
+        r#"
+        DELETE FROM passkey_ceremonies
+        WHERE id = $1 AND kind = ANY($2) AND expires_at > now()
+        RETURNING kind, user_id, email, state
+        "#,
+    )
+    .bind(id)
+    .bind(&kinds)
+    .fetch_optional(pool)
+    .await?
+    .ok_or_else(|| ApiError::BadRequest("unknown or expired ceremony".into()))?;
+
+    let kind = match row.get::<String, _>("kind").as_str() {
+        "register" => CeremonyKind::Register,
+        "signup" => CeremonyKind::Signup,
+        _ => CeremonyKind::Login,
+    };
+    let Json(state) = row
+        .try_get::<Json<T>, _>("state")
+        .map_err(|_| ApiError::Internal)?;
+
+    Ok((
+        kind,
+        Ceremony {
+            user_id: row.get("user_id"),
+            email: row.get("email"),
+            state,
+        },
+    ))
+}
+
+/// A user's stored passkeys, keyed by row id.
+pub async fn load_passkeys(pool: &PgPool, user_id: Uuid) -> Result<Vec<(Uuid, Passkey)>, ApiError> {
+    let rows =
+        sqlx::query("SELECT id, passkey FROM user_passkeys WHERE user_id = $1 ORDER BY created_at")
+            .bind(user_id)
+            .fetch_all(pool)
+            .await?;
+
+    Ok(rows
+        .iter()
+        .filter_map(|row| {
+            let Json(passkey) = row.try_get::<Json<Passkey>, _>("passkey").ok()?;
+            Some((row.get("id"), passkey))
+        })
+        .collect())
+}
+
+/// The credential id as stored in `user_passkeys.credential_id`.
+pub fn credential_id(passkey: &Passkey) -> String {
+    URL_SAFE_NO_PAD.encode(passkey.cred_id())
+}
@@ -0,0 +1,167 @@
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::models::LearningRule;
+
+/// Compiled regexes larger than this are refused so a rule cannot stall `learn_command`.
+const MAX_REGEX_SIZE: usize = 256 * 1024;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleKind {
+    /// The command as sent by the shell starts with the pattern (leading spaces included).
+    Prefix,
+    /// The trimmed command matches the regular expression.
+    Regex,
+    /// The working directory is, or is below, a directory matching the glob.
+    PwdGlob,
+    /// The client OS equals the pattern, ignoring case.
+    Os,
+}
+
+impl RuleKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RuleKind::Prefix => "prefix",
+            RuleKind::Regex => "regex",
+            RuleKind::PwdGlob => "pwd_glob",
+            RuleKind::Os => "os",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "prefix" => Some(RuleKind::Prefix),
+            "regex" => Some(RuleKind::Regex),
+            "pwd_glob" => Some(RuleKind::PwdGlob),
+            "os" => Some(RuleKind::Os),
+            _ => None,
+        }
+    }
+}
+
+/// What a rule is matched against.
+pub struct Execution<'a> {
+    /// The command exactly as the client sent it.
+    pub raw: &'a str,
+    pub os: Option<&'a str>,
+    pub pwd: Option<&'a str>,
+}
+
+enum Matcher {
+    Prefix(String),
+    Command(Regex),
+    Pwd(Regex),
+    Os(String),
+}
+
+pub struct CompiledRule {
+    pub id: Uuid,
+    matcher: Matcher,
+}
+
+/// Validates and compiles a rule; the error is suitable for a 400 response.
+pub fn compile(id: Uuid, kind: RuleKind, pattern: &str) -> Result<CompiledRule, String> {
+    if pattern.is_empty() {
+        return Err("pattern is required".into());
+    }
+
+    let matcher = match kind {
+        RuleKind::Prefix => Matcher::Prefix(pattern.to_string()),
+        RuleKind::Regex => Matcher::Command(build_regex(pattern)?),
+        RuleKind::PwdGlob => Matcher::Pwd(build_regex(&glob_to_regex(pattern))?),
+        RuleKind::Os => Matcher::Os(pattern.trim().to_lowercase()),
+    };
+
+    Ok(CompiledRule { id, matcher })
+}
+
+/// Compiles stored rules, skipping any that no longer compile.
+pub fn compile_all(rules: &[LearningRule]) -> Vec<CompiledRule> {
+    rules
+        .iter()
+        .filter_map(|rule| {
+            let kind = RuleKind::parse(&rule.kind)?;
+            compile(rule.id, kind, &rule.pattern).ok()
+        })
+        .collect()
+}
+
+/// Returns the id of the first rule that excludes this execution from learning.
+pub fn first_match(rules: &[CompiledRule], execution: &Execution) -> Option<Uuid> {
+    rules
+        .iter()
+        .find(|rule| rule.matches(execution))
+        .map(|rule| rule.id)
+}
+
+/// Like `first_match`, for learned entries already stored. Their content was trimmed when
+/// learned, so prefixes are compared without their leading whitespace.
+pub fn first_stored_match(rules: &[CompiledRule], execution: &Execution) -> Option<Uuid> {
+    rules
+        .iter()
+        .find(|rule| match &rule.matcher {
+            Matcher::Prefix(prefix) => {
+                let prefix = prefix.trim_start();
+                !prefix.is_empty() && execution.raw.starts_with(prefix)
+            }
+            _ => rule.matches(execution),
+        })
+        .map(|rule| rule.id)
+}
+
+impl CompiledRule {
+    /// Whitespace-only prefixes (the " " no-history convention) only apply at learn
+    /// time; the whitespace is gone from stored entries.
+    pub fn applies_to_stored(&self) -> bool {
+        !matches!(&self.matcher, Matcher::Prefix(prefix) if prefix.trim().is_empty())
+    }
+
+    fn matches(&self, execution: &Execution) -> bool {
+        match &self.matcher {
+            Matcher::Prefix(prefix) => execution.raw.starts_with(prefix.as_str()),
+            Matcher::Command(regex) => regex.is_match(execution.raw.trim()),
+            Matcher::Pwd(regex) => execution.pwd.is_some_and(|pwd| regex.is_match(pwd)),
+            Matcher::Os(os) => execution
+                .os
+                .is_some_and(|value| value.trim().to_lowercase() == *os),
+        }
+    }
+}
+
+fn build_regex(pattern: &str) -> Result<Regex, String> {
+    RegexBuilder::new(pattern)
+        .size_limit(MAX_REGEX_SIZE)
+        .build()
+        .map_err(|e| format!("invalid pattern: {e}"))
+}
+
+/// Translates a directory glob into an anchored regex that also matches subdirectories.
+/// `*` and `?` stay within one path segment, `**` crosses segments, and a leading `~`
+/// stands for any home directory since the server does not know the client's `$HOME`.
+fn glob_to_regex(glob: &str) -> String {
+    let mut out = String::from("^");
+    let mut rest = glob.trim_end_matches('/');
+
+    if let Some(after) = rest.strip_prefix('~') {
+        out.push_str("(/home/[^/]+|/Users/[^/]+|/root)");
+        rest = after;
+    }
+
+    let mut chars = rest.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                out.push_str(".*");
+            }
+            '*' => out.push_str("[^/]*"),
+            '?' => out.push_str("[^/]"),
+            other => out.push_str(&regex::escape(&other.to_string())),
+        }
+    }
+
+    out.push_str("(/.*)?$");
+    out
+}
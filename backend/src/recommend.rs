@@ -0,0 +1,153 @@
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::secrets;
+
+/// Entries used fewer times than this are never recommended.
+pub const MIN_USAGE: i32 = 3;
+/// Recommendations need at least this score.
+const MIN_SCORE: f64 = 30.0;
+
+/// A learned entry that has not been saved, promoted or dismissed yet.
+pub struct Candidate {
+    pub id: Uuid,
+    pub content: String,
+    pub os: Option<String>,
+    pub usage_count: i32,
+    pub directories: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Suggested {
+    pub title: String,
+    pub platform: String,
+    pub tags: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Recommendation {
+    pub learned_id: Uuid,
+    pub content: String,
+    pub usage_count: i32,
+    pub directories: i64,
+    pub score: f64,
+    pub reasons: Vec<String>,
+    pub suggested: Suggested,
+}
+
+/// Scores a candidate, returning `None` when it is not worth saving.
+pub fn evaluate(candidate: Candidate) -> Option<Recommendation> {
+    if candidate.usage_count < MIN_USAGE || secrets::contains_secret(&candidate.content) {
+        return None;
+    }
+
+    let mut score = 0.0;
+    let mut reasons = Vec::new();
+
+    let usage = candidate.usage_count.min(50) as f64;
+    score += usage * 2.0;
+    if candidate.usage_count >= 5 {
+        reasons.push(format!("used {} times", candidate.usage_count));
+    }
+
+    // Long commands are the ones worth not retyping
+    let length = candidate.content.chars().count();
+    if length > 40 {
+        score += ((length - 40) as f64 / 4.0).min(30.0);
+        reasons.push(format!("{length} characters long"));
+    }
+
+    let flags = candidate
+        .content
+        .split_whitespace()
+        .filter(|word| word.starts_with('-') && word.len() > 1)
+        .count();
+    if flags >= 3 {
+        score += (flags as f64 * 5.0).min(25.0);
+        reasons.push(format!("{flags} flags"));
+    }
+
+    if candidate.directories >= 2 {
+        score += (candidate.directories as f64 * 8.0).min(24.0);
+        reasons.push(format!("used in {} directories", candidate.directories));
+    }
+
+    if score < MIN_SCORE {
+        return None;
+    }
+
+    let suggested = suggest(&candidate.content, candidate.os.as_deref());
+
+    Some(Recommendation {
+        learned_id: candidate.id,
+        content: candidate.content,
+        usage_count: candidate.usage_count,
+        directories: candidate.directories,
+        score,
+        reasons,
+        suggested,
+    })
+}
+
+/// Title, platform and tags proposed for saving `content`.
+pub fn suggest(content: &str, os: Option<&str>) -> Suggested {
+    Suggested {
+        title: suggest_title(content),
+        platform: platform_for(os).to_string(),
+        tags: program(content).into_iter().collect(),
+    }
+}
+
+/// Maps `uname -s` style OS names to the platforms used by `commands.platform`.
+fn platform_for(os: Option<&str>) -> &'static str {
+    let Some(os) = os.map(str::to_lowercase) else {
+        return "Others";
+    };
+    if os.starts_with("linux") {
+        "Linux"
+    } else if os.starts_with("darwin") || os.starts_with("mac") {
+        "macOS"
+    } else if os.starts_with("windows") || os.starts_with("mingw") || os.starts_with("cygwin") {
+        "Windows"
+    } else if os.starts_with("android") {
+        "Android"
+    } else {
+        "Others"
+    }
+}
+
+/// Words of the command from the program on, skipping `sudo` and `VAR=value` prefixes.
+fn words(content: &str) -> impl Iterator<Item = &str> {
+    content
+        .split_whitespace()
+        .skip_while(|word| *word == "sudo" || is_assignment(word))
+}
+
+fn program(content: &str) -> Option<String> {
+    words(content)
+        .next()
+        .map(|word| word.rsplit('/').next().unwrap_or(word).to_string())
+}
+
+/// `program subcommand`, e.g. "docker compose" for `docker compose up -d --build`.
+fn suggest_title(content: &str) -> String {
+    let Some(program) = program(content) else {
+        return content.chars().take(60).collect();
+    };
+
+    match words(content).nth(1) {
+        Some(sub)
+            if sub.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+                && !sub.starts_with('-') =>
+        {
+            format!("{program} {sub}")
+        }
+        _ => program,
+    }
+}
+
+fn is_assignment(word: &str) -> bool {
+    word.split_once('=').is_some_and(|(name, _)| {
+        !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+    })
+}
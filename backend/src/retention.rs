@@ -0,0 +1,104 @@
+use serde::{Deserialize, Serialize};
+use sqlx::{PgPool, Row};
+use uuid::Uuid;
+
+/// How much learned history a user keeps. Unset limits are not enforced.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    /// Prune entries not used for this many days.
+    pub max_age_days: Option<i32>,
+    /// Keep at most this many live entries per token, dropping the least recently used.
+    pub max_rows: Option<i32>,
+    /// Entries used at least this many times are never pruned.
+    pub min_usage: Option<i32>,
+}
+
+impl RetentionPolicy {
+    /// Rejects negative limits and treats zero as "not set".
+    pub fn normalized(self) -> Result<Self, String> {
+        let check = |name: &str, value: Option<i32>| match value {
+            Some(v) if v < 0 => Err(format!("{name} must not be negative")),
+            Some(0) | None => Ok(None),
+            Some(v) => Ok(Some(v)),
+        };
+
+        Ok(RetentionPolicy {
+            max_age_days: check("max_age_days", self.max_age_days)?,
+            max_rows: check("max_rows", self.max_rows)?,
+            min_usage: check("min_usage", self.min_usage)?,
+        })
+    }
+
+    /// `min_usage` only protects entries, so it prunes nothing on its own.
+    pub fn prunes(&self) -> bool {
+        self.max_age_days.is_some() || self.max_rows.is_some()
+    }
+}
+
+/// Live, never-promoted learned entries of `user_id`'s tokens that `policy` would prune,
+/// least recently used first.
+pub async fn prunable(
+    pool: &PgPool,
+    user_id: Uuid,
+    policy: &RetentionPolicy,
+) -> Result<Vec<Uuid>, sqlx::Error> {
+    if !policy.prunes() {
+        return Ok(Vec::new());
+    }
+
+    let rows = sqlx::query(
+        r#"
+        WITH ranked AS (
+            SELECT l.id,
+                   l.usage_count,
+                   COALESCE(l.last_used_at, l.created_at) AS seen,
+                   row_number() OVER (
+                       PARTITION BY l.owner_token
+                       ORDER BY l.last_used_at DESC NULLS LAST, l.usage_count DESC, l.created_at DESC
+                   ) AS rank
+            FROM learned_commands l
+            JOIN api_tokens t ON t.id = l.owner_token
+            WHERE t.user_id = $1
+              AND l.deleted_at IS NULL
+              AND l.promoted_at IS NULL
+        )
+        SELECT id
+        FROM ranked
+        WHERE ($4::int IS NULL OR usage_count < $4)
+          AND (
+              ($2::int IS NOT NULL AND seen < now() - make_interval(days => $2))
+              OR ($3::int IS NOT NULL AND rank > $3)
+          )
+        ORDER BY seen ASC
+        "#,
+    )
+    .bind(user_id)
+    .bind(policy.max_age_days)
+    .bind(policy.max_rows)
+    .bind(policy.min_usage)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows.iter().map(|row| row.get("id")).collect())
+}
+
+/// Moves everything `policy` would prune to the trash, returning how many rows moved.
+pub async fn prune(
+    pool: &PgPool,
+    user_id: Uuid,
+    policy: &RetentionPolicy,
+) -> Result<u64, sqlx::Error> {
+    let ids = prunable(pool, user_id, policy).await?;
+    if ids.is_empty() {
+        return Ok(0);
+    }
+
+    let result = sqlx::query(
+        "UPDATE learned_commands SET deleted_at = now() WHERE id = ANY($1) AND deleted_at IS NULL",
+    )
+    .bind(&ids)
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected())
+}
@@ -1,26 +1,67 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 use actix_web::{
-    delete, get, post,
+    delete, get,
+    http::{
+        header::{CONTENT_DISPOSITION, LOCATION, USER_AGENT},
+        StatusCode,
+    },
+    post, put,
     web::{self, Data},
     HttpRequest, HttpResponse,
 };
 use argon2::{password_hash::SaltString, Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
-use chrono::{Duration, Utc};
-use rand::{distributions::Uniform, Rng};
+use chrono::{DateTime, Duration, Utc};
+use log::warn;
 use rand_core::OsRng;
-use sqlx::{PgPool, QueryBuilder, Row};
+use sqlx::{PgConnection, PgExecutor, PgPool, QueryBuilder, Row};
 use uuid::Uuid;
+use webauthn_rs::prelude::{CredentialID, PasskeyAuthentication, PasskeyRegistration};
 
 use crate::{
+    accounts, admin,
+    audit::{self, Action, Actor, Event},
     auth,
+    cheatsheets::{self, CheatsheetError},
+    danger,
+    device_flow::{self, DeviceFlowError},
     errors::ApiError,
+    history,
     models::{
-        AuthResponse, Command, CommandPayload, CommandWithTags, DeviceCodeResponse,
-        ExchangeTokenRequest, ExchangeTokenResponse, HealthResponse, LearnRequest, LearnedCommand,
-        LoginRequest, PromotePayload, RegisterRequest, SuggestRequest, SuggestionRow,
+        AccountDeletionSummary, AccountInfo, AdminCommandsQuery, AdminUserQuery, ApiToken,
+        AuditQuery, AuthResponse, BatchItemResult, BatchResponse, BlockUserRequest,
+        CheatsheetImportQuery, CheatsheetImportSummary, Command, CommandBatchAction,
+        CommandBatchRequest, CommandPayload, CommandWithTags, DeleteAccountRequest, DeviceApproval,
+        DeviceAuthorizationRequest, DeviceAuthorizationResponse, DeviceCodeResponse,
+        DeviceRequestInfo, DeviceScope, DeviceTokenRequest, DeviceTokenResponse,
+        ExchangeTokenRequest, ExchangeTokenResponse, ForgotPasswordRequest, HealthResponse,
+        HistoryImportQuery, HistoryImportSummary, ImpersonationRequest, ImpersonationResponse,
+        LearnBatchRequest, LearnBatchResponse, LearnItemResult, LearnRequest, LearnedBatchAction,
+        LearnedBatchRequest, LearnedCommand, LearningRule, LearningRulePayload, LoginRequest,
+        LoginSecondFactor, LogoutAllRequest, LogoutAllSummary, OidcAuthorizeQuery,
+        OidcCallbackQuery, PasskeyChallenge, PasskeyInfo, PasskeyLoginFinish, PasskeyLoginStart,
+        PasskeyRegistrationFinish, PasskeyRegistrationStart, PromotePayload, RecommendationAccept,
+        RecommendationQuery, RecoveryCodes, RegisterRequest, ResetPasswordRequest,
+        RetentionPreview, RetentionPreviewQuery, RoleUpdate, RulePurgeQuery, RulePurgeSummary,
+        SecurityEventsQuery, SessionResponse, SettingsPayload, StatsQuery, SuggestRequest,
+        Suggestion, SuggestionRow, TotpEnrollment, TwoFactorChallenge, TwoFactorCode,
+        TwoFactorEnabled, TwoFactorStatus, UsageEventRequest, UsageSource, UserSettings,
+        VaultExportQuery, VaultImportError, VaultImportQuery, VaultImportSummary,
+        VerifyEmailRequest,
     },
+    oidc::{self, LoginChallenge},
+    passkeys::{self, Ceremony, CeremonyKind},
+    privacy::{self, Execution},
+    recommend::{self, Candidate, Recommendation},
+    retention::{self, RetentionPolicy},
+    secrets::{self, Scrubbed, SecretFinding, SecretPolicy},
+    sessions::{self, RefreshError},
     state::AppState,
+    stats::{self, StatsRange},
+    throttle::{self, Scope},
+    two_factor,
+    vault::{self, ImportStrategy, VaultCommand, VaultDocument, VaultFormat, VaultLearned},
+    verification::{self, TokenPurpose},
 };
 
 #[get("/health")]
@@ -34,41 +75,25 @@ pub async fn health() -> HttpResponse {
 #[post("/api/register")]
 pub async fn register_user(
     state: Data<AppState>,
+    req: HttpRequest,
     payload: web::Json<RegisterRequest>,
 ) -> Result<HttpResponse, ApiError> {
     let pool = &state.pool;
-    let email = payload.email.trim().to_lowercase();
-    if email.is_empty() || payload.password.len() < 8 {
-        return Err(ApiError::BadRequest(
-            "email required and password must be at least 8 characters".into(),
-        ));
-    }
-
-    let salt = SaltString::generate(&mut OsRng);
-    let password_hash = Argon2::default()
-        .hash_password(payload.password.as_bytes(), &salt)
-        .map_err(|_| ApiError::Internal)?
-        .to_string();
+    let email = verification::normalize_email(&payload.email)?;
+    let password_hash = hash_password(&payload.password)?;
 
     let user_id = Uuid::new_v4();
-    let token_id = Uuid::new_v4();
-    let token_value = Uuid::new_v4().to_string();
-    let label = payload
-        .label
-        .clone()
-        .unwrap_or_else(|| "Default Token".to_string());
 
-    let mut tx = pool.begin().await?;
     sqlx::query(
         r#"
-        INSERT INTO users (id, email, password_hash, created_at)
-        VALUES ($1, $2, $3, now())
+        INSERT INTO users (id, email, password_hash, registration_method, created_at)
+        VALUES ($1, $2, $3, 'password', now())
         "#,
     )
     .bind(user_id)
     .bind(&email)
     .bind(&password_hash)
-    .execute(&mut *tx)
+    .execute(pool)
     .await
     .map_err(|err| match err {
         sqlx::Error::Database(db_err) if db_err.is_unique_violation() => {
@@ -76,471 +101,4223 @@ pub async fn register_user(
         }
         other => ApiError::Db(other),
     })?;
+    send_verification_email(&state, user_id, &email).await;
 
-    sqlx::query(
-        r#"
-        INSERT INTO api_tokens (id, label, token, user_id, created_at)
-        VALUES ($1, $2, $3, $4, now())
-        "#,
-    )
-    .bind(token_id)
-    .bind(&label)
-    .bind(&token_value)
-    .bind(user_id)
-    .execute(&mut *tx)
-    .await?;
-
-    tx.commit().await?;
-
-    Ok(HttpResponse::Created().json(AuthResponse {
-        token: token_value,
+    let label = payload.label.as_deref().unwrap_or("Default Token");
+    login_response(
+        &state,
+        &req,
         user_id,
-    }))
+        label,
+        payload.session,
+        StatusCode::CREATED,
+        Event::new(Action::AccountRegistered).details(serde_json::json!({ "method": "password" })),
+    )
+    .await
 }
 
 #[post("/api/login")]
 pub async fn login_user(
     state: Data<AppState>,
+    req: HttpRequest,
     payload: web::Json<LoginRequest>,
 ) -> Result<HttpResponse, ApiError> {
     let pool = &state.pool;
     let email = payload.email.trim().to_lowercase();
+    let ip = throttle::client_ip(&req, state.trust_proxy_headers);
+    throttle::check(pool, Scope::LoginIp, &ip).await?;
+    throttle::check(pool, Scope::LoginAccount, &email).await?;
 
-    let row = sqlx::query(r#"SELECT id, password_hash FROM users WHERE email = $1"#)
-        .bind(&email)
-        .fetch_optional(pool)
+    // Unknown emails count against the address too, so lockouts do not reveal accounts
+    let Some(user_id) = check_password(pool, &email, &payload.password).await? else {
+        throttle::record_failure(pool, Scope::LoginIp, &ip).await?;
+        throttle::record_failure(pool, Scope::LoginAccount, &email).await?;
+        let account: Option<Uuid> = sqlx::query_scalar("SELECT id FROM users WHERE email = $1")
+            .bind(&email)
+            .fetch_optional(pool)
+            .await?;
+        let event = match account {
+            Some(account) => Event::new(Action::LoginFailed).target("user", account),
+            None => Event::new(Action::LoginFailed).target("email", &email),
+        };
+        event.record(pool, &Actor::new(&req, &state)).await?;
+        return Err(ApiError::Unauthorized);
+    };
+    throttle::reset(pool, Scope::LoginAccount, &email).await?;
+
+    let label = payload.label.as_deref().unwrap_or("Login Token");
+    if two_factor::is_enabled(pool, user_id).await? {
+        let (challenge, expires_at) = two_factor::start_challenge(
+            pool,
+            &two_factor::LoginChallenge {
+                user_id,
+                label: label.to_string(),
+                session: payload.session,
+                method: "password".into(),
+            },
+        )
         .await?;
+        return Ok(HttpResponse::Ok().json(TwoFactorChallenge {
+            two_factor_required: true,
+            challenge,
+            expires_at,
+        }));
+    }
+
+    login_response(
+        &state,
+        &req,
+        user_id,
+        label,
+        payload.session,
+        StatusCode::OK,
+        Event::new(Action::LoginSucceeded).details(serde_json::json!({ "method": "password" })),
+    )
+    .await
+}
 
-    let row = row.ok_or(ApiError::Unauthorized)?;
-    let user_id: Uuid = row.try_get("id").unwrap();
-    let password_hash: String = row.try_get("password_hash").unwrap();
+/// Finishes a password or OIDC login that answered with a `TwoFactorChallenge`.
+#[post("/api/login/2fa")]
+pub async fn login_second_factor(
+    state: Data<AppState>,
+    req: HttpRequest,
+    payload: web::Json<LoginSecondFactor>,
+) -> Result<HttpResponse, ApiError> {
+    let pool = &state.pool;
+    let challenge = two_factor::attempt_challenge(pool, &payload.challenge).await?;
+    if let Err(err) = check_second_factor(pool, challenge.user_id, &payload.code).await {
+        if let ApiError::BadRequest(_) = err {
+            Event::new(Action::SecondFactorFailed)
+                .target("user", challenge.user_id)
+                .record(pool, &Actor::new(&req, &state))
+                .await?;
+            return Err(ApiError::Unauthorized);
+        }
+        return Err(err);
+    }
+    two_factor::finish_challenge(pool, &payload.challenge).await?;
 
-    let parsed_hash = PasswordHash::new(&password_hash).map_err(|_| ApiError::Unauthorized)?;
-    Argon2::default()
-        .verify_password(payload.password.as_bytes(), &parsed_hash)
-        .map_err(|_| ApiError::Unauthorized)?;
+    login_response(
+        &state,
+        &req,
+        challenge.user_id,
+        &challenge.label,
+        challenge.session,
+        StatusCode::OK,
+        Event::new(Action::LoginSucceeded).details(serde_json::json!({
+            "method": challenge.method,
+            "second_factor": true,
+        })),
+    )
+    .await
+}
 
-    let token_id = Uuid::new_v4();
-    let token_value = Uuid::new_v4().to_string();
-    let label = payload
-        .label
-        .clone()
-        .unwrap_or_else(|| "Login Token".to_string());
+/// Starts an OpenID Connect login by redirecting to the provider.
+#[get("/api/auth/oidc/authorize")]
+pub async fn oidc_authorize(
+    state: Data<AppState>,
+    query: web::Query<OidcAuthorizeQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let client = state.oidc.as_ref().ok_or(ApiError::NotFound)?;
+    let challenge = LoginChallenge::generate();
+    let url = client.authorization_url(&challenge).await?;
 
     sqlx::query(
         r#"
-        INSERT INTO api_tokens (id, label, token, user_id, created_at)
-        VALUES ($1, $2, $3, $4, now())
+        INSERT INTO oidc_login_states (state, code_verifier, nonce, label, session, created_at, expires_at)
+        VALUES ($1, $2, $3, $4, $5, now(), $6)
         "#,
     )
-    .bind(token_id)
-    .bind(&label)
-    .bind(&token_value)
-    .bind(user_id)
-    .execute(pool)
+    .bind(&challenge.state)
+    .bind(&challenge.code_verifier)
+    .bind(&challenge.nonce)
+    .bind(&query.label)
+    .bind(query.session)
+    .bind(Utc::now() + Duration::minutes(oidc::STATE_TTL_MINUTES))
+    .execute(&state.pool)
     .await?;
 
-    Ok(HttpResponse::Ok().json(AuthResponse {
-        token: token_value,
-        user_id,
-    }))
-}
-
-#[derive(Debug, serde::Deserialize)]
-pub struct CommandQuery {
-    pub q: Option<String>,
-    pub tag: Option<String>,
-    pub limit: Option<i64>,
-    pub offset: Option<i64>,
+    Ok(HttpResponse::Found()
+        .insert_header((LOCATION, url))
+        .finish())
 }
 
-#[get("/api/commands")]
-pub async fn list_commands(
+/// Completes an OpenID Connect login and returns a fresh API token, or a
+/// `TwoFactorChallenge` when the account has two-factor enabled.
+#[get("/api/auth/oidc/callback")]
+pub async fn oidc_callback(
     state: Data<AppState>,
     req: HttpRequest,
-    query: web::Query<CommandQuery>,
+    query: web::Query<OidcCallbackQuery>,
 ) -> Result<HttpResponse, ApiError> {
     let pool = &state.pool;
-    let token = auth::optional_token(&req, pool).await?;
-    let limit = query.limit.unwrap_or(20).clamp(1, 200);
-    let offset = query.offset.unwrap_or(0).max(0);
-
-    let mut builder = QueryBuilder::new(
-        r#"
-        SELECT id, title, text, description, platform, visibility, favorite, usage_count, owner_token, created_at, updated_at, last_used_at
-        FROM commands
-        WHERE 1=1
-    "#,
-    );
+    let client = state.oidc.as_ref().ok_or(ApiError::NotFound)?;
+    let query = query.into_inner();
 
-    if let Some(t) = token.as_ref() {
-        builder.push(" AND (owner_token = ");
-        builder.push_bind(t.id);
-        builder.push(" OR visibility = 'PUBLIC')");
-    } else {
-        builder.push(" AND visibility = 'PUBLIC'");
+    if let Some(error) = query.error {
+        return Err(ApiError::BadRequest(match query.error_description {
+            Some(description) => format!("login failed: {error}: {description}"),
+            None => format!("login failed: {error}"),
+        }));
     }
+    let (Some(code), Some(login_state)) = (query.code, query.state) else {
+        return Err(ApiError::BadRequest("code and state are required".into()));
+    };
 
-    if let Some(filter) = &query.q {
-        builder.push(" AND text ILIKE ");
-        builder.push_bind(format!("%{filter}%"));
-    }
+    // States are single use, whether or not the exchange below succeeds
+    let row = sqlx::query(
+        r#"
+        DELETE FROM oidc_login_states
+        WHERE state = $1 AND expires_at > now()
+        RETURNING code_verifier, nonce, label, session
+        "#,
+    )
+    .bind(&login_state)
+    .fetch_optional(pool)
+    .await?
+    .ok_or_else(|| ApiError::BadRequest("unknown or expired login state".into()))?;
 
-    if let Some(tag_filter) = &query.tag {
-        builder.push(
-            " AND EXISTS (SELECT 1 FROM command_tags ct JOIN tags t ON t.id = ct.tag_id WHERE ct.command_id = commands.id AND t.name ILIKE ",
-        );
-        builder.push_bind(format!("%{tag_filter}%"));
-        builder.push(")");
-    }
+    let code_verifier: String = row.get("code_verifier");
+    let nonce: String = row.get("nonce");
+    let label: Option<String> = row.get("label");
+    let session: bool = row.get("session");
 
-    builder.push(" ORDER BY created_at DESC");
-    builder.push(" LIMIT ");
-    builder.push_bind(limit);
-    builder.push(" OFFSET ");
-    builder.push_bind(offset);
+    let claims = client.exchange(&code, &code_verifier, &nonce).await?;
+    let user_id = oidc::link_user(pool, &claims).await?;
+    let label = label.as_deref().unwrap_or("Login Token");
 
-    let commands: Vec<Command> = builder.build_query_as().fetch_all(pool).await?;
+    if two_factor::is_enabled(pool, user_id).await? {
+        let (challenge, expires_at) = two_factor::start_challenge(
+            pool,
+            &two_factor::LoginChallenge {
+                user_id,
+                label: label.to_string(),
+                session,
+                method: "oidc".into(),
+            },
+        )
+        .await?;
+        return Ok(HttpResponse::Ok().json(TwoFactorChallenge {
+            two_factor_required: true,
+            challenge,
+            expires_at,
+        }));
+    }
 
-    let mut count_builder = QueryBuilder::new(
-        r#"
-        SELECT count(*) as total
-        FROM commands
-        WHERE 1=1
-    "#,
-    );
+    login_response(
+        &state,
+        &req,
+        user_id,
+        label,
+        session,
+        StatusCode::OK,
+        Event::new(Action::LoginSucceeded).details(serde_json::json!({ "method": "oidc" })),
+    )
+    .await
+}
 
-    if let Some(t) = token.as_ref() {
-        count_builder.push(" AND (owner_token = ");
-        count_builder.push_bind(t.id);
-        count_builder.push(" OR visibility = 'PUBLIC')");
-    } else {
-        count_builder.push(" AND visibility = 'PUBLIC'");
+/// Answers a successful login with a bearer token, or with session cookies when asked,
+/// and records `event` against the new token.
+async fn login_response(
+    state: &AppState,
+    req: &HttpRequest,
+    user_id: Uuid,
+    label: &str,
+    session: bool,
+    status: StatusCode,
+    event: Event,
+) -> Result<HttpResponse, ApiError> {
+    let blocked: Option<DateTime<Utc>> =
+        sqlx::query_scalar("SELECT blocked_at FROM users WHERE id = $1")
+            .bind(user_id)
+            .fetch_one(&state.pool)
+            .await?;
+    if blocked.is_some() {
+        Event::new(Action::LoginFailed)
+            .target("user", user_id)
+            .details(serde_json::json!({ "reason": "blocked" }))
+            .record(&state.pool, &Actor::new(req, state))
+            .await?;
+        return Err(ApiError::Forbidden("account is blocked".into()));
     }
 
-    if let Some(filter) = &query.q {
-        count_builder.push(" AND text ILIKE ");
-        count_builder.push_bind(format!("%{filter}%"));
+    if !session {
+        let (token_id, token_value) = issue_login_token(&state.pool, user_id, label).await?;
+        let actor = Actor::new(req, state).user(user_id, Some(token_id));
+        event.record(&state.pool, &actor).await?;
+        return Ok(HttpResponse::build(status).json(AuthResponse {
+            token: token_value,
+            user_id,
+        }));
     }
 
-    if let Some(tag_filter) = &query.tag {
-        count_builder.push(
-            " AND EXISTS (SELECT 1 FROM command_tags ct JOIN tags t ON t.id = ct.tag_id WHERE ct.command_id = commands.id AND t.name ILIKE ",
-        );
-        count_builder.push_bind(format!("%{tag_filter}%"));
-        count_builder.push(")");
-    }
+    let user_agent = req.headers().get(USER_AGENT).and_then(|h| h.to_str().ok());
+    let issued = sessions::start(&state.pool, user_id, label, user_agent).await?;
+    let actor = Actor::new(req, state).user(user_id, Some(issued.token_id));
+    event.record(&state.pool, &actor).await?;
+    session_response(state, &issued, status)
+}
 
-    let total_row = count_builder.build().fetch_one(pool).await?;
-    let total: i64 = total_row.try_get("total").unwrap_or(0);
+fn session_response(
+    state: &AppState,
+    issued: &sessions::IssuedSession,
+    status: StatusCode,
+) -> Result<HttpResponse, ApiError> {
+    let mut response = HttpResponse::build(status);
+    for cookie in state.sessions.cookies(issued) {
+        response.cookie(cookie);
+    }
 
-    let ids: Vec<Uuid> = commands.iter().map(|c| c.id).collect();
-    let tags = load_tags(pool, &ids).await?;
+    Ok(response.json(SessionResponse {
+        user_id: issued.user_id,
+        csrf_token: issued.csrf_token.clone(),
+        access_expires_at: issued.access_expires_at,
+        expires_at: issued.expires_at,
+    }))
+}
 
-    let merged: Vec<CommandWithTags> = commands
-        .into_iter()
-        .map(|c| CommandWithTags {
-            id: c.id,
-            title: c.title,
-            text: c.text,
-            description: c.description,
-            platform: c.platform,
-            visibility: c.visibility,
-            favorite: c.favorite,
-            usage_count: c.usage_count,
-            tags: tags.get(&c.id).cloned().unwrap_or_default(),
-            created_at: c.created_at,
-            last_used_at: c.last_used_at,
-        })
-        .collect();
+/// The current cookie session, so a reloaded UI can recover its CSRF token.
+#[get("/api/session")]
+pub async fn get_session(
+    state: Data<AppState>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    let session = sessions::from_cookie(&req, &state.pool)
+        .await?
+        .ok_or(ApiError::Unauthorized)?;
+    let user_id = session.token.user_id.ok_or(ApiError::Unauthorized)?;
 
-    Ok(HttpResponse::Ok().json(crate::models::Page {
-        items: merged,
-        total,
-        limit,
-        offset,
+    Ok(HttpResponse::Ok().json(SessionResponse {
+        user_id,
+        csrf_token: session.csrf_token,
+        access_expires_at: session.access_expires_at,
+        expires_at: session.expires_at,
     }))
 }
 
-#[post("/api/commands")]
-pub async fn create_command(
+/// Rotates the refresh cookie and issues a new access cookie.
+#[post("/api/session/refresh")]
+pub async fn refresh_session(
     state: Data<AppState>,
     req: HttpRequest,
-    payload: web::Json<CommandPayload>,
 ) -> Result<HttpResponse, ApiError> {
-    let pool = &state.pool;
-    let token = auth::require_token(&req, pool).await?;
-
-    let visibility = payload
-        .visibility
-        .clone()
-        .unwrap_or_else(|| "PRIVATE".to_string());
+    let Some(cookie) = req.cookie(sessions::REFRESH_COOKIE) else {
+        return Err(ApiError::Unauthorized);
+    };
 
-    if visibility != "PUBLIC" && visibility != "PRIVATE" {
-        return Err(ApiError::BadRequest(
-            "visibility must be PUBLIC or PRIVATE".into(),
-        ));
+    match sessions::refresh(&state.pool, cookie.value()).await {
+        Ok(issued) => session_response(&state, &issued, StatusCode::OK),
+        Err(RefreshError::Db(err)) => Err(ApiError::Db(err)),
+        Err(err) => {
+            if let RefreshError::Reused(user_id) = err {
+                Event::new(Action::RefreshTokenReused)
+                    .target("user", user_id)
+                    .record(&state.pool, &Actor::new(&req, &state))
+                    .await?;
+            }
+            let mut response = HttpResponse::Unauthorized();
+            for cookie in state.sessions.cleared() {
+                response.cookie(cookie);
+            }
+            Ok(response.json(serde_json::json!({ "error": "Unauthorized" })))
+        }
     }
+}
 
-    let command_id = Uuid::new_v4();
-    let now = Utc::now();
-
-    sqlx::query(
-        r#"
-        INSERT INTO commands (
-            id, title, text, description, platform, visibility, favorite, usage_count, owner_token, created_at, updated_at, last_used_at
-        ) VALUES (
-            $1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $10, $10
-        )
-        "#,
-    )
-    .bind(command_id)
-    .bind(&payload.title)
-    .bind(&payload.text)
-    .bind(&payload.description)
-    .bind(&payload.platform)
-    .bind(&visibility)
-    .bind(payload.favorite.unwrap_or(false))
-    .bind(0_i32)
-    .bind(token.id)
-    .bind(now)
-    .execute(pool)
-    .await?;
-
-    if let Some(tags) = &payload.tags {
-        for name in tags {
-            let tag_id = sqlx::query(
-                r#"
-                INSERT INTO tags (id, name, owner_token)
-                VALUES ($1, $2, $3)
-                ON CONFLICT (owner_token, name) DO UPDATE SET name = EXCLUDED.name
-                RETURNING id
-                "#,
-            )
-            .bind(Uuid::new_v4())
-            .bind(name)
-            .bind(token.id)
-            .fetch_one(pool)
-            .await?
-            .get::<Uuid, _>("id");
-
-            sqlx::query(
-                "INSERT INTO command_tags (command_id, tag_id) VALUES ($1, $2) ON CONFLICT DO NOTHING",
-            )
-            .bind(command_id)
-            .bind(tag_id)
-            .execute(pool)
+#[post("/api/session/logout")]
+pub async fn logout_session(
+    state: Data<AppState>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    // Logging out works without the CSRF header too; it just goes unrecorded
+    if let Ok(Some(token)) = auth::optional_token(&req, &state.pool).await {
+        let actor = Actor::new(&req, &state).token(&token);
+        Event::new(Action::LoggedOut)
+            .record(&state.pool, &actor)
             .await?;
-        }
     }
+    sessions::revoke_current(&req, &state.pool).await?;
 
-    let tags = load_tags(pool, &[command_id]).await?;
-
-    let response = CommandWithTags {
-        id: command_id,
-        title: payload.title.clone(),
-        text: payload.text.clone(),
-        description: payload.description.clone(),
-        platform: payload.platform.clone(),
-        visibility,
-        favorite: payload.favorite.unwrap_or(false),
-        usage_count: 0,
-        tags: tags.get(&command_id).cloned().unwrap_or_default(),
-        created_at: now,
-        last_used_at: Some(now),
-    };
-
-    Ok(HttpResponse::Created().json(response))
+    let mut response = HttpResponse::NoContent();
+    for cookie in state.sessions.cleared() {
+        response.cookie(cookie);
+    }
+    Ok(response.finish())
 }
 
-#[delete("/api/commands/{id}")]
-pub async fn delete_command(
+/// Ends every web session of the account, and optionally revokes its bearer tokens.
+#[post("/api/session/logout-all")]
+pub async fn logout_everywhere(
     state: Data<AppState>,
     req: HttpRequest,
-    path: web::Path<Uuid>,
+    payload: Option<web::Json<LogoutAllRequest>>,
 ) -> Result<HttpResponse, ApiError> {
     let pool = &state.pool;
     let token = auth::require_token(&req, pool).await?;
-    let command_id = path.into_inner();
+    let user_id = token.user_id.ok_or(ApiError::NotFound)?;
+    let payload = payload.map(|p| p.into_inner()).unwrap_or_default();
 
-    let result = sqlx::query("DELETE FROM commands WHERE id = $1 AND owner_token = $2")
-        .bind(command_id)
-        .bind(token.id)
-        .execute(pool)
+    let sessions_revoked = sessions::revoke_all(pool, user_id, "logout_all").await?;
+    let tokens_revoked = if payload.include_tokens {
+        revoke_user_tokens(pool, user_id).await?
+    } else {
+        0
+    };
+    Event::new(Action::LoggedOutEverywhere)
+        .details(serde_json::json!({
+            "sessions_revoked": sessions_revoked,
+            "tokens_revoked": tokens_revoked,
+        }))
+        .record(pool, &Actor::new(&req, &state).token(&token))
         .await?;
 
-    if result.rows_affected() == 0 {
-        return Err(ApiError::NotFound);
+    let mut response = HttpResponse::Ok();
+    for cookie in state.sessions.cleared() {
+        response.cookie(cookie);
     }
-
-    Ok(HttpResponse::NoContent().finish())
+    Ok(response.json(LogoutAllSummary {
+        sessions_revoked,
+        tokens_revoked,
+    }))
 }
 
-#[post("/api/suggest")]
-pub async fn suggest_commands(
+#[get("/api/account")]
+pub async fn get_account(
+    state: Data<AppState>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    let pool = &state.pool;
+    let token = auth::require_token(&req, pool).await?;
+    let user_id = token.user_id.ok_or(ApiError::NotFound)?;
+
+    let account = sqlx::query_as::<_, AccountInfo>(
+        r#"
+        SELECT id AS user_id, email, email_verified_at, password_hash IS NOT NULL AS has_password
+        FROM users
+        WHERE id = $1
+        "#,
+    )
+    .bind(user_id)
+    .fetch_one(pool)
+    .await?;
+
+    Ok(HttpResponse::Ok().json(account))
+}
+
+/// How recent the login of a passwordless account must be to delete it.
+const ACCOUNT_DELETION_REAUTH_MINUTES: i64 = 10;
+
+/// Deletes the signed-in account and everything its tokens own. Needs the password, or a
+/// recent login for accounts without one, plus a second factor when 2FA is enabled.
+#[delete("/api/account")]
+pub async fn delete_account(
+    state: Data<AppState>,
+    req: HttpRequest,
+    payload: web::Json<DeleteAccountRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let pool = &state.pool;
+    let token = auth::require_token(&req, pool).await?;
+    let user_id = token.user_id.ok_or(ApiError::NotFound)?;
+
+    let row = sqlx::query(
+        "SELECT email, password_hash IS NOT NULL AS has_password FROM users WHERE id = $1",
+    )
+    .bind(user_id)
+    .fetch_one(pool)
+    .await?;
+    let email: String = row.get("email");
+
+    if row.get::<bool, _>("has_password") {
+        let password = payload
+            .password
+            .as_deref()
+            .ok_or_else(|| ApiError::BadRequest("password is required".into()))?;
+        throttle::check(pool, Scope::LoginAccount, &email).await?;
+        if check_password(pool, &email, password).await? != Some(user_id) {
+            throttle::record_failure(pool, Scope::LoginAccount, &email).await?;
+            return Err(ApiError::BadRequest("wrong password".into()));
+        }
+    } else if Utc::now() - token.created_at > Duration::minutes(ACCOUNT_DELETION_REAUTH_MINUTES) {
+        return Err(ApiError::Forbidden(format!(
+            "log in again (within {ACCOUNT_DELETION_REAUTH_MINUTES} minutes) to delete this account"
+        )));
+    }
+
+    if two_factor::is_enabled(pool, user_id).await? {
+        let code = payload
+            .code
+            .as_deref()
+            .ok_or_else(|| ApiError::BadRequest("a two-factor code is required".into()))?;
+        check_second_factor(pool, user_id, code).await?;
+    }
+
+    let mut tx = pool.begin().await?;
+    let archived_commands = if payload.transfer_public_commands {
+        accounts::archive_public_commands(&mut tx, user_id).await?
+    } else {
+        0
+    };
+    // Recorded first so the deletion strips this entry's request metadata as well
+    Event::new(Action::AccountDeleted)
+        .target("user", user_id)
+        .details(serde_json::json!({ "archived_commands": archived_commands }))
+        .record(&mut *tx, &Actor::new(&req, &state).token(&token))
+        .await?;
+    accounts::delete_user(&mut tx, user_id).await?;
+    tx.commit().await?;
+
+    throttle::reset(pool, Scope::LoginAccount, &email).await?;
+    throttle::reset(pool, Scope::SecondFactor, &user_id.to_string()).await?;
+
+    let mut response = HttpResponse::Ok();
+    for cookie in state.sessions.cleared() {
+        response.cookie(cookie);
+    }
+    Ok(response.json(AccountDeletionSummary { archived_commands }))
+}
+
+/// Everything stored about the signed-in user, across all of their tokens.
+#[get("/api/account/export")]
+pub async fn export_account(
+    state: Data<AppState>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    let pool = &state.pool;
+    let (token, user_id) = require_user(&req, pool).await?;
+    auth::deny_impersonation(&req)?;
+    let document = accounts::export(pool, user_id).await?;
+    Event::new(Action::AccountExported)
+        .record(pool, &Actor::new(&req, &state).token(&token))
+        .await?;
+    let body = serde_json::to_string_pretty(&document).map_err(|_| ApiError::Internal)?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .insert_header((
+            CONTENT_DISPOSITION,
+            "attachment; filename=\"cmvault-account.json\"",
+        ))
+        .body(body))
+}
+
+/// The signed-in user's logins, credential changes and other security events, newest first.
+#[get("/api/account/security-events")]
+pub async fn list_security_events(
+    state: Data<AppState>,
+    req: HttpRequest,
+    query: web::Query<SecurityEventsQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let pool = &state.pool;
+    let (_, user_id) = require_user(&req, pool).await?;
+    auth::deny_impersonation(&req)?;
+    let limit = query.limit.unwrap_or(50).clamp(1, 200);
+    let offset = query.offset.unwrap_or(0).max(0);
+
+    let (items, total) = audit::security_events(pool, user_id, limit, offset).await?;
+
+    Ok(HttpResponse::Ok().json(crate::models::Page {
+        items,
+        total,
+        limit,
+        offset,
+    }))
+}
+
+/// The full audit log, for admins.
+#[get("/api/admin/audit")]
+pub async fn search_audit_log(
+    state: Data<AppState>,
+    req: HttpRequest,
+    query: web::Query<AuditQuery>,
+) -> Result<HttpResponse, ApiError> {
+    auth::require_admin(&req, &state).await?;
+    let limit = query.limit.unwrap_or(50).clamp(1, 500);
+    let offset = query.offset.unwrap_or(0).max(0);
+
+    let (items, total) = audit::search(&state.pool, &query, limit, offset).await?;
+
+    Ok(HttpResponse::Ok().json(crate::models::Page {
+        items,
+        total,
+        limit,
+        offset,
+    }))
+}
+
+#[get("/api/admin/users")]
+pub async fn admin_list_users(
+    state: Data<AppState>,
+    req: HttpRequest,
+    query: web::Query<AdminUserQuery>,
+) -> Result<HttpResponse, ApiError> {
+    auth::require_admin(&req, &state).await?;
+    let limit = query.limit.unwrap_or(50).clamp(1, 200);
+    let offset = query.offset.unwrap_or(0).max(0);
+
+    let (items, total) = admin::list_users(&state.pool, &query, limit, offset).await?;
+
+    Ok(HttpResponse::Ok().json(crate::models::Page {
+        items,
+        total,
+        limit,
+        offset,
+    }))
+}
+
+#[get("/api/admin/users/{id}")]
+pub async fn admin_get_user(
+    state: Data<AppState>,
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+) -> Result<HttpResponse, ApiError> {
+    auth::require_admin(&req, &state).await?;
+    let user = admin::get_user(&state.pool, path.into_inner()).await?;
+    Ok(HttpResponse::Ok().json(user))
+}
+
+/// The user's commands across all their tokens, private ones included.
+#[get("/api/admin/users/{id}/commands")]
+pub async fn admin_user_commands(
+    state: Data<AppState>,
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+    query: web::Query<AdminCommandsQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let pool = &state.pool;
+    let admin_token = auth::require_admin(&req, &state).await?;
+    let user_id = admin::get_user(pool, path.into_inner()).await?.id;
+    let limit = query.limit.unwrap_or(20).clamp(1, 200);
+    let offset = query.offset.unwrap_or(0).max(0);
+    let include_deleted = query.include_deleted.unwrap_or(false);
+
+    let (commands, total) =
+        admin::user_commands(pool, user_id, include_deleted, limit, offset).await?;
+    Event::new(Action::AdminUserViewed)
+        .target("user", user_id)
+        .details(serde_json::json!({ "view": "commands" }))
+        .record(pool, &Actor::new(&req, &state).token(&admin_token))
+        .await?;
+
+    let ids: Vec<Uuid> = commands.iter().map(|c| c.id).collect();
+    let tags = load_tags(pool, &ids).await?;
+    let forks = load_fork_counts(pool, &ids).await?;
+    let items: Vec<CommandWithTags> = commands
+        .into_iter()
+        .map(|c| command_response(c, &tags, &forks))
+        .collect();
+
+    Ok(HttpResponse::Ok().json(crate::models::Page {
+        items,
+        total,
+        limit,
+        offset,
+    }))
+}
+
+/// The user's tokens, revoked ones included; token values are never shown.
+#[get("/api/admin/users/{id}/tokens")]
+pub async fn admin_user_tokens(
+    state: Data<AppState>,
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+) -> Result<HttpResponse, ApiError> {
+    let pool = &state.pool;
+    let admin_token = auth::require_admin(&req, &state).await?;
+    let user_id = admin::get_user(pool, path.into_inner()).await?.id;
+
+    let tokens = admin::user_tokens(pool, user_id).await?;
+    Event::new(Action::AdminUserViewed)
+        .target("user", user_id)
+        .details(serde_json::json!({ "view": "tokens" }))
+        .record(pool, &Actor::new(&req, &state).token(&admin_token))
+        .await?;
+
+    Ok(HttpResponse::Ok().json(tokens))
+}
+
+#[put("/api/admin/users/{id}/role")]
+pub async fn admin_set_role(
+    state: Data<AppState>,
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+    payload: web::Json<RoleUpdate>,
+) -> Result<HttpResponse, ApiError> {
+    let pool = &state.pool;
+    let admin_token = auth::require_admin(&req, &state).await?;
+    let user = admin::get_user(pool, path.into_inner()).await?;
+    let role = payload.role.trim().to_lowercase();
+    if !admin::ROLES.contains(&role.as_str()) {
+        return Err(ApiError::BadRequest("role must be user or admin".into()));
+    }
+    if admin_token.user_id == Some(user.id) && role != admin::ROLE_ADMIN {
+        return Err(ApiError::BadRequest(
+            "you cannot remove your own admin role".into(),
+        ));
+    }
+
+    if role != user.role {
+        sqlx::query("UPDATE users SET role = $2 WHERE id = $1")
+            .bind(user.id)
+            .bind(&role)
+            .execute(pool)
+            .await?;
+        // Support access must not carry over into admin access
+        if role == admin::ROLE_ADMIN {
+            admin::revoke_impersonations(pool, user.id).await?;
+        }
+        Event::new(Action::AdminRoleChanged)
+            .target("user", user.id)
+            .details(serde_json::json!({ "from": user.role, "to": role }))
+            .record(pool, &Actor::new(&req, &state).token(&admin_token))
+            .await?;
+    }
+
+    Ok(HttpResponse::Ok().json(admin::get_user(pool, user.id).await?))
+}
+
+/// Blocks logins and stops the user's tokens and sessions from working. Web sessions
+/// are ended for good; API tokens work again after unblocking.
+#[post("/api/admin/users/{id}/block")]
+pub async fn admin_block_user(
+    state: Data<AppState>,
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+    payload: Option<web::Json<BlockUserRequest>>,
+) -> Result<HttpResponse, ApiError> {
+    let pool = &state.pool;
+    let admin_token = auth::require_admin(&req, &state).await?;
+    let user = admin::get_user(pool, path.into_inner()).await?;
+    let payload = payload.map(|p| p.into_inner()).unwrap_or_default();
+    if admin_token.user_id == Some(user.id) {
+        return Err(ApiError::BadRequest(
+            "you cannot block your own account".into(),
+        ));
+    }
+
+    if user.blocked_at.is_none() {
+        let reason = payload
+            .reason
+            .as_deref()
+            .map(str::trim)
+            .filter(|r| !r.is_empty());
+        sqlx::query("UPDATE users SET blocked_at = now(), blocked_reason = $2 WHERE id = $1")
+            .bind(user.id)
+            .bind(reason)
+            .execute(pool)
+            .await?;
+        let sessions_revoked = sessions::revoke_all(pool, user.id, "blocked").await?;
+        admin::revoke_impersonations(pool, user.id).await?;
+        Event::new(Action::AdminUserBlocked)
+            .target("user", user.id)
+            .details(serde_json::json!({
+                "reason": reason,
+                "sessions_revoked": sessions_revoked,
+            }))
+            .record(pool, &Actor::new(&req, &state).token(&admin_token))
+            .await?;
+    }
+
+    Ok(HttpResponse::Ok().json(admin::get_user(pool, user.id).await?))
+}
+
+#[post("/api/admin/users/{id}/unblock")]
+pub async fn admin_unblock_user(
+    state: Data<AppState>,
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+) -> Result<HttpResponse, ApiError> {
+    let pool = &state.pool;
+    let admin_token = auth::require_admin(&req, &state).await?;
+    let user = admin::get_user(pool, path.into_inner()).await?;
+
+    if user.blocked_at.is_some() {
+        sqlx::query("UPDATE users SET blocked_at = NULL, blocked_reason = NULL WHERE id = $1")
+            .bind(user.id)
+            .execute(pool)
+            .await?;
+        Event::new(Action::AdminUserUnblocked)
+            .target("user", user.id)
+            .record(pool, &Actor::new(&req, &state).token(&admin_token))
+            .await?;
+    }
+
+    Ok(HttpResponse::Ok().json(admin::get_user(pool, user.id).await?))
+}
+
+/// Revokes every token, web session and impersonation grant of the user. The data the
+/// tokens own is kept.
+#[post("/api/admin/users/{id}/revoke-tokens")]
+pub async fn admin_revoke_user_tokens(
+    state: Data<AppState>,
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+) -> Result<HttpResponse, ApiError> {
+    let pool = &state.pool;
+    let admin_token = auth::require_admin(&req, &state).await?;
+    let user = admin::get_user(pool, path.into_inner()).await?;
+
+    let sessions_revoked = sessions::revoke_all(pool, user.id, "admin_revoked").await?;
+    let tokens_revoked = revoke_user_tokens(pool, user.id).await?;
+    admin::revoke_impersonations(pool, user.id).await?;
+    Event::new(Action::AdminTokensRevoked)
+        .target("user", user.id)
+        .details(serde_json::json!({
+            "sessions_revoked": sessions_revoked,
+            "tokens_revoked": tokens_revoked,
+        }))
+        .record(pool, &Actor::new(&req, &state).token(&admin_token))
+        .await?;
+
+    Ok(HttpResponse::Ok().json(LogoutAllSummary {
+        sessions_revoked,
+        tokens_revoked,
+    }))
+}
+
+/// Issues a short-lived bearer token that acts as one of the user's tokens for GET
+/// requests only, so support can see what the user sees.
+#[post("/api/admin/users/{id}/impersonate")]
+pub async fn admin_impersonate_user(
+    state: Data<AppState>,
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+    payload: web::Json<ImpersonationRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let pool = &state.pool;
+    let admin_token = auth::require_admin(&req, &state).await?;
+    let user = admin::get_user(pool, path.into_inner()).await?;
+    let reason = payload.reason.trim();
+    if reason.is_empty() {
+        return Err(ApiError::BadRequest("reason is required".into()));
+    }
+    if user.role == admin::ROLE_ADMIN {
+        return Err(ApiError::BadRequest("admins cannot be impersonated".into()));
+    }
+
+    let token_id: Uuid = match payload.token_id {
+        Some(token_id) => {
+            sqlx::query_scalar("SELECT id FROM api_tokens WHERE id = $1 AND user_id = $2")
+                .bind(token_id)
+                .bind(user.id)
+                .fetch_optional(pool)
+                .await?
+                .ok_or(ApiError::NotFound)?
+        }
+        None => sqlx::query_scalar(
+            r#"
+            SELECT id FROM api_tokens
+            WHERE user_id = $1 AND revoked_at IS NULL
+            ORDER BY created_at DESC
+            LIMIT 1
+            "#,
+        )
+        .bind(user.id)
+        .fetch_optional(pool)
+        .await?
+        .ok_or_else(|| ApiError::BadRequest("user has no active tokens; pass token_id".into()))?,
+    };
+
+    let mut tx = pool.begin().await?;
+    let (token, expires_at) =
+        admin::grant_impersonation(&mut tx, user.id, token_id, &admin_token, reason).await?;
+    Event::new(Action::AdminImpersonationStarted)
+        .target("user", user.id)
+        .details(serde_json::json!({
+            "token_id": token_id,
+            "reason": reason,
+            "expires_at": expires_at,
+        }))
+        .record(&mut *tx, &Actor::new(&req, &state).token(&admin_token))
+        .await?;
+    tx.commit().await?;
+
+    Ok(HttpResponse::Created().json(ImpersonationResponse {
+        token,
+        token_id,
+        expires_at,
+    }))
+}
+
+/// Sends a fresh verification link to the signed-in user's address.
+#[post("/api/email/verify/request")]
+pub async fn request_email_verification(
+    state: Data<AppState>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    let pool = &state.pool;
+    let token = auth::require_token(&req, pool).await?;
+    let user_id = token.user_id.ok_or(ApiError::NotFound)?;
+
+    let row = sqlx::query(
+        "SELECT email, email_verified_at IS NOT NULL AS verified FROM users WHERE id = $1",
+    )
+    .bind(user_id)
+    .fetch_one(pool)
+    .await?;
+    if row.get::<bool, _>("verified") {
+        return Err(ApiError::BadRequest("email already verified".into()));
+    }
+
+    let email: String = row.get("email");
+    let secret = verification::issue(pool, user_id, &email, TokenPurpose::VerifyEmail).await?;
+    state.mailer.deliver(verification::verification_email(
+        &state.web_url,
+        &email,
+        &secret,
+    ));
+
+    Ok(HttpResponse::Accepted().finish())
+}
+
+#[post("/api/email/verify")]
+pub async fn verify_email(
+    state: Data<AppState>,
+    req: HttpRequest,
+    payload: web::Json<VerifyEmailRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let mut tx = state.pool.begin().await?;
+    let (user_id, email) =
+        verification::consume(&mut *tx, &payload.token, TokenPurpose::VerifyEmail).await?;
+
+    // A link sent to an address the account no longer uses proves nothing
+    let result = sqlx::query(
+        "UPDATE users SET email_verified_at = COALESCE(email_verified_at, now()) WHERE id = $1 AND email = $2",
+    )
+    .bind(user_id)
+    .bind(&email)
+    .execute(&mut *tx)
+    .await?;
+    if result.rows_affected() == 0 {
+        return Err(ApiError::BadRequest("invalid or expired token".into()));
+    }
+    Event::new(Action::EmailVerified)
+        .target("user", user_id)
+        .record(&mut *tx, &Actor::new(&req, &state))
+        .await?;
+    tx.commit().await?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Emails a reset link if the address has an account. Always answers 202 so the
+/// endpoint cannot be used to find out which addresses are registered.
+#[post("/api/password/forgot")]
+pub async fn forgot_password(
+    state: Data<AppState>,
+    payload: web::Json<ForgotPasswordRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let pool = &state.pool;
+    let email = payload.email.trim().to_lowercase();
+
+    let user_id: Option<Uuid> = sqlx::query_scalar("SELECT id FROM users WHERE email = $1")
+        .bind(&email)
+        .fetch_optional(pool)
+        .await?;
+    if let Some(user_id) = user_id {
+        let secret =
+            verification::issue(pool, user_id, &email, TokenPurpose::PasswordReset).await?;
+        state.mailer.deliver(verification::password_reset_email(
+            &state.web_url,
+            &email,
+            &secret,
+        ));
+    }
+
+    Ok(HttpResponse::Accepted().finish())
+}
+
+/// Sets a new password and signs the account out everywhere, since whoever knew the
+/// old one may still hold sessions or tokens.
+#[post("/api/password/reset")]
+pub async fn reset_password(
+    state: Data<AppState>,
+    req: HttpRequest,
+    payload: web::Json<ResetPasswordRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let pool = &state.pool;
+    let password_hash = hash_password(&payload.password)?;
+
+    let mut tx = pool.begin().await?;
+    let (user_id, email) =
+        verification::consume(&mut *tx, &payload.token, TokenPurpose::PasswordReset).await?;
+    // Receiving the link also proves the address
+    let result = sqlx::query(
+        r#"
+        UPDATE users
+        SET password_hash = $3, email_verified_at = COALESCE(email_verified_at, now())
+        WHERE id = $1 AND email = $2
+        "#,
+    )
+    .bind(user_id)
+    .bind(&email)
+    .bind(&password_hash)
+    .execute(&mut *tx)
+    .await?;
+    if result.rows_affected() == 0 {
+        return Err(ApiError::BadRequest("invalid or expired token".into()));
+    }
+    Event::new(Action::PasswordReset)
+        .target("user", user_id)
+        .record(&mut *tx, &Actor::new(&req, &state))
+        .await?;
+    tx.commit().await?;
+
+    sessions::revoke_all(pool, user_id, "password_reset").await?;
+    revoke_user_tokens(pool, user_id).await?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[get("/api/account/2fa")]
+pub async fn get_two_factor(
+    state: Data<AppState>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    let pool = &state.pool;
+    let (_, user_id) = require_user(&req, pool).await?;
+
+    let row = sqlx::query(
+        r#"
+        SELECT
+            (SELECT enabled_at FROM user_totp WHERE user_id = $1) AS enabled_at,
+            (SELECT COUNT(*) FROM user_recovery_codes WHERE user_id = $1 AND used_at IS NULL) AS remaining
+        "#,
+    )
+    .bind(user_id)
+    .fetch_one(pool)
+    .await?;
+
+    let enabled_at: Option<DateTime<Utc>> = row.get("enabled_at");
+    Ok(HttpResponse::Ok().json(TwoFactorStatus {
+        enabled: enabled_at.is_some(),
+        enabled_at,
+        recovery_codes_remaining: row.get("remaining"),
+        challenged_logins: two_factor::CHALLENGED_LOGINS,
+    }))
+}
+
+/// Generates a TOTP secret. 2FA is only switched on once a code from it is confirmed.
+#[post("/api/account/2fa/totp")]
+pub async fn start_totp_enrollment(
+    state: Data<AppState>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    let pool = &state.pool;
+    let (_, user_id) = require_user(&req, pool).await?;
+    let secret = two_factor::new_secret();
+
+    let email: Option<String> = sqlx::query_scalar(
+        r#"
+        WITH enrolled AS (
+            INSERT INTO user_totp (user_id, secret, created_at)
+            VALUES ($1, $2, now())
+            ON CONFLICT (user_id) DO UPDATE
+            SET secret = EXCLUDED.secret, created_at = now()
+            WHERE user_totp.enabled_at IS NULL
+            RETURNING user_id
+        )
+        SELECT u.email FROM users u JOIN enrolled e ON e.user_id = u.id
+        "#,
+    )
+    .bind(user_id)
+    .bind(&secret)
+    .fetch_optional(pool)
+    .await?;
+    let email = email.ok_or_else(|| {
+        ApiError::BadRequest("two-factor authentication is already enabled".into())
+    })?;
+
+    Ok(HttpResponse::Ok().json(TotpEnrollment {
+        otpauth_uri: two_factor::otpauth_uri(&secret, &email)?,
+        secret,
+    }))
+}
+
+/// Turns 2FA on with a code from the new secret and returns the first recovery codes.
+#[post("/api/account/2fa/totp/confirm")]
+pub async fn confirm_totp_enrollment(
+    state: Data<AppState>,
+    req: HttpRequest,
+    payload: web::Json<TwoFactorCode>,
+) -> Result<HttpResponse, ApiError> {
+    let pool = &state.pool;
+    let (token, user_id) = require_user(&req, pool).await?;
+    let subject = user_id.to_string();
+    throttle::check(pool, Scope::SecondFactor, &subject).await?;
+
+    let row = sqlx::query(
+        r#"
+        SELECT t.secret, u.email
+        FROM user_totp t
+        JOIN users u ON u.id = t.user_id
+        WHERE t.user_id = $1 AND t.enabled_at IS NULL
+        "#,
+    )
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await?
+    .ok_or_else(|| ApiError::BadRequest("start enrollment first".into()))?;
+
+    let secret: String = row.get("secret");
+    let email: String = row.get("email");
+    let Some(step) = two_factor::verify_code(&secret, &email, &payload.code, None)? else {
+        throttle::record_failure(pool, Scope::SecondFactor, &subject).await?;
+        return Err(ApiError::BadRequest("invalid code".into()));
+    };
+    throttle::reset(pool, Scope::SecondFactor, &subject).await?;
+
+    let mut tx = pool.begin().await?;
+    let result = sqlx::query(
+        "UPDATE user_totp SET enabled_at = now(), last_used_step = $2 WHERE user_id = $1 AND enabled_at IS NULL",
+    )
+    .bind(user_id)
+    .bind(step)
+    .execute(&mut *tx)
+    .await?;
+    if result.rows_affected() == 0 {
+        return Err(ApiError::BadRequest(
+            "two-factor authentication is already enabled".into(),
+        ));
+    }
+    let recovery_codes = two_factor::replace_recovery_codes(&mut tx, user_id).await?;
+    Event::new(Action::TwoFactorEnabled)
+        .record(&mut *tx, &Actor::new(&req, &state).token(&token))
+        .await?;
+    tx.commit().await?;
+
+    Ok(HttpResponse::Ok().json(TwoFactorEnabled {
+        recovery_codes,
+        challenged_logins: two_factor::CHALLENGED_LOGINS,
+    }))
+}
+
+/// Replaces the recovery codes; the old ones stop working.
+#[post("/api/account/2fa/recovery-codes")]
+pub async fn regenerate_recovery_codes(
+    state: Data<AppState>,
+    req: HttpRequest,
+    payload: web::Json<TwoFactorCode>,
+) -> Result<HttpResponse, ApiError> {
+    let pool = &state.pool;
+    let (token, user_id) = require_user(&req, pool).await?;
+    check_second_factor(pool, user_id, &payload.code).await?;
+
+    let mut tx = pool.begin().await?;
+    let recovery_codes = two_factor::replace_recovery_codes(&mut tx, user_id).await?;
+    Event::new(Action::RecoveryCodesRegenerated)
+        .record(&mut *tx, &Actor::new(&req, &state).token(&token))
+        .await?;
+    tx.commit().await?;
+
+    Ok(HttpResponse::Ok().json(RecoveryCodes { recovery_codes }))
+}
+
+#[post("/api/account/2fa/disable")]
+pub async fn disable_two_factor(
+    state: Data<AppState>,
+    req: HttpRequest,
+    payload: web::Json<TwoFactorCode>,
+) -> Result<HttpResponse, ApiError> {
+    let pool = &state.pool;
+    let (token, user_id) = require_user(&req, pool).await?;
+    check_second_factor(pool, user_id, &payload.code).await?;
+
+    let mut tx = pool.begin().await?;
+    Event::new(Action::TwoFactorDisabled)
+        .record(&mut *tx, &Actor::new(&req, &state).token(&token))
+        .await?;
+    sqlx::query("DELETE FROM user_totp WHERE user_id = $1")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("DELETE FROM user_recovery_codes WHERE user_id = $1")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Requires a valid second factor from an account with 2FA enabled, throttling wrong codes.
+async fn check_second_factor(pool: &PgPool, user_id: Uuid, code: &str) -> Result<(), ApiError> {
+    let subject = user_id.to_string();
+    throttle::check(pool, Scope::SecondFactor, &subject).await?;
+
+    if !two_factor::verify_second_factor(pool, user_id, code).await? {
+        throttle::record_failure(pool, Scope::SecondFactor, &subject).await?;
+        return Err(ApiError::BadRequest("invalid code".into()));
+    }
+    throttle::reset(pool, Scope::SecondFactor, &subject).await
+}
+
+/// The caller's token and the user it belongs to.
+async fn require_user(req: &HttpRequest, pool: &PgPool) -> Result<(ApiToken, Uuid), ApiError> {
+    let token = auth::require_token(req, pool).await?;
+    let user_id = token.user_id.ok_or_else(|| {
+        ApiError::BadRequest("this endpoint requires a token that belongs to a user".into())
+    })?;
+    Ok((token, user_id))
+}
+
+/// The user `password` belongs to; `None` for unknown emails, wrong passwords and
+/// accounts created through OIDC or a passkey, which have no password to log in with.
+async fn check_password(
+    pool: &PgPool,
+    email: &str,
+    password: &str,
+) -> Result<Option<Uuid>, ApiError> {
+    let row = sqlx::query(r#"SELECT id, password_hash FROM users WHERE email = $1"#)
+        .bind(email)
+        .fetch_optional(pool)
+        .await?;
+    let Some(row) = row else {
+        return Ok(None);
+    };
+
+    let password_hash: Option<String> = row.get("password_hash");
+    let matches = password_hash
+        .as_deref()
+        .and_then(|hash| PasswordHash::new(hash).ok())
+        .is_some_and(|parsed| {
+            Argon2::default()
+                .verify_password(password.as_bytes(), &parsed)
+                .is_ok()
+        });
+
+    Ok(matches.then(|| row.get("id")))
+}
+
+/// Checks the minimum length and hashes a new password with Argon2.
+fn hash_password(password: &str) -> Result<String, ApiError> {
+    if password.len() < 8 {
+        return Err(ApiError::BadRequest(
+            "password must be at least 8 characters".into(),
+        ));
+    }
+
+    let salt = SaltString::generate(&mut OsRng);
+    Ok(Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|_| ApiError::Internal)?
+        .to_string())
+}
+
+/// Best effort: the account exists either way, and the user can ask for another link.
+async fn send_verification_email(state: &AppState, user_id: Uuid, email: &str) {
+    match verification::issue(&state.pool, user_id, email, TokenPurpose::VerifyEmail).await {
+        Ok(secret) => state.mailer.deliver(verification::verification_email(
+            &state.web_url,
+            email,
+            &secret,
+        )),
+        Err(err) => warn!(
+            "Could not issue verification token for {}: {}",
+            user_id, err
+        ),
+    }
+}
+
+async fn revoke_user_tokens(pool: &PgPool, user_id: Uuid) -> Result<u64, ApiError> {
+    let result = sqlx::query(
+        "UPDATE api_tokens SET revoked_at = now() WHERE user_id = $1 AND revoked_at IS NULL",
+    )
+    .bind(user_id)
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+async fn issue_login_token<'e>(
+    executor: impl PgExecutor<'e>,
+    user_id: Uuid,
+    label: &str,
+) -> Result<(Uuid, String), ApiError> {
+    let token_id = Uuid::new_v4();
+    let token_value = Uuid::new_v4().to_string();
+
+    sqlx::query(
+        r#"
+        INSERT INTO api_tokens (id, label, token, user_id, created_at)
+        VALUES ($1, $2, $3, $4, now())
+        "#,
+    )
+    .bind(token_id)
+    .bind(label)
+    .bind(&token_value)
+    .bind(user_id)
+    .execute(executor)
+    .await?;
+
+    Ok((token_id, token_value))
+}
+
+/// Starts adding a passkey to the signed-in account, or a passkey-only signup for `email`.
+#[post("/api/passkeys/register/start")]
+pub async fn start_passkey_registration(
+    state: Data<AppState>,
+    req: HttpRequest,
+    payload: Option<web::Json<PasskeyRegistrationStart>>,
+) -> Result<HttpResponse, ApiError> {
+    let pool = &state.pool;
+    let webauthn = state.passkeys.as_ref().ok_or(ApiError::NotFound)?;
+    let payload = payload.map(|p| p.into_inner()).unwrap_or_default();
+
+    let (kind, user_id, email) = match auth::optional_token(&req, pool).await? {
+        Some(token) => {
+            let user_id = token.user_id.ok_or_else(|| {
+                ApiError::BadRequest("passkeys can only be added to user accounts".into())
+            })?;
+            let email: String = sqlx::query_scalar("SELECT email FROM users WHERE id = $1")
+                .bind(user_id)
+                .fetch_one(pool)
+                .await?;
+            (CeremonyKind::Register, user_id, email)
+        }
+        None => {
+            let email = payload
+                .email
+                .as_deref()
+                .ok_or_else(|| ApiError::BadRequest("email required".into()))
+                .and_then(verification::normalize_email)?;
+            let taken: bool =
+                sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM users WHERE email = $1)")
+                    .bind(&email)
+                    .fetch_one(pool)
+                    .await?;
+            if taken {
+                return Err(ApiError::BadRequest("email already registered".into()));
+            }
+            (CeremonyKind::Signup, Uuid::new_v4(), email)
+        }
+    };
+
+    let exclude: Vec<CredentialID> = passkeys::load_passkeys(pool, user_id)
+        .await?
+        .into_iter()
+        .map(|(_, passkey)| passkey.cred_id().clone())
+        .collect();
+
+    let (options, registration) = webauthn
+        .start_passkey_registration(user_id, &email, &email, Some(exclude))
+        .map_err(passkeys::rejected)?;
+
+    let ceremony = Ceremony {
+        user_id,
+        email: (kind == CeremonyKind::Signup).then_some(email),
+        state: registration,
+    };
+    let ceremony_id = passkeys::save_ceremony(pool, kind, &ceremony).await?;
+
+    Ok(HttpResponse::Ok().json(PasskeyChallenge {
+        ceremony_id,
+        options,
+    }))
+}
+
+/// Stores the new passkey. Signups get an `AuthResponse`, signed-in users the passkey.
+#[post("/api/passkeys/register/finish")]
+pub async fn finish_passkey_registration(
+    state: Data<AppState>,
+    req: HttpRequest,
+    payload: web::Json<PasskeyRegistrationFinish>,
+) -> Result<HttpResponse, ApiError> {
+    let pool = &state.pool;
+    let webauthn = state.passkeys.as_ref().ok_or(ApiError::NotFound)?;
+    let payload = payload.into_inner();
+
+    let (kind, ceremony) = passkeys::take_ceremony::<PasskeyRegistration>(
+        pool,
+        payload.ceremony_id,
+        &[CeremonyKind::Register, CeremonyKind::Signup],
+    )
+    .await?;
+    let token = if kind == CeremonyKind::Register {
+        let token = auth::require_token(&req, pool).await?;
+        if token.user_id != Some(ceremony.user_id) {
+            return Err(ApiError::Unauthorized);
+        }
+        Some(token)
+    } else {
+        None
+    };
+
+    let passkey = webauthn
+        .finish_passkey_registration(&payload.credential, &ceremony.state)
+        .map_err(passkeys::rejected)?;
+    let name = payload
+        .name
+        .as_deref()
+        .map(str::trim)
+        .filter(|n| !n.is_empty())
+        .unwrap_or("Passkey");
+
+    let mut tx = pool.begin().await?;
+    if let Some(email) = &ceremony.email {
+        sqlx::query(
+            r#"
+            INSERT INTO users (id, email, password_hash, registration_method, created_at)
+            VALUES ($1, $2, NULL, 'passkey', now())
+            "#,
+        )
+        .bind(ceremony.user_id)
+        .bind(email)
+        .execute(&mut *tx)
+        .await
+        .map_err(|err| match err {
+            sqlx::Error::Database(db_err) if db_err.is_unique_violation() => {
+                ApiError::BadRequest("email already registered".into())
+            }
+            other => ApiError::Db(other),
+        })?;
+    }
+
+    let info = sqlx::query_as::<_, PasskeyInfo>(
+        r#"
+        INSERT INTO user_passkeys (id, user_id, credential_id, name, passkey, created_at)
+        VALUES ($1, $2, $3, $4, $5, now())
+        RETURNING id, name, created_at, last_used_at
+        "#,
+    )
+    .bind(Uuid::new_v4())
+    .bind(ceremony.user_id)
+    .bind(passkeys::credential_id(&passkey))
+    .bind(name)
+    .bind(sqlx::types::Json(&passkey))
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(|err| match err {
+        sqlx::Error::Database(db_err) if db_err.is_unique_violation() => {
+            ApiError::BadRequest("passkey already registered".into())
+        }
+        other => ApiError::Db(other),
+    })?;
+
+    if let Some(token) = &token {
+        Event::new(Action::PasskeyAdded)
+            .target("passkey", info.id)
+            .record(&mut *tx, &Actor::new(&req, &state).token(token))
+            .await?;
+        tx.commit().await?;
+        return Ok(HttpResponse::Created().json(info));
+    }
+
+    tx.commit().await?;
+    if let Some(email) = &ceremony.email {
+        send_verification_email(&state, ceremony.user_id, email).await;
+    }
+
+    let label = payload.label.as_deref().unwrap_or("Default Token");
+    login_response(
+        &state,
+        &req,
+        ceremony.user_id,
+        label,
+        payload.session,
+        StatusCode::CREATED,
+        Event::new(Action::AccountRegistered).details(serde_json::json!({ "method": "passkey" })),
+    )
+    .await
+}
+
+#[post("/api/passkeys/login/start")]
+pub async fn start_passkey_login(
+    state: Data<AppState>,
+    payload: web::Json<PasskeyLoginStart>,
+) -> Result<HttpResponse, ApiError> {
+    let pool = &state.pool;
+    let webauthn = state.passkeys.as_ref().ok_or(ApiError::NotFound)?;
+    let email = payload.email.trim().to_lowercase();
+
+    let user_id: Option<Uuid> = sqlx::query_scalar("SELECT id FROM users WHERE email = $1")
+        .bind(&email)
+        .fetch_optional(pool)
+        .await?;
+    let credentials: Vec<_> = match user_id {
+        Some(user_id) => passkeys::load_passkeys(pool, user_id)
+            .await?
+            .into_iter()
+            .map(|(_, passkey)| passkey)
+            .collect(),
+        None => Vec::new(),
+    };
+
+    // Emails without passkeys get a challenge too, one that no answer can satisfy
+    let (options, authentication) = if credentials.is_empty() {
+        (passkeys::decoy_login_options(webauthn)?, None)
+    } else {
+        let (options, authentication) = webauthn
+            .start_passkey_authentication(&credentials)
+            .map_err(passkeys::rejected)?;
+        (options, Some(authentication))
+    };
+
+    let ceremony = Ceremony {
+        user_id: user_id.unwrap_or_default(),
+        email: None,
+        state: authentication,
+    };
+    let ceremony_id = passkeys::save_ceremony(pool, CeremonyKind::Login, &ceremony).await?;
+
+    Ok(HttpResponse::Ok().json(PasskeyChallenge {
+        ceremony_id,
+        options,
+    }))
+}
+
+#[post("/api/passkeys/login/finish")]
+pub async fn finish_passkey_login(
+    state: Data<AppState>,
+    req: HttpRequest,
+    payload: web::Json<PasskeyLoginFinish>,
+) -> Result<HttpResponse, ApiError> {
+    let pool = &state.pool;
+    let webauthn = state.passkeys.as_ref().ok_or(ApiError::NotFound)?;
+
+    let (_, ceremony) = passkeys::take_ceremony::<Option<PasskeyAuthentication>>(
+        pool,
+        payload.ceremony_id,
+        &[CeremonyKind::Login],
+    )
+    .await?;
+    let authentication = ceremony.state.ok_or(ApiError::Unauthorized)?;
+
+    let result = webauthn
+        .finish_passkey_authentication(&payload.credential, &authentication)
+        .map_err(passkeys::rejected)?;
+
+    // Keep the signature counter and backup flags current so cloned keys are detected
+    let stored = passkeys::load_passkeys(pool, ceremony.user_id).await?;
+    let (passkey_id, mut passkey) = stored
+        .into_iter()
+        .find(|(_, passkey)| passkey.cred_id() == result.cred_id())
+        .ok_or(ApiError::Unauthorized)?;
+    let changed = passkey.update_credential(&result).unwrap_or(false);
+
+    sqlx::query(
+        r#"
+        UPDATE user_passkeys
+        SET last_used_at = now(), passkey = CASE WHEN $2 THEN $3 ELSE passkey END
+        WHERE id = $1
+        "#,
+    )
+    .bind(passkey_id)
+    .bind(changed)
+    .bind(sqlx::types::Json(&passkey))
+    .execute(pool)
+    .await?;
+
+    let label = payload.label.as_deref().unwrap_or("Login Token");
+    login_response(
+        &state,
+        &req,
+        ceremony.user_id,
+        label,
+        payload.session,
+        StatusCode::OK,
+        Event::new(Action::LoginSucceeded).details(serde_json::json!({ "method": "passkey" })),
+    )
+    .await
+}
+
+#[get("/api/passkeys")]
+pub async fn list_passkeys(
+    state: Data<AppState>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    let pool = &state.pool;
+    let token = auth::require_token(&req, pool).await?;
+    let user_id = token.user_id.ok_or(ApiError::NotFound)?;
+
+    let items = sqlx::query_as::<_, PasskeyInfo>(
+        r#"
+        SELECT id, name, created_at, last_used_at
+        FROM user_passkeys
+        WHERE user_id = $1
+        ORDER BY created_at
+        "#,
+    )
+    .bind(user_id)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(HttpResponse::Ok().json(items))
+}
+
+#[delete("/api/passkeys/{id}")]
+pub async fn delete_passkey(
+    state: Data<AppState>,
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+) -> Result<HttpResponse, ApiError> {
+    let pool = &state.pool;
+    let token = auth::require_token(&req, pool).await?;
+    let user_id = token.user_id.ok_or(ApiError::NotFound)?;
+    let id = path.into_inner();
+
+    // Refuse to remove the last way back into a passkey-only account
+    let row = sqlx::query(
+        r#"
+        SELECT
+            EXISTS (SELECT 1 FROM user_passkeys WHERE id = $1 AND user_id = $2) AS owned,
+            (SELECT count(*) FROM user_passkeys WHERE user_id = $2) AS passkeys,
+            (SELECT password_hash IS NOT NULL FROM users WHERE id = $2) AS has_password,
+            EXISTS (SELECT 1 FROM user_identities WHERE user_id = $2) AS has_identity
+        "#,
+    )
+    .bind(id)
+    .bind(user_id)
+    .fetch_one(pool)
+    .await?;
+
+    if !row.get::<bool, _>("owned") {
+        return Err(ApiError::NotFound);
+    }
+    if row.get::<i64, _>("passkeys") == 1
+        && !row.get::<bool, _>("has_password")
+        && !row.get::<bool, _>("has_identity")
+    {
+        return Err(ApiError::BadRequest(
+            "cannot delete the only passkey of an account without a password".into(),
+        ));
+    }
+
+    sqlx::query("DELETE FROM user_passkeys WHERE id = $1 AND user_id = $2")
+        .bind(id)
+        .bind(user_id)
+        .execute(pool)
+        .await?;
+    Event::new(Action::PasskeyRemoved)
+        .target("passkey", id)
+        .record(pool, &Actor::new(&req, &state).token(&token))
+        .await?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct CommandQuery {
+    pub q: Option<String>,
+    pub tag: Option<String>,
+    pub sort: Option<String>,
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+#[get("/api/commands")]
+pub async fn list_commands(
+    state: Data<AppState>,
+    req: HttpRequest,
+    query: web::Query<CommandQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let pool = &state.pool;
+    let token = auth::optional_token(&req, pool).await?;
+    let limit = query.limit.unwrap_or(20).clamp(1, 200);
+    let offset = query.offset.unwrap_or(0).max(0);
+    let order_by = match query.sort.as_deref().unwrap_or("recent") {
+        "recent" => "created_at DESC",
+        "last_used" => "last_used_at DESC NULLS LAST, created_at DESC",
+        "most_used" => "usage_count DESC, created_at DESC",
+        "most_copied" => "copy_count DESC, usage_count DESC, created_at DESC",
+        _ => {
+            return Err(ApiError::BadRequest(
+                "sort must be recent, last_used, most_used or most_copied".into(),
+            ))
+        }
+    };
+
+    let mut builder = QueryBuilder::new(
+        r#"
+        SELECT id, title, text, description, platform, visibility, favorite, usage_count, copy_count, run_count, accept_count, owner_token, created_at, updated_at, last_used_at, deleted_at, forked_from, risk_level
+        FROM commands
+        WHERE deleted_at IS NULL
+    "#,
+    );
+
+    if let Some(t) = token.as_ref() {
+        builder.push(" AND (owner_token = ");
+        builder.push_bind(t.id);
+        builder.push(" OR visibility = 'PUBLIC')");
+    } else {
+        builder.push(" AND visibility = 'PUBLIC'");
+    }
+
+    if let Some(filter) = &query.q {
+        builder.push(" AND text ILIKE ");
+        builder.push_bind(format!("%{filter}%"));
+    }
+
+    if let Some(tag_filter) = &query.tag {
+        builder.push(
+            " AND EXISTS (SELECT 1 FROM command_tags ct JOIN tags t ON t.id = ct.tag_id WHERE ct.command_id = commands.id AND t.name ILIKE ",
+        );
+        builder.push_bind(format!("%{tag_filter}%"));
+        builder.push(")");
+    }
+
+    builder.push(" ORDER BY ");
+    builder.push(order_by);
+    builder.push(" LIMIT ");
+    builder.push_bind(limit);
+    builder.push(" OFFSET ");
+    builder.push_bind(offset);
+
+    let commands: Vec<Command> = builder.build_query_as().fetch_all(pool).await?;
+
+    let mut count_builder = QueryBuilder::new(
+        r#"
+        SELECT count(*) as total
+        FROM commands
+        WHERE deleted_at IS NULL
+    "#,
+    );
+
+    if let Some(t) = token.as_ref() {
+        count_builder.push(" AND (owner_token = ");
+        count_builder.push_bind(t.id);
+        count_builder.push(" OR visibility = 'PUBLIC')");
+    } else {
+        count_builder.push(" AND visibility = 'PUBLIC'");
+    }
+
+    if let Some(filter) = &query.q {
+        count_builder.push(" AND text ILIKE ");
+        count_builder.push_bind(format!("%{filter}%"));
+    }
+
+    if let Some(tag_filter) = &query.tag {
+        count_builder.push(
+            " AND EXISTS (SELECT 1 FROM command_tags ct JOIN tags t ON t.id = ct.tag_id WHERE ct.command_id = commands.id AND t.name ILIKE ",
+        );
+        count_builder.push_bind(format!("%{tag_filter}%"));
+        count_builder.push(")");
+    }
+
+    let total_row = count_builder.build().fetch_one(pool).await?;
+    let total: i64 = total_row.try_get("total").unwrap_or(0);
+
+    let ids: Vec<Uuid> = commands.iter().map(|c| c.id).collect();
+    let tags = load_tags(pool, &ids).await?;
+    let forks = load_fork_counts(pool, &ids).await?;
+
+    let merged: Vec<CommandWithTags> = commands
+        .into_iter()
+        .map(|c| command_response(c, &tags, &forks))
+        .collect();
+
+    Ok(HttpResponse::Ok().json(crate::models::Page {
+        items: merged,
+        total,
+        limit,
+        offset,
+    }))
+}
+
+#[post("/api/commands")]
+pub async fn create_command(
+    state: Data<AppState>,
+    req: HttpRequest,
+    payload: web::Json<CommandPayload>,
+) -> Result<HttpResponse, ApiError> {
+    let pool = &state.pool;
+    let token = auth::require_token(&req, pool).await?;
+
+    let visibility = validate_command(
+        &payload.text,
+        &payload.platform,
+        payload.visibility.as_deref(),
+    )?;
+    ensure_may_publish(&state, &token, &visibility).await?;
+    let risk = danger::classify(&payload.text);
+
+    let command_id = Uuid::new_v4();
+    let now = Utc::now();
+
+    sqlx::query(
+        r#"
+        INSERT INTO commands (
+            id, title, text, description, platform, visibility, favorite, usage_count, owner_token, created_at, updated_at, last_used_at, risk_level
+        ) VALUES (
+            $1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $10, $10, $11
+        )
+        "#,
+    )
+    .bind(command_id)
+    .bind(&payload.title)
+    .bind(&payload.text)
+    .bind(&payload.description)
+    .bind(&payload.platform)
+    .bind(&visibility)
+    .bind(payload.favorite.unwrap_or(false))
+    .bind(0_i32)
+    .bind(token.id)
+    .bind(now)
+    .bind(risk.level.as_str())
+    .execute(pool)
+    .await?;
+
+    if let Some(tags) = &payload.tags {
+        let mut conn = pool.acquire().await?;
+        attach_tags(&mut conn, token.id, command_id, tags).await?;
+    }
+    Event::new(Action::CommandCreated)
+        .target("command", command_id)
+        .details(serde_json::json!({ "visibility": visibility }))
+        .record(pool, &Actor::new(&req, &state).token(&token))
+        .await?;
+
+    let tags = load_tags(pool, &[command_id]).await?;
+
+    let response = CommandWithTags {
+        id: command_id,
+        title: payload.title.clone(),
+        text: payload.text.clone(),
+        description: payload.description.clone(),
+        platform: payload.platform.clone(),
+        visibility,
+        favorite: payload.favorite.unwrap_or(false),
+        usage_count: 0,
+        copy_count: 0,
+        run_count: 0,
+        accept_count: 0,
+        tags: tags.get(&command_id).cloned().unwrap_or_default(),
+        created_at: now,
+        last_used_at: Some(now),
+        deleted_at: None,
+        forked_from: None,
+        fork_count: 0,
+        risk_level: Some(risk.level.as_str().to_string()),
+    };
+
+    Ok(HttpResponse::Created().json(response))
+}
+
+#[delete("/api/commands/{id}")]
+pub async fn delete_command(
+    state: Data<AppState>,
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+) -> Result<HttpResponse, ApiError> {
+    let pool = &state.pool;
+    let token = auth::require_token(&req, pool).await?;
+    let command_id = path.into_inner();
+
+    let result = sqlx::query(
+        "UPDATE commands SET deleted_at = now() WHERE id = $1 AND owner_token = $2 AND deleted_at IS NULL",
+    )
+    .bind(command_id)
+    .bind(token.id)
+    .execute(pool)
+    .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(ApiError::NotFound);
+    }
+    Event::new(Action::CommandDeleted)
+        .target("command", command_id)
+        .record(pool, &Actor::new(&req, &state).token(&token))
+        .await?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[post("/api/commands/{id}/fork")]
+pub async fn fork_command(
+    state: Data<AppState>,
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+) -> Result<HttpResponse, ApiError> {
+    let pool = &state.pool;
+    let token = auth::require_token(&req, pool).await?;
+    let source_id = path.into_inner();
+
+    let source = sqlx::query_as::<_, Command>(
+        r#"
+        SELECT id, title, text, description, platform, visibility, favorite, usage_count, copy_count, run_count, accept_count, owner_token, created_at, updated_at, last_used_at, deleted_at, forked_from, risk_level
+        FROM commands
+        WHERE id = $1
+          AND deleted_at IS NULL
+          AND (owner_token = $2 OR visibility = 'PUBLIC')
+        "#,
+    )
+    .bind(source_id)
+    .bind(token.id)
+    .fetch_optional(pool)
+    .await?
+    .ok_or(ApiError::NotFound)?;
+
+    let source_tags = load_tags(pool, &[source.id])
+        .await?
+        .remove(&source.id)
+        .unwrap_or_default();
+
+    let command_id = Uuid::new_v4();
+    let now = Utc::now();
+    let risk_level = danger::classify(&source.text).level.as_str().to_string();
+
+    let mut tx = pool.begin().await?;
+    sqlx::query(
+        r#"
+        INSERT INTO commands (
+            id, title, text, description, platform, visibility, favorite, usage_count, owner_token, created_at, updated_at, last_used_at, forked_from, risk_level
+        ) VALUES (
+            $1, $2, $3, $4, $5, 'PRIVATE', false, 0, $6, $7, $7, $7, $8, $9
+        )
+        "#,
+    )
+    .bind(command_id)
+    .bind(&source.title)
+    .bind(&source.text)
+    .bind(&source.description)
+    .bind(&source.platform)
+    .bind(token.id)
+    .bind(now)
+    .bind(source.id)
+    .bind(&risk_level)
+    .execute(&mut *tx)
+    .await?;
+
+    attach_tags(&mut tx, token.id, command_id, &source_tags).await?;
+    Event::new(Action::CommandForked)
+        .target("command", command_id)
+        .details(serde_json::json!({ "forked_from": source.id }))
+        .record(&mut *tx, &Actor::new(&req, &state).token(&token))
+        .await?;
+    tx.commit().await?;
+
+    let tags = load_tags(pool, &[command_id]).await?;
+
+    let response = CommandWithTags {
+        id: command_id,
+        title: source.title,
+        text: source.text,
+        description: source.description,
+        platform: source.platform,
+        visibility: "PRIVATE".to_string(),
+        favorite: false,
+        usage_count: 0,
+        copy_count: 0,
+        run_count: 0,
+        accept_count: 0,
+        tags: tags.get(&command_id).cloned().unwrap_or_default(),
+        created_at: now,
+        last_used_at: Some(now),
+        deleted_at: None,
+        forked_from: Some(source.id),
+        fork_count: 0,
+        risk_level: Some(risk_level),
+    };
+
+    Ok(HttpResponse::Created().json(response))
+}
+
+/// Window in which repeated uses of another owner's command by one caller count once.
+const USAGE_DEDUPE_MINUTES: i64 = 10;
+
+#[post("/api/usage")]
+pub async fn record_usage_event(
+    state: Data<AppState>,
+    req: HttpRequest,
+    payload: web::Json<UsageEventRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let pool = &state.pool;
+    let token = auth::optional_token(&req, pool).await?;
+    let token_id = token.as_ref().map(|t| t.id);
+    let ip = throttle::client_ip(&req, state.trust_proxy_headers);
+    if token.is_none() {
+        throttle::check(pool, Scope::UsageIp, &ip).await?;
+        throttle::record_failure(pool, Scope::UsageIp, &ip).await?;
+    }
+
+    let command_ids: Vec<Uuid> = match (payload.command_id, payload.text.as_deref()) {
+        (Some(command_id), None) => {
+            let row = sqlx::query(
+                r#"
+                SELECT id, owner_token FROM commands
+                WHERE id = $1
+                  AND deleted_at IS NULL
+                  AND (visibility = 'PUBLIC' OR owner_token = $2)
+                "#,
+            )
+            .bind(command_id)
+            .bind(token_id)
+            .fetch_optional(pool)
+            .await?
+            .ok_or(ApiError::NotFound)?;
+
+            // Uses of someone else's command count once per caller and window, so
+            // public rankings cannot be inflated by repeating the request
+            let owner: Uuid = row.get("owner_token");
+            if Some(owner) != token_id {
+                let actor = match token_id {
+                    Some(id) => format!("token:{id}"),
+                    None => format!("ip:{}", auth::hash_token(&ip)),
+                };
+                let counted: Option<bool> = sqlx::query_scalar(
+                    r#"
+                    INSERT INTO usage_dedupe (actor, command_id, source, counted_at)
+                    VALUES ($1, $2, $3, now())
+                    ON CONFLICT (actor, command_id, source) DO UPDATE SET counted_at = now()
+                    WHERE usage_dedupe.counted_at < now() - make_interval(mins => $4)
+                    RETURNING true
+                    "#,
+                )
+                .bind(&actor)
+                .bind(command_id)
+                .bind(payload.source.as_str())
+                .bind(USAGE_DEDUPE_MINUTES as i32)
+                .fetch_optional(pool)
+                .await?;
+                if counted.is_none() {
+                    return Ok(
+                        HttpResponse::Ok().json(serde_json::json!({ "status": "duplicate" }))
+                    );
+                }
+            }
+            vec![row.get("id")]
+        }
+        // Shell clients only know the suggestion text, so match the caller's own commands
+        (None, Some(text)) => {
+            let token = token.as_ref().ok_or(ApiError::Unauthorized)?;
+            sqlx::query(
+                "SELECT id FROM commands WHERE owner_token = $1 AND text = $2 AND deleted_at IS NULL",
+            )
+            .bind(token.id)
+            .bind(text.trim())
+            .fetch_all(pool)
+            .await?
+            .iter()
+            .map(|row| row.get::<Uuid, _>("id"))
+            .collect()
+        }
+        _ => {
+            return Err(ApiError::BadRequest(
+                "exactly one of command_id or text is required".into(),
+            ))
+        }
+    };
+
+    if command_ids.is_empty() {
+        return Err(ApiError::NotFound);
+    }
+
+    let mut tx = pool.begin().await?;
+    record_usage(&mut tx, &command_ids, token_id, payload.source, Utc::now()).await?;
+    tx.commit().await?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "status": "ok" })))
+}
+
+#[post("/api/suggest")]
+pub async fn suggest_commands(
     state: web::Data<AppState>,
     req: HttpRequest,
-    payload: web::Json<SuggestRequest>,
+    payload: web::Json<SuggestRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let pool = &state.pool;
+    let token = auth::optional_token(&req, pool).await?;
+
+    // 1. Prepare search terms
+    let query_input = payload.query.trim();
+    if query_input.is_empty() {
+        return Ok(HttpResponse::Ok().json(Vec::<String>::new()));
+    }
+
+    // Patterns for SQL
+    let starts_with = format!("{}%", query_input); // High priority
+    let contains = format!("%{}%", query_input); // Low priority
+
+    // 2. Resolve Context
+    let os_filter = payload.os.as_deref().unwrap_or("%"); // Wildcard if no OS provided
+                                                          // 3. Define the User ID (use a dummy UUID or handle None if user is guest)
+                                                          // If no token, we can't search learned_commands (private), so we use a null placeholder
+    let user_id = token.as_ref().map(|t| t.id);
+    let hide_dangerous = match token.as_ref() {
+        Some(t) => load_settings(pool, t).await?.hide_dangerous_suggestions,
+        None => false,
+    };
+
+    // 4. Execute the Hybrid Query
+    // Uses trigram similarity (pg_trgm) for fuzzy matching and last_used_at for recency
+    let rows = sqlx::query_as::<_, SuggestionRow>(
+        r#"
+        WITH combined_results AS (
+            -- SOURCE 1: Saved Commands (Curated)
+            SELECT
+                text,
+                -- Accepted suggestions are a stronger signal than plain runs
+                usage_count + accept_count as usage_count,
+                COALESCE(last_used_at, created_at) as last_used_at,
+                platform as os,
+                -- Score Boosts
+                20.0 as base_score, -- Saved commands get a baseline boost over random history
+                CASE WHEN text ILIKE $2 THEN 50.0 ELSE 0.0 END as prefix_bonus,
+                -- Trigram similarity score (0.0 to 1.0) scaled to 0-30 points
+                (similarity(text, $5) * 30.0) as trgm_score,
+                0.0 as failure_penalty
+            FROM commands
+            WHERE (visibility = 'PUBLIC' OR owner_token = $1)
+              AND deleted_at IS NULL
+              AND NOT ($6 AND risk_level IN ('dangerous', 'critical'))
+              AND (text ILIKE $3 OR similarity(text, $5) > 0.1)
+
+            UNION ALL
+
+            -- SOURCE 2: Learned Commands (History)
+            -- Only queried if user_id ($1) is NOT NULL (Handled via WHERE clause)
+            SELECT
+                content as text,
+                usage_count,
+                COALESCE(last_used_at, created_at) as last_used_at,
+                os,
+                -- Score Boosts
+                0.0 as base_score,
+                CASE WHEN content ILIKE $2 THEN 50.0 ELSE 0.0 END as prefix_bonus,
+                -- Trigram similarity score (0.0 to 1.0) scaled to 0-30 points
+                (similarity(content, $5) * 30.0) as trgm_score,
+                -- Demote commands that failed in most of at least 3 reported runs
+                CASE
+                    WHEN success_count + failure_count >= 3 AND failure_count > success_count
+                    THEN 60.0 * failure_count / (success_count + failure_count)
+                    ELSE 0.0
+                END as failure_penalty
+            FROM learned_commands
+            WHERE owner_token = $1
+              AND deleted_at IS NULL
+              AND NOT ($6 AND risk_level IN ('dangerous', 'critical'))
+              AND (content ILIKE $3 OR similarity(content, $5) > 0.1)
+              -- Context Filter: heavily prefer current OS, or allow if OS is generic
+              AND (os IS NULL OR os ILIKE $4)
+        )
+        SELECT
+            text,
+            -- Calculate Final Relevance Score
+            SUM(
+                base_score +
+                prefix_bonus +
+                trgm_score -
+                failure_penalty +
+                (usage_count::float * 2.0) +
+                -- Recency Bonus: more points for recently used items (last 7 days = max bonus)
+                GREATEST(0.0, 25.0 - (EXTRACT(EPOCH FROM (now() - last_used_at)) / 86400.0 / 7.0 * 25.0))
+            ) as score
+        FROM combined_results
+        GROUP BY text -- Deduplicate: merge identical commands from saved/learned
+        ORDER BY score DESC
+        LIMIT 10
+        "#
+    )
+    .bind(user_id)          // $1
+    .bind(&starts_with)     // $2 (Prefix match)
+    .bind(&contains)        // $3 (Broad match)
+    .bind(os_filter)        // $4 (OS Context)
+    .bind(query_input)      // $5 (Trigram similarity input)
+    .bind(hide_dangerous)   // $6 (User opted out of dangerous suggestions)
+    .fetch_all(pool)
+    .await?;
+
+    if payload.include_metadata.unwrap_or(false) {
+        let suggestions: Vec<Suggestion> = rows
+            .into_iter()
+            .map(|r| {
+                let risk = danger::classify(&r.text);
+                Suggestion {
+                    text: r.text,
+                    risk_level: risk.level,
+                    risk_reasons: risk.reasons,
+                }
+            })
+            .collect();
+        return Ok(HttpResponse::Ok().json(suggestions));
+    }
+
+    let suggestions: Vec<String> = rows.into_iter().map(|r| r.text).collect();
+
+    Ok(HttpResponse::Ok().json(suggestions))
+}
+
+#[get("/api/stats")]
+pub async fn get_stats(
+    state: Data<AppState>,
+    req: HttpRequest,
+    query: web::Query<StatsQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let pool = &state.pool;
+    let token = auth::require_token(&req, pool).await?;
+
+    let to = query.to.unwrap_or_else(Utc::now);
+    let from = query.from.unwrap_or(to - Duration::days(30));
+    if from >= to {
+        return Err(ApiError::BadRequest("from must be before to".into()));
+    }
+    let bucket = query.bucket.unwrap_or_default();
+    if (to - from).num_days() / bucket.approx_days() > MAX_STATS_BUCKETS {
+        return Err(ApiError::BadRequest(format!(
+            "range spans more than {MAX_STATS_BUCKETS} buckets; use a larger bucket"
+        )));
+    }
+
+    let range = StatsRange {
+        from,
+        to,
+        bucket,
+        limit: query.limit.unwrap_or(10).clamp(1, 50),
+    };
+
+    Ok(HttpResponse::Ok().json(stats::collect(pool, token.id, &range).await?))
+}
+
+#[get("/api/settings")]
+pub async fn get_settings(
+    state: Data<AppState>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    let pool = &state.pool;
+    let token = auth::require_token(&req, pool).await?;
+
+    Ok(HttpResponse::Ok().json(load_settings(pool, &token).await?))
+}
+
+#[put("/api/settings")]
+pub async fn update_settings(
+    state: Data<AppState>,
+    req: HttpRequest,
+    payload: web::Json<SettingsPayload>,
+) -> Result<HttpResponse, ApiError> {
+    let pool = &state.pool;
+    let token = auth::require_token(&req, pool).await?;
+    let user_id = token.user_id.ok_or_else(|| {
+        ApiError::BadRequest("settings require a token that belongs to a user".into())
+    })?;
+
+    let current = load_settings(pool, &token).await?;
+    let retention = match payload.retention {
+        Some(retention) => retention.normalized().map_err(ApiError::BadRequest)?,
+        None => current.retention,
+    };
+    let settings = UserSettings {
+        hide_dangerous_suggestions: payload
+            .hide_dangerous_suggestions
+            .unwrap_or(current.hide_dangerous_suggestions),
+        secret_policy: payload.secret_policy.unwrap_or(current.secret_policy),
+        retention,
+    };
+
+    sqlx::query(
+        r#"
+        INSERT INTO user_settings (
+            user_id, hide_dangerous_suggestions, secret_policy,
+            learned_max_age_days, learned_max_rows, learned_min_usage, updated_at
+        )
+        VALUES ($1, $2, $3, $4, $5, $6, now())
+        ON CONFLICT (user_id) DO UPDATE
+        SET hide_dangerous_suggestions = EXCLUDED.hide_dangerous_suggestions,
+            secret_policy = EXCLUDED.secret_policy,
+            learned_max_age_days = EXCLUDED.learned_max_age_days,
+            learned_max_rows = EXCLUDED.learned_max_rows,
+            learned_min_usage = EXCLUDED.learned_min_usage,
+            updated_at = now()
+        "#,
+    )
+    .bind(user_id)
+    .bind(settings.hide_dangerous_suggestions)
+    .bind(settings.secret_policy.as_str())
+    .bind(settings.retention.max_age_days)
+    .bind(settings.retention.max_rows)
+    .bind(settings.retention.min_usage)
+    .execute(pool)
+    .await?;
+    Event::new(Action::SettingsUpdated)
+        .details(serde_json::to_value(&settings).map_err(|_| ApiError::Internal)?)
+        .record(pool, &Actor::new(&req, &state).token(&token))
+        .await?;
+
+    Ok(HttpResponse::Ok().json(settings))
+}
+
+#[get("/api/learning-rules")]
+pub async fn list_learning_rules(
+    state: Data<AppState>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    let pool = &state.pool;
+    let token = auth::require_token(&req, pool).await?;
+
+    let rules = sqlx::query_as::<_, LearningRule>(
+        "SELECT id, kind, pattern, created_at FROM learning_rules WHERE owner_token = $1 ORDER BY created_at",
+    )
+    .bind(token.id)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(HttpResponse::Ok().json(rules))
+}
+
+#[post("/api/learning-rules")]
+pub async fn create_learning_rule(
+    state: Data<AppState>,
+    req: HttpRequest,
+    payload: web::Json<LearningRulePayload>,
+) -> Result<HttpResponse, ApiError> {
+    let pool = &state.pool;
+    let token = auth::require_token(&req, pool).await?;
+    let id = Uuid::new_v4();
+    privacy::compile(id, payload.kind, &payload.pattern).map_err(ApiError::BadRequest)?;
+
+    let rule = sqlx::query_as::<_, LearningRule>(
+        r#"
+        INSERT INTO learning_rules (id, owner_token, kind, pattern, created_at)
+        VALUES ($1, $2, $3, $4, now())
+        RETURNING id, kind, pattern, created_at
+        "#,
+    )
+    .bind(id)
+    .bind(token.id)
+    .bind(payload.kind.as_str())
+    .bind(&payload.pattern)
+    .fetch_one(pool)
+    .await?;
+    Event::new(Action::LearningRuleCreated)
+        .target("learning_rule", id)
+        .record(pool, &Actor::new(&req, &state).token(&token))
+        .await?;
+
+    Ok(HttpResponse::Created().json(rule))
+}
+
+#[put("/api/learning-rules/{id}")]
+pub async fn update_learning_rule(
+    state: Data<AppState>,
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+    payload: web::Json<LearningRulePayload>,
+) -> Result<HttpResponse, ApiError> {
+    let pool = &state.pool;
+    let token = auth::require_token(&req, pool).await?;
+    let id = path.into_inner();
+    privacy::compile(id, payload.kind, &payload.pattern).map_err(ApiError::BadRequest)?;
+
+    let rule = sqlx::query_as::<_, LearningRule>(
+        r#"
+        UPDATE learning_rules SET kind = $1, pattern = $2
+        WHERE id = $3 AND owner_token = $4
+        RETURNING id, kind, pattern, created_at
+        "#,
+    )
+    .bind(payload.kind.as_str())
+    .bind(&payload.pattern)
+    .bind(id)
+    .bind(token.id)
+    .fetch_optional(pool)
+    .await?
+    .ok_or(ApiError::NotFound)?;
+    Event::new(Action::LearningRuleUpdated)
+        .target("learning_rule", id)
+        .record(pool, &Actor::new(&req, &state).token(&token))
+        .await?;
+
+    Ok(HttpResponse::Ok().json(rule))
+}
+
+#[delete("/api/learning-rules/{id}")]
+pub async fn delete_learning_rule(
+    state: Data<AppState>,
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+) -> Result<HttpResponse, ApiError> {
+    let pool = &state.pool;
+    let token = auth::require_token(&req, pool).await?;
+    let id = path.into_inner();
+
+    let result = sqlx::query("DELETE FROM learning_rules WHERE id = $1 AND owner_token = $2")
+        .bind(id)
+        .bind(token.id)
+        .execute(pool)
+        .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(ApiError::NotFound);
+    }
+    Event::new(Action::LearningRuleDeleted)
+        .target("learning_rule", id)
+        .record(pool, &Actor::new(&req, &state).token(&token))
+        .await?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Learned entries checked per query when purging rule matches.
+const RULE_PURGE_PAGE_SIZE: i64 = 1000;
+
+/// Permanently removes learned entries (live or trashed) that today's rules would have ignored.
+#[post("/api/learning-rules/purge")]
+pub async fn purge_learning_rule_matches(
+    state: Data<AppState>,
+    req: HttpRequest,
+    query: web::Query<RulePurgeQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let pool = &state.pool;
+    let token = auth::require_token(&req, pool).await?;
+    let dry_run = query.dry_run.unwrap_or(false);
+    let (rules, skipped_rules): (Vec<_>, Vec<_>) = load_learning_rules(pool, token.id)
+        .await?
+        .into_iter()
+        .partition(|rule| rule.applies_to_stored());
+    let skipped_rules: Vec<Uuid> = skipped_rules.into_iter().map(|rule| rule.id).collect();
+
+    let mut matched = 0;
+    let mut purged = 0;
+    let mut after = Uuid::nil();
+    while !rules.is_empty() {
+        let rows = sqlx::query(
+            r#"
+            SELECT id, content, os, pwd FROM learned_commands
+            WHERE owner_token = $1 AND id > $2
+            ORDER BY id
+            LIMIT $3
+            "#,
+        )
+        .bind(token.id)
+        .bind(after)
+        .bind(RULE_PURGE_PAGE_SIZE)
+        .fetch_all(pool)
+        .await?;
+        let Some(last) = rows.last() else {
+            break;
+        };
+        after = last.get("id");
+
+        let page: Vec<Uuid> = rows
+            .iter()
+            .filter(|row| {
+                let execution = Execution {
+                    raw: row.get("content"),
+                    os: row.get("os"),
+                    pwd: row.get("pwd"),
+                };
+                privacy::first_stored_match(&rules, &execution).is_some()
+            })
+            .map(|row| row.get("id"))
+            .collect();
+        matched += page.len();
+
+        if !dry_run && !page.is_empty() {
+            purged +=
+                sqlx::query("DELETE FROM learned_commands WHERE id = ANY($1) AND owner_token = $2")
+                    .bind(&page)
+                    .bind(token.id)
+                    .execute(pool)
+                    .await?
+                    .rows_affected();
+        }
+    }
+    if purged > 0 {
+        Event::new(Action::LearningRuleMatchesPurged)
+            .details(serde_json::json!({ "purged": purged }))
+            .record(pool, &Actor::new(&req, &state).token(&token))
+            .await?;
+    }
+
+    Ok(HttpResponse::Ok().json(RulePurgeSummary {
+        dry_run,
+        matched,
+        purged,
+        skipped_rules,
+    }))
+}
+
+#[post("/api/learn")]
+pub async fn learn_command(
+    state: Data<AppState>,
+    req: HttpRequest,
+    payload: web::Json<LearnRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let pool = &state.pool;
+    let token = auth::require_token(&req, pool).await?;
+    let context = LearnContext {
+        rules: load_learning_rules(pool, token.id).await?,
+        policy: load_settings(pool, &token).await?.secret_policy,
+    };
+
+    let mut tx = pool.begin().await?;
+    let outcome = learn_one(&mut tx, token.id, &context, &payload, Utc::now()).await?;
+    tx.commit().await?;
+
+    let body = match outcome {
+        LearnOutcome::Stored => serde_json::json!({ "status": "ok" }),
+        LearnOutcome::Redacted(redactions) => serde_json::json!({
+            "status": "redacted",
+            "policy": context.policy,
+            "redactions": redactions,
+        }),
+        LearnOutcome::Ignored(rule_id) => serde_json::json!({
+            "status": "ignored",
+            "rule_id": rule_id,
+        }),
+        LearnOutcome::Duplicate => serde_json::json!({ "status": "duplicate" }),
+        LearnOutcome::Rejected(message) => return Err(ApiError::BadRequest(message)),
+    };
+
+    Ok(HttpResponse::Ok().json(body))
+}
+
+/// Learns many executions in one transaction, e.g. a queue flushed by a shell client that
+/// was offline. Items are applied in order; retried items are recognised by idempotency key.
+#[post("/api/learn/batch")]
+pub async fn learn_batch(
+    state: Data<AppState>,
+    req: HttpRequest,
+    payload: web::Json<LearnBatchRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let pool = &state.pool;
+    let token = auth::require_token(&req, pool).await?;
+    let items = payload.into_inner().items;
+
+    if items.is_empty() {
+        return Err(ApiError::BadRequest("items must not be empty".into()));
+    }
+    if items.len() > MAX_BATCH_SIZE {
+        return Err(ApiError::BadRequest(format!(
+            "at most {MAX_BATCH_SIZE} items per batch"
+        )));
+    }
+
+    let context = LearnContext {
+        rules: load_learning_rules(pool, token.id).await?,
+        policy: load_settings(pool, &token).await?.secret_policy,
+    };
+    let now = Utc::now();
+
+    let mut response = LearnBatchResponse::default();
+    let mut tx = pool.begin().await?;
+    for (index, item) in items.iter().enumerate() {
+        let mut result = LearnItemResult {
+            index,
+            idempotency_key: item.idempotency_key.clone(),
+            status: "ok",
+            rule_id: None,
+            redactions: Vec::new(),
+            error: None,
+        };
+
+        match learn_one(&mut tx, token.id, &context, item, now).await? {
+            LearnOutcome::Stored => response.stored += 1,
+            LearnOutcome::Redacted(redactions) => {
+                result.status = "redacted";
+                result.redactions = redactions;
+                response.stored += 1;
+            }
+            LearnOutcome::Ignored(rule_id) => {
+                result.status = "ignored";
+                result.rule_id = Some(rule_id);
+                response.skipped += 1;
+            }
+            LearnOutcome::Duplicate => {
+                result.status = "duplicate";
+                response.skipped += 1;
+            }
+            LearnOutcome::Rejected(message) => {
+                result.status = "rejected";
+                result.error = Some(message);
+                response.failed += 1;
+            }
+        }
+        response.results.push(result);
+    }
+    tx.commit().await?;
+
+    Ok(HttpResponse::Ok().json(response))
+}
+
+#[post("/api/learned/import")]
+pub async fn import_history(
+    state: Data<AppState>,
+    req: HttpRequest,
+    query: web::Query<HistoryImportQuery>,
+    body: web::Bytes,
+) -> Result<HttpResponse, ApiError> {
+    let pool = &state.pool;
+    let token = auth::require_token(&req, pool).await?;
+    let mut parsed = history::parse(query.format, &body);
+    let now = Utc::now();
+
+    let total = parsed.entries.len();
+    let rules = load_learning_rules(pool, token.id).await?;
+    parsed.entries.retain(|entry| {
+        let execution = Execution {
+            raw: &entry.command,
+            os: query.os.as_deref(),
+            pwd: None,
+        };
+        privacy::first_match(&rules, &execution).is_none()
+    });
+    let ignored = total - parsed.entries.len();
+
+    let policy = load_settings(pool, &token).await?.secret_policy;
+    let mut redacted = 0;
+    let mut secrets_rejected = 0;
+    parsed.entries.retain_mut(|entry| {
+        match secrets::scrub(&entry.command, policy) {
+            Scrubbed::Clean(_) => {}
+            Scrubbed::Changed { content, .. } => {
+                entry.command = content;
+                redacted += 1;
+            }
+            Scrubbed::Rejected(_) => {
+                secrets_rejected += 1;
+                return false;
+            }
+        }
+        true
+    });
+
+    let grouped = history::group(&parsed.entries);
+    let duplicates = parsed.entries.len() - grouped.len();
+    let mut imported = 0;
+    let mut skipped = 0;
+    let mut tx = pool.begin().await?;
+    for entry in &grouped {
+        if merge_learned(
+            &mut tx,
+            token.id,
+            entry,
+            query.os.as_deref(),
+            None,
+            now,
+            LearnedConflict::Merge,
+        )
+        .await?
+        {
+            imported += 1;
+        } else {
+            skipped += 1;
+        }
+    }
+    Event::new(Action::HistoryImported)
+        .details(serde_json::json!({ "parsed": total, "imported": imported }))
+        .record(&mut *tx, &Actor::new(&req, &state).token(&token))
+        .await?;
+    tx.commit().await?;
+
+    let rejected = parsed.rejected.len();
+    Ok(HttpResponse::Ok().json(HistoryImportSummary {
+        parsed: total,
+        imported,
+        skipped,
+        duplicates,
+        ignored,
+        redacted,
+        secrets_rejected,
+        rejected,
+        rejected_lines: parsed.rejected.into_iter().take(100).collect(),
+    }))
+}
+
+#[get("/api/learned")]
+pub async fn list_learned(
+    state: Data<AppState>,
+    req: HttpRequest,
+    query: web::Query<CommandQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let pool = &state.pool;
+    let token = auth::require_token(&req, pool).await?;
+    let limit = query.limit.unwrap_or(20).clamp(1, 200);
+    let offset = query.offset.unwrap_or(0).max(0);
+    let search = query.q.as_ref().map(|s| format!("%{}%", s));
+
+    let mut rows = sqlx::query_as::<_, LearnedCommand>(
+        r#"
+        SELECT id, content, os, pwd, ls_output, owner_token, usage_count, created_at, last_used_at, deleted_at, risk_level, promoted_at,
+               success_count, failure_count
+        FROM learned_commands
+        WHERE owner_token = $1
+          AND deleted_at IS NULL
+          AND ($2::text IS NULL OR content ILIKE $2)
+        ORDER BY last_used_at DESC NULLS LAST, usage_count DESC, created_at DESC
+        LIMIT $3 OFFSET $4
+        "#,
+    )
+    .bind(token.id)
+    .bind(search.as_deref())
+    .bind(limit)
+    .bind(offset)
+    .fetch_all(pool)
+    .await?;
+
+    let total_row = sqlx::query(
+        r#"
+        SELECT count(*) as total
+        FROM learned_commands
+        WHERE owner_token = $1
+          AND deleted_at IS NULL
+          AND ($2::text IS NULL OR content ILIKE $2)
+        "#,
+    )
+    .bind(token.id)
+    .bind(search.as_deref())
+    .fetch_one(pool)
+    .await?;
+    let total: i64 = total_row.try_get("total").unwrap_or(0);
+
+    let ids: Vec<Uuid> = rows.iter().map(|row| row.id).collect();
+    let medians: HashMap<Uuid, f64> = sqlx::query(
+        r#"
+        SELECT learned_id,
+               percentile_cont(0.5) WITHIN GROUP (ORDER BY duration_ms) AS median
+        FROM learned_executions
+        WHERE learned_id = ANY($1) AND duration_ms IS NOT NULL
+        GROUP BY learned_id
+        "#,
+    )
+    .bind(&ids)
+    .fetch_all(pool)
+    .await?
+    .iter()
+    .map(|row| (row.get("learned_id"), row.get("median")))
+    .collect();
+
+    for row in &mut rows {
+        let reported = row.success_count + row.failure_count;
+        if reported > 0 {
+            row.success_rate = Some(row.success_count as f64 / reported as f64);
+        }
+        row.median_duration_ms = medians.get(&row.id).copied();
+    }
+
+    Ok(HttpResponse::Ok().json(crate::models::Page {
+        items: rows,
+        total,
+        limit,
+        offset,
+    }))
+}
+
+/// Lists the learned entries the retention policy would prune next. Limits passed in the
+/// query replace the saved policy, so a policy can be tried before it is saved.
+#[get("/api/learned/retention/preview")]
+pub async fn preview_retention(
+    state: Data<AppState>,
+    req: HttpRequest,
+    query: web::Query<RetentionPreviewQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let pool = &state.pool;
+    let token = auth::require_token(&req, pool).await?;
+    let user_id = token.user_id.ok_or_else(|| {
+        ApiError::BadRequest("retention requires a token that belongs to a user".into())
+    })?;
+    let limit = query.limit.unwrap_or(50).clamp(1, 200);
+
+    let policy =
+        if query.max_age_days.is_some() || query.max_rows.is_some() || query.min_usage.is_some() {
+            RetentionPolicy {
+                max_age_days: query.max_age_days,
+                max_rows: query.max_rows,
+                min_usage: query.min_usage,
+            }
+            .normalized()
+            .map_err(ApiError::BadRequest)?
+        } else {
+            load_settings(pool, &token).await?.retention
+        };
+
+    let ids = retention::prunable(pool, user_id, &policy).await?;
+    let sample: Vec<Uuid> = ids.iter().take(limit as usize).copied().collect();
+
+    let mut items = sqlx::query_as::<_, LearnedCommand>(
+        r#"
+        SELECT id, content, os, pwd, ls_output, owner_token, usage_count, created_at, last_used_at, deleted_at, risk_level, promoted_at,
+               success_count, failure_count
+        FROM learned_commands
+        WHERE id = ANY($1)
+        "#,
+    )
+    .bind(&sample)
+    .fetch_all(pool)
+    .await?;
+    items.sort_by_key(|item| sample.iter().position(|id| *id == item.id));
+
+    Ok(HttpResponse::Ok().json(RetentionPreview {
+        policy,
+        total: ids.len(),
+        items,
+    }))
+}
+
+#[get("/api/learned/recommendations")]
+pub async fn list_recommendations(
+    state: Data<AppState>,
+    req: HttpRequest,
+    query: web::Query<RecommendationQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let pool = &state.pool;
+    let token = auth::require_token(&req, pool).await?;
+    let limit = query.limit.unwrap_or(20).clamp(1, 100) as usize;
+
+    // Bounded by usage so scoring stays cheap on very large histories
+    let rows = sqlx::query(
+        r#"
+        SELECT l.id, l.content, l.os, l.usage_count,
+               (
+                   SELECT count(DISTINCT dir)
+                   FROM (
+                       SELECT e.pwd FROM learned_executions e WHERE e.learned_id = l.id
+                       UNION SELECT l.pwd
+                   ) dirs(dir)
+                   WHERE dir IS NOT NULL
+               ) AS directories
+        FROM learned_commands l
+        WHERE l.owner_token = $1
+          AND l.deleted_at IS NULL
+          AND l.promoted_at IS NULL
+          AND l.recommendation_dismissed_at IS NULL
+          AND l.usage_count >= $2
+          AND NOT EXISTS (
+              SELECT 1 FROM commands c
+              WHERE c.owner_token = l.owner_token AND c.text = l.content AND c.deleted_at IS NULL
+          )
+        ORDER BY l.usage_count DESC
+        LIMIT 500
+        "#,
+    )
+    .bind(token.id)
+    .bind(recommend::MIN_USAGE)
+    .fetch_all(pool)
+    .await?;
+
+    let mut recommendations: Vec<Recommendation> = rows
+        .into_iter()
+        .filter_map(|row| {
+            recommend::evaluate(Candidate {
+                id: row.get("id"),
+                content: row.get("content"),
+                os: row.get("os"),
+                usage_count: row.get("usage_count"),
+                directories: row.get("directories"),
+            })
+        })
+        .collect();
+    recommendations.sort_by(|a, b| b.score.total_cmp(&a.score));
+    recommendations.truncate(limit);
+
+    Ok(HttpResponse::Ok().json(recommendations))
+}
+
+/// Promotes a recommended entry, filling in the suggested title, platform and tags
+/// for anything the request leaves out.
+#[post("/api/learned/recommendations/{id}/accept")]
+pub async fn accept_recommendation(
+    state: Data<AppState>,
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+    payload: Option<web::Json<RecommendationAccept>>,
+) -> Result<HttpResponse, ApiError> {
+    let pool = &state.pool;
+    let token = auth::require_token(&req, pool).await?;
+    let learned_id = path.into_inner();
+    let overrides = payload.map(|p| p.into_inner()).unwrap_or_default();
+
+    let row = sqlx::query(
+        "SELECT content, os, usage_count FROM learned_commands WHERE id = $1 AND owner_token = $2 AND deleted_at IS NULL",
+    )
+    .bind(learned_id)
+    .bind(token.id)
+    .fetch_optional(pool)
+    .await?
+    .ok_or(ApiError::NotFound)?;
+
+    let content: String = row.get("content");
+    let os: Option<String> = row.get("os");
+    let suggested = recommend::suggest(&content, os.as_deref());
+
+    let promote = PromotePayload {
+        title: Some(overrides.title.unwrap_or(suggested.title)),
+        description: overrides.description,
+        platform: overrides.platform.unwrap_or(suggested.platform),
+        visibility: overrides.visibility,
+        tags: Some(overrides.tags.unwrap_or(suggested.tags)),
+    };
+    let visibility = parse_visibility(promote.visibility.as_deref())?;
+    ensure_may_publish(&state, &token, &visibility).await?;
+
+    let mut tx = pool.begin().await?;
+    let command_id = promote_one(&mut tx, token.id, learned_id, &promote, &visibility)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+    Event::new(Action::LearnedPromoted)
+        .target("learned", learned_id)
+        .details(serde_json::json!({ "command_id": command_id, "visibility": visibility }))
+        .record(&mut *tx, &Actor::new(&req, &state).token(&token))
+        .await?;
+    tx.commit().await?;
+
+    Ok(HttpResponse::Created().json(serde_json::json!({ "command_id": command_id })))
+}
+
+#[post("/api/learned/recommendations/{id}/dismiss")]
+pub async fn dismiss_recommendation(
+    state: Data<AppState>,
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+) -> Result<HttpResponse, ApiError> {
+    let pool = &state.pool;
+    let token = auth::require_token(&req, pool).await?;
+
+    let result = sqlx::query(
+        "UPDATE learned_commands SET recommendation_dismissed_at = now() WHERE id = $1 AND owner_token = $2 AND deleted_at IS NULL",
+    )
+    .bind(path.into_inner())
+    .bind(token.id)
+    .execute(pool)
+    .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(ApiError::NotFound);
+    }
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[post("/api/learned/{id}/promote")]
+pub async fn promote_learned(
+    state: Data<AppState>,
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+    payload: web::Json<PromotePayload>,
+) -> Result<HttpResponse, ApiError> {
+    let pool = &state.pool;
+    let token = auth::require_token(&req, pool).await?;
+    let learned_id = path.into_inner();
+
+    let visibility = parse_visibility(payload.visibility.as_deref())?;
+    ensure_may_publish(&state, &token, &visibility).await?;
+
+    let mut tx = pool.begin().await?;
+    let command_id = promote_one(&mut tx, token.id, learned_id, &payload, &visibility)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+    Event::new(Action::LearnedPromoted)
+        .target("learned", learned_id)
+        .details(serde_json::json!({ "command_id": command_id, "visibility": visibility }))
+        .record(&mut *tx, &Actor::new(&req, &state).token(&token))
+        .await?;
+    tx.commit().await?;
+
+    Ok(HttpResponse::Created().finish())
+}
+
+#[delete("/api/learned/{id}")]
+pub async fn delete_learned(
+    state: Data<AppState>,
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+) -> Result<HttpResponse, ApiError> {
+    let pool = &state.pool;
+    let token = auth::require_token(&req, pool).await?;
+    let learned_id = path.into_inner();
+
+    let result = sqlx::query(
+        "UPDATE learned_commands SET deleted_at = now() WHERE id = $1 AND owner_token = $2 AND deleted_at IS NULL",
+    )
+    .bind(learned_id)
+    .bind(token.id)
+    .execute(pool)
+    .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(ApiError::NotFound);
+    }
+    Event::new(Action::LearnedDeleted)
+        .target("learned", learned_id)
+        .record(pool, &Actor::new(&req, &state).token(&token))
+        .await?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[post("/api/device-codes")]
+pub async fn create_device_code(
+    state: Data<AppState>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    let pool = &state.pool;
+    let token = auth::require_token(&req, pool).await?;
+    let expires_at = Utc::now() + Duration::minutes(10);
+    let code = device_flow::generate_pairing_code();
+
+    sqlx::query(
+        r#"
+        INSERT INTO device_codes (code_hash, token_id, created_at, expires_at, consumed)
+        VALUES ($1, $2, now(), $3, false)
+        "#,
+    )
+    .bind(auth::hash_token(&code))
+    .bind(token.id)
+    .bind(expires_at)
+    .execute(pool)
+    .await?;
+    Event::new(Action::DeviceCodeCreated)
+        .record(pool, &Actor::new(&req, &state).token(&token))
+        .await?;
+
+    Ok(HttpResponse::Ok().json(DeviceCodeResponse {
+        code: device_flow::display_user_code(&code),
+        expires_at,
+    }))
+}
+
+#[post("/api/exchange-token")]
+pub async fn exchange_token(
+    state: Data<AppState>,
+    req: HttpRequest,
+    payload: web::Json<ExchangeTokenRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let pool = &state.pool;
+    let ip = throttle::client_ip(&req, state.trust_proxy_headers);
+    throttle::check(pool, Scope::DeviceCodeIp, &ip).await?;
+    let code_hash = auth::hash_token(&device_flow::normalize_user_code(&payload.code));
+
+    // Claiming and checking in one statement, so two requests cannot both redeem a code
+    let claimed: Option<Uuid> = sqlx::query_scalar(
+        r#"
+        UPDATE device_codes SET consumed = TRUE
+        WHERE code_hash = $1 AND NOT consumed AND expires_at > now()
+        RETURNING token_id
+        "#,
+    )
+    .bind(&code_hash)
+    .fetch_optional(pool)
+    .await?;
+
+    let Some(token_id) = claimed else {
+        let consumed: Option<bool> =
+            sqlx::query_scalar("SELECT consumed FROM device_codes WHERE code_hash = $1")
+                .bind(&code_hash)
+                .fetch_optional(pool)
+                .await?;
+        return match consumed {
+            Some(true) => Err(ApiError::BadRequest("code already used".into())),
+            Some(false) => Err(ApiError::BadRequest("code expired".into())),
+            None => {
+                throttle::record_failure(pool, Scope::DeviceCodeIp, &ip).await?;
+                Event::new(Action::DeviceCodeRejected)
+                    .record(pool, &Actor::new(&req, &state))
+                    .await?;
+                Err(ApiError::NotFound)
+            }
+        };
+    };
+
+    // The code may outlive the session that created it
+    let token = sqlx::query_as::<_, crate::models::ApiToken>(
+        r#"
+        SELECT id, label, token, user_id, created_at
+        FROM api_tokens
+        WHERE id = $1 AND revoked_at IS NULL
+        "#,
+    )
+    .bind(token_id)
+    .fetch_optional(pool)
+    .await?
+    .ok_or_else(|| ApiError::BadRequest("code no longer valid; create a new one".into()))?;
+
+    Event::new(Action::DeviceCodeExchanged)
+        .target("token", token.id)
+        .record(pool, &Actor::new(&req, &state).token(&token))
+        .await?;
+
+    Ok(HttpResponse::Ok().json(ExchangeTokenResponse { token: token.token }))
+}
+
+/// Starts a terminal login (RFC 8628). The terminal shows `user_code` and
+/// `verification_uri`, then polls `/api/device/token` with `device_code`.
+#[post("/api/device/authorize")]
+pub async fn start_device_authorization(
+    state: Data<AppState>,
+    req: HttpRequest,
+    form: Option<web::Form<DeviceAuthorizationRequest>>,
+) -> Result<HttpResponse, DeviceFlowError> {
+    let pool = &state.pool;
+    let form = form.map(|f| f.into_inner()).unwrap_or_default();
+    let scopes = device_flow::parse_scopes(form.scope.as_deref())?;
+    let device_name = form
+        .device_name
+        .as_deref()
+        .map(str::trim)
+        .filter(|n| !n.is_empty())
+        .unwrap_or("Terminal")
+        .chars()
+        .take(100)
+        .collect::<String>();
+    let device_code = auth::random_token();
+
+    let user_code = loop {
+        let candidate = device_flow::generate_user_code();
+        let exists: bool = sqlx::query_scalar(
+            "SELECT EXISTS (SELECT 1 FROM device_authorizations WHERE user_code = $1)",
+        )
+        .bind(&candidate)
+        .fetch_one(pool)
+        .await?;
+
+        if !exists {
+            break candidate;
+        }
+    };
+
+    sqlx::query(
+        r#"
+        INSERT INTO device_authorizations (
+            id, device_code_hash, user_code, device_name, scopes, requester_ip, user_agent,
+            status, poll_interval, created_at, expires_at
+        )
+        VALUES ($1, $2, $3, $4, $5, $6, $7, 'pending', $8, now(), $9)
+        "#,
+    )
+    .bind(Uuid::new_v4())
+    .bind(auth::hash_token(&device_code))
+    .bind(&user_code)
+    .bind(&device_name)
+    .bind(&scopes)
+    .bind(throttle::client_ip(&req, state.trust_proxy_headers))
+    .bind(req.headers().get(USER_AGENT).and_then(|h| h.to_str().ok()))
+    .bind(device_flow::POLL_INTERVAL_SECONDS)
+    .bind(Utc::now() + Duration::seconds(device_flow::EXPIRES_IN_SECONDS))
+    .execute(pool)
+    .await?;
+
+    let display_code = device_flow::display_user_code(&user_code);
+    let verification_uri = format!("{}/device", state.web_url);
+    Ok(HttpResponse::Ok().json(DeviceAuthorizationResponse {
+        device_code,
+        verification_uri_complete: format!("{verification_uri}?user_code={display_code}"),
+        verification_uri,
+        user_code: display_code,
+        expires_in: device_flow::EXPIRES_IN_SECONDS,
+        interval: device_flow::POLL_INTERVAL_SECONDS,
+    }))
+}
+
+/// Polled by the terminal until the request is approved, denied or expires.
+#[post("/api/device/token")]
+pub async fn poll_device_token(
+    state: Data<AppState>,
+    req: HttpRequest,
+    form: web::Form<DeviceTokenRequest>,
+) -> Result<HttpResponse, DeviceFlowError> {
+    if form.grant_type != device_flow::GRANT_TYPE {
+        return Err(DeviceFlowError::UnsupportedGrantType);
+    }
+
+    let mut tx = state.pool.begin().await?;
+    let row = sqlx::query(
+        r#"
+        SELECT id, status, scopes, token_id, poll_interval, expires_at,
+               last_polled_at > now() - make_interval(secs => poll_interval) AS too_soon
+        FROM device_authorizations
+        WHERE device_code_hash = $1
+        FOR UPDATE
+        "#,
+    )
+    .bind(auth::hash_token(&form.device_code))
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or(DeviceFlowError::InvalidGrant)?;
+
+    let id: Uuid = row.get("id");
+    let status: String = row.get("status");
+    let expires_at: DateTime<Utc> = row.get("expires_at");
+    let too_soon: Option<bool> = row.get("too_soon");
+
+    if status == "redeemed" {
+        return Err(DeviceFlowError::InvalidGrant);
+    }
+    if expires_at < Utc::now() {
+        return Err(DeviceFlowError::ExpiredToken);
+    }
+
+    // Remember the poll (and any slower interval) even when answering with an error
+    let outcome = if too_soon.unwrap_or(false) {
+        sqlx::query(
+            "UPDATE device_authorizations SET poll_interval = poll_interval + 5, last_polled_at = now() WHERE id = $1",
+        )
+        .bind(id)
+        .execute(&mut *tx)
+        .await?;
+        Err(DeviceFlowError::SlowDown)
+    } else {
+        sqlx::query("UPDATE device_authorizations SET last_polled_at = now() WHERE id = $1")
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        match status.as_str() {
+            "approved" => Ok(row.get::<Option<Uuid>, _>("token_id")),
+            "denied" => Err(DeviceFlowError::AccessDenied),
+            _ => Err(DeviceFlowError::AuthorizationPending),
+        }
+    };
+
+    let token_id = match outcome {
+        Ok(Some(token_id)) => token_id,
+        Ok(None) => return Err(DeviceFlowError::InvalidGrant),
+        Err(err) => {
+            tx.commit().await?;
+            return Err(err);
+        }
+    };
+
+    let token = sqlx::query_as::<_, ApiToken>(
+        "SELECT id, label, token, user_id, created_at FROM api_tokens WHERE id = $1 AND revoked_at IS NULL",
+    )
+    .bind(token_id)
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or(DeviceFlowError::InvalidGrant)?;
+    sqlx::query("UPDATE device_authorizations SET status = 'redeemed' WHERE id = $1")
+        .bind(id)
+        .execute(&mut *tx)
+        .await?;
+    Event::new(Action::DeviceTokenIssued)
+        .target("device_authorization", id)
+        .record(&mut *tx, &Actor::new(&req, &state).token(&token))
+        .await?;
+    tx.commit().await?;
+
+    let scopes: Vec<String> = row.get("scopes");
+    Ok(HttpResponse::Ok().json(DeviceTokenResponse {
+        access_token: token.token,
+        token_type: "Bearer".to_string(),
+        scope: scopes.join(" "),
+    }))
+}
+
+/// What a pending request asks for, shown before the user approves it.
+#[get("/api/device/requests/{user_code}")]
+pub async fn get_device_request(
+    state: Data<AppState>,
+    req: HttpRequest,
+    path: web::Path<String>,
 ) -> Result<HttpResponse, ApiError> {
     let pool = &state.pool;
-    let token = auth::optional_token(&req, pool).await?;
+    auth::require_token(&req, pool).await?;
+    let row = pending_device_request(&state, &req, pool, &path).await?;
+
+    let user_code: String = row.get("user_code");
+    let scopes: Vec<String> = row.get("scopes");
+    Ok(HttpResponse::Ok().json(DeviceRequestInfo {
+        user_code: device_flow::display_user_code(&user_code),
+        device_name: row.get("device_name"),
+        scopes: scopes
+            .into_iter()
+            .map(|name| DeviceScope {
+                description: device_flow::scope_description(&name).to_string(),
+                name,
+            })
+            .collect(),
+        requester_ip: row.get("requester_ip"),
+        user_agent: row.get("user_agent"),
+        created_at: row.get("created_at"),
+        expires_at: row.get("expires_at"),
+    }))
+}
+
+/// Issues a new token for the device, owned by the approving user.
+#[post("/api/device/requests/{user_code}/approve")]
+pub async fn approve_device_request(
+    state: Data<AppState>,
+    req: HttpRequest,
+    path: web::Path<String>,
+    payload: Option<web::Json<DeviceApproval>>,
+) -> Result<HttpResponse, ApiError> {
+    let pool = &state.pool;
+    let token = auth::require_token(&req, pool).await?;
+    let user_id = token.user_id.ok_or_else(|| {
+        ApiError::BadRequest("approving devices requires a token that belongs to a user".into())
+    })?;
+    let row = pending_device_request(&state, &req, pool, &path).await?;
+    let id: Uuid = row.get("id");
+    let device_name: String = row.get("device_name");
+    let scopes: Vec<String> = row.get("scopes");
+    let payload = payload.map(|p| p.into_inner()).unwrap_or_default();
+    let label = payload
+        .label
+        .as_deref()
+        .map(str::trim)
+        .filter(|l| !l.is_empty())
+        .unwrap_or(&device_name);
+
+    let mut tx = pool.begin().await?;
+    let (token_id, _) = issue_login_token(&mut *tx, user_id, label).await?;
+    sqlx::query("UPDATE api_tokens SET read_only = $2 WHERE id = $1")
+        .bind(token_id)
+        .bind(device_flow::is_read_only(&scopes))
+        .execute(&mut *tx)
+        .await?;
+    let result = sqlx::query(
+        r#"
+        UPDATE device_authorizations
+        SET status = 'approved', user_id = $2, token_id = $3, decided_at = now()
+        WHERE id = $1 AND status = 'pending'
+        "#,
+    )
+    .bind(id)
+    .bind(user_id)
+    .bind(token_id)
+    .execute(&mut *tx)
+    .await?;
+    if result.rows_affected() == 0 {
+        return Err(ApiError::NotFound);
+    }
+    Event::new(Action::DeviceRequestApproved)
+        .target("device_authorization", id)
+        .details(serde_json::json!({
+            "token_id": token_id,
+            "device_name": device_name,
+            "scopes": scopes,
+        }))
+        .record(&mut *tx, &Actor::new(&req, &state).token(&token))
+        .await?;
+    tx.commit().await?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[post("/api/device/requests/{user_code}/deny")]
+pub async fn deny_device_request(
+    state: Data<AppState>,
+    req: HttpRequest,
+    path: web::Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    let pool = &state.pool;
+    let token = auth::require_token(&req, pool).await?;
+    let row = pending_device_request(&state, &req, pool, &path).await?;
+    let id: Uuid = row.get("id");
+
+    sqlx::query(
+        r#"
+        UPDATE device_authorizations
+        SET status = 'denied', user_id = $2, decided_at = now()
+        WHERE id = $1 AND status = 'pending'
+        "#,
+    )
+    .bind(id)
+    .bind(token.user_id)
+    .execute(pool)
+    .await?;
+    Event::new(Action::DeviceRequestDenied)
+        .target("device_authorization", id)
+        .record(pool, &Actor::new(&req, &state).token(&token))
+        .await?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Looks up a live request by the code the user typed. Misses count towards the
+/// caller's device-code throttle, so user codes cannot be enumerated.
+async fn pending_device_request(
+    state: &AppState,
+    req: &HttpRequest,
+    pool: &PgPool,
+    user_code: &str,
+) -> Result<sqlx::postgres::PgRow, ApiError> {
+    let ip = throttle::client_ip(req, state.trust_proxy_headers);
+    throttle::check(pool, Scope::DeviceCodeIp, &ip).await?;
+
+    let row = sqlx::query(
+        r#"
+        SELECT id, user_code, device_name, scopes, requester_ip, user_agent, created_at, expires_at
+        FROM device_authorizations
+        WHERE user_code = $1 AND status = 'pending' AND expires_at > now()
+        "#,
+    )
+    .bind(device_flow::normalize_user_code(user_code))
+    .fetch_optional(pool)
+    .await?;
+
+    match row {
+        Some(row) => Ok(row),
+        None => {
+            throttle::record_failure(pool, Scope::DeviceCodeIp, &ip).await?;
+            Err(ApiError::NotFound)
+        }
+    }
+}
+
+const MAX_STATS_BUCKETS: i64 = 1000;
+const MAX_BATCH_SIZE: usize = 1000;
+
+/// Ids selected by a batch filter. Filters matching more than one batch are refused
+/// rather than applied to an arbitrary part of the matches.
+fn filter_matches(rows: Vec<sqlx::postgres::PgRow>) -> Result<Vec<Uuid>, ApiError> {
+    let matches: i64 = rows.first().map_or(0, |row| row.get("matches"));
+    if matches > MAX_BATCH_SIZE as i64 {
+        return Err(ApiError::BadRequest(format!(
+            "filter matches {matches} entries; at most {MAX_BATCH_SIZE} per batch, so narrow it down"
+        )));
+    }
+    Ok(rows.iter().map(|row| row.get("id")).collect())
+}
+
+#[post("/api/commands/batch")]
+pub async fn batch_commands(
+    state: Data<AppState>,
+    req: HttpRequest,
+    payload: web::Json<CommandBatchRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let pool = &state.pool;
+    let token = auth::require_token(&req, pool).await?;
+    let payload = payload.into_inner();
+
+    let visibility = match &payload.action {
+        CommandBatchAction::SetVisibility { visibility } => {
+            Some(parse_visibility(Some(visibility))?)
+        }
+        _ => None,
+    };
+    if let Some(visibility) = &visibility {
+        ensure_may_publish(&state, &token, visibility).await?;
+    }
+
+    let mut tx = pool.begin().await?;
+
+    let ids = match (payload.ids, payload.filter) {
+        (Some(ids), None) => dedupe_ids(ids)?,
+        (None, Some(filter)) => {
+            let mut builder = QueryBuilder::new(
+                "SELECT id, count(*) OVER () AS matches FROM commands WHERE deleted_at IS NULL AND owner_token = ",
+            );
+            builder.push_bind(token.id);
+            if let Some(q) = &filter.q {
+                builder.push(" AND text ILIKE ");
+                builder.push_bind(format!("%{q}%"));
+            }
+            if let Some(tag_filter) = &filter.tag {
+                builder.push(
+                    " AND EXISTS (SELECT 1 FROM command_tags ct JOIN tags t ON t.id = ct.tag_id WHERE ct.command_id = commands.id AND t.name ILIKE ",
+                );
+                builder.push_bind(format!("%{tag_filter}%"));
+                builder.push(")");
+            }
+            builder.push(" ORDER BY created_at DESC LIMIT ");
+            builder.push_bind(MAX_BATCH_SIZE as i64);
+            filter_matches(builder.build().fetch_all(&mut *tx).await?)?
+        }
+        _ => {
+            return Err(ApiError::BadRequest(
+                "exactly one of ids or filter is required".into(),
+            ))
+        }
+    };
+
+    let owned: HashMap<Uuid, String> = sqlx::query(
+        "SELECT id, text FROM commands WHERE id = ANY($1) AND owner_token = $2 AND deleted_at IS NULL FOR UPDATE",
+    )
+    .bind(&ids)
+    .bind(token.id)
+    .fetch_all(&mut *tx)
+    .await?
+    .iter()
+    .map(|row| (row.get("id"), row.get("text")))
+    .collect();
+
+    let mut results = Vec::with_capacity(ids.len());
+    for id in ids {
+        let Some(text) = owned.get(&id) else {
+            results.push(batch_result(id, "not_found", None));
+            continue;
+        };
+        if visibility.as_deref() == Some("PUBLIC") && secrets::contains_secret(text) {
+            results.push(batch_result(id, "contains_secret", None));
+            continue;
+        }
+
+        match &payload.action {
+            CommandBatchAction::Delete => {
+                sqlx::query("UPDATE commands SET deleted_at = now() WHERE id = $1")
+                    .bind(id)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+            CommandBatchAction::SetVisibility { .. } => {
+                sqlx::query(
+                    "UPDATE commands SET visibility = $1, updated_at = now() WHERE id = $2",
+                )
+                .bind(visibility.as_deref())
+                .bind(id)
+                .execute(&mut *tx)
+                .await?;
+            }
+            CommandBatchAction::SetFavorite { favorite } => {
+                sqlx::query("UPDATE commands SET favorite = $1, updated_at = now() WHERE id = $2")
+                    .bind(favorite)
+                    .bind(id)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+            CommandBatchAction::AddTags { tags } => {
+                attach_tags(&mut tx, token.id, id, tags).await?;
+                sqlx::query("UPDATE commands SET updated_at = now() WHERE id = $1")
+                    .bind(id)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+            CommandBatchAction::RemoveTags { tags } => {
+                sqlx::query(
+                    r#"
+                    DELETE FROM command_tags
+                    WHERE command_id = $1
+                      AND tag_id IN (SELECT id FROM tags WHERE owner_token = $2 AND name = ANY($3))
+                    "#,
+                )
+                .bind(id)
+                .bind(token.id)
+                .bind(tags)
+                .execute(&mut *tx)
+                .await?;
+                sqlx::query("UPDATE commands SET updated_at = now() WHERE id = $1")
+                    .bind(id)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+        }
+
+        results.push(batch_result(id, "ok", None));
+    }
+
+    record_batch(
+        &mut tx,
+        &Actor::new(&req, &state).token(&token),
+        Action::CommandsBatchUpdated,
+        payload.action.as_str(),
+        &results,
+    )
+    .await?;
+    tx.commit().await?;
+
+    Ok(HttpResponse::Ok().json(batch_response(results)))
+}
+
+#[post("/api/learned/batch")]
+pub async fn batch_learned(
+    state: Data<AppState>,
+    req: HttpRequest,
+    payload: web::Json<LearnedBatchRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let pool = &state.pool;
+    let token = auth::require_token(&req, pool).await?;
+    let payload = payload.into_inner();
+
+    let visibility = match &payload.action {
+        LearnedBatchAction::Promote(promote) => {
+            Some(parse_visibility(promote.visibility.as_deref())?)
+        }
+        LearnedBatchAction::Delete => None,
+    };
+    if let Some(visibility) = &visibility {
+        ensure_may_publish(&state, &token, visibility).await?;
+    }
+
+    let mut tx = pool.begin().await?;
+
+    let ids = match (payload.ids, payload.filter) {
+        (Some(ids), None) => dedupe_ids(ids)?,
+        (None, Some(filter)) => {
+            if filter.tag.is_some() {
+                return Err(ApiError::BadRequest(
+                    "learned entries cannot be filtered by tag".into(),
+                ));
+            }
+            let search = filter.q.as_ref().map(|s| format!("%{}%", s));
+            let rows = sqlx::query(
+                r#"
+                SELECT id, count(*) OVER () AS matches FROM learned_commands
+                WHERE owner_token = $1
+                  AND deleted_at IS NULL
+                  AND ($2::text IS NULL OR content ILIKE $2)
+                ORDER BY last_used_at DESC NULLS LAST, usage_count DESC, created_at DESC
+                LIMIT $3
+                "#,
+            )
+            .bind(token.id)
+            .bind(search.as_deref())
+            .bind(MAX_BATCH_SIZE as i64)
+            .fetch_all(&mut *tx)
+            .await?;
+            filter_matches(rows)?
+        }
+        _ => {
+            return Err(ApiError::BadRequest(
+                "exactly one of ids or filter is required".into(),
+            ))
+        }
+    };
+
+    let mut results = Vec::with_capacity(ids.len());
+    for id in ids {
+        match &payload.action {
+            LearnedBatchAction::Delete => {
+                let result = sqlx::query(
+                    "UPDATE learned_commands SET deleted_at = now() WHERE id = $1 AND owner_token = $2 AND deleted_at IS NULL",
+                )
+                .bind(id)
+                .bind(token.id)
+                .execute(&mut *tx)
+                .await?;
+
+                if result.rows_affected() == 0 {
+                    results.push(batch_result(id, "not_found", None));
+                } else {
+                    results.push(batch_result(id, "ok", None));
+                }
+            }
+            LearnedBatchAction::Promote(promote) => {
+                let visibility = visibility.as_deref().unwrap_or("PRIVATE");
+                match promote_one(&mut tx, token.id, id, promote, visibility).await {
+                    Ok(Some(command_id)) => results.push(batch_result(id, "ok", Some(command_id))),
+                    Ok(None) => results.push(batch_result(id, "not_found", None)),
+                    Err(ApiError::BadRequest(_)) => {
+                        results.push(batch_result(id, "contains_secret", None))
+                    }
+                    Err(err) => return Err(err),
+                }
+            }
+        }
+    }
+
+    record_batch(
+        &mut tx,
+        &Actor::new(&req, &state).token(&token),
+        Action::LearnedBatchUpdated,
+        payload.action.as_str(),
+        &results,
+    )
+    .await?;
+    tx.commit().await?;
+
+    Ok(HttpResponse::Ok().json(batch_response(results)))
+}
+
+fn dedupe_ids(ids: Vec<Uuid>) -> Result<Vec<Uuid>, ApiError> {
+    let mut seen = HashSet::new();
+    let ids: Vec<Uuid> = ids.into_iter().filter(|id| seen.insert(*id)).collect();
+
+    if ids.is_empty() {
+        return Err(ApiError::BadRequest("ids must not be empty".into()));
+    }
+    if ids.len() > MAX_BATCH_SIZE {
+        return Err(ApiError::BadRequest(format!(
+            "at most {MAX_BATCH_SIZE} ids per batch"
+        )));
+    }
+
+    Ok(ids)
+}
+
+fn batch_result(id: Uuid, status: &str, command_id: Option<Uuid>) -> BatchItemResult {
+    BatchItemResult {
+        id,
+        status: status.to_string(),
+        command_id,
+    }
+}
+
+/// One audit entry per batch, listing the ids it changed; nothing when none succeeded.
+async fn record_batch(
+    conn: &mut PgConnection,
+    actor: &Actor,
+    action: Action,
+    batch_action: &str,
+    results: &[BatchItemResult],
+) -> Result<(), ApiError> {
+    let ids: Vec<Uuid> = results
+        .iter()
+        .filter(|r| r.status == "ok")
+        .map(|r| r.id)
+        .collect();
+    if ids.is_empty() {
+        return Ok(());
+    }
+
+    Event::new(action)
+        .details(serde_json::json!({ "action": batch_action, "ids": ids }))
+        .record(conn, actor)
+        .await
+}
+
+fn batch_response(results: Vec<BatchItemResult>) -> BatchResponse {
+    let succeeded = results.iter().filter(|r| r.status == "ok").count();
+    BatchResponse {
+        failed: results.len() - succeeded,
+        succeeded,
+        results,
+    }
+}
+
+#[get("/api/export")]
+pub async fn export_vault(
+    state: Data<AppState>,
+    req: HttpRequest,
+    query: web::Query<VaultExportQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let pool = &state.pool;
+    let token = auth::require_token(&req, pool).await?;
+    auth::deny_impersonation(&req)?;
+    let format = query.format.unwrap_or(VaultFormat::Json);
+
+    let commands = sqlx::query_as::<_, Command>(
+        r#"
+        SELECT id, title, text, description, platform, visibility, favorite, usage_count, copy_count, run_count, accept_count, owner_token, created_at, updated_at, last_used_at, deleted_at, forked_from, risk_level
+        FROM commands
+        WHERE owner_token = $1 AND deleted_at IS NULL
+        ORDER BY created_at
+        "#,
+    )
+    .bind(token.id)
+    .fetch_all(pool)
+    .await?;
+
+    let ids: Vec<Uuid> = commands.iter().map(|c| c.id).collect();
+    let mut tags = load_tags(pool, &ids).await?;
+
+    let tag_names: Vec<String> =
+        sqlx::query("SELECT name FROM tags WHERE owner_token = $1 ORDER BY name")
+            .bind(token.id)
+            .fetch_all(pool)
+            .await?
+            .iter()
+            .map(|row| row.get::<String, _>("name"))
+            .collect();
+
+    let learned = if query.include_learned.unwrap_or(false) {
+        let rows = sqlx::query_as::<_, LearnedCommand>(
+            r#"
+            SELECT id, content, os, pwd, ls_output, owner_token, usage_count, created_at, last_used_at, deleted_at, risk_level, promoted_at,
+               success_count, failure_count
+            FROM learned_commands
+            WHERE owner_token = $1 AND deleted_at IS NULL
+            ORDER BY created_at
+            "#,
+        )
+        .bind(token.id)
+        .fetch_all(pool)
+        .await?;
+
+        Some(
+            rows.into_iter()
+                .map(|l| VaultLearned {
+                    content: l.content,
+                    os: l.os,
+                    pwd: l.pwd,
+                    usage_count: l.usage_count,
+                    created_at: Some(l.created_at),
+                    last_used_at: l.last_used_at,
+                })
+                .collect(),
+        )
+    } else {
+        None
+    };
+
+    let document = VaultDocument {
+        version: vault::VAULT_FORMAT_VERSION,
+        exported_at: Some(Utc::now()),
+        tags: tag_names,
+        commands: commands
+            .into_iter()
+            .map(|c| VaultCommand {
+                tags: tags.remove(&c.id).unwrap_or_default(),
+                title: c.title,
+                text: c.text,
+                description: c.description,
+                platform: c.platform,
+                visibility: Some(c.visibility),
+                favorite: Some(c.favorite),
+                created_at: Some(c.created_at),
+            })
+            .collect(),
+        learned,
+    };
+
+    let body = vault::render(&document, format).map_err(|_| ApiError::Internal)?;
+
+    Ok(HttpResponse::Ok()
+        .content_type(format.content_type())
+        .insert_header((
+            CONTENT_DISPOSITION,
+            format!(
+                "attachment; filename=\"cmvault-export.{}\"",
+                format.extension()
+            ),
+        ))
+        .body(body))
+}
+
+#[post("/api/import")]
+pub async fn import_vault(
+    state: Data<AppState>,
+    req: HttpRequest,
+    query: web::Query<VaultImportQuery>,
+    body: web::Bytes,
+) -> Result<HttpResponse, ApiError> {
+    let pool = &state.pool;
+    let token = auth::require_token(&req, pool).await?;
+    let format = query.format.unwrap_or(VaultFormat::Json);
+    let strategy = query.strategy.unwrap_or_default();
+    let document = vault::parse(&body, format).map_err(ApiError::BadRequest)?;
+    let may_publish = may_publish(&state, &token).await?;
+    let rules = load_learning_rules(pool, token.id).await?;
+    let policy = load_settings(pool, &token).await?.secret_policy;
+    let now = Utc::now();
+
+    let mut summary = VaultImportSummary::default();
+    let mut tx = pool.begin().await?;
+
+    for name in &document.tags {
+        sqlx::query(
+            r#"
+            INSERT INTO tags (id, name, owner_token)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (owner_token, name) DO NOTHING
+            "#,
+        )
+        .bind(Uuid::new_v4())
+        .bind(name)
+        .bind(token.id)
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    for (index, command) in document.commands.iter().enumerate() {
+        let text = match secrets::scrub(&command.text, policy) {
+            Scrubbed::Clean(text) => text,
+            Scrubbed::Changed { content, .. } => {
+                summary.redacted += 1;
+                content
+            }
+            Scrubbed::Rejected(findings) => {
+                summary.errors.push(VaultImportError {
+                    index,
+                    text: redacted(&command.text),
+                    error: format!(
+                        "command contains secrets ({}) and was not imported",
+                        secrets::kinds(&findings)
+                    ),
+                });
+                continue;
+            }
+        };
+        let visibility =
+            match validate_command(&text, &command.platform, command.visibility.as_deref())
+                .and_then(|visibility| match visibility.as_str() {
+                    "PUBLIC" if !may_publish => Err(unverified_publish()),
+                    _ => Ok(visibility),
+                }) {
+                Ok(visibility) => visibility,
+                Err(err) => {
+                    summary.errors.push(VaultImportError {
+                        index,
+                        text: text.clone(),
+                        error: err.to_string(),
+                    });
+                    continue;
+                }
+            };
+
+        let existing: Option<Uuid> = if strategy == ImportStrategy::Duplicate {
+            None
+        } else {
+            sqlx::query(
+                r#"
+                SELECT id FROM commands
+                WHERE owner_token = $1 AND text = $2 AND deleted_at IS NULL
+                ORDER BY created_at
+                LIMIT 1
+                FOR UPDATE
+                "#,
+            )
+            .bind(token.id)
+            .bind(&text)
+            .fetch_optional(&mut *tx)
+            .await?
+            .map(|row| row.get("id"))
+        };
+
+        match (existing, strategy) {
+            (Some(_), ImportStrategy::Skip) => summary.skipped += 1,
+            (Some(command_id), _) => {
+                sqlx::query(
+                    r#"
+                    UPDATE commands
+                    SET title = $1, description = $2, platform = $3, visibility = $4, favorite = $5, updated_at = $6
+                    WHERE id = $7
+                    "#,
+                )
+                .bind(&command.title)
+                .bind(&command.description)
+                .bind(&command.platform)
+                .bind(&visibility)
+                .bind(command.favorite.unwrap_or(false))
+                .bind(now)
+                .bind(command_id)
+                .execute(&mut *tx)
+                .await?;
+
+                sqlx::query("DELETE FROM command_tags WHERE command_id = $1")
+                    .bind(command_id)
+                    .execute(&mut *tx)
+                    .await?;
+                attach_tags(&mut tx, token.id, command_id, &command.tags).await?;
+                summary.updated += 1;
+            }
+            (None, _) => {
+                let command_id = Uuid::new_v4();
+                let created_at = command.created_at.unwrap_or(now);
+                sqlx::query(
+                    r#"
+                    INSERT INTO commands (
+                        id, title, text, description, platform, visibility, favorite, usage_count, owner_token, created_at, updated_at, last_used_at, risk_level
+                    ) VALUES (
+                        $1, $2, $3, $4, $5, $6, $7, 0, $8, $9, $10, $9, $11
+                    )
+                    "#,
+                )
+                .bind(command_id)
+                .bind(&command.title)
+                .bind(&text)
+                .bind(&command.description)
+                .bind(&command.platform)
+                .bind(&visibility)
+                .bind(command.favorite.unwrap_or(false))
+                .bind(token.id)
+                .bind(created_at)
+                .bind(now)
+                .bind(danger::classify(&text).level.as_str())
+                .execute(&mut *tx)
+                .await?;
+
+                attach_tags(&mut tx, token.id, command_id, &command.tags).await?;
+                summary.created += 1;
+            }
+        }
+    }
+
+    // Learned history keeps one row per command, so `duplicate` merges like a history import
+    let conflict = match strategy {
+        ImportStrategy::Skip => LearnedConflict::Keep,
+        ImportStrategy::Overwrite => LearnedConflict::Replace,
+        ImportStrategy::Duplicate => LearnedConflict::Merge,
+    };
+    for learned in document.learned.iter().flatten() {
+        let content = learned.content.trim();
+        if content.is_empty() {
+            continue;
+        }
+        // An old export must not bring back what the owner has since excluded
+        let execution = Execution {
+            raw: &learned.content,
+            os: learned.os.as_deref(),
+            pwd: learned.pwd.as_deref(),
+        };
+        if privacy::first_match(&rules, &execution).is_some() {
+            summary.learned_ignored += 1;
+            continue;
+        }
+        let content = match secrets::scrub(content, policy) {
+            Scrubbed::Clean(content) => content,
+            Scrubbed::Changed { content, .. } => {
+                summary.redacted += 1;
+                content
+            }
+            Scrubbed::Rejected(_) => {
+                summary.secrets_rejected += 1;
+                continue;
+            }
+        };
+        let entry = history::GroupedEntry {
+            command: content,
+            count: learned.usage_count.max(1),
+            first_seen: learned.created_at,
+            last_seen: learned.last_used_at.or(learned.created_at),
+        };
+        if merge_learned(
+            &mut tx,
+            token.id,
+            &entry,
+            learned.os.as_deref(),
+            learned.pwd.as_deref(),
+            now,
+            conflict,
+        )
+        .await?
+        {
+            summary.learned_imported += 1;
+        } else if conflict == LearnedConflict::Keep {
+            summary.learned_skipped += 1;
+        } else {
+            summary.learned_merged += 1;
+        }
+    }
+
+    Event::new(Action::CommandsImported)
+        .details(serde_json::json!({
+            "source": "vault",
+            "created": summary.created,
+            "updated": summary.updated,
+            "learned_imported": summary.learned_imported,
+        }))
+        .record(&mut *tx, &Actor::new(&req, &state).token(&token))
+        .await?;
+    tx.commit().await?;
+
+    Ok(HttpResponse::Ok().json(summary))
+}
+
+#[post("/api/cheatsheets/import")]
+pub async fn import_cheatsheets(
+    state: Data<AppState>,
+    req: HttpRequest,
+    query: web::Query<CheatsheetImportQuery>,
+    body: web::Bytes,
+) -> Result<HttpResponse, ApiError> {
+    let pool = &state.pool;
+    let token = auth::require_token(&req, pool).await?;
+    let query = query.into_inner();
+    let visibility = parse_visibility(query.visibility.as_deref())?;
+    ensure_may_publish(&state, &token, &visibility).await?;
+    let dry_run = query.dry_run.unwrap_or(true);
+    let source = query.source;
+    let platform = query.platform.unwrap_or_else(|| "Others".to_string());
 
-    // 1. Prepare search terms
-    let query_input = payload.query.trim();
-    if query_input.is_empty() {
-        return Ok(HttpResponse::Ok().json(Vec::<String>::new()));
+    let directory = match &query.path {
+        Some(path) => {
+            let root = state.cheatsheet_dir.as_ref().ok_or_else(|| {
+                ApiError::BadRequest("server-side cheatsheet imports are not enabled".into())
+            })?;
+            let relative = std::path::Path::new(path);
+            if !cheatsheets::is_safe_relative(relative) {
+                return Err(ApiError::BadRequest(
+                    "path must be relative to the import directory".into(),
+                ));
+            }
+            Some(root.join(relative))
+        }
+        None if body.is_empty() => {
+            return Err(ApiError::BadRequest(
+                "upload an archive or pass a path".into(),
+            ))
+        }
+        None => None,
+    };
+
+    let parsed = web::block(move || match directory {
+        Some(dir) => cheatsheets::from_directory(source, &dir, &platform),
+        None => cheatsheets::from_upload(source, &body, &platform),
+    })
+    .await
+    .map_err(|_| ApiError::Internal)?
+    .map_err(ApiError::BadRequest)?;
+
+    let mut errors = parsed.errors;
+    let parsed_count = parsed.commands.len();
+
+    let texts: Vec<String> = parsed.commands.iter().map(|c| c.text.clone()).collect();
+    let mut seen: HashSet<String> = sqlx::query(
+        "SELECT text FROM commands WHERE owner_token = $1 AND deleted_at IS NULL AND text = ANY($2)",
+    )
+    .bind(token.id)
+    .bind(&texts)
+    .fetch_all(pool)
+    .await?
+    .iter()
+    .map(|row| row.get::<String, _>("text"))
+    .collect();
+
+    let mut fresh = Vec::new();
+    let mut skipped = 0;
+    for command in parsed.commands {
+        if let Err(err) = validate_command(&command.text, &command.platform, Some(&visibility)) {
+            errors.push(CheatsheetError {
+                file: command.file,
+                message: err.to_string(),
+            });
+            continue;
+        }
+        if seen.insert(command.text.clone()) {
+            fresh.push(command);
+        } else {
+            skipped += 1;
+        }
     }
 
-    // Patterns for SQL
-    let starts_with = format!("{}%", query_input); // High priority
-    let contains = format!("%{}%", query_input); // Low priority
+    if !dry_run {
+        let now = Utc::now();
+        let mut tx = pool.begin().await?;
+        for command in &fresh {
+            let command_id = Uuid::new_v4();
+            sqlx::query(
+                r#"
+                INSERT INTO commands (
+                    id, title, text, description, platform, visibility, favorite, usage_count, owner_token, created_at, updated_at, last_used_at, risk_level
+                ) VALUES (
+                    $1, $2, $3, $4, $5, $6, false, 0, $7, $8, $8, $8, $9
+                )
+                "#,
+            )
+            .bind(command_id)
+            .bind(&command.title)
+            .bind(&command.text)
+            .bind(&command.description)
+            .bind(&command.platform)
+            .bind(&visibility)
+            .bind(token.id)
+            .bind(now)
+            .bind(danger::classify(&command.text).level.as_str())
+            .execute(&mut *tx)
+            .await?;
 
-    // 2. Resolve Context
-    let os_filter = payload.os.as_deref().unwrap_or("%"); // Wildcard if no OS provided
-                                                          // 3. Define the User ID (use a dummy UUID or handle None if user is guest)
-                                                          // If no token, we can't search learned_commands (private), so we use a null placeholder
-    let user_id = token.as_ref().map(|t| t.id);
+            attach_tags(&mut tx, token.id, command_id, &command.tags).await?;
+        }
+        Event::new(Action::CommandsImported)
+            .details(serde_json::json!({ "source": "cheatsheets", "created": fresh.len() }))
+            .record(&mut *tx, &Actor::new(&req, &state).token(&token))
+            .await?;
+        tx.commit().await?;
+    }
 
-    // 4. Execute the Hybrid Query
-    // Uses trigram similarity (pg_trgm) for fuzzy matching and last_used_at for recency
-    let rows = sqlx::query_as::<_, SuggestionRow>(
-        r#"
-        WITH combined_results AS (
-            -- SOURCE 1: Saved Commands (Curated)
-            SELECT
-                text,
-                usage_count,
-                COALESCE(last_used_at, created_at) as last_used_at,
-                platform as os,
-                -- Score Boosts
-                20.0 as base_score, -- Saved commands get a baseline boost over random history
-                CASE WHEN text ILIKE $2 THEN 50.0 ELSE 0.0 END as prefix_bonus,
-                -- Trigram similarity score (0.0 to 1.0) scaled to 0-30 points
-                (similarity(text, $5) * 30.0) as trgm_score
-            FROM commands
-            WHERE (visibility = 'PUBLIC' OR owner_token = $1)
-              AND (text ILIKE $3 OR similarity(text, $5) > 0.1)
+    let new_commands = fresh.len();
+    fresh.truncate(100);
 
-            UNION ALL
+    Ok(HttpResponse::Ok().json(CheatsheetImportSummary {
+        dry_run,
+        parsed: parsed_count,
+        new_commands,
+        skipped,
+        errors,
+        preview: fresh,
+    }))
+}
 
-            -- SOURCE 2: Learned Commands (History)
-            -- Only queried if user_id ($1) is NOT NULL (Handled via WHERE clause)
-            SELECT
-                content as text,
-                usage_count,
-                COALESCE(last_used_at, created_at) as last_used_at,
-                os,
-                -- Score Boosts
-                0.0 as base_score,
-                CASE WHEN content ILIKE $2 THEN 50.0 ELSE 0.0 END as prefix_bonus,
-                -- Trigram similarity score (0.0 to 1.0) scaled to 0-30 points
-                (similarity(content, $5) * 30.0) as trgm_score
-            FROM learned_commands
-            WHERE owner_token = $1
-              AND (content ILIKE $3 OR similarity(content, $5) > 0.1)
-              -- Context Filter: heavily prefer current OS, or allow if OS is generic
-              AND (os IS NULL OR os ILIKE $4)
-        )
-        SELECT
-            text,
-            -- Calculate Final Relevance Score
-            SUM(
-                base_score +
-                prefix_bonus +
-                trgm_score +
-                (usage_count::float * 2.0) +
-                -- Recency Bonus: more points for recently used items (last 7 days = max bonus)
-                GREATEST(0.0, 25.0 - (EXTRACT(EPOCH FROM (now() - last_used_at)) / 86400.0 / 7.0 * 25.0))
-            ) as score
-        FROM combined_results
-        GROUP BY text -- Deduplicate: merge identical commands from saved/learned
-        ORDER BY score DESC
-        LIMIT 10
-        "#
+#[get("/api/trash/commands")]
+pub async fn list_trashed_commands(
+    state: Data<AppState>,
+    req: HttpRequest,
+    query: web::Query<CommandQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let pool = &state.pool;
+    let token = auth::require_token(&req, pool).await?;
+    let limit = query.limit.unwrap_or(20).clamp(1, 200);
+    let offset = query.offset.unwrap_or(0).max(0);
+    let search = query.q.as_ref().map(|s| format!("%{}%", s));
+
+    let commands = sqlx::query_as::<_, Command>(
+        r#"
+        SELECT id, title, text, description, platform, visibility, favorite, usage_count, copy_count, run_count, accept_count, owner_token, created_at, updated_at, last_used_at, deleted_at, forked_from, risk_level
+        FROM commands
+        WHERE owner_token = $1
+          AND deleted_at IS NOT NULL
+          AND ($2::text IS NULL OR text ILIKE $2)
+        ORDER BY deleted_at DESC
+        LIMIT $3 OFFSET $4
+        "#,
     )
-    .bind(user_id)          // $1
-    .bind(&starts_with)     // $2 (Prefix match)
-    .bind(&contains)        // $3 (Broad match)
-    .bind(os_filter)        // $4 (OS Context)
-    .bind(query_input)      // $5 (Trigram similarity input)
+    .bind(token.id)
+    .bind(search.as_deref())
+    .bind(limit)
+    .bind(offset)
     .fetch_all(pool)
     .await?;
 
-    let suggestions: Vec<String> = rows.into_iter().map(|r| r.text).collect();
+    let total_row = sqlx::query(
+        r#"
+        SELECT count(*) as total
+        FROM commands
+        WHERE owner_token = $1
+          AND deleted_at IS NOT NULL
+          AND ($2::text IS NULL OR text ILIKE $2)
+        "#,
+    )
+    .bind(token.id)
+    .bind(search.as_deref())
+    .fetch_one(pool)
+    .await?;
+    let total: i64 = total_row.try_get("total").unwrap_or(0);
 
-    Ok(HttpResponse::Ok().json(suggestions))
+    let ids: Vec<Uuid> = commands.iter().map(|c| c.id).collect();
+    let tags = load_tags(pool, &ids).await?;
+    let forks = load_fork_counts(pool, &ids).await?;
+    let items: Vec<CommandWithTags> = commands
+        .into_iter()
+        .map(|c| command_response(c, &tags, &forks))
+        .collect();
+
+    Ok(HttpResponse::Ok().json(crate::models::Page {
+        items,
+        total,
+        limit,
+        offset,
+    }))
 }
 
-#[post("/api/learn")]
-pub async fn learn_command(
+#[post("/api/trash/commands/{id}/restore")]
+pub async fn restore_command(
     state: Data<AppState>,
     req: HttpRequest,
-    payload: web::Json<LearnRequest>,
+    path: web::Path<Uuid>,
 ) -> Result<HttpResponse, ApiError> {
     let pool = &state.pool;
     let token = auth::require_token(&req, pool).await?;
-    let content = payload.executed_command.trim();
-
-    if content.is_empty() {
-        return Err(ApiError::BadRequest("executed_command is required".into()));
-    }
-
-    let now = Utc::now();
+    let command_id = path.into_inner();
 
-    let existing = sqlx::query(
-        r#"SELECT id, usage_count FROM learned_commands WHERE owner_token = $1 AND content = $2"#,
+    let result = sqlx::query(
+        "UPDATE commands SET deleted_at = NULL, updated_at = now() WHERE id = $1 AND owner_token = $2 AND deleted_at IS NOT NULL",
     )
+    .bind(command_id)
     .bind(token.id)
-    .bind(content)
-    .fetch_optional(pool)
+    .execute(pool)
     .await?;
 
-    if let Some(row) = existing {
-        let usage = row.try_get::<i32, _>("usage_count").unwrap_or(1) + 1;
-        let id: Uuid = row.try_get("id").unwrap();
-        sqlx::query(
-            "UPDATE learned_commands SET usage_count = $1, last_used_at = $2 WHERE id = $3",
-        )
-        .bind(usage)
-        .bind(now)
-        .bind(id)
-        .execute(pool)
-        .await?;
-    } else {
-        sqlx::query(
-            r#"
-            INSERT INTO learned_commands (id, content, os, pwd, ls_output, owner_token, usage_count, created_at, last_used_at)
-            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
-            "#,
-        )
-        .bind(Uuid::new_v4())
-        .bind(content)
-        .bind(&payload.os)
-        .bind(&payload.pwd)
-        .bind(&payload.ls_output)
-        .bind(token.id)
-        .bind(1_i32)
-        .bind(now)
-        .bind(now)
-        .execute(pool)
-        .await?;
+    if result.rows_affected() == 0 {
+        return Err(ApiError::NotFound);
     }
+    Event::new(Action::CommandRestored)
+        .target("command", command_id)
+        .record(pool, &Actor::new(&req, &state).token(&token))
+        .await?;
 
-    // Also update last_used_at for any matching command in the commands table
-    sqlx::query(
-        "UPDATE commands SET last_used_at = $1, usage_count = usage_count + 1 WHERE owner_token = $2 AND text = $3",
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[delete("/api/trash/commands/{id}")]
+pub async fn purge_command(
+    state: Data<AppState>,
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+) -> Result<HttpResponse, ApiError> {
+    let pool = &state.pool;
+    let token = auth::require_token(&req, pool).await?;
+    let command_id = path.into_inner();
+
+    let result = sqlx::query(
+        "DELETE FROM commands WHERE id = $1 AND owner_token = $2 AND deleted_at IS NOT NULL",
     )
-    .bind(now)
+    .bind(command_id)
     .bind(token.id)
-    .bind(content)
     .execute(pool)
     .await?;
 
-    Ok(HttpResponse::Ok().json(serde_json::json!({ "status": "ok" })))
+    if result.rows_affected() == 0 {
+        return Err(ApiError::NotFound);
+    }
+    Event::new(Action::CommandPurged)
+        .target("command", command_id)
+        .record(pool, &Actor::new(&req, &state).token(&token))
+        .await?;
+
+    Ok(HttpResponse::NoContent().finish())
 }
 
-#[get("/api/learned")]
-pub async fn list_learned(
+#[get("/api/trash/learned")]
+pub async fn list_trashed_learned(
     state: Data<AppState>,
     req: HttpRequest,
     query: web::Query<CommandQuery>,
@@ -553,11 +4330,13 @@ pub async fn list_learned(
 
     let rows = sqlx::query_as::<_, LearnedCommand>(
         r#"
-        SELECT id, content, os, pwd, ls_output, owner_token, usage_count, created_at, last_used_at
+        SELECT id, content, os, pwd, ls_output, owner_token, usage_count, created_at, last_used_at, deleted_at, risk_level, promoted_at,
+               success_count, failure_count
         FROM learned_commands
         WHERE owner_token = $1
+          AND deleted_at IS NOT NULL
           AND ($2::text IS NULL OR content ILIKE $2)
-        ORDER BY last_used_at DESC NULLS LAST, usage_count DESC, created_at DESC
+        ORDER BY deleted_at DESC
         LIMIT $3 OFFSET $4
         "#,
     )
@@ -573,58 +4352,192 @@ pub async fn list_learned(
         SELECT count(*) as total
         FROM learned_commands
         WHERE owner_token = $1
+          AND deleted_at IS NOT NULL
           AND ($2::text IS NULL OR content ILIKE $2)
         "#,
     )
     .bind(token.id)
-    .bind(search.as_deref())
-    .fetch_one(pool)
+    .bind(search.as_deref())
+    .fetch_one(pool)
+    .await?;
+    let total: i64 = total_row.try_get("total").unwrap_or(0);
+
+    Ok(HttpResponse::Ok().json(crate::models::Page {
+        items: rows,
+        total,
+        limit,
+        offset,
+    }))
+}
+
+#[post("/api/trash/learned/{id}/restore")]
+pub async fn restore_learned(
+    state: Data<AppState>,
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+) -> Result<HttpResponse, ApiError> {
+    let pool = &state.pool;
+    let token = auth::require_token(&req, pool).await?;
+    let learned_id = path.into_inner();
+
+    let mut tx = pool.begin().await?;
+
+    let trashed = sqlx::query(
+        r#"
+        SELECT content, usage_count, last_used_at
+        FROM learned_commands
+        WHERE id = $1 AND owner_token = $2 AND deleted_at IS NOT NULL
+        FOR UPDATE
+        "#,
+    )
+    .bind(learned_id)
+    .bind(token.id)
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or(ApiError::NotFound)?;
+
+    let content: String = trashed.try_get("content").unwrap();
+    let usage_count: i32 = trashed.try_get("usage_count").unwrap_or(1);
+    let last_used_at: Option<chrono::DateTime<Utc>> =
+        trashed.try_get("last_used_at").unwrap_or(None);
+
+    // The same command may have been learned again while this row sat in the trash;
+    // fold the restored history into the live row instead of creating a duplicate.
+    let merged = sqlx::query(
+        r#"
+        UPDATE learned_commands live
+        SET usage_count = live.usage_count + $1,
+            last_used_at = GREATEST(live.last_used_at, $2),
+            success_count = live.success_count + trashed.success_count,
+            failure_count = live.failure_count + trashed.failure_count
+        FROM learned_commands trashed
+        WHERE trashed.id = $5
+          AND live.owner_token = $3 AND live.content = $4 AND live.deleted_at IS NULL
+        RETURNING live.id
+        "#,
+    )
+    .bind(usage_count)
+    .bind(last_used_at)
+    .bind(token.id)
+    .bind(&content)
+    .bind(learned_id)
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    if let Some(live) = merged {
+        sqlx::query("UPDATE learned_executions SET learned_id = $1 WHERE learned_id = $2")
+            .bind(live.get::<Uuid, _>("id"))
+            .bind(learned_id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("DELETE FROM learned_commands WHERE id = $1")
+            .bind(learned_id)
+            .execute(&mut *tx)
+            .await?;
+    } else {
+        sqlx::query("UPDATE learned_commands SET deleted_at = NULL WHERE id = $1")
+            .bind(learned_id)
+            .execute(&mut *tx)
+            .await?;
+    }
+
+    Event::new(Action::LearnedRestored)
+        .target("learned", learned_id)
+        .record(&mut *tx, &Actor::new(&req, &state).token(&token))
+        .await?;
+    tx.commit().await?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[delete("/api/trash/learned/{id}")]
+pub async fn purge_learned(
+    state: Data<AppState>,
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+) -> Result<HttpResponse, ApiError> {
+    let pool = &state.pool;
+    let token = auth::require_token(&req, pool).await?;
+    let learned_id = path.into_inner();
+
+    let result = sqlx::query(
+        "DELETE FROM learned_commands WHERE id = $1 AND owner_token = $2 AND deleted_at IS NOT NULL",
+    )
+    .bind(learned_id)
+    .bind(token.id)
+    .execute(pool)
     .await?;
-    let total: i64 = total_row.try_get("total").unwrap_or(0);
 
-    Ok(HttpResponse::Ok().json(crate::models::Page {
-        items: rows,
-        total,
-        limit,
-        offset,
-    }))
+    if result.rows_affected() == 0 {
+        return Err(ApiError::NotFound);
+    }
+    Event::new(Action::LearnedPurged)
+        .target("learned", learned_id)
+        .record(pool, &Actor::new(&req, &state).token(&token))
+        .await?;
+
+    Ok(HttpResponse::NoContent().finish())
 }
 
-#[post("/api/learned/{id}/promote")]
-pub async fn promote_learned(
+#[delete("/api/trash")]
+pub async fn empty_trash(
     state: Data<AppState>,
     req: HttpRequest,
-    path: web::Path<Uuid>,
-    payload: web::Json<PromotePayload>,
 ) -> Result<HttpResponse, ApiError> {
     let pool = &state.pool;
     let token = auth::require_token(&req, pool).await?;
-    let learned_id = path.into_inner();
 
+    let mut tx = pool.begin().await?;
+    let commands =
+        sqlx::query("DELETE FROM commands WHERE owner_token = $1 AND deleted_at IS NOT NULL")
+            .bind(token.id)
+            .execute(&mut *tx)
+            .await?;
+    let learned = sqlx::query(
+        "DELETE FROM learned_commands WHERE owner_token = $1 AND deleted_at IS NOT NULL",
+    )
+    .bind(token.id)
+    .execute(&mut *tx)
+    .await?;
+    let summary = serde_json::json!({
+        "commands": commands.rows_affected(),
+        "learned": learned.rows_affected(),
+    });
+    Event::new(Action::TrashEmptied)
+        .details(summary.clone())
+        .record(&mut *tx, &Actor::new(&req, &state).token(&token))
+        .await?;
+    tx.commit().await?;
+
+    Ok(HttpResponse::Ok().json(summary))
+}
+
+/// Copies a live learned entry into `commands`, returning the new command id or
+/// `None` when the entry does not exist for this owner.
+async fn promote_one(
+    conn: &mut PgConnection,
+    owner: Uuid,
+    learned_id: Uuid,
+    payload: &PromotePayload,
+    visibility: &str,
+) -> Result<Option<Uuid>, ApiError> {
     let learned = sqlx::query_as::<_, LearnedCommand>(
         r#"
-        SELECT id, content, os, pwd, ls_output, owner_token, usage_count, created_at, last_used_at
+        SELECT id, content, os, pwd, ls_output, owner_token, usage_count, created_at, last_used_at, deleted_at, risk_level, promoted_at,
+               success_count, failure_count
         FROM learned_commands
-        WHERE id = $1 AND owner_token = $2
+        WHERE id = $1 AND owner_token = $2 AND deleted_at IS NULL
         "#,
     )
     .bind(learned_id)
-    .bind(token.id)
-    .fetch_optional(pool)
+    .bind(owner)
+    .fetch_optional(&mut *conn)
     .await?;
 
-    let learned = learned.ok_or(ApiError::NotFound)?;
-
-    let visibility = payload
-        .visibility
-        .clone()
-        .unwrap_or_else(|| "PRIVATE".to_string());
-
-    if visibility != "PUBLIC" && visibility != "PRIVATE" {
-        return Err(ApiError::BadRequest(
-            "visibility must be PUBLIC or PRIVATE".into(),
-        ));
-    }
+    let Some(learned) = learned else {
+        return Ok(None);
+    };
+    ensure_no_secrets(&learned.content, "promoted")?;
 
     let command_id = Uuid::new_v4();
     let now = Utc::now();
@@ -632,9 +4545,9 @@ pub async fn promote_learned(
     sqlx::query(
         r#"
         INSERT INTO commands (
-            id, title, text, description, platform, visibility, favorite, usage_count, owner_token, created_at, updated_at, last_used_at
+            id, title, text, description, platform, visibility, favorite, usage_count, owner_token, created_at, updated_at, last_used_at, risk_level
         ) VALUES (
-            $1, $2, $3, $4, $5, $6, false, $7, $8, $9, $9, $10
+            $1, $2, $3, $4, $5, $6, false, $7, $8, $9, $9, $10, $11
         )
         "#,
     )
@@ -643,153 +4556,534 @@ pub async fn promote_learned(
     .bind(&learned.content)
     .bind(&payload.description)
     .bind(&payload.platform)
-    .bind(&visibility)
+    .bind(visibility)
     .bind(learned.usage_count)
-    .bind(token.id)
+    .bind(owner)
     .bind(now)
     .bind(learned.last_used_at.unwrap_or(now))
-    .execute(pool)
+    .bind(danger::classify(&learned.content).level.as_str())
+    .execute(&mut *conn)
     .await?;
 
     if let Some(tags) = &payload.tags {
-        for name in tags {
-            let tag_id = sqlx::query(
-                r#"
-                INSERT INTO tags (id, name, owner_token)
-                VALUES ($1, $2, $3)
-                ON CONFLICT (owner_token, name) DO UPDATE SET name = EXCLUDED.name
-                RETURNING id
-                "#,
-            )
-            .bind(Uuid::new_v4())
-            .bind(name)
-            .bind(token.id)
-            .fetch_one(pool)
-            .await?
-            .get::<Uuid, _>("id");
+        attach_tags(conn, owner, command_id, tags).await?;
+    }
 
-            sqlx::query(
-                "INSERT INTO command_tags (command_id, tag_id) VALUES ($1, $2) ON CONFLICT DO NOTHING",
-            )
-            .bind(command_id)
-            .bind(tag_id)
-            .execute(pool)
-            .await?;
+    sqlx::query("UPDATE learned_commands SET promoted_at = $1 WHERE id = $2")
+        .bind(now)
+        .bind(learned.id)
+        .execute(&mut *conn)
+        .await?;
+
+    Ok(Some(command_id))
+}
+
+/// Per-owner state loaded once per learn request.
+struct LearnContext {
+    rules: Vec<privacy::CompiledRule>,
+    policy: SecretPolicy,
+}
+
+enum LearnOutcome {
+    Stored,
+    Redacted(Vec<SecretFinding>),
+    Ignored(Uuid),
+    Duplicate,
+    Rejected(String),
+}
+
+/// Upserts one execution into `learned_commands` and counts a run for matching saved
+/// commands. `executed_at` from the client is used when it is not in the future.
+async fn learn_one(
+    conn: &mut PgConnection,
+    owner: Uuid,
+    context: &LearnContext,
+    item: &LearnRequest,
+    now: DateTime<Utc>,
+) -> Result<LearnOutcome, ApiError> {
+    let executed = item.executed_command.trim();
+    if executed.is_empty() {
+        return Ok(LearnOutcome::Rejected(
+            "executed_command is required".into(),
+        ));
+    }
+
+    let execution = Execution {
+        raw: &item.executed_command,
+        os: item.os.as_deref(),
+        pwd: item.pwd.as_deref(),
+    };
+    if let Some(rule_id) = privacy::first_match(&context.rules, &execution) {
+        return Ok(LearnOutcome::Ignored(rule_id));
+    }
+
+    let (content, redactions) = match secrets::scrub(executed, context.policy) {
+        Scrubbed::Clean(content) => (content, Vec::new()),
+        Scrubbed::Changed { content, findings } => (content, findings),
+        Scrubbed::Rejected(findings) => {
+            return Ok(LearnOutcome::Rejected(format!(
+                "executed_command contains secrets ({}) and was not stored",
+                secrets::kinds(&findings)
+            )))
+        }
+    };
+
+    // Only items that will be stored use up their key, so a retry of an ignored or
+    // rejected item gets the same answer again instead of `duplicate`
+    if let Some(key) = &item.idempotency_key {
+        let receipt = sqlx::query(
+            r#"
+            INSERT INTO learn_receipts (owner_token, idempotency_key, created_at)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (owner_token, idempotency_key) DO NOTHING
+            "#,
+        )
+        .bind(owner)
+        .bind(key)
+        .bind(now)
+        .execute(&mut *conn)
+        .await?;
+
+        if receipt.rows_affected() == 0 {
+            return Ok(LearnOutcome::Duplicate);
         }
     }
 
-    Ok(HttpResponse::Created().finish())
-}
+    let at = item.executed_at.map_or(now, |at| at.min(now));
+    let (succeeded, failed) = match item.exit_code {
+        Some(0) => (1, 0),
+        // Interrupted (Ctrl-C) or suspended runs say nothing about whether the command works
+        Some(130) | Some(148) | None => (0, 0),
+        Some(_) => (0, 1),
+    };
 
-#[delete("/api/learned/{id}")]
-pub async fn delete_learned(
-    state: Data<AppState>,
-    req: HttpRequest,
-    path: web::Path<Uuid>,
-) -> Result<HttpResponse, ApiError> {
-    let pool = &state.pool;
-    let token = auth::require_token(&req, pool).await?;
-    let learned_id = path.into_inner();
+    let existing = sqlx::query(
+        r#"SELECT id, usage_count FROM learned_commands WHERE owner_token = $1 AND content = $2 AND deleted_at IS NULL"#,
+    )
+    .bind(owner)
+    .bind(&content)
+    .fetch_optional(&mut *conn)
+    .await?;
 
-    let result = sqlx::query("DELETE FROM learned_commands WHERE id = $1 AND owner_token = $2")
-        .bind(learned_id)
-        .bind(token.id)
-        .execute(pool)
+    let learned_id = if let Some(row) = existing {
+        let usage = row.try_get::<i32, _>("usage_count").unwrap_or(1) + 1;
+        let id: Uuid = row.try_get("id").unwrap();
+        sqlx::query(
+            r#"
+            UPDATE learned_commands
+            SET usage_count = $1,
+                last_used_at = GREATEST(last_used_at, $2),
+                created_at = LEAST(created_at, $2),
+                success_count = success_count + $3,
+                failure_count = failure_count + $4
+            WHERE id = $5
+            "#,
+        )
+        .bind(usage)
+        .bind(at)
+        .bind(succeeded)
+        .bind(failed)
+        .bind(id)
+        .execute(&mut *conn)
+        .await?;
+        id
+    } else {
+        let id = Uuid::new_v4();
+        sqlx::query(
+            r#"
+            INSERT INTO learned_commands (
+                id, content, os, pwd, ls_output, owner_token, usage_count, created_at, last_used_at, risk_level,
+                success_count, failure_count
+            )
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12)
+            "#,
+        )
+        .bind(id)
+        .bind(&content)
+        .bind(&item.os)
+        .bind(&item.pwd)
+        .bind(&item.ls_output)
+        .bind(owner)
+        .bind(1_i32)
+        .bind(at)
+        .bind(at)
+        .bind(danger::classify(&content).level.as_str())
+        .bind(succeeded)
+        .bind(failed)
+        .execute(&mut *conn)
         .await?;
+        id
+    };
 
-    if result.rows_affected() == 0 {
-        return Err(ApiError::NotFound);
+    let duration_ms = item.duration_ms.filter(|ms| *ms >= 0);
+    if item.exit_code.is_some()
+        || duration_ms.is_some()
+        || item.shell.is_some()
+        || item.hostname.is_some()
+    {
+        sqlx::query(
+            r#"
+            INSERT INTO learned_executions (id, learned_id, exit_code, duration_ms, shell, hostname, pwd, executed_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+            "#,
+        )
+        .bind(Uuid::new_v4())
+        .bind(learned_id)
+        .bind(item.exit_code)
+        .bind(duration_ms)
+        .bind(&item.shell)
+        .bind(&item.hostname)
+        .bind(&item.pwd)
+        .bind(at)
+        .execute(&mut *conn)
+        .await?;
     }
 
-    Ok(HttpResponse::NoContent().finish())
+    // Also count a run for any matching command in the commands table
+    let matching: Vec<Uuid> = sqlx::query(
+        "SELECT id FROM commands WHERE owner_token = $1 AND text = $2 AND deleted_at IS NULL",
+    )
+    .bind(owner)
+    .bind(executed)
+    .fetch_all(&mut *conn)
+    .await?
+    .iter()
+    .map(|row| row.get::<Uuid, _>("id"))
+    .collect();
+
+    record_usage(conn, &matching, Some(owner), UsageSource::Run, at).await?;
+
+    if redactions.is_empty() {
+        Ok(LearnOutcome::Stored)
+    } else {
+        Ok(LearnOutcome::Redacted(redactions))
+    }
 }
 
-#[post("/api/device-codes")]
-pub async fn create_device_code(
-    state: Data<AppState>,
-    req: HttpRequest,
-) -> Result<HttpResponse, ApiError> {
-    let pool = &state.pool;
-    let token = auth::require_token(&req, pool).await?;
-    let expires_at = Utc::now() + Duration::minutes(10);
+/// Bumps the per-command aggregates and appends one usage event per command.
+async fn record_usage(
+    conn: &mut PgConnection,
+    command_ids: &[Uuid],
+    token_id: Option<Uuid>,
+    source: UsageSource,
+    at: DateTime<Utc>,
+) -> Result<(), ApiError> {
+    if command_ids.is_empty() {
+        return Ok(());
+    }
 
-    let code = loop {
-        let candidate = generate_code();
-        let exists = sqlx::query("SELECT 1 as one FROM device_codes WHERE code = $1")
-            .bind(&candidate)
-            .fetch_optional(pool)
-            .await?;
+    let counter = match source {
+        UsageSource::Copy => "copy_count",
+        UsageSource::Run => "run_count",
+        UsageSource::SuggestAccept => "accept_count",
+    };
 
-        if exists.is_none() {
-            break candidate;
-        }
+    let bumped: Vec<Uuid> = sqlx::query(&format!(
+        r#"
+        UPDATE commands
+        SET usage_count = usage_count + 1,
+            {counter} = {counter} + 1,
+            last_used_at = $1
+        WHERE id = ANY($2)
+        RETURNING id
+        "#
+    ))
+    .bind(at)
+    .bind(command_ids)
+    .fetch_all(&mut *conn)
+    .await?
+    .iter()
+    .map(|row| row.get::<Uuid, _>("id"))
+    .collect();
+
+    for command_id in bumped {
+        sqlx::query(
+            r#"
+            INSERT INTO usage_events (id, command_id, token_id, source, created_at)
+            VALUES ($1, $2, $3, $4, $5)
+            "#,
+        )
+        .bind(Uuid::new_v4())
+        .bind(command_id)
+        .bind(token_id)
+        .bind(source.as_str())
+        .bind(at)
+        .execute(&mut *conn)
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// What an imported learned entry does to a live entry with the same content.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LearnedConflict {
+    /// Keep the larger usage count and widen the first and last use.
+    Merge,
+    /// Take the imported usage count, timestamps, os and pwd.
+    Replace,
+    /// Leave the live entry as it is.
+    Keep,
+}
+
+/// Folds a grouped history entry into a live learned entry, or inserts a new one.
+/// Every conflict mode gives the same result when the same entry is imported twice.
+/// Returns `true` when a new row was created.
+async fn merge_learned(
+    conn: &mut PgConnection,
+    owner: Uuid,
+    entry: &history::GroupedEntry,
+    os: Option<&str>,
+    pwd: Option<&str>,
+    now: DateTime<Utc>,
+    conflict: LearnedConflict,
+) -> Result<bool, ApiError> {
+    let existing = if conflict == LearnedConflict::Keep {
+        sqlx::query(
+            "SELECT 1 FROM learned_commands WHERE owner_token = $1 AND content = $2 AND deleted_at IS NULL LIMIT 1",
+        )
+        .bind(owner)
+        .bind(&entry.command)
+        .fetch_optional(&mut *conn)
+        .await?
+        .is_some()
+    } else {
+        sqlx::query(
+            r#"
+            UPDATE learned_commands
+            SET usage_count = CASE WHEN $6 THEN $1 ELSE GREATEST(usage_count, $1) END,
+                created_at = CASE WHEN $6 THEN COALESCE($2, created_at)
+                                  ELSE LEAST(created_at, COALESCE($2, created_at)) END,
+                last_used_at = CASE WHEN $6 THEN COALESCE($3, last_used_at)
+                                    ELSE GREATEST(last_used_at, $3) END,
+                os = CASE WHEN $6 THEN $7 ELSE os END,
+                pwd = CASE WHEN $6 THEN $8 ELSE pwd END
+            WHERE owner_token = $4 AND content = $5 AND deleted_at IS NULL
+            "#,
+        )
+        .bind(entry.count)
+        .bind(entry.first_seen)
+        .bind(entry.last_seen)
+        .bind(owner)
+        .bind(&entry.command)
+        .bind(conflict == LearnedConflict::Replace)
+        .bind(os)
+        .bind(pwd)
+        .execute(&mut *conn)
+        .await?
+        .rows_affected()
+            > 0
     };
+    if existing {
+        return Ok(false);
+    }
 
     sqlx::query(
         r#"
-        INSERT INTO device_codes (code, token_id, expires_at, consumed)
-        VALUES ($1, $2, $3, false)
+        INSERT INTO learned_commands (id, content, os, pwd, ls_output, owner_token, usage_count, created_at, last_used_at, risk_level)
+        VALUES ($1, $2, $3, $4, NULL, $5, $6, $7, $8, $9)
         "#,
     )
-    .bind(&code)
-    .bind(token.id)
-    .bind(expires_at)
-    .execute(pool)
+    .bind(Uuid::new_v4())
+    .bind(&entry.command)
+    .bind(os)
+    .bind(pwd)
+    .bind(owner)
+    .bind(entry.count)
+    .bind(entry.first_seen.unwrap_or(now))
+    .bind(entry.last_seen.unwrap_or(now))
+    .bind(danger::classify(&entry.command).level.as_str())
+    .execute(&mut *conn)
     .await?;
 
-    Ok(HttpResponse::Ok().json(DeviceCodeResponse { code, expires_at }))
+    Ok(true)
 }
 
-#[post("/api/exchange-token")]
-pub async fn exchange_token(
-    state: Data<AppState>,
-    payload: web::Json<ExchangeTokenRequest>,
-) -> Result<HttpResponse, ApiError> {
-    let pool = &state.pool;
-    let now = Utc::now();
+async fn attach_tags(
+    conn: &mut PgConnection,
+    owner: Uuid,
+    command_id: Uuid,
+    tags: &[String],
+) -> Result<(), ApiError> {
+    for name in tags {
+        let tag_id = sqlx::query(
+            r#"
+            INSERT INTO tags (id, name, owner_token)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (owner_token, name) DO UPDATE SET name = EXCLUDED.name
+            RETURNING id
+            "#,
+        )
+        .bind(Uuid::new_v4())
+        .bind(name)
+        .bind(owner)
+        .fetch_one(&mut *conn)
+        .await?
+        .get::<Uuid, _>("id");
 
-    let code_row = sqlx::query(
-        r#"
-        SELECT token_id, expires_at, consumed
-        FROM device_codes
-        WHERE code = $1
-        "#,
-    )
-    .bind(&payload.code)
-    .fetch_optional(pool)
-    .await?;
+        sqlx::query(
+            "INSERT INTO command_tags (command_id, tag_id) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+        )
+        .bind(command_id)
+        .bind(tag_id)
+        .execute(&mut *conn)
+        .await?;
+    }
 
-    let code_row = code_row.ok_or(ApiError::NotFound)?;
+    Ok(())
+}
 
-    let consumed: bool = code_row.try_get("consumed").unwrap_or(false);
-    let expires_at: chrono::DateTime<Utc> = code_row.try_get("expires_at").unwrap();
-    let token_id: Uuid = code_row.try_get("token_id").unwrap();
+/// Validation shared by every path that creates a command from user input.
+fn validate_command(
+    text: &str,
+    platform: &str,
+    visibility: Option<&str>,
+) -> Result<String, ApiError> {
+    if text.trim().is_empty() {
+        return Err(ApiError::BadRequest("text is required".into()));
+    }
+    if platform.trim().is_empty() {
+        return Err(ApiError::BadRequest("platform is required".into()));
+    }
+    let visibility = parse_visibility(visibility)?;
+    if visibility == "PUBLIC" {
+        ensure_no_secrets(text, "published")?;
+    }
+    Ok(visibility)
+}
 
-    if consumed {
-        return Err(ApiError::BadRequest("code already used".into()));
+/// `text` with its secrets replaced, for echoing back a command that was refused.
+fn redacted(text: &str) -> String {
+    match secrets::scrub(text, SecretPolicy::Redact) {
+        Scrubbed::Changed { content, .. } => content,
+        _ => text.to_string(),
     }
+}
 
-    if expires_at < now {
-        return Err(ApiError::BadRequest("code expired".into()));
+/// Refuses commands that would expose a secret, naming what was found.
+fn ensure_no_secrets(text: &str, action: &str) -> Result<(), ApiError> {
+    let findings = secrets::scan(text);
+    if findings.is_empty() {
+        return Ok(());
     }
+    Err(ApiError::BadRequest(format!(
+        "command contains secrets ({}) and cannot be {}",
+        secrets::kinds(&findings),
+        action
+    )))
+}
 
-    let token = sqlx::query_as::<_, crate::models::ApiToken>(
+fn parse_visibility(value: Option<&str>) -> Result<String, ApiError> {
+    match value.unwrap_or("PRIVATE") {
+        v @ ("PUBLIC" | "PRIVATE") => Ok(v.to_string()),
+        _ => Err(ApiError::BadRequest(
+            "visibility must be PUBLIC or PRIVATE".into(),
+        )),
+    }
+}
+
+fn command_response(
+    c: Command,
+    tags: &HashMap<Uuid, Vec<String>>,
+    forks: &HashMap<Uuid, i64>,
+) -> CommandWithTags {
+    CommandWithTags {
+        tags: tags.get(&c.id).cloned().unwrap_or_default(),
+        fork_count: forks.get(&c.id).copied().unwrap_or(0),
+        id: c.id,
+        title: c.title,
+        text: c.text,
+        description: c.description,
+        platform: c.platform,
+        visibility: c.visibility,
+        favorite: c.favorite,
+        usage_count: c.usage_count,
+        copy_count: c.copy_count,
+        run_count: c.run_count,
+        accept_count: c.accept_count,
+        created_at: c.created_at,
+        last_used_at: c.last_used_at,
+        deleted_at: c.deleted_at,
+        forked_from: c.forked_from,
+        risk_level: c.risk_level,
+    }
+}
+
+/// Whether `token` may publish `PUBLIC` commands; only restricted when
+/// `REQUIRE_VERIFIED_EMAIL` is set, and never for tokens without a user.
+async fn may_publish(state: &AppState, token: &ApiToken) -> Result<bool, ApiError> {
+    let Some(user_id) = token.user_id.filter(|_| state.require_verified_email) else {
+        return Ok(true);
+    };
+
+    let verified: Option<bool> =
+        sqlx::query_scalar("SELECT email_verified_at IS NOT NULL FROM users WHERE id = $1")
+            .bind(user_id)
+            .fetch_optional(&state.pool)
+            .await?;
+    Ok(verified.unwrap_or(false))
+}
+
+async fn ensure_may_publish(
+    state: &AppState,
+    token: &ApiToken,
+    visibility: &str,
+) -> Result<(), ApiError> {
+    if visibility == "PUBLIC" && !may_publish(state, token).await? {
+        return Err(unverified_publish());
+    }
+    Ok(())
+}
+
+fn unverified_publish() -> ApiError {
+    ApiError::Forbidden("verify your email address before publishing public commands".into())
+}
+
+/// Settings for the user behind `token`, falling back to defaults for
+/// tokens without a user or users who never saved any.
+async fn load_settings(pool: &PgPool, token: &ApiToken) -> Result<UserSettings, ApiError> {
+    let Some(user_id) = token.user_id else {
+        return Ok(UserSettings::default());
+    };
+
+    let row = sqlx::query(
         r#"
-        SELECT id, label, token, user_id, created_at
-        FROM api_tokens
-        WHERE id = $1
+        SELECT hide_dangerous_suggestions, secret_policy,
+               learned_max_age_days, learned_max_rows, learned_min_usage
+        FROM user_settings
+        WHERE user_id = $1
         "#,
     )
-    .bind(token_id)
-    .fetch_one(pool)
+    .bind(user_id)
+    .fetch_optional(pool)
     .await?;
 
-    sqlx::query("UPDATE device_codes SET consumed = TRUE WHERE code = $1")
-        .bind(&payload.code)
-        .execute(pool)
-        .await?;
+    Ok(match row {
+        Some(row) => UserSettings {
+            hide_dangerous_suggestions: row.get("hide_dangerous_suggestions"),
+            secret_policy: SecretPolicy::parse(row.get("secret_policy")).unwrap_or_default(),
+            retention: RetentionPolicy {
+                max_age_days: row.get("learned_max_age_days"),
+                max_rows: row.get("learned_max_rows"),
+                min_usage: row.get("learned_min_usage"),
+            },
+        },
+        None => UserSettings::default(),
+    })
+}
 
-    Ok(HttpResponse::Ok().json(ExchangeTokenResponse { token: token.token }))
+async fn load_learning_rules(
+    pool: &PgPool,
+    owner: Uuid,
+) -> Result<Vec<privacy::CompiledRule>, ApiError> {
+    let rules = sqlx::query_as::<_, LearningRule>(
+        "SELECT id, kind, pattern, created_at FROM learning_rules WHERE owner_token = $1 ORDER BY created_at",
+    )
+    .bind(owner)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(privacy::compile_all(&rules))
 }
 
 async fn load_tags(pool: &PgPool, ids: &[Uuid]) -> Result<HashMap<Uuid, Vec<String>>, ApiError> {
@@ -821,8 +5115,29 @@ async fn load_tags(pool: &PgPool, ids: &[Uuid]) -> Result<HashMap<Uuid, Vec<Stri
     Ok(map)
 }
 
-fn generate_code() -> String {
-    let mut rng = rand::thread_rng();
-    let digits = Uniform::new_inclusive(0u8, 9u8);
-    (0..6).map(|_| rng.sample(&digits).to_string()).collect()
+async fn load_fork_counts(pool: &PgPool, ids: &[Uuid]) -> Result<HashMap<Uuid, i64>, ApiError> {
+    if ids.is_empty() {
+        return Ok(HashMap::new());
+    }
+
+    let rows = sqlx::query(
+        r#"
+        SELECT forked_from, count(*) as forks
+        FROM commands
+        WHERE forked_from = ANY($1) AND deleted_at IS NULL
+        GROUP BY forked_from
+        "#,
+    )
+    .bind(ids)
+    .fetch_all(pool)
+    .await?;
+
+    let mut map = HashMap::new();
+    for row in rows {
+        let source: Uuid = row.try_get("forked_from").unwrap();
+        let forks: i64 = row.try_get("forks").unwrap_or(0);
+        map.insert(source, forks);
+    }
+
+    Ok(map)
 }
@@ -0,0 +1,261 @@
+use std::collections::HashMap;
+use std::sync::LazyLock;
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+/// Placeholder written in place of a secret under the `redact` policy.
+pub const REDACTED: &str = "[REDACTED]";
+
+/// Shortest token considered by the entropy check.
+const MIN_ENTROPY_LEN: usize = 24;
+/// Bits per character above which a mixed-case alphanumeric token looks random.
+const ENTROPY_THRESHOLD: f64 = 4.0;
+
+/// What to do with a learned command that contains a secret.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SecretPolicy {
+    /// Refuse to store the command.
+    Reject,
+    /// Replace each secret with `[REDACTED]`.
+    #[default]
+    Redact,
+    /// Keep the first and last characters and star out the rest.
+    Mask,
+}
+
+impl SecretPolicy {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SecretPolicy::Reject => "reject",
+            SecretPolicy::Redact => "redact",
+            SecretPolicy::Mask => "mask",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "reject" => Some(SecretPolicy::Reject),
+            "redact" => Some(SecretPolicy::Redact),
+            "mask" => Some(SecretPolicy::Mask),
+            _ => None,
+        }
+    }
+}
+
+/// A secret found in command text; offsets are byte positions in the original text.
+#[derive(Debug, Clone, Serialize)]
+pub struct SecretFinding {
+    pub kind: &'static str,
+    pub start: usize,
+    pub end: usize,
+}
+
+/// Result of running a policy over command text.
+#[derive(Debug)]
+pub enum Scrubbed {
+    Clean(String),
+    Changed {
+        content: String,
+        findings: Vec<SecretFinding>,
+    },
+    Rejected(Vec<SecretFinding>),
+}
+
+struct Pattern {
+    kind: &'static str,
+    /// The `secret` capture group (or the whole match) is what gets replaced.
+    regex: Regex,
+}
+
+fn pattern(kind: &'static str, regex: &str) -> Pattern {
+    Pattern {
+        kind,
+        regex: Regex::new(regex).expect("invalid secret pattern"),
+    }
+}
+
+static PATTERNS: LazyLock<Vec<Pattern>> = LazyLock::new(|| {
+    vec![
+        pattern(
+            "private_key",
+            r"-----BEGIN [A-Z ]*PRIVATE KEY-----[\s\S]*?(-----END [A-Z ]*PRIVATE KEY-----|$)",
+        ),
+        pattern(
+            "aws_access_key_id",
+            r"\b(?P<secret>(AKIA|ASIA)[0-9A-Z]{16})\b",
+        ),
+        pattern(
+            "github_token",
+            r"\b(?P<secret>gh[pousr]_[A-Za-z0-9]{36,}|github_pat_[A-Za-z0-9_]{22,})",
+        ),
+        pattern(
+            "slack_token",
+            r"\b(?P<secret>xox[abposr]-[A-Za-z0-9-]{10,})",
+        ),
+        pattern(
+            "api_key",
+            r"\b(?P<secret>[sr]k_(live|test)_[A-Za-z0-9]{16,}|sk-[A-Za-z0-9_-]{20,})",
+        ),
+        pattern(
+            "jwt",
+            r"\b(?P<secret>eyJ[A-Za-z0-9_-]{8,}\.eyJ[A-Za-z0-9_-]{8,}\.[A-Za-z0-9_-]{8,})",
+        ),
+        pattern(
+            "auth_header",
+            r#"(?i)\b(authorization:\s*(bearer|basic|token)|x-api-key:|api-key:|private-token:)\s*(?P<secret>[^\s'"]+)"#,
+        ),
+        pattern(
+            "url_credentials",
+            r"\b[a-zA-Z][a-zA-Z0-9+.-]*://[^\s/:@]+:(?P<secret>[^\s/@]+)@",
+        ),
+        pattern(
+            "secret_assignment",
+            r#"(?i)\b[a-z0-9_]*(secret|token|passwd|password|api_?key|access_?key|private_?key|credentials?)[a-z0-9_]*=(?P<secret>"[^"$]+"|'[^']+'|[^\s$'"][^\s;&|]*)"#,
+        ),
+        pattern(
+            "password_flag",
+            r#"(?:^|\s)--password[= ](?P<secret>[^\s$]\S*)"#,
+        ),
+        pattern(
+            "password_flag",
+            r"\b(mysql|mysqldump|mysqladmin|mariadb)\b.*?\s-p(?P<secret>[^\s-]\S*)",
+        ),
+        pattern("password_flag", r"\bsshpass\s+-p\s*(?P<secret>\S+)"),
+        pattern(
+            "password_flag",
+            r"\bdocker\s+login\b.*?\s-p\s+(?P<secret>[^\s$]\S*)",
+        ),
+        pattern(
+            "basic_auth",
+            r"\bcurl\b.*?\s(-u|--user)\s+[^\s:]+:(?P<secret>[^\s$]\S*)",
+        ),
+    ]
+});
+
+static TOKEN: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"[A-Za-z0-9+=_-]{24,}").expect("invalid token pattern"));
+
+/// Finds non-overlapping secrets in `text`, ordered by position.
+pub fn scan(text: &str) -> Vec<SecretFinding> {
+    let mut findings = Vec::new();
+
+    for pattern in PATTERNS.iter() {
+        for caps in pattern.regex.captures_iter(text) {
+            let Some(m) = caps.name("secret").or_else(|| caps.get(0)) else {
+                continue;
+            };
+            if is_scrubbed(m.as_str()) {
+                continue;
+            }
+            findings.push(SecretFinding {
+                kind: pattern.kind,
+                start: m.start(),
+                end: m.end(),
+            });
+        }
+    }
+
+    for m in TOKEN.find_iter(text) {
+        if looks_random(m.as_str()) {
+            findings.push(SecretFinding {
+                kind: "high_entropy",
+                start: m.start(),
+                end: m.end(),
+            });
+        }
+    }
+
+    // Specific patterns were pushed first, so they win over overlapping entropy hits
+    findings.sort_by_key(|f| f.start);
+    let mut kept: Vec<SecretFinding> = Vec::with_capacity(findings.len());
+    for finding in findings {
+        match kept.last_mut() {
+            Some(last) if finding.start < last.end => last.end = last.end.max(finding.end),
+            _ => kept.push(finding),
+        }
+    }
+
+    kept
+}
+
+pub fn contains_secret(text: &str) -> bool {
+    !scan(text).is_empty()
+}
+
+/// Distinct kinds of the findings, for error messages.
+pub fn kinds(findings: &[SecretFinding]) -> String {
+    let mut kinds: Vec<&str> = findings.iter().map(|f| f.kind).collect();
+    kinds.sort_unstable();
+    kinds.dedup();
+    kinds.join(", ")
+}
+
+/// Applies `policy` to `text`.
+pub fn scrub(text: &str, policy: SecretPolicy) -> Scrubbed {
+    let findings = scan(text);
+    if findings.is_empty() {
+        return Scrubbed::Clean(text.to_string());
+    }
+    if policy == SecretPolicy::Reject {
+        return Scrubbed::Rejected(findings);
+    }
+
+    let mut content = String::with_capacity(text.len());
+    let mut cursor = 0;
+    for finding in &findings {
+        content.push_str(&text[cursor..finding.start]);
+        match policy {
+            SecretPolicy::Mask => content.push_str(&mask(&text[finding.start..finding.end])),
+            _ => content.push_str(REDACTED),
+        }
+        cursor = finding.end;
+    }
+    content.push_str(&text[cursor..]);
+
+    Scrubbed::Changed { content, findings }
+}
+
+fn mask(secret: &str) -> String {
+    let chars: Vec<char> = secret.chars().collect();
+    if chars.len() < 12 {
+        return "*".repeat(chars.len().max(4));
+    }
+    let mut out: String = chars[..2].iter().collect();
+    out.push_str(&"*".repeat(chars.len() - 4));
+    out.extend(&chars[chars.len() - 2..]);
+    out
+}
+
+/// Values already redacted or masked must not be reported again.
+fn is_scrubbed(value: &str) -> bool {
+    value.contains(REDACTED) || value.contains("****")
+}
+
+/// Mixed-case alphanumeric tokens with high Shannon entropy (API keys, random passwords).
+/// Hex digests and UUIDs stay below the threshold because they lack upper case.
+fn looks_random(token: &str) -> bool {
+    if token.len() < MIN_ENTROPY_LEN
+        || !token.bytes().any(|b| b.is_ascii_uppercase())
+        || !token.bytes().any(|b| b.is_ascii_lowercase())
+        || !token.bytes().any(|b| b.is_ascii_digit())
+    {
+        return false;
+    }
+
+    let mut counts: HashMap<u8, usize> = HashMap::new();
+    for b in token.bytes() {
+        *counts.entry(b).or_default() += 1;
+    }
+    let len = token.len() as f64;
+    let entropy: f64 = counts
+        .values()
+        .map(|&c| {
+            let p = c as f64 / len;
+            -p * p.log2()
+        })
+        .sum();
+
+    entropy > ENTROPY_THRESHOLD
+}
@@ -0,0 +1,365 @@
+use std::env;
+
+use actix_web::cookie::{time, Cookie, SameSite};
+use actix_web::HttpRequest;
+use chrono::{DateTime, Duration, Utc};
+use log::warn;
+use sqlx::{PgPool, Row};
+use thiserror::Error;
+use uuid::Uuid;
+
+use crate::auth::{hash_token, random_token};
+use crate::errors::ApiError;
+use crate::models::ApiToken;
+
+pub const ACCESS_COOKIE: &str = "cmvault_session";
+pub const REFRESH_COOKIE: &str = "cmvault_refresh";
+/// Header that must echo the session's CSRF token on state-changing requests.
+pub const CSRF_HEADER: &str = "x-csrf-token";
+
+/// Lifetime of the access cookie; the UI refreshes it when a request comes back 401.
+const ACCESS_TTL_MINUTES: i64 = 15;
+/// Lifetime of each refresh token, renewed on every rotation.
+const REFRESH_TTL_DAYS: i64 = 14;
+/// Sessions end this long after login no matter how often they are refreshed.
+const SESSION_MAX_DAYS: i64 = 30;
+
+/// Cookie settings, from `SESSION_COOKIE_SECURE` (default `true`).
+#[derive(Debug, Clone, Copy)]
+pub struct SessionConfig {
+    /// Send cookies over HTTPS only; browsers also honour this on `http://localhost`.
+    pub secure: bool,
+}
+
+impl SessionConfig {
+    pub fn from_env() -> Self {
+        let secure = env::var("SESSION_COOKIE_SECURE")
+            .map(|v| !matches!(v.trim(), "false" | "0" | "no"))
+            .unwrap_or(true);
+
+        SessionConfig { secure }
+    }
+
+    fn cookie(&self, name: &'static str, value: String, path: &'static str) -> Cookie<'static> {
+        Cookie::build(name, value)
+            .path(path)
+            .http_only(true)
+            .secure(self.secure)
+            .same_site(SameSite::Strict)
+            .finish()
+    }
+
+    /// The access and refresh cookies for a freshly issued or rotated session.
+    pub fn cookies(&self, issued: &IssuedSession) -> [Cookie<'static>; 2] {
+        let mut access = self.cookie(ACCESS_COOKIE, issued.access_token.clone(), "/api");
+        access.set_max_age(time::Duration::minutes(ACCESS_TTL_MINUTES));
+
+        // Only the session endpoints ever see the refresh token
+        let mut refresh = self.cookie(REFRESH_COOKIE, issued.refresh_token.clone(), "/api/session");
+        let remaining = (issued.refresh_expires_at - Utc::now())
+            .num_seconds()
+            .max(0);
+        refresh.set_max_age(time::Duration::seconds(remaining));
+
+        [access, refresh]
+    }
+
+    /// Expired cookies that make the browser forget the session.
+    pub fn cleared(&self) -> [Cookie<'static>; 2] {
+        let mut access = self.cookie(ACCESS_COOKIE, String::new(), "/api");
+        access.make_removal();
+        let mut refresh = self.cookie(REFRESH_COOKIE, String::new(), "/api/session");
+        refresh.make_removal();
+
+        [access, refresh]
+    }
+}
+
+/// Secrets handed out when a session starts or rotates. Only their hashes are stored,
+/// except the CSRF token, which the UI reads from the response body.
+pub struct IssuedSession {
+    pub user_id: Uuid,
+    /// The API token backing the session.
+    pub token_id: Uuid,
+    pub access_token: String,
+    pub refresh_token: String,
+    pub csrf_token: String,
+    pub access_expires_at: DateTime<Utc>,
+    pub refresh_expires_at: DateTime<Utc>,
+    /// When the session ends regardless of refreshes.
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Why a refresh was refused; all but `Db` end the browser's session.
+#[derive(Debug, Error)]
+pub enum RefreshError {
+    #[error("unknown refresh token")]
+    Unknown,
+    #[error("session expired")]
+    Expired,
+    /// An already rotated token came back, so it was stolen or replayed; the whole
+    /// session has been revoked.
+    /// Carries the session's user, whose session was revoked in response.
+    #[error("refresh token reused")]
+    Reused(Uuid),
+    #[error(transparent)]
+    Db(#[from] sqlx::Error),
+}
+
+/// Starts a cookie session for `user_id`, backed by a new API token that owns its data
+/// like any login token but is never shown to the browser.
+pub async fn start(
+    pool: &PgPool,
+    user_id: Uuid,
+    label: &str,
+    user_agent: Option<&str>,
+) -> Result<IssuedSession, ApiError> {
+    let now = Utc::now();
+    let session_id = Uuid::new_v4();
+    let token_id = Uuid::new_v4();
+    let access_token = random_token();
+    let refresh_token = random_token();
+    let csrf_token = random_token();
+    let access_expires_at = now + Duration::minutes(ACCESS_TTL_MINUTES);
+    let expires_at = now + Duration::days(SESSION_MAX_DAYS);
+    let refresh_expires_at = (now + Duration::days(REFRESH_TTL_DAYS)).min(expires_at);
+
+    let mut tx = pool.begin().await?;
+    sqlx::query(
+        r#"
+        INSERT INTO api_tokens (id, label, token, user_id, created_at)
+        VALUES ($1, $2, $3, $4, now())
+        "#,
+    )
+    .bind(token_id)
+    .bind(label)
+    .bind(random_token())
+    .bind(user_id)
+    .execute(&mut *tx)
+    .await?;
+
+    sqlx::query(
+        r#"
+        INSERT INTO web_sessions
+            (id, user_id, token_id, access_token_hash, access_expires_at, csrf_token, user_agent, created_at, expires_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, now(), $8)
+        "#,
+    )
+    .bind(session_id)
+    .bind(user_id)
+    .bind(token_id)
+    .bind(hash_token(&access_token))
+    .bind(access_expires_at)
+    .bind(&csrf_token)
+    .bind(user_agent)
+    .bind(expires_at)
+    .execute(&mut *tx)
+    .await?;
+
+    insert_refresh_token(&mut tx, session_id, &refresh_token, refresh_expires_at).await?;
+    tx.commit().await?;
+
+    Ok(IssuedSession {
+        user_id,
+        token_id,
+        access_token,
+        refresh_token,
+        csrf_token,
+        access_expires_at,
+        refresh_expires_at,
+        expires_at,
+    })
+}
+
+/// Trades a refresh token for a new access and refresh token. Each refresh token works
+/// once; presenting a used one revokes the session it belongs to.
+pub async fn refresh(pool: &PgPool, refresh_token: &str) -> Result<IssuedSession, RefreshError> {
+    let mut tx = pool.begin().await?;
+
+    // Locking the token and its session serialises concurrent refreshes
+    let row = sqlx::query(
+        r#"
+        SELECT r.session_id, r.expires_at, r.used_at, s.user_id, s.token_id, s.csrf_token, s.expires_at AS session_expires_at,
+               s.revoked_at
+        FROM session_refresh_tokens r
+        JOIN web_sessions s ON s.id = r.session_id
+        WHERE r.token_hash = $1
+        FOR UPDATE
+        "#,
+    )
+    .bind(hash_token(refresh_token))
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    let Some(row) = row else {
+        return Err(RefreshError::Unknown);
+    };
+    let session_id: Uuid = row.get("session_id");
+    let now = Utc::now();
+
+    if row.get::<Option<DateTime<Utc>>, _>("revoked_at").is_some() {
+        return Err(RefreshError::Expired);
+    }
+    if row.get::<Option<DateTime<Utc>>, _>("used_at").is_some() {
+        sqlx::query(
+            "UPDATE web_sessions SET revoked_at = now(), revoked_reason = 'refresh_reuse' WHERE id = $1",
+        )
+        .bind(session_id)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        warn!("Refresh token reused; revoked session {}", session_id);
+        return Err(RefreshError::Reused(row.get("user_id")));
+    }
+    let session_expires_at: DateTime<Utc> = row.get("session_expires_at");
+    if row.get::<DateTime<Utc>, _>("expires_at") <= now || session_expires_at <= now {
+        return Err(RefreshError::Expired);
+    }
+
+    sqlx::query("UPDATE session_refresh_tokens SET used_at = now() WHERE token_hash = $1")
+        .bind(hash_token(refresh_token))
+        .execute(&mut *tx)
+        .await?;
+
+    let access_token = random_token();
+    let new_refresh_token = random_token();
+    let access_expires_at = now + Duration::minutes(ACCESS_TTL_MINUTES);
+    let refresh_expires_at = (now + Duration::days(REFRESH_TTL_DAYS)).min(session_expires_at);
+
+    sqlx::query(
+        r#"
+        UPDATE web_sessions
+        SET access_token_hash = $2, access_expires_at = $3, last_refreshed_at = now()
+        WHERE id = $1
+        "#,
+    )
+    .bind(session_id)
+    .bind(hash_token(&access_token))
+    .bind(access_expires_at)
+    .execute(&mut *tx)
+    .await?;
+
+    insert_refresh_token(&mut tx, session_id, &new_refresh_token, refresh_expires_at).await?;
+    tx.commit().await?;
+
+    Ok(IssuedSession {
+        user_id: row.get("user_id"),
+        token_id: row.get("token_id"),
+        access_token,
+        refresh_token: new_refresh_token,
+        csrf_token: row.get("csrf_token"),
+        access_expires_at,
+        refresh_expires_at,
+        expires_at: session_expires_at,
+    })
+}
+
+async fn insert_refresh_token(
+    tx: &mut sqlx::PgConnection,
+    session_id: Uuid,
+    refresh_token: &str,
+    expires_at: DateTime<Utc>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"
+        INSERT INTO session_refresh_tokens (token_hash, session_id, created_at, expires_at)
+        VALUES ($1, $2, now(), $3)
+        "#,
+    )
+    .bind(hash_token(refresh_token))
+    .bind(session_id)
+    .bind(expires_at)
+    .execute(tx)
+    .await?;
+
+    Ok(())
+}
+
+/// A live session found through the access cookie.
+pub struct ActiveSession {
+    pub csrf_token: String,
+    pub access_expires_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub token: ApiToken,
+}
+
+/// Resolves the access cookie of `req`, if any, to its session and backing API token.
+pub async fn from_cookie(
+    req: &HttpRequest,
+    pool: &PgPool,
+) -> Result<Option<ActiveSession>, ApiError> {
+    let Some(cookie) = req.cookie(ACCESS_COOKIE) else {
+        return Ok(None);
+    };
+
+    let row = sqlx::query(
+        r#"
+        SELECT s.csrf_token, s.access_expires_at, s.expires_at,
+               t.id, t.label, t.token, t.user_id, t.created_at
+        FROM web_sessions s
+        JOIN api_tokens t ON t.id = s.token_id
+        JOIN users u ON u.id = s.user_id
+        WHERE s.access_token_hash = $1
+          AND s.access_expires_at > now()
+          AND s.expires_at > now()
+          AND s.revoked_at IS NULL
+          AND t.revoked_at IS NULL
+          AND u.blocked_at IS NULL
+        "#,
+    )
+    .bind(hash_token(cookie.value()))
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(row.map(|row| ActiveSession {
+        csrf_token: row.get("csrf_token"),
+        access_expires_at: row.get("access_expires_at"),
+        expires_at: row.get("expires_at"),
+        token: ApiToken {
+            id: row.get("id"),
+            label: row.get("label"),
+            token: row.get("token"),
+            user_id: row.get("user_id"),
+            created_at: row.get("created_at"),
+        },
+    }))
+}
+
+/// Ends the session the request's cookies belong to, if any.
+pub async fn revoke_current(req: &HttpRequest, pool: &PgPool) -> Result<(), ApiError> {
+    let access = req.cookie(ACCESS_COOKIE).map(|c| hash_token(c.value()));
+    let refresh = req.cookie(REFRESH_COOKIE).map(|c| hash_token(c.value()));
+
+    sqlx::query(
+        r#"
+        UPDATE web_sessions
+        SET revoked_at = now(), revoked_reason = 'logout'
+        WHERE revoked_at IS NULL
+          AND (access_token_hash = $1
+               OR id = (SELECT session_id FROM session_refresh_tokens WHERE token_hash = $2))
+        "#,
+    )
+    .bind(access)
+    .bind(refresh)
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Ends every session of `user_id`, returning how many were live.
+pub async fn revoke_all(pool: &PgPool, user_id: Uuid, reason: &str) -> Result<u64, ApiError> {
+    let result = sqlx::query(
+        r#"
+        UPDATE web_sessions
+        SET revoked_at = now(), revoked_reason = $2
+        WHERE user_id = $1 AND revoked_at IS NULL AND expires_at > now()
+        "#,
+    )
+    .bind(user_id)
+    .bind(reason)
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected())
+}
@@ -1,6 +1,31 @@
+use std::path::PathBuf;
+
 use sqlx::PgPool;
+use uuid::Uuid;
+use webauthn_rs::Webauthn;
+
+use crate::mailer::Mailer;
+use crate::oidc::OidcClient;
+use crate::sessions::SessionConfig;
 
 #[derive(Clone)]
 pub struct AppState {
     pub pool: PgPool,
+    /// Server-side root that `POST /api/cheatsheets/import?path=` may read from.
+    pub cheatsheet_dir: Option<PathBuf>,
+    /// Set when `OIDC_ISSUER`, `OIDC_CLIENT_ID` and `OIDC_REDIRECT_URL` are configured.
+    pub oidc: Option<OidcClient>,
+    /// Set when `WEBAUTHN_RP_ORIGIN` is configured.
+    pub passkeys: Option<Webauthn>,
+    pub sessions: SessionConfig,
+    pub mailer: Mailer,
+    /// Base URL of the web UI, used for links in emails (`WEB_ORIGIN`).
+    pub web_url: String,
+    /// Only accounts with a verified email may publish `PUBLIC` commands
+    /// (`REQUIRE_VERIFIED_EMAIL`).
+    pub require_verified_email: bool,
+    /// Take the client IP from `X-Forwarded-For` when throttling (`TRUST_PROXY_HEADERS`).
+    pub trust_proxy_headers: bool,
+    /// The token seeded from `ADMIN_API_TOKEN`, allowed on `/api/admin` endpoints.
+    pub admin_token_id: Uuid,
 }
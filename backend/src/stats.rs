@@ -0,0 +1,281 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgPool};
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Bucket {
+    #[default]
+    Day,
+    Week,
+    Month,
+}
+
+impl Bucket {
+    /// The `date_trunc` field name, also valid as an interval unit.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Bucket::Day => "day",
+            Bucket::Week => "week",
+            Bucket::Month => "month",
+        }
+    }
+
+    pub fn approx_days(self) -> i64 {
+        match self {
+            Bucket::Day => 1,
+            Bucket::Week => 7,
+            Bucket::Month => 30,
+        }
+    }
+}
+
+/// The time window and size limits of one stats request.
+pub struct StatsRange {
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+    pub bucket: Bucket,
+    pub limit: i64,
+}
+
+#[derive(Debug, Serialize, FromRow)]
+pub struct Totals {
+    pub saved_commands: i64,
+    pub public_commands: i64,
+    pub learned_commands: i64,
+    pub learned_runs: i64,
+}
+
+#[derive(Debug, Serialize, FromRow)]
+pub struct TopCommand {
+    pub text: String,
+    pub runs: i64,
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize, FromRow)]
+pub struct TopProgram {
+    pub program: String,
+    pub runs: i64,
+    pub distinct_commands: i64,
+}
+
+#[derive(Debug, Serialize, FromRow)]
+pub struct UsageBucket {
+    pub bucket_start: DateTime<Utc>,
+    /// Learned entries seen for the first time.
+    pub new_learned: i64,
+    /// Executions that reported exit status or duration.
+    pub executions: i64,
+    pub copies: i64,
+    pub runs: i64,
+    pub accepts: i64,
+}
+
+#[derive(Debug, Serialize, FromRow)]
+pub struct Breakdown {
+    pub key: Option<String>,
+    pub commands: i64,
+    pub runs: i64,
+}
+
+#[derive(Debug, Serialize, FromRow)]
+pub struct PromotionCandidate {
+    pub id: Uuid,
+    pub content: String,
+    pub usage_count: i32,
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Stats {
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+    pub bucket: Bucket,
+    pub totals: Totals,
+    pub top_commands: Vec<TopCommand>,
+    pub top_programs: Vec<TopProgram>,
+    pub usage_over_time: Vec<UsageBucket>,
+    pub directories: Vec<Breakdown>,
+    pub operating_systems: Vec<Breakdown>,
+    pub promotion_candidates: Vec<PromotionCandidate>,
+}
+
+/// Learned entries count towards the window when they were last used inside it;
+/// their run counts are lifetime totals because individual runs are not stored.
+const LEARNED_IN_RANGE: &str = r#"
+    owner_token = $1
+    AND deleted_at IS NULL
+    AND COALESCE(last_used_at, created_at) >= $2
+    AND COALESCE(last_used_at, created_at) < $3
+"#;
+
+/// Gathers every section of `GET /api/stats` for one token.
+pub async fn collect(pool: &PgPool, owner: Uuid, range: &StatsRange) -> Result<Stats, sqlx::Error> {
+    let totals = sqlx::query_as::<_, Totals>(
+        r#"
+        SELECT
+            (SELECT count(*) FROM commands WHERE owner_token = $1 AND deleted_at IS NULL) AS saved_commands,
+            (SELECT count(*) FROM commands WHERE owner_token = $1 AND deleted_at IS NULL AND visibility = 'PUBLIC') AS public_commands,
+            (SELECT count(*) FROM learned_commands WHERE owner_token = $1 AND deleted_at IS NULL) AS learned_commands,
+            (SELECT COALESCE(sum(usage_count), 0)::bigint FROM learned_commands WHERE owner_token = $1 AND deleted_at IS NULL) AS learned_runs
+        "#,
+    )
+    .bind(owner)
+    .fetch_one(pool)
+    .await?;
+
+    let top_commands = sqlx::query_as::<_, TopCommand>(&format!(
+        r#"
+        SELECT content AS text, usage_count::bigint AS runs, last_used_at
+        FROM learned_commands
+        WHERE {LEARNED_IN_RANGE}
+        ORDER BY usage_count DESC, last_used_at DESC NULLS LAST
+        LIMIT $4
+        "#
+    ))
+    .bind(owner)
+    .bind(range.from)
+    .bind(range.to)
+    .bind(range.limit)
+    .fetch_all(pool)
+    .await?;
+
+    // The program is the first word after `sudo` and leading `VAR=value` assignments
+    let top_programs = sqlx::query_as::<_, TopProgram>(&format!(
+        r#"
+        SELECT program, sum(usage_count)::bigint AS runs, count(*) AS distinct_commands
+        FROM (
+            SELECT substring(content FROM '^\s*(?:sudo\s+)?(?:[A-Za-z_][A-Za-z0-9_]*=\S*\s+)*(\S+)') AS program,
+                   usage_count
+            FROM learned_commands
+            WHERE {LEARNED_IN_RANGE}
+        ) programs
+        WHERE program IS NOT NULL
+        GROUP BY program
+        ORDER BY runs DESC, program
+        LIMIT $4
+        "#
+    ))
+    .bind(owner)
+    .bind(range.from)
+    .bind(range.to)
+    .bind(range.limit)
+    .fetch_all(pool)
+    .await?;
+
+    let usage_over_time = sqlx::query_as::<_, UsageBucket>(
+        r#"
+        WITH buckets AS (
+            SELECT generate_series(
+                date_trunc($4, $2),
+                $3 - interval '1 microsecond',
+                ('1 ' || $4)::interval
+            ) AS bucket_start
+        ),
+        learned AS (
+            SELECT date_trunc($4, created_at) AS bucket_start, count(*) AS new_learned
+            FROM learned_commands
+            WHERE owner_token = $1 AND created_at >= $2 AND created_at < $3
+            GROUP BY 1
+        ),
+        executions AS (
+            SELECT date_trunc($4, e.executed_at) AS bucket_start, count(*) AS executions
+            FROM learned_executions e
+            JOIN learned_commands l ON l.id = e.learned_id
+            WHERE l.owner_token = $1 AND e.executed_at >= $2 AND e.executed_at < $3
+            GROUP BY 1
+        ),
+        events AS (
+            SELECT date_trunc($4, created_at) AS bucket_start,
+                   count(*) FILTER (WHERE source = 'copy') AS copies,
+                   count(*) FILTER (WHERE source = 'run') AS runs,
+                   count(*) FILTER (WHERE source = 'suggest_accept') AS accepts
+            FROM usage_events
+            WHERE token_id = $1 AND created_at >= $2 AND created_at < $3
+            GROUP BY 1
+        )
+        SELECT b.bucket_start,
+               COALESCE(l.new_learned, 0) AS new_learned,
+               COALESCE(x.executions, 0) AS executions,
+               COALESCE(e.copies, 0) AS copies,
+               COALESCE(e.runs, 0) AS runs,
+               COALESCE(e.accepts, 0) AS accepts
+        FROM buckets b
+        LEFT JOIN learned l ON l.bucket_start = b.bucket_start
+        LEFT JOIN executions x ON x.bucket_start = b.bucket_start
+        LEFT JOIN events e ON e.bucket_start = b.bucket_start
+        ORDER BY b.bucket_start
+        "#,
+    )
+    .bind(owner)
+    .bind(range.from)
+    .bind(range.to)
+    .bind(range.bucket.as_str())
+    .fetch_all(pool)
+    .await?;
+
+    let directories = breakdown(pool, owner, range, "pwd").await?;
+    let operating_systems = breakdown(pool, owner, range, "os").await?;
+
+    let promotion_candidates = sqlx::query_as::<_, PromotionCandidate>(&format!(
+        r#"
+        SELECT l.id, l.content, l.usage_count, l.last_used_at
+        FROM learned_commands l
+        WHERE {LEARNED_IN_RANGE}
+          AND l.promoted_at IS NULL
+          AND l.usage_count >= 3
+          AND NOT EXISTS (
+              SELECT 1 FROM commands c
+              WHERE c.owner_token = l.owner_token AND c.text = l.content AND c.deleted_at IS NULL
+          )
+        ORDER BY l.usage_count DESC, l.last_used_at DESC NULLS LAST
+        LIMIT $4
+        "#
+    ))
+    .bind(owner)
+    .bind(range.from)
+    .bind(range.to)
+    .bind(range.limit)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(Stats {
+        from: range.from,
+        to: range.to,
+        bucket: range.bucket,
+        totals,
+        top_commands,
+        top_programs,
+        usage_over_time,
+        directories,
+        operating_systems,
+        promotion_candidates,
+    })
+}
+
+/// Groups learned entries in the window by one of their context columns.
+async fn breakdown(
+    pool: &PgPool,
+    owner: Uuid,
+    range: &StatsRange,
+    column: &'static str,
+) -> Result<Vec<Breakdown>, sqlx::Error> {
+    sqlx::query_as::<_, Breakdown>(&format!(
+        r#"
+        SELECT {column} AS key, count(*) AS commands, COALESCE(sum(usage_count), 0)::bigint AS runs
+        FROM learned_commands
+        WHERE {LEARNED_IN_RANGE}
+        GROUP BY {column}
+        ORDER BY runs DESC
+        LIMIT $4
+        "#
+    ))
+    .bind(owner)
+    .bind(range.from)
+    .bind(range.to)
+    .bind(range.limit)
+    .fetch_all(pool)
+    .await
+}
@@ -0,0 +1,250 @@
+use std::time::Duration as StdDuration;
+
+use chrono::{Duration, Utc};
+use log::{error, info};
+use sqlx::{PgPool, Row};
+use uuid::Uuid;
+
+use crate::{danger, retention, retention::RetentionPolicy, throttle};
+
+const PURGE_INTERVAL: StdDuration = StdDuration::from_secs(60 * 60);
+/// How long learn idempotency keys are remembered; clients must retry within this window.
+const RECEIPT_TTL_DAYS: i64 = 7;
+
+/// Periodically hard-deletes trashed commands and learned entries older than `retention`,
+/// and forgets whatever short-lived state has run out: learn idempotency keys, OIDC login
+/// states, passkey challenges, web sessions, email links, login throttles, device
+/// authorizations, two-factor challenges and usage dedupe entries.
+pub fn spawn_cleanup(pool: PgPool, retention: Duration) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(PURGE_INTERVAL);
+        loop {
+            interval.tick().await;
+            match purge_trash(&pool, retention).await {
+                Ok((0, 0)) => {}
+                Ok((commands, learned)) => info!(
+                    "Purged {} trashed commands and {} trashed learned entries",
+                    commands, learned
+                ),
+                Err(err) => error!("Trash purge failed: {}", err),
+            }
+            if let Err(err) = expire_learn_receipts(&pool).await {
+                error!("Expiring learn receipts failed: {}", err);
+            }
+            if let Err(err) = expire_oidc_states(&pool).await {
+                error!("Expiring OIDC login states failed: {}", err);
+            }
+            if let Err(err) = expire_passkey_ceremonies(&pool).await {
+                error!("Expiring passkey challenges failed: {}", err);
+            }
+            if let Err(err) = expire_web_sessions(&pool).await {
+                error!("Expiring web sessions failed: {}", err);
+            }
+            if let Err(err) = expire_email_tokens(&pool).await {
+                error!("Expiring email tokens failed: {}", err);
+            }
+            if let Err(err) = expire_auth_throttles(&pool).await {
+                error!("Expiring login throttles failed: {}", err);
+            }
+            if let Err(err) = expire_device_authorizations(&pool).await {
+                error!("Expiring device authorizations failed: {}", err);
+            }
+            if let Err(err) = expire_login_challenges(&pool).await {
+                error!("Expiring login challenges failed: {}", err);
+            }
+            if let Err(err) = expire_usage_dedupe(&pool).await {
+                error!("Expiring usage dedupe entries failed: {}", err);
+            }
+        }
+    });
+}
+
+async fn purge_trash(pool: &PgPool, retention: Duration) -> Result<(u64, u64), sqlx::Error> {
+    let cutoff = Utc::now() - retention;
+
+    let commands =
+        sqlx::query("DELETE FROM commands WHERE deleted_at IS NOT NULL AND deleted_at < $1")
+            .bind(cutoff)
+            .execute(pool)
+            .await?;
+
+    let learned = sqlx::query(
+        "DELETE FROM learned_commands WHERE deleted_at IS NOT NULL AND deleted_at < $1",
+    )
+    .bind(cutoff)
+    .execute(pool)
+    .await?;
+
+    Ok((commands.rows_affected(), learned.rows_affected()))
+}
+
+async fn expire_learn_receipts(pool: &PgPool) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query("DELETE FROM learn_receipts WHERE created_at < $1")
+        .bind(Utc::now() - Duration::days(RECEIPT_TTL_DAYS))
+        .execute(pool)
+        .await?;
+
+    Ok(result.rows_affected())
+}
+
+async fn expire_oidc_states(pool: &PgPool) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query("DELETE FROM oidc_login_states WHERE expires_at < now()")
+        .execute(pool)
+        .await?;
+
+    Ok(result.rows_affected())
+}
+
+async fn expire_passkey_ceremonies(pool: &PgPool) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query("DELETE FROM passkey_ceremonies WHERE expires_at < now()")
+        .execute(pool)
+        .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// Revoked sessions are kept for a day so reused refresh tokens are still recognised.
+async fn expire_web_sessions(pool: &PgPool) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query(
+        "DELETE FROM web_sessions WHERE expires_at < now() OR revoked_at < now() - interval '1 day'",
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+async fn expire_email_tokens(pool: &PgPool) -> Result<u64, sqlx::Error> {
+    let result =
+        sqlx::query("DELETE FROM email_tokens WHERE expires_at < now() OR used_at IS NOT NULL")
+            .execute(pool)
+            .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// Drops counters whose failures have aged out and that no longer lock anyone out.
+async fn expire_auth_throttles(pool: &PgPool) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query(
+        r#"
+        DELETE FROM auth_throttles
+        WHERE last_failure_at < now() - make_interval(secs => $1)
+          AND (locked_until IS NULL OR locked_until < now())
+        "#,
+    )
+    .bind(throttle::FAILURE_WINDOW_SECONDS as f64)
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// Finished requests are kept a day so a late poll still gets a meaningful error.
+async fn expire_device_authorizations(pool: &PgPool) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query(
+        "DELETE FROM device_authorizations WHERE expires_at < now() - interval '1 day'",
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+async fn expire_login_challenges(pool: &PgPool) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query("DELETE FROM login_challenges WHERE expires_at < now()")
+        .execute(pool)
+        .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// Entries only matter for a few minutes; a day keeps the table small.
+async fn expire_usage_dedupe(pool: &PgPool) -> Result<u64, sqlx::Error> {
+    let result =
+        sqlx::query("DELETE FROM usage_dedupe WHERE counted_at < now() - interval '1 day'")
+            .execute(pool)
+            .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// Periodically moves learned entries outside each user's retention policy to the trash.
+pub fn spawn_retention_prune(pool: PgPool) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(PURGE_INTERVAL);
+        loop {
+            interval.tick().await;
+            match prune_learned(&pool).await {
+                Ok(0) => {}
+                Ok(count) => info!("Retention pruned {} learned entries", count),
+                Err(err) => error!("Retention prune failed: {}", err),
+            }
+        }
+    });
+}
+
+async fn prune_learned(pool: &PgPool) -> Result<u64, sqlx::Error> {
+    let users = sqlx::query(
+        r#"
+        SELECT user_id, learned_max_age_days, learned_max_rows, learned_min_usage
+        FROM user_settings
+        WHERE learned_max_age_days IS NOT NULL OR learned_max_rows IS NOT NULL
+        "#,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut pruned = 0;
+    for row in users {
+        let policy = RetentionPolicy {
+            max_age_days: row.get("learned_max_age_days"),
+            max_rows: row.get("learned_max_rows"),
+            min_usage: row.get("learned_min_usage"),
+        };
+        pruned += retention::prune(pool, row.get("user_id"), &policy).await?;
+    }
+
+    Ok(pruned)
+}
+
+/// Classifies rows stored before risk levels existed (or by older server versions).
+pub fn spawn_risk_backfill(pool: PgPool) {
+    tokio::spawn(async move {
+        match backfill_risk_levels(&pool).await {
+            Ok(0) => {}
+            Ok(count) => info!("Classified risk level for {} stored commands", count),
+            Err(err) => error!("Risk level backfill failed: {}", err),
+        }
+    });
+}
+
+async fn backfill_risk_levels(pool: &PgPool) -> Result<u64, sqlx::Error> {
+    let mut classified = 0;
+
+    for (table, column) in [("commands", "text"), ("learned_commands", "content")] {
+        loop {
+            let rows = sqlx::query(&format!(
+                "SELECT id, {column} AS text FROM {table} WHERE risk_level IS NULL LIMIT 500"
+            ))
+            .fetch_all(pool)
+            .await?;
+
+            if rows.is_empty() {
+                break;
+            }
+
+            for row in rows {
+                let id: Uuid = row.get("id");
+                let text: String = row.get("text");
+                sqlx::query(&format!("UPDATE {table} SET risk_level = $1 WHERE id = $2"))
+                    .bind(danger::classify(&text).level.as_str())
+                    .bind(id)
+                    .execute(pool)
+                    .await?;
+                classified += 1;
+            }
+        }
+    }
+
+    Ok(classified)
+}
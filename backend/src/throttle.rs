@@ -0,0 +1,139 @@
+use actix_web::HttpRequest;
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+
+use crate::errors::ApiError;
+
+/// Failures older than this are forgotten and the count starts over.
+pub const FAILURE_WINDOW_SECONDS: i64 = 60 * 60;
+/// The longest a subject is locked out; backoff doubles up to this.
+pub const MAX_LOCKOUT_SECONDS: i64 = 15 * 60;
+
+/// What is being protected, and whose attempts are counted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    /// Password logins from one client IP, across all accounts.
+    LoginIp,
+    /// Password logins for one email address, from anywhere.
+    LoginAccount,
+    /// Device code exchanges from one client IP.
+    DeviceCodeIp,
+    /// Two-factor codes for one account, at login or when changing 2FA settings.
+    SecondFactor,
+    /// Usage events sent without a token from one client IP; every event counts.
+    UsageIp,
+}
+
+impl Scope {
+    fn as_str(self) -> &'static str {
+        match self {
+            Scope::LoginIp => "login_ip",
+            Scope::LoginAccount => "login_account",
+            Scope::DeviceCodeIp => "device_code_ip",
+            Scope::SecondFactor => "second_factor",
+            Scope::UsageIp => "usage_ip",
+        }
+    }
+
+    /// Failures allowed before backoff starts. IPs get more room since many users
+    /// can share one behind NAT.
+    fn free_attempts(self) -> i32 {
+        match self {
+            Scope::LoginIp => 20,
+            Scope::LoginAccount => 5,
+            Scope::DeviceCodeIp => 5,
+            Scope::SecondFactor => 5,
+            Scope::UsageIp => 120,
+        }
+    }
+}
+
+/// The client address attempts are counted against. `X-Forwarded-For` is only
+/// honoured when the server runs behind a trusted proxy, since clients can set it freely.
+pub fn client_ip(req: &HttpRequest, trust_proxy_headers: bool) -> String {
+    if trust_proxy_headers {
+        if let Some(ip) = req.connection_info().realip_remote_addr() {
+            return ip.to_string();
+        }
+    }
+    req.peer_addr()
+        .map(|addr| addr.ip().to_string())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+/// Refuses the attempt while `subject` is backing off.
+pub async fn check(pool: &PgPool, scope: Scope, subject: &str) -> Result<(), ApiError> {
+    let locked_until: Option<DateTime<Utc>> = sqlx::query_scalar(
+        "SELECT locked_until FROM auth_throttles WHERE scope = $1 AND subject = $2 AND locked_until > now()",
+    )
+    .bind(scope.as_str())
+    .bind(subject)
+    .fetch_optional(pool)
+    .await?
+    .flatten();
+
+    match locked_until {
+        Some(until) => Err(ApiError::TooManyRequests(retry_after(until))),
+        None => Ok(()),
+    }
+}
+
+/// Counts a failed attempt, locking `subject` for 1s, 2s, 4s, ... once its free
+/// attempts are used up.
+pub async fn record_failure(pool: &PgPool, scope: Scope, subject: &str) -> Result<(), ApiError> {
+    let failures: i32 = sqlx::query_scalar(
+        r#"
+        INSERT INTO auth_throttles (scope, subject, failures, last_failure_at)
+        VALUES ($1, $2, 1, now())
+        ON CONFLICT (scope, subject) DO UPDATE
+        SET failures = CASE
+                WHEN auth_throttles.last_failure_at < now() - make_interval(secs => $3) THEN 1
+                ELSE auth_throttles.failures + 1
+            END,
+            last_failure_at = now()
+        RETURNING failures
+        "#,
+    )
+    .bind(scope.as_str())
+    .bind(subject)
+    .bind(FAILURE_WINDOW_SECONDS as f64)
+    .fetch_one(pool)
+    .await?;
+
+    let over = failures - scope.free_attempts();
+    if over > 0 {
+        let delay = 1i64
+            .checked_shl((over - 1) as u32)
+            .unwrap_or(MAX_LOCKOUT_SECONDS)
+            .min(MAX_LOCKOUT_SECONDS);
+        sqlx::query(
+            r#"
+            UPDATE auth_throttles
+            SET locked_until = now() + make_interval(secs => $3)
+            WHERE scope = $1 AND subject = $2
+            "#,
+        )
+        .bind(scope.as_str())
+        .bind(subject)
+        .bind(delay as f64)
+        .execute(pool)
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// Forgets the failures of `subject` after a successful attempt.
+pub async fn reset(pool: &PgPool, scope: Scope, subject: &str) -> Result<(), ApiError> {
+    sqlx::query("DELETE FROM auth_throttles WHERE scope = $1 AND subject = $2")
+        .bind(scope.as_str())
+        .bind(subject)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+fn retry_after(until: DateTime<Utc>) -> u64 {
+    let millis = (until - Utc::now()).num_milliseconds().max(0) as u64;
+    millis.div_ceil(1000).max(1)
+}
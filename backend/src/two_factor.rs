@@ -0,0 +1,256 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use chrono::{Duration, Utc};
+use rand::{distributions::Uniform, Rng};
+use sqlx::{PgPool, Row};
+use totp_rs::{Algorithm, Secret, TOTP};
+use uuid::Uuid;
+
+use crate::auth::{hash_token, random_token};
+use crate::errors::ApiError;
+
+/// Login methods that ask for the second factor. Passkeys are not challenged: they
+/// already prove possession of a device and verify the user on it.
+pub const CHALLENGED_LOGINS: &[&str] = &["password", "oidc"];
+
+/// How long a password or OIDC login may wait for its second factor.
+pub const CHALLENGE_TTL_MINUTES: i64 = 5;
+/// Wrong codes accepted per challenge before the login must be started again.
+pub const MAX_CHALLENGE_ATTEMPTS: i32 = 5;
+pub const RECOVERY_CODE_COUNT: usize = 10;
+
+const ISSUER: &str = "CMVault";
+const STEP_SECONDS: u64 = 30;
+
+/// A fresh 160-bit secret, base32 encoded.
+pub fn new_secret() -> String {
+    match Secret::generate_secret().to_encoded() {
+        Secret::Encoded(secret) => secret,
+        Secret::Raw(_) => unreachable!("to_encoded always returns an encoded secret"),
+    }
+}
+
+fn totp(secret: &str, email: &str) -> Result<TOTP, ApiError> {
+    let bytes = Secret::Encoded(secret.to_string())
+        .to_bytes()
+        .map_err(|_| ApiError::Internal)?;
+    // Authenticator apps show issuer and account; ':' is not allowed in either
+    TOTP::new(
+        Algorithm::SHA1,
+        6,
+        0,
+        STEP_SECONDS,
+        bytes,
+        Some(ISSUER.to_string()),
+        email.replace(':', ""),
+    )
+    .map_err(|_| ApiError::Internal)
+}
+
+/// The `otpauth://` URI authenticator apps import, usually as a QR code.
+pub fn otpauth_uri(secret: &str, email: &str) -> Result<String, ApiError> {
+    Ok(totp(secret, email)?.get_url())
+}
+
+/// The time step `code` is valid for, allowing one step of clock drift either way.
+/// Steps at or before `last_used_step` are rejected so a code works only once.
+pub fn verify_code(
+    secret: &str,
+    email: &str,
+    code: &str,
+    last_used_step: Option<i64>,
+) -> Result<Option<i64>, ApiError> {
+    let totp = totp(secret, email)?;
+    let code: String = code.chars().filter(|c| !c.is_whitespace()).collect();
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|_| ApiError::Internal)?
+        .as_secs();
+
+    let current = now / STEP_SECONDS;
+    let matched = [current - 1, current, current + 1]
+        .into_iter()
+        .find(|step| totp.check(&code, step * STEP_SECONDS))
+        .map(|step| step as i64);
+
+    Ok(matched.filter(|step| last_used_step.is_none_or(|last| *step > last)))
+}
+
+/// Ten codes like `k7q2m-x9d4p`, shown to the user once.
+pub fn generate_recovery_codes() -> Vec<String> {
+    const ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+    let mut rng = rand::thread_rng();
+    let index = Uniform::new(0, ALPHABET.len());
+
+    (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let chars: String = (0..10)
+                .map(|_| ALPHABET[rng.sample(index)] as char)
+                .collect();
+            format!("{}-{}", &chars[..5], &chars[5..])
+        })
+        .collect()
+}
+
+/// Recovery codes are compared case-insensitively and without the dash.
+pub fn recovery_code_hash(code: &str) -> String {
+    let normalized: String = code
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    hash_token(&normalized)
+}
+
+/// Replaces all recovery codes of `user_id`, returning the new ones in plain text.
+pub async fn replace_recovery_codes(
+    tx: &mut sqlx::PgConnection,
+    user_id: Uuid,
+) -> Result<Vec<String>, ApiError> {
+    sqlx::query("DELETE FROM user_recovery_codes WHERE user_id = $1")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+
+    let codes = generate_recovery_codes();
+    let hashes: Vec<String> = codes.iter().map(|c| recovery_code_hash(c)).collect();
+    sqlx::query(
+        r#"
+        INSERT INTO user_recovery_codes (code_hash, user_id, created_at)
+        SELECT hash, $2, now() FROM UNNEST($1::text[]) AS hash
+        "#,
+    )
+    .bind(&hashes)
+    .bind(user_id)
+    .execute(&mut *tx)
+    .await?;
+
+    Ok(codes)
+}
+
+/// Checks a second factor for an account with TOTP enabled: a current authenticator
+/// code, or an unused recovery code, which is used up.
+pub async fn verify_second_factor(
+    pool: &PgPool,
+    user_id: Uuid,
+    code: &str,
+) -> Result<bool, ApiError> {
+    let row = sqlx::query(
+        r#"
+        SELECT t.secret, t.last_used_step, u.email
+        FROM user_totp t
+        JOIN users u ON u.id = t.user_id
+        WHERE t.user_id = $1 AND t.enabled_at IS NOT NULL
+        "#,
+    )
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await?
+    .ok_or_else(|| ApiError::BadRequest("two-factor authentication is not enabled".into()))?;
+
+    let secret: String = row.get("secret");
+    let email: String = row.get("email");
+    if let Some(step) = verify_code(&secret, &email, code, row.get("last_used_step"))? {
+        // Guarded so two concurrent logins cannot both use the same code
+        let result = sqlx::query(
+            r#"
+            UPDATE user_totp SET last_used_step = $2
+            WHERE user_id = $1 AND (last_used_step IS NULL OR last_used_step < $2)
+            "#,
+        )
+        .bind(user_id)
+        .bind(step)
+        .execute(pool)
+        .await?;
+        return Ok(result.rows_affected() == 1);
+    }
+
+    let used = sqlx::query(
+        r#"
+        UPDATE user_recovery_codes SET used_at = now()
+        WHERE user_id = $1 AND code_hash = $2 AND used_at IS NULL
+        "#,
+    )
+    .bind(user_id)
+    .bind(recovery_code_hash(code))
+    .execute(pool)
+    .await?;
+    Ok(used.rows_affected() == 1)
+}
+
+pub async fn is_enabled(pool: &PgPool, user_id: Uuid) -> Result<bool, ApiError> {
+    let enabled: bool = sqlx::query_scalar(
+        "SELECT EXISTS (SELECT 1 FROM user_totp WHERE user_id = $1 AND enabled_at IS NOT NULL)",
+    )
+    .bind(user_id)
+    .fetch_one(pool)
+    .await?;
+    Ok(enabled)
+}
+
+/// A login waiting for its second factor.
+pub struct LoginChallenge {
+    pub user_id: Uuid,
+    pub label: String,
+    pub session: bool,
+    /// One of `CHALLENGED_LOGINS`.
+    pub method: String,
+}
+
+/// Remembers a login whose first factor checked out; the returned token identifies it.
+pub async fn start_challenge(
+    pool: &PgPool,
+    challenge: &LoginChallenge,
+) -> Result<(String, chrono::DateTime<Utc>), ApiError> {
+    let token = random_token();
+    let expires_at = Utc::now() + Duration::minutes(CHALLENGE_TTL_MINUTES);
+
+    sqlx::query(
+        r#"
+        INSERT INTO login_challenges (token_hash, user_id, label, session, method, created_at, expires_at)
+        VALUES ($1, $2, $3, $4, $5, now(), $6)
+        "#,
+    )
+    .bind(hash_token(&token))
+    .bind(challenge.user_id)
+    .bind(&challenge.label)
+    .bind(challenge.session)
+    .bind(&challenge.method)
+    .bind(expires_at)
+    .execute(pool)
+    .await?;
+
+    Ok((token, expires_at))
+}
+
+/// Counts an attempt against a live challenge. The challenge is removed once its
+/// attempts run out; call `finish_challenge` when the code was right.
+pub async fn attempt_challenge(pool: &PgPool, token: &str) -> Result<LoginChallenge, ApiError> {
+    let row = sqlx::query(
+        r#"
+        UPDATE login_challenges SET attempts = attempts + 1
+        WHERE token_hash = $1 AND expires_at > now() AND attempts < $2
+        RETURNING user_id, label, session, method
+        "#,
+    )
+    .bind(hash_token(token))
+    .bind(MAX_CHALLENGE_ATTEMPTS)
+    .fetch_optional(pool)
+    .await?
+    .ok_or(ApiError::Unauthorized)?;
+
+    Ok(LoginChallenge {
+        user_id: row.get("user_id"),
+        label: row.get("label"),
+        session: row.get("session"),
+        method: row.get("method"),
+    })
+}
+
+pub async fn finish_challenge(pool: &PgPool, token: &str) -> Result<(), ApiError> {
+    sqlx::query("DELETE FROM login_challenges WHERE token_hash = $1")
+        .bind(hash_token(token))
+        .execute(pool)
+        .await?;
+    Ok(())
+}
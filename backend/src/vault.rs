@@ -0,0 +1,273 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Bumped whenever the document layout changes incompatibly.
+pub const VAULT_FORMAT_VERSION: u32 = 1;
+
+const UNTITLED: &str = "Untitled command";
+
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum VaultFormat {
+    Json,
+    Yaml,
+    Markdown,
+}
+
+impl VaultFormat {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            VaultFormat::Json => "application/json",
+            VaultFormat::Yaml => "application/yaml",
+            VaultFormat::Markdown => "text/markdown; charset=utf-8",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            VaultFormat::Json => "json",
+            VaultFormat::Yaml => "yaml",
+            VaultFormat::Markdown => "md",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ImportStrategy {
+    /// Keep the existing command when one with the same text exists.
+    #[default]
+    Skip,
+    /// Replace the existing command's metadata and tags.
+    Overwrite,
+    /// Always create a new command.
+    Duplicate,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VaultDocument {
+    pub version: u32,
+    #[serde(default)]
+    pub exported_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub commands: Vec<VaultCommand>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub learned: Option<Vec<VaultLearned>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VaultCommand {
+    #[serde(default)]
+    pub title: Option<String>,
+    pub text: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    pub platform: String,
+    #[serde(default)]
+    pub visibility: Option<String>,
+    #[serde(default)]
+    pub favorite: Option<bool>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub created_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VaultLearned {
+    pub content: String,
+    #[serde(default)]
+    pub os: Option<String>,
+    #[serde(default)]
+    pub pwd: Option<String>,
+    #[serde(default = "default_usage")]
+    pub usage_count: i32,
+    #[serde(default)]
+    pub created_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+fn default_usage() -> i32 {
+    1
+}
+
+pub fn render(doc: &VaultDocument, format: VaultFormat) -> Result<String, String> {
+    match format {
+        VaultFormat::Json => serde_json::to_string_pretty(doc).map_err(|e| e.to_string()),
+        VaultFormat::Yaml => serde_yaml::to_string(doc).map_err(|e| e.to_string()),
+        VaultFormat::Markdown => Ok(render_markdown(doc)),
+    }
+}
+
+pub fn parse(raw: &[u8], format: VaultFormat) -> Result<VaultDocument, String> {
+    let doc = match format {
+        VaultFormat::Json => {
+            serde_json::from_slice::<VaultDocument>(raw).map_err(|e| e.to_string())?
+        }
+        VaultFormat::Yaml => {
+            serde_yaml::from_slice::<VaultDocument>(raw).map_err(|e| e.to_string())?
+        }
+        VaultFormat::Markdown => parse_markdown(&String::from_utf8_lossy(raw)),
+    };
+
+    if doc.version > VAULT_FORMAT_VERSION {
+        return Err(format!(
+            "unsupported vault version {} (newest supported is {})",
+            doc.version, VAULT_FORMAT_VERSION
+        ));
+    }
+
+    Ok(doc)
+}
+
+/// Renders a cheatsheet that `parse_markdown` can read back.
+fn render_markdown(doc: &VaultDocument) -> String {
+    let mut out = String::from("# CMVault cheatsheet\n\n");
+    out.push_str(&format!("<!-- cmvault-version: {} -->\n", doc.version));
+    if let Some(at) = doc.exported_at {
+        out.push_str(&format!("_Exported {}_\n", at.to_rfc3339()));
+    }
+
+    for command in &doc.commands {
+        out.push_str(&format!(
+            "\n## {}\n\n",
+            command.title.as_deref().unwrap_or(UNTITLED)
+        ));
+        if let Some(description) = &command.description {
+            out.push_str(description.trim());
+            out.push_str("\n\n");
+        }
+        out.push_str(&format!("- Platform: {}\n", command.platform));
+        if let Some(visibility) = &command.visibility {
+            out.push_str(&format!("- Visibility: {}\n", visibility));
+        }
+        if command.favorite == Some(true) {
+            out.push_str("- Favorite: yes\n");
+        }
+        if !command.tags.is_empty() {
+            let tags: Vec<String> = command.tags.iter().map(|t| format!("`{t}`")).collect();
+            out.push_str(&format!("- Tags: {}\n", tags.join(", ")));
+        }
+        let fence = fence_for(&command.text);
+        out.push_str(&format!("\n{fence}sh\n{}\n{fence}\n", command.text));
+    }
+
+    if let Some(learned) = &doc.learned {
+        out.push_str("\n# Learned history\n\n| Runs | Command |\n| ---: | --- |\n");
+        for entry in learned {
+            let content = entry.content.replace('|', "\\|").replace('\n', "<br>");
+            out.push_str(&format!("| {} | `{}` |\n", entry.usage_count, content));
+        }
+    }
+
+    out
+}
+
+/// Picks a backtick fence longer than any run of backticks inside the command.
+fn fence_for(text: &str) -> String {
+    let mut longest = 0;
+    let mut run = 0;
+    for c in text.chars() {
+        if c == '`' {
+            run += 1;
+            longest = longest.max(run);
+        } else {
+            run = 0;
+        }
+    }
+    "`".repeat(longest.max(2) + 1)
+}
+
+/// Reads the command sections of a cheatsheet produced by `render_markdown`.
+/// The learned history table is informational only and is not imported.
+fn parse_markdown(input: &str) -> VaultDocument {
+    let mut version = VAULT_FORMAT_VERSION;
+    let mut commands = Vec::new();
+    let mut current: Option<VaultCommand> = None;
+    let mut description: Vec<&str> = Vec::new();
+    let mut lines = input.lines();
+
+    while let Some(line) = lines.next() {
+        if let Some(heading) = line.strip_prefix("## ") {
+            if let Some(done) = current.take() {
+                commands.push(done);
+            }
+            description.clear();
+            let heading = heading.trim();
+            current = Some(VaultCommand {
+                title: (heading != UNTITLED).then(|| heading.to_string()),
+                text: String::new(),
+                description: None,
+                platform: String::new(),
+                visibility: None,
+                favorite: None,
+                tags: Vec::new(),
+                created_at: None,
+            });
+            continue;
+        }
+
+        if let Some(marker) = line.strip_prefix("<!-- cmvault-version:") {
+            if let Ok(v) = marker.trim_end_matches("-->").trim().parse() {
+                version = v;
+            }
+            continue;
+        }
+
+        if line.starts_with("# ") {
+            if let Some(done) = current.take() {
+                commands.push(done);
+            }
+            continue;
+        }
+
+        let Some(command) = current.as_mut() else {
+            continue;
+        };
+
+        if let Some(platform) = line.strip_prefix("- Platform: ") {
+            command.platform = platform.trim().to_string();
+        } else if let Some(visibility) = line.strip_prefix("- Visibility: ") {
+            command.visibility = Some(visibility.trim().to_string());
+        } else if let Some(favorite) = line.strip_prefix("- Favorite: ") {
+            command.favorite = Some(favorite.trim().eq_ignore_ascii_case("yes"));
+        } else if let Some(tags) = line.strip_prefix("- Tags: ") {
+            command.tags = tags
+                .split(',')
+                .map(|t| t.trim().trim_matches('`').to_string())
+                .filter(|t| !t.is_empty())
+                .collect();
+        } else if line.starts_with("```") {
+            let fence: String = line.chars().take_while(|c| *c == '`').collect();
+            let mut body: Vec<&str> = Vec::new();
+            for inner in lines.by_ref() {
+                if inner.trim_end() == fence {
+                    break;
+                }
+                body.push(inner);
+            }
+            command.text = body.join("\n");
+            if !description.is_empty() {
+                command.description = Some(description.join("\n").trim().to_string());
+                description.clear();
+            }
+        } else if command.text.is_empty() && !line.trim().is_empty() {
+            description.push(line);
+        }
+    }
+
+    if let Some(done) = current {
+        commands.push(done);
+    }
+
+    VaultDocument {
+        version,
+        exported_at: None,
+        tags: Vec::new(),
+        commands,
+        learned: None,
+    }
+}
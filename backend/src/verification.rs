@@ -0,0 +1,131 @@
+use chrono::{Duration, Utc};
+use sqlx::{PgExecutor, PgPool, Row};
+use uuid::Uuid;
+
+use crate::auth::{hash_token, random_token};
+use crate::errors::ApiError;
+use crate::mailer::Email;
+
+/// How long a verification link stays valid.
+pub const VERIFY_TTL_HOURS: i64 = 48;
+/// How long a password reset link stays valid.
+pub const RESET_TTL_MINUTES: i64 = 60;
+
+/// What an emailed token proves when it is redeemed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenPurpose {
+    VerifyEmail,
+    PasswordReset,
+}
+
+impl TokenPurpose {
+    fn as_str(self) -> &'static str {
+        match self {
+            TokenPurpose::VerifyEmail => "verify_email",
+            TokenPurpose::PasswordReset => "password_reset",
+        }
+    }
+
+    fn ttl(self) -> Duration {
+        match self {
+            TokenPurpose::VerifyEmail => Duration::hours(VERIFY_TTL_HOURS),
+            TokenPurpose::PasswordReset => Duration::minutes(RESET_TTL_MINUTES),
+        }
+    }
+}
+
+/// Lowercases and checks an address before it is stored on an account.
+pub fn normalize_email(raw: &str) -> Result<String, ApiError> {
+    let email = raw.trim().to_lowercase();
+    match email.parse::<lettre::Address>() {
+        Ok(address) if address.domain().contains('.') || address.domain() == "localhost" => {
+            Ok(email)
+        }
+        _ => Err(ApiError::BadRequest(
+            "a valid email address is required".into(),
+        )),
+    }
+}
+
+/// Creates a token for `user_id`, replacing any unused one with the same purpose so
+/// only the most recent link works.
+pub async fn issue(
+    pool: &PgPool,
+    user_id: Uuid,
+    email: &str,
+    purpose: TokenPurpose,
+) -> Result<String, ApiError> {
+    let token = random_token();
+    let mut tx = pool.begin().await?;
+
+    sqlx::query("DELETE FROM email_tokens WHERE user_id = $1 AND purpose = $2 AND used_at IS NULL")
+        .bind(user_id)
+        .bind(purpose.as_str())
+        .execute(&mut *tx)
+        .await?;
+
+    sqlx::query(
+        r#"
+        INSERT INTO email_tokens (token_hash, user_id, purpose, email, created_at, expires_at)
+        VALUES ($1, $2, $3, $4, now(), $5)
+        "#,
+    )
+    .bind(hash_token(&token))
+    .bind(user_id)
+    .bind(purpose.as_str())
+    .bind(email)
+    .bind(Utc::now() + purpose.ttl())
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+    Ok(token)
+}
+
+/// Marks a token used and returns the user and address it was issued for.
+pub async fn consume<'e>(
+    executor: impl PgExecutor<'e>,
+    token: &str,
+    purpose: TokenPurpose,
+) -> Result<(Uuid, String), ApiError> {
+    let row = sqlx::query(
+        r#"
+        UPDATE email_tokens
+        SET used_at = now()
+        WHERE token_hash = $1 AND purpose = $2 AND used_at IS NULL AND expires_at > now()
+        RETURNING user_id, email
+        "#,
+    )
+    .bind(hash_token(token.trim()))
+    .bind(purpose.as_str())
+    .fetch_optional(executor)
+    .await?
+    .ok_or_else(|| ApiError::BadRequest("invalid or expired token".into()))?;
+
+    Ok((row.get("user_id"), row.get("email")))
+}
+
+pub fn verification_email(web_url: &str, to: &str, token: &str) -> Email {
+    Email {
+        to: to.to_string(),
+        subject: "Verify your CMVault email address".to_string(),
+        body: format!(
+            "Confirm that this address belongs to your CMVault account:\n\n\
+             {web_url}/auth/verify?token={token}\n\n\
+             The link expires in {VERIFY_TTL_HOURS} hours. If you did not sign up, ignore this email.\n"
+        ),
+    }
+}
+
+pub fn password_reset_email(web_url: &str, to: &str, token: &str) -> Email {
+    Email {
+        to: to.to_string(),
+        subject: "Reset your CMVault password".to_string(),
+        body: format!(
+            "Someone asked to reset the password of your CMVault account. Choose a new one here:\n\n\
+             {web_url}/auth/reset?token={token}\n\n\
+             The link expires in {RESET_TTL_MINUTES} minutes and signs out all your sessions and tokens. \
+             If you did not ask for this, ignore this email.\n"
+        ),
+    }
+}
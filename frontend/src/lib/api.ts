@@ -3,48 +3,77 @@ import type { Command, LearnedCommand, Page, Visibility } from './types';
 const API_BASE = import.meta.env.VITE_API_URL ?? 'http://localhost:8080';
 const API_TOKEN = import.meta.env.VITE_API_TOKEN ?? '';
 
-let tokenCache: string | null = null;
-
-function loadToken(): string | null {
-  if (tokenCache) return tokenCache;
-  if (typeof localStorage !== 'undefined') {
-    const stored = localStorage.getItem('cmvault_token');
-    if (stored) {
-      tokenCache = stored;
-      return stored;
-    }
-  }
-  return null;
+export interface SessionInfo {
+  user_id: string;
+  csrf_token: string;
+  access_expires_at: string;
+  expires_at: string;
 }
 
-export function setAuthToken(token: string | null) {
-  tokenCache = token;
-  if (typeof localStorage !== 'undefined') {
-    if (token) {
-      localStorage.setItem('cmvault_token', token);
-    } else {
-      localStorage.removeItem('cmvault_token');
-    }
+// The session itself lives in HTTP-only cookies the page cannot read; only the CSRF
+// token is kept, in memory, and recovered from `/api/session` after a reload.
+let session: SessionInfo | null = null;
+let refreshing: Promise<boolean> | null = null;
+
+function applySession(info: SessionInfo | null) {
+  session = info;
+}
+
+export function isAuthenticated(): boolean {
+  return session !== null || Boolean(API_TOKEN);
+}
+
+/** Resumes the cookie session after a page load, refreshing it if the access cookie expired. */
+export async function restoreSession(): Promise<boolean> {
+  if (isAuthenticated()) return true;
+  try {
+    applySession(await request<SessionInfo>('/api/session', {}, false));
+    return true;
+  } catch {
+    return refreshSession();
   }
 }
 
-export function getAuthToken(): string | null {
-  return loadToken();
+/** Rotates the refresh cookie; concurrent callers share one request so the token is used once. */
+export function refreshSession(): Promise<boolean> {
+  if (!refreshing) {
+    refreshing = request<SessionInfo>('/api/session/refresh', { method: 'POST' }, false)
+      .then((info) => {
+        applySession(info);
+        return true;
+      })
+      .catch(() => {
+        applySession(null);
+        return false;
+      })
+      .finally(() => {
+        refreshing = null;
+      });
+  }
+  return refreshing;
 }
 
-async function request<T>(path: string, init: RequestInit = {}): Promise<T> {
-  const activeToken = getAuthToken() ?? API_TOKEN;
+async function request<T>(path: string, init: RequestInit = {}, retry = true): Promise<T> {
+  const method = (init.method ?? 'GET').toUpperCase();
+  const bearer = session ? null : API_TOKEN;
   const headers: HeadersInit = {
     'Content-Type': 'application/json',
-    ...(activeToken ? { Authorization: `Bearer ${activeToken}` } : {}),
+    ...(bearer ? { Authorization: `Bearer ${bearer}` } : {}),
+    ...(session && method !== 'GET' ? { 'X-CSRF-Token': session.csrf_token } : {}),
     ...init.headers
   };
 
   const res = await fetch(`${API_BASE}${path}`, {
     ...init,
-    headers
+    headers,
+    credentials: 'include'
   });
 
+  // The access cookie is short-lived; refresh once and replay the request
+  if (res.status === 401 && retry && session && (await refreshSession())) {
+    return request<T>(path, init, false);
+  }
+
   if (!res.ok) {
     let message = res.statusText;
     try {
@@ -144,21 +173,197 @@ export async function createDeviceCode(): Promise<DeviceCodeResponse> {
   return request<DeviceCodeResponse>('/api/device-codes', { method: 'POST' });
 }
 
-export interface AuthResponse {
-  token: string;
+export async function register(payload: { email: string; password: string; label?: string }): Promise<SessionInfo> {
+  const info = await request<SessionInfo>('/api/register', {
+    method: 'POST',
+    body: JSON.stringify({ ...payload, session: true })
+  });
+  applySession(info);
+  return info;
+}
+
+export interface TwoFactorChallenge {
+  two_factor_required: true;
+  challenge: string;
+  expires_at: string;
+}
+
+/** Resolves to a challenge instead of a session when the account has two-factor enabled. */
+export async function login(payload: { email: string; password: string; label?: string }): Promise<SessionInfo | TwoFactorChallenge> {
+  const info = await request<SessionInfo | TwoFactorChallenge>('/api/login', {
+    method: 'POST',
+    body: JSON.stringify({ ...payload, session: true })
+  });
+  if (!('two_factor_required' in info)) {
+    applySession(info);
+  }
+  return info;
+}
+
+export async function loginSecondFactor(challenge: string, code: string): Promise<SessionInfo> {
+  const info = await request<SessionInfo>('/api/login/2fa', {
+    method: 'POST',
+    body: JSON.stringify({ challenge, code })
+  });
+  applySession(info);
+  return info;
+}
+
+export async function logout(): Promise<void> {
+  try {
+    await request<void>('/api/session/logout', { method: 'POST' }, false);
+  } finally {
+    applySession(null);
+  }
+}
+
+export async function logoutEverywhere(includeTokens = false): Promise<void> {
+  try {
+    await request('/api/session/logout-all', {
+      method: 'POST',
+      body: JSON.stringify({ include_tokens: includeTokens })
+    });
+  } finally {
+    applySession(null);
+  }
+}
+
+export interface AccountInfo {
   user_id: string;
+  email: string;
+  email_verified_at: string | null;
+  has_password: boolean;
+}
+
+export async function fetchAccount(): Promise<AccountInfo> {
+  return request<AccountInfo>('/api/account');
+}
+
+export interface SecurityEvent {
+  id: string;
+  actor_user_id: string | null;
+  token_id: string | null;
+  action: string;
+  target_type: string | null;
+  target_id: string | null;
+  ip: string | null;
+  user_agent: string | null;
+  details: Record<string, unknown> | null;
+  created_at: string;
+}
+
+export async function fetchSecurityEvents(options?: { limit?: number; offset?: number }): Promise<Page<SecurityEvent>> {
+  const params = new URLSearchParams();
+  if (options?.limit) params.set('limit', String(options.limit));
+  if (options?.offset) params.set('offset', String(options.offset));
+  const qs = params.toString();
+  return request<Page<SecurityEvent>>(`/api/account/security-events${qs ? `?${qs}` : ''}`);
+}
+
+/** Everything stored about the account, as one JSON document. */
+export async function exportAccount(): Promise<Record<string, unknown>> {
+  return request<Record<string, unknown>>('/api/account/export');
+}
+
+export async function deleteAccount(payload: {
+  password?: string;
+  code?: string;
+  transfer_public_commands?: boolean;
+}): Promise<{ archived_commands: number }> {
+  try {
+    return await request<{ archived_commands: number }>('/api/account', {
+      method: 'DELETE',
+      body: JSON.stringify(payload)
+    });
+  } finally {
+    applySession(null);
+  }
 }
 
-export async function register(payload: { email: string; password: string; label?: string }): Promise<AuthResponse> {
-  return request<AuthResponse>('/api/register', {
+export async function requestEmailVerification(): Promise<void> {
+  await request('/api/email/verify/request', { method: 'POST' });
+}
+
+export async function verifyEmail(token: string): Promise<void> {
+  await request('/api/email/verify', {
     method: 'POST',
-    body: JSON.stringify(payload)
+    body: JSON.stringify({ token })
   });
 }
 
-export async function login(payload: { email: string; password: string; label?: string }): Promise<AuthResponse> {
-  return request<AuthResponse>('/api/login', {
+export async function forgotPassword(email: string): Promise<void> {
+  await request('/api/password/forgot', {
     method: 'POST',
-    body: JSON.stringify(payload)
+    body: JSON.stringify({ email })
+  });
+}
+
+export async function resetPassword(token: string, password: string): Promise<void> {
+  await request('/api/password/reset', {
+    method: 'POST',
+    body: JSON.stringify({ token, password })
+  });
+}
+
+export interface DeviceRequest {
+  user_code: string;
+  device_name: string;
+  scopes: { name: string; description: string }[];
+  requester_ip: string | null;
+  user_agent: string | null;
+  created_at: string;
+  expires_at: string;
+}
+
+export async function fetchDeviceRequest(userCode: string): Promise<DeviceRequest> {
+  return request<DeviceRequest>(`/api/device/requests/${encodeURIComponent(userCode)}`);
+}
+
+export async function approveDeviceRequest(userCode: string, label?: string): Promise<void> {
+  await request(`/api/device/requests/${encodeURIComponent(userCode)}/approve`, {
+    method: 'POST',
+    body: JSON.stringify({ label })
+  });
+}
+
+export async function denyDeviceRequest(userCode: string): Promise<void> {
+  await request(`/api/device/requests/${encodeURIComponent(userCode)}/deny`, { method: 'POST' });
+}
+
+export interface TwoFactorStatus {
+  enabled: boolean;
+  enabled_at: string | null;
+  recovery_codes_remaining: number;
+  challenged_logins: string[];
+}
+
+export async function fetchTwoFactorStatus(): Promise<TwoFactorStatus> {
+  return request<TwoFactorStatus>('/api/account/2fa');
+}
+
+export async function startTotpEnrollment(): Promise<{ secret: string; otpauth_uri: string }> {
+  return request('/api/account/2fa/totp', { method: 'POST' });
+}
+
+export async function confirmTotpEnrollment(code: string): Promise<string[]> {
+  const res = await request<{ recovery_codes: string[] }>('/api/account/2fa/totp/confirm', {
+    method: 'POST',
+    body: JSON.stringify({ code })
+  });
+  return res.recovery_codes;
+}
+
+export async function regenerateRecoveryCodes(code: string): Promise<string[]> {
+  const res = await request<{ recovery_codes: string[] }>('/api/account/2fa/recovery-codes', {
+    method: 'POST',
+    body: JSON.stringify({ code })
+  });
+  return res.recovery_codes;
+}
+
+export async function disableTwoFactor(code: string): Promise<void> {
+  await request('/api/account/2fa/disable', {
+    method: 'POST',
+    body: JSON.stringify({ code })
   });
 }